@@ -1,44 +1,398 @@
-use crate::parser::encrypt::{as_bulk_str, as_array};
-use crate::parser::{AtomicItem, AggrRESPObject, SimpleRESPObject};
-use crate::server::LaunchConfig;
+use crate::error::{RedisError, Result};
+use crate::parser::{ProtocolVersion, RespValue, ToResp};
+use crate::server::{LaunchConfig, ClientSession, ServerType, REDIS_VERSION};
 
-use std::collections::HashMap;
+use crate::persistence::{unix_time_ms, Entry, Keyspace, RedisStorage, RedisValue};
 
+mod expire;
+pub use expire::{expire, ttl, expire_time, persist, TimeUnit, Deadline};
+mod keyspace;
+pub use keyspace::{del, exists, touch, rename, copy, keys, scan, randomkey, dbsize, flush};
+pub mod list;
+pub mod scan;
+pub mod hash;
+pub mod set;
+pub mod zset;
+pub mod stream;
+pub mod string;
+pub mod bitmap;
+pub mod hyperloglog;
+pub mod geo;
 
-pub fn echo(msg: &[u8]) -> Box<[u8]>{
-   as_bulk_str(Some(msg)) 
+
+/* redis style arity of every routed command, the command name included
+ * a positive arity is exact, a negative one is a lower bound
+ * */
+pub fn arity(cmd: &str) -> Option<i64>{
+    let arity = match cmd {
+        "ping" => -1,
+        "echo" => 2,
+        "set" => -3,
+        "get" => 2,
+        "incr" | "decr" => 2,
+        "incrby" | "decrby" | "incrbyfloat" => 3,
+        "append" => 3,
+        "strlen" => 2,
+        "getrange" => 4,
+        "setrange" => 4,
+        "mget" => -2,
+        "mset" | "msetnx" => -3,
+        "getdel" => 2,
+        "getex" => -2,
+        "lcs" => -3,
+        "setbit" => 4,
+        "getbit" => 3,
+        "bitcount" => -2,
+        "bitpos" => -3,
+        "bitop" => -4,
+        "bitfield" | "bitfield_ro" => -2,
+        "pfadd" | "pfcount" | "pfmerge" => -2,
+        "pfdebug" => -3,
+        "geoadd" => -5,
+        "geopos" | "geohash" => -2,
+        "geodist" => -4,
+        "geosearch" => -7,
+        "geosearchstore" => -8,
+        "del" | "unlink" | "exists" | "touch" => -2,
+        "rename" | "renamenx" => 3,
+        "copy" => -3,
+        "keys" => 2,
+        "scan" => -2,
+        "randomkey" | "dbsize" => 1,
+        "flushdb" | "flushall" => -1,
+        "info" => -1,
+        "replconf" => -1,
+        "hello" => -1,
+        "auth" => -2,
+        "expire" | "pexpire" | "expireat" | "pexpireat" => -3,
+        "ttl" | "pttl" | "expiretime" | "pexpiretime" => 2,
+        "persist" => 2,
+        "type" => 2,
+        "lpush" | "rpush" | "lpushx" | "rpushx" => -3,
+        "lpop" | "rpop" => -2,
+        "llen" => 2,
+        "lrange" | "ltrim" => 4,
+        "lindex" => 3,
+        "lset" | "lrem" => 4,
+        "linsert" => 5,
+        "lpos" => -3,
+        "lmove" => 5,
+        "rpoplpush" => 3,
+        "lmpop" => -4,
+        "blpop" | "brpop" => -3,
+        "blmove" => 6,
+        "brpoplpush" => 4,
+        "blmpop" => -5,
+        "hset" | "hmset" => -4,
+        "hsetnx" => 4,
+        "hget" | "hexists" | "hstrlen" => 3,
+        "hmget" | "hdel" => -3,
+        "hlen" | "hkeys" | "hvals" | "hgetall" => 2,
+        "hincrby" | "hincrbyfloat" => 4,
+        "hrandfield" => -2,
+        "hscan" => -3,
+        "hexpire" | "hpexpire" | "hexpireat" | "hpexpireat" => -6,
+        "httl" | "hpttl" | "hexpiretime" | "hpexpiretime" | "hpersist" => -5,
+        "sadd" | "srem" | "smismember" => -3,
+        "sismember" => 3,
+        "smembers" | "scard" => 2,
+        "smove" => 4,
+        "spop" | "srandmember" => -2,
+        "sinter" | "sunion" | "sdiff" => -2,
+        "sinterstore" | "sunionstore" | "sdiffstore" => -3,
+        "sintercard" => -3,
+        "sscan" => -3,
+        "zadd" => -4,
+        "zincrby" => 4,
+        "zrem" | "zmscore" => -3,
+        "zcard" => 2,
+        "zscore" => 3,
+        "zrank" | "zrevrank" => -3,
+        "zcount" | "zlexcount" => 4,
+        "zrange" | "zrevrange" | "zrangebyscore" | "zrevrangebyscore" | "zrangebylex" | "zrevrangebylex" => -4,
+        "zrangestore" => -5,
+        "zremrangebyrank" | "zremrangebyscore" | "zremrangebylex" => 4,
+        "zunion" | "zinter" | "zdiff" => -3,
+        "zunionstore" | "zinterstore" | "zdiffstore" => -4,
+        "zintercard" => -3,
+        "zrandmember" => -2,
+        "zscan" => -3,
+        "zpopmin" | "zpopmax" => -2,
+        "zmpop" => -4,
+        "bzpopmin" | "bzpopmax" => -3,
+        "bzmpop" => -5,
+        "xadd" => -5,
+        "xlen" => 2,
+        "xrange" | "xrevrange" => -4,
+        "xdel" => -3,
+        "xtrim" => -4,
+        "xread" => -4,
+        "xgroup" => -2,
+        "xreadgroup" => -7,
+        "xack" => -4,
+        "xpending" => -3,
+        "xclaim" => -6,
+        "xautoclaim" => -6,
+        "xinfo" => -2,
+        "client" => -2,
+        _ => return None
+    };
+    Some(arity)
 }
 
-pub fn ping() -> Box<[u8]>{
-    as_bulk_str(Some(b"PONG"))
+/* commands carrying redis' write flag, a master hands every one of them that
+ * succeeded down to its replicas
+ * */
+pub fn is_write(cmd: &str) -> bool{
+    matches!(cmd,
+        "set" | "incr" | "decr" | "incrby" | "decrby" | "incrbyfloat" | "append" | "setrange"
+        | "mset" | "msetnx" | "getdel" | "getex"
+        | "setbit" | "bitop" | "bitfield"
+        | "pfadd" | "pfmerge" | "pfdebug"
+        | "geoadd" | "geosearchstore"
+        | "del" | "unlink" | "rename" | "renamenx" | "copy" | "flushdb" | "flushall"
+        | "expire" | "pexpire" | "expireat" | "pexpireat" | "persist"
+        | "lpush" | "rpush" | "lpushx" | "rpushx" | "lpop" | "rpop" | "ltrim" | "lset" | "lrem" | "linsert"
+        | "lmove" | "rpoplpush" | "lmpop" | "blpop" | "brpop" | "blmove" | "brpoplpush" | "blmpop"
+        | "hset" | "hmset" | "hsetnx" | "hdel" | "hincrby" | "hincrbyfloat"
+        | "hexpire" | "hpexpire" | "hexpireat" | "hpexpireat" | "hpersist"
+        | "sadd" | "srem" | "smove" | "spop" | "sinterstore" | "sunionstore" | "sdiffstore"
+        | "zadd" | "zincrby" | "zrem" | "zrangestore" | "zremrangebyrank" | "zremrangebyscore" | "zremrangebylex"
+        | "zunionstore" | "zinterstore" | "zdiffstore" | "zpopmin" | "zpopmax" | "zmpop"
+        | "bzpopmin" | "bzpopmax" | "bzmpop"
+        | "xadd" | "xdel" | "xtrim" | "xgroup" | "xreadgroup" | "xack" | "xclaim" | "xautoclaim"
+    )
 }
 
 
-pub fn set(
-    key: &[u8], val: &[u8],
-    storage: &mut HashMap<Box<[u8]>, Box<[u8]>>
-) -> Box<[u8]>{
-    let (mut malloced_key, mut malloced_val) = (vec!(), vec!());
-    malloced_key.extend_from_slice(key);
-    malloced_val.extend_from_slice(val);
-    
-    storage.insert(malloced_key.into_boxed_slice(), malloced_val.into_boxed_slice());
-    as_bulk_str(Some(b"OK"))
+/* what a successful write hands down to the replicas. most commands go out as
+ * the client sent them, the ones whose effect hangs on the clock, on randomness
+ * or on when a blocked client got served are replaced by commands replaying
+ * exactly what happened on the master
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Propagate{
+    Verbatim,
+    // sent in place of the client's command, nothing at all when empty
+    Rewritten(Vec<Vec<Vec<u8>>>)
 }
 
-pub fn get(var: &[u8], storage: &HashMap<Box<[u8]>, Box<[u8]>>) -> Box<[u8]>{
-   match storage.get(var) {
-       Some(val) => as_bulk_str(Some(val)),
-       None => as_bulk_str(None)
-   } 
+impl Propagate{
+    pub fn nothing() -> Self{
+        Propagate::Rewritten(vec!())
+    }
+
+    pub fn command(args: &[&[u8]]) -> Self{
+        Propagate::Rewritten(vec!(to_args(args)))
+    }
+}
+
+pub fn to_args(args: &[&[u8]]) -> Vec<Vec<u8>>{
+    args.iter().map(|arg| {arg.to_vec()}).collect()
+}
+
+
+pub fn echo(msg: &[u8]) -> Result<RespValue>{
+   Ok(msg.to_resp())
+}
+
+pub fn ping(params: Vec<&[u8]>) -> Result<RespValue>{
+    match params[..] {
+        [] => Ok(RespValue::SimpleStr(String::from("PONG"))),
+        [msg] => Ok(msg.to_resp()),
+        _ => Err(RedisError::wrong_arity("ping"))
+    }
+}
+
+
+/* integer argument parsing as strict as redis' string2ll
+ * an optional '-' then digits without leading zeros, anything else is rejected
+ * */
+pub fn parse_int(arg: &[u8]) -> Result<i64>{
+    let digits = arg.strip_prefix(b"-").unwrap_or(arg);
+    let well_formed = match digits {
+        [] => false,
+        [b'0'] => digits.len() == arg.len(),
+        [b'0', ..] => false,
+        _ => digits.iter().all(u8::is_ascii_digit)
+    };
+    if !well_formed {return Err(RedisError::NotInteger);}
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| {s.parse::<i64>().ok()})
+        .ok_or(RedisError::NotInteger)
+}
+
+
+// float argument as accepted by strtold, +inf and -inf included but never NaN
+pub fn parse_float(arg: &[u8]) -> Result<f64>{
+    std::str::from_utf8(arg).ok()
+        .filter(|s| {!s.is_empty() && !s.starts_with(char::is_whitespace) && !s.ends_with(char::is_whitespace)})
+        .and_then(|s| {s.parse::<f64>().ok()})
+        .filter(|num| {!num.is_nan()})
+        .ok_or(RedisError::NotFloat)
+}
+
+/* the text INCRBYFLOAT and HINCRBYFLOAT store and reply with
+ * redis adds in long double and prints "%.17Lf" with the trailing zeros cut,
+ * which hides the rounding noise of the addition: 0.1 + 0.2 gives "0.3". an
+ * f64 is only good for 15 significant digits, so those are kept, in plain
+ * notation like redis and never more than 17 decimals
+ * */
+pub fn format_float(num: f64) -> String{
+    let exponent = format!("{num:.14e}").split_once('e').and_then(|(_, exp)| {exp.parse::<i32>().ok()}).unwrap_or(0);
+    let decimals = (14 - exponent).clamp(0, 17) as usize;
+    let mut repr = format!("{num:.decimals$}");
+    if repr.contains('.') {
+        let trimmed = repr.trim_end_matches('0').trim_end_matches('.').len();
+        repr.truncate(trimmed);
+    }
+    if repr == "-0" {repr.remove(0);}
+    repr
+}
+
+// count like arguments that only make sense from zero up
+pub fn parse_positive(arg: &[u8]) -> Result<i64>{
+    let num = parse_int(arg)?;
+    if num < 0 {return Err(RedisError::NotPositive);}
+    Ok(num)
+}
+
+// integer bounded to [min, max], `msg` is the error reported outside of it
+pub fn parse_bounded(arg: &[u8], min: i64, max: i64, msg: &str) -> Result<i64>{
+    let num = parse_int(arg)?;
+    if num < min || num > max {return Err(RedisError::Other(String::from(msg)));}
+    Ok(num)
+}
+
+/* resolve an inclusive [start, stop] pair where negative indexes count from
+ * the end, None when the range selects nothing
+ * */
+pub fn clamp_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)>{
+    let len = len as i64;
+    let start = if start < 0 {(len + start).max(0)} else {start};
+    let stop = if stop < 0 {len + stop} else {stop.min(len - 1)};
+    if start > stop || start >= len {return None;}
+    Some((start as usize, stop as usize))
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SetCondition{
+    Always,
+    // NX
+    IfAbsent,
+    // XX
+    IfPresent
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SetExpiry{
+    // plain SET drops any ttl the key had
+    Clear,
+    KeepTtl,
+    At(u64)
 }
 
+// turn EX/PX/EXAT/PXAT into an absolute unix ms deadline
+fn parse_expiry(option: &[u8], arg: &[u8], cmd: &str) -> Result<u64>{
+    let num = parse_int(arg)?;
+    if num <= 0 {return Err(RedisError::invalid_expire_time(cmd));}
+    let in_ms = match option {
+        b"ex" | b"exat" => num.checked_mul(1000),
+        _ => Some(num)
+    };
+    let deadline = match option {
+        b"ex" | b"px" => in_ms.and_then(|ms| {ms.checked_add(unix_time_ms() as i64)}),
+        _ => in_ms
+    };
+    deadline.map(|ms| {ms as u64}).ok_or_else(|| {RedisError::invalid_expire_time(cmd)})
+}
+
+
+// SET key value [NX | XX] [GET] [EX seconds | PX ms | EXAT unix-s | PXAT unix-ms | KEEPTTL]
+// a relative expiry goes to replicas as PXAT so they land on the same deadline
+pub fn set(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<(RespValue, Propagate)>{
+    let (key, val) = (params[0], params[1]);
+    let (mut condition, mut expiry, mut reply_old) = (SetCondition::Always, SetExpiry::Clear, false);
+    let mut expiry_given = false;
+
+    let mut options = params[2..].iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_lowercase();
+        match option.as_slice() {
+            b"nx" if condition != SetCondition::IfPresent => condition = SetCondition::IfAbsent,
+            b"xx" if condition != SetCondition::IfAbsent => condition = SetCondition::IfPresent,
+            b"get" => reply_old = true,
+            b"keepttl" if !expiry_given => {
+                expiry = SetExpiry::KeepTtl;
+                expiry_given = true;
+            },
+            b"ex" | b"px" | b"exat" | b"pxat" if !expiry_given => {
+                let arg = options.next().ok_or(RedisError::Syntax)?;
+                expiry = SetExpiry::At(parse_expiry(&option, arg, "set")?);
+                expiry_given = true;
+            },
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+
+    let old = storage.get(key);
+    let old_expiry = old.and_then(|entry| {entry.expires_at()});
+    // only GET cares about the type of the value being replaced
+    let old_value = match old {
+        Some(entry) if reply_old => Some(entry.value.as_string()?.clone()),
+        Some(_) => Some(vec!()),
+        None => None
+    };
+    let should_set = match condition {
+        SetCondition::Always => true,
+        SetCondition::IfAbsent => old_value.is_none(),
+        SetCondition::IfPresent => old_value.is_some()
+    };
+
+    if should_set {
+        // keys and values are opaque bytes, no encoding is assumed
+        let expires_at = match expiry {
+            SetExpiry::Clear => None,
+            SetExpiry::KeepTtl => old_expiry,
+            SetExpiry::At(deadline) => Some(deadline)
+        };
+        storage.insert(key, Entry::with_expiry(RedisValue::from(val), expires_at));
+    }
+
+    let propagate = match (should_set, expiry) {
+        (false, _) => Propagate::nothing(),
+        (true, SetExpiry::At(deadline)) => {
+            Propagate::command(&[b"SET", key, val, b"PXAT", deadline.to_string().as_bytes()])
+        },
+        (true, _) => Propagate::Verbatim
+    };
+    let reply = match (reply_old, should_set) {
+        (true, _) => old_value.to_resp(),
+        (false, true) => RespValue::ok(),
+        (false, false) => RespValue::Null
+    };
+    Ok((reply, propagate))
+}
 
-pub fn info(query: &[u8], server_state: &LaunchConfig) -> Box<[u8]>{
-   match query{
-       b"replica" => {
+pub fn get(var: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+   Ok(storage.get_string(var)?.to_resp())
+}
+
+
+// TYPE reports "none" for a missing key rather than nil
+pub fn key_type(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    let name = storage.peek(key).map_or("none", |entry| {entry.value.type_name()});
+    Ok(RespValue::SimpleStr(String::from(name)))
+}
+
+
+pub fn info(params: Vec<&[u8]>, server_state: &LaunchConfig) -> Result<RespValue>{
+   let section = params.first().map(|s| {s.to_ascii_lowercase()});
+   match section.as_deref(){
+       None | Some(b"replication" | b"replica") => {
            match server_state.replicaof {
-               Some(_) => as_bulk_str(Some(b"role:slave")),
+               Some(_) => Ok("role:slave".to_resp()),
                None => {
                   let random_seed = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
                   let response = vec!(
@@ -46,16 +400,145 @@ pub fn info(query: &[u8], server_state: &LaunchConfig) -> Box<[u8]>{
                       format!("master_replid:{random_seed}"),
                       String::from("master_repl_offset:0")
                   );
-                  as_array(response)
+                  Ok(response.to_resp())
                }
            } 
        },
-       _ => as_bulk_str(None) 
+       _ => Ok(RespValue::Null)
    } 
 }
 
 
-pub fn replconf(params: Vec<&[u8]>) -> Box<[u8]>{
-    as_bulk_str(Some(b"Ok"))
+pub fn replconf(_params: Vec<&[u8]>) -> Result<RespValue>{
+    Ok(RespValue::ok())
 }
 
+
+// only the default user exists, it accepts any password unless requirepass is set
+fn check_credentials(username: &[u8], password: &[u8], server_state: &LaunchConfig) -> bool{
+    username == b"default" && match &server_state.requirepass {
+        Some(requirepass) => requirepass.as_bytes() == password,
+        None => true
+    }
+}
+
+pub fn auth(params: Vec<&[u8]>, session: &mut ClientSession, server_state: &LaunchConfig) -> Result<RespValue>{
+    let (username, password): (&[u8], &[u8]) = match params[..] {
+        [password] => {
+            if server_state.requirepass.is_none() {
+                return Err(RedisError::Other(String::from("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?")));
+            }
+            (b"default", password)
+        },
+        [username, password] => (username, password),
+        _ => return Err(RedisError::Syntax)
+    };
+    if !check_credentials(username, password, server_state) {
+        return Err(RedisError::WrongPass);
+    }
+    session.authenticated = true;
+    Ok(RespValue::ok())
+}
+
+
+/* HELLO [protover [AUTH username password] [SETNAME clientname]]
+ * switches the connection protocol and replies with a map describing the server
+ * */
+fn validate_client_name(name: &[u8]) -> Result<Vec<u8>>{
+    if name.iter().any(|c| {*c <= b' ' || *c > b'~'}) {
+        return Err(RedisError::Other(String::from("Client names cannot contain spaces, newlines or special characters.")));
+    }
+    Ok(name.to_vec())
+}
+
+pub fn hello(params: Vec<&[u8]>, session: &mut ClientSession, server_state: &LaunchConfig) -> Result<RespValue>{
+    let mut params = params.into_iter();
+    let protocol = match params.next() {
+        None => session.protocol,
+        Some(b"2") => ProtocolVersion::Resp2,
+        Some(b"3") => ProtocolVersion::Resp3,
+        Some(_) => return Err(RedisError::NoProto)
+    };
+
+    let (mut authenticated, mut name) = (session.authenticated, None);
+    while let Some(option) = params.next() {
+        if option.eq_ignore_ascii_case(b"auth") {
+            let (Some(username), Some(password)) = (params.next(), params.next()) else {
+                return Err(RedisError::Other(String::from("Syntax error in HELLO option 'auth'")));
+            };
+            if !check_credentials(username, password, server_state) {
+                return Err(RedisError::WrongPass);
+            }
+            authenticated = true;
+        }else if option.eq_ignore_ascii_case(b"setname") {
+            let Some(clientname) = params.next() else {
+                return Err(RedisError::Other(String::from("Syntax error in HELLO option 'setname'")));
+            };
+            name = Some(validate_client_name(clientname)?);
+        }else {
+            return Err(RedisError::Other(format!("Syntax error in HELLO option '{}'", String::from_utf8_lossy(option))));
+        }
+    }
+    if !authenticated {
+        return Err(RedisError::NoAuth("HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"));
+    }
+
+    session.authenticated = true;
+    session.protocol = protocol;
+    if name.is_some() {session.name = name;}
+
+    let role = match server_state.server_type {
+        ServerType::Master => "master",
+        ServerType::Slave => "replica"
+    };
+    let proto_num = match protocol {
+        ProtocolVersion::Resp2 => 2,
+        ProtocolVersion::Resp3 => 3
+    };
+    Ok(RespValue::Map(vec!(
+        ("server".to_resp(), "redis".to_resp()),
+        ("version".to_resp(), REDIS_VERSION.to_resp()),
+        ("proto".to_resp(), proto_num.to_resp()),
+        ("id".to_resp(), session.id.to_resp()),
+        ("mode".to_resp(), "standalone".to_resp()),
+        ("role".to_resp(), role.to_resp()),
+        ("modules".to_resp(), RespValue::Array(vec!()))
+    )))
+}
+
+
+// CLIENT ID | GETNAME | SETNAME name | UNBLOCK id [TIMEOUT | ERROR]
+pub fn client(params: Vec<&[u8]>, session: &mut ClientSession, storage: &RedisStorage) -> Result<RespValue>{
+    let sub = params[0].to_ascii_lowercase();
+    match (sub.as_slice(), &params[1..]) {
+        (b"id", []) => Ok(session.id.to_resp()),
+        (b"getname", []) => Ok(session.name.clone().to_resp()),
+        (b"setname", [name]) => {
+            let name = validate_client_name(name)?;
+            session.name = (!name.is_empty()).then_some(name);
+            Ok(RespValue::ok())
+        },
+        (b"unblock", [client_id, rest @ ..]) if rest.len() <= 1 => {
+            let client_id = parse_int(client_id)?;
+            let reply = match rest.first().map(|mode| {mode.to_ascii_lowercase()}).as_deref() {
+                None | Some(b"timeout") => None,
+                Some(b"error") => Some(RedisError::Unblocked.to_resp()),
+                Some(_) => return Err(RedisError::Other(String::from("CLIENT UNBLOCK reason should be TIMEOUT or ERROR")))
+            };
+            let waiter = storage.lock().blocked.unblock(client_id as u64);
+            match waiter {
+                Some(waiter) => {
+                    let _ = waiter.reply.send((reply.unwrap_or(waiter.timeout_reply), Propagate::nothing()));
+                    Ok(1.to_resp())
+                },
+                None => Ok(0.to_resp())
+            }
+        },
+        (b"id" | b"getname" | b"setname" | b"unblock", _) => {
+            Err(RedisError::Other(format!(
+                "wrong number of arguments for 'client|{}' command", String::from_utf8_lossy(&sub)
+            )))
+        },
+        _ => Err(RedisError::unknown_subcommand("client", params[0]))
+    }
+}
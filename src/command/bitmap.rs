@@ -0,0 +1,384 @@
+/* bit level commands over string values
+ * bit 0 is the most significant bit of the first byte, the order redis uses.
+ * reads past the end of the string see zeros, writes grow it with zero bytes,
+ * so a missing key is the same as an empty string until something is set
+ * */
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{Entry, Keyspace, RedisValue};
+
+use super::parse_int;
+
+
+fn get_bitmap<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<&'a [u8]>{
+    Ok(storage.get_string(key)?.map_or(&[][..], |value| {value.as_slice()}))
+}
+
+// the string under the key grown to at least `len` bytes, created when missing
+fn get_bitmap_mut<'a>(storage: &'a mut Keyspace, key: &[u8], len: usize) -> Result<&'a mut Vec<u8>>{
+    storage.get_string(key)?;
+    let value = storage.get_or_insert_with(key, || {RedisValue::String(vec!())}).value.as_string_mut()?;
+    if value.len() < len {value.resize(len, 0);}
+    Ok(value)
+}
+
+fn get_bit(bitmap: &[u8], offset: u64) -> u8{
+    bitmap.get((offset / 8) as usize).map_or(0, |byte| {(byte >> (7 - offset % 8)) & 1})
+}
+
+// the bitmap must already hold the byte
+fn set_bit(bitmap: &mut [u8], offset: u64, bit: u8){
+    let mask = 1 << (7 - offset % 8);
+    let byte = &mut bitmap[(offset / 8) as usize];
+    if bit == 1 {*byte |= mask;} else {*byte &= !mask;}
+}
+
+/* bit offsets are limited to what fits in a string of `max_len` bytes, the
+ * configured proto-max-bulk-len. BITFIELD also takes #n, meaning the n-th field
+ * of `width` bits
+ * */
+fn parse_bit_offset(arg: &[u8], width: Option<u32>, max_len: usize) -> Result<u64>{
+    let invalid = || {RedisError::Other(String::from("bit offset is not an integer or out of range"))};
+    let (arg, multiplier) = match (arg, width) {
+        ([b'#', rest @ ..], Some(width)) => (rest, width as i64),
+        _ => (arg, 1)
+    };
+    let offset = parse_int(arg).ok().and_then(|offset| {offset.checked_mul(multiplier)}).ok_or_else(invalid)?;
+    if offset < 0 || (offset as u64) >> 3 >= max_len as u64 {return Err(invalid());}
+    Ok(offset as u64)
+}
+
+fn byte_len_for(offset: u64, width: u64) -> usize{
+    ((offset + width - 1) / 8 + 1) as usize
+}
+
+
+pub fn setbit(params: Vec<&[u8]>, max_len: usize, storage: &mut Keyspace) -> Result<RespValue>{
+    let offset = parse_bit_offset(params[1], None, max_len)?;
+    let bit = match params[2] {
+        b"0" => 0,
+        b"1" => 1,
+        _ => return Err(RedisError::Other(String::from("bit is not an integer or out of range")))
+    };
+    let bitmap = get_bitmap_mut(storage, params[0], byte_len_for(offset, 1))?;
+    let old = get_bit(bitmap, offset);
+    set_bit(bitmap, offset, bit);
+    Ok((old as i64).to_resp())
+}
+
+pub fn getbit(params: Vec<&[u8]>, max_len: usize, storage: &mut Keyspace) -> Result<RespValue>{
+    let offset = parse_bit_offset(params[1], None, max_len)?;
+    Ok((get_bit(get_bitmap(storage, params[0])?, offset) as i64).to_resp())
+}
+
+
+/* start and end of BITCOUNT and BITPOS, in bytes unless BIT is given. negative
+ * values count from the end and both ends are clamped into the string, unlike
+ * GETRANGE a range entirely before the start still selects the first unit.
+ * returns the selected bits as an inclusive range, None when it is empty
+ * */
+fn resolve_bit_range(start: i64, end: i64, bit_mode: bool, len: usize) -> Option<(u64, u64)>{
+    let total = if bit_mode {len as i64 * 8} else {len as i64};
+    let start = if start < 0 {(total + start).max(0)} else {start};
+    let end = if end < 0 {(total + end).max(0)} else {end};
+    let end = end.min(total - 1);
+    if start > end {return None;}
+    Some(match bit_mode {
+        true => (start as u64, end as u64),
+        false => (start as u64 * 8, end as u64 * 8 + 7)
+    })
+}
+
+fn parse_range_mode(arg: Option<&&[u8]>) -> Result<bool>{
+    match arg.map(|mode| {mode.to_ascii_lowercase()}).as_deref() {
+        None | Some(b"byte") => Ok(false),
+        Some(b"bit") => Ok(true),
+        Some(_) => Err(RedisError::Syntax)
+    }
+}
+
+// BITCOUNT key [start end [BYTE | BIT]]
+pub fn bitcount(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let range = match &params[1..] {
+        [] => None,
+        [start, end, mode @ ..] if mode.len() <= 1 => {
+            Some((parse_int(start)?, parse_int(end)?, parse_range_mode(mode.first())?))
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    let bitmap = get_bitmap(storage, params[0])?;
+    let (start, end) = match range {
+        None if bitmap.is_empty() => return Ok(0.to_resp()),
+        None => (0, bitmap.len() as u64 * 8 - 1),
+        Some((start, end, bit_mode)) => match resolve_bit_range(start, end, bit_mode, bitmap.len()) {
+            Some(range) => range,
+            None => return Ok(0.to_resp())
+        }
+    };
+    // whole bytes in the middle are counted a byte at a time
+    let (first_byte, last_byte) = (start.div_ceil(8), (end + 1) / 8);
+    let count_bits = |offsets: &mut dyn Iterator<Item = u64>| {offsets.map(|offset| {get_bit(bitmap, offset) as u64}).sum::<u64>()};
+    let count = match first_byte < last_byte {
+        true => {
+            let middle = &bitmap[first_byte as usize..last_byte as usize];
+            count_bits(&mut (start..first_byte * 8).chain(last_byte * 8..=end))
+                + middle.iter().map(|byte| {byte.count_ones() as u64}).sum::<u64>()
+        },
+        false => count_bits(&mut (start..=end))
+    };
+    Ok(count.to_resp())
+}
+
+// BITPOS key bit [start [end [BYTE | BIT]]]
+pub fn bitpos(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let bit = match params[1] {
+        b"0" => 0,
+        b"1" => 1,
+        _ => return Err(RedisError::Other(String::from("The bit argument must be 1 or 0.")))
+    };
+    let (start, end, bit_mode) = match &params[2..] {
+        [] => (0, None, false),
+        [start] => (parse_int(start)?, None, false),
+        [start, end, mode @ ..] if mode.len() <= 1 => {
+            (parse_int(start)?, Some(parse_int(end)?), parse_range_mode(mode.first())?)
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    let end_given = end.is_some();
+    let bitmap = get_bitmap(storage, params[0])?;
+    if bitmap.is_empty() {
+        return Ok(if bit == 1 {-1} else {0}.to_resp());
+    }
+    let (start, end) = match resolve_bit_range(start, end.unwrap_or(-1), bit_mode, bitmap.len()) {
+        Some(range) => range,
+        None => return Ok((-1).to_resp())
+    };
+    // bytes made only of the other bit are skipped whole
+    let skip = if bit == 1 {0x00} else {0xff};
+    let mut offset = start;
+    while offset <= end {
+        if offset.is_multiple_of(8) && offset + 7 <= end && bitmap[(offset / 8) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if get_bit(bitmap, offset) == bit {return Ok((offset as i64).to_resp());}
+        offset += 1;
+    }
+    // without an explicit end the string is seen as padded with clear bits
+    Ok(match bit == 0 && !end_given {
+        true => ((end + 1) as i64).to_resp(),
+        false => (-1).to_resp()
+    })
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BitOp{
+    And,
+    Or,
+    Xor,
+    Not,
+    // bits of the first key set in none of the others
+    Diff,
+    // bits set in exactly one of the keys
+    One
+}
+
+// BITOP AND | OR | XOR | NOT | DIFF | ONE destkey key [key ...]
+pub fn bitop(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let op = match params[0].to_ascii_lowercase().as_slice() {
+        b"and" => BitOp::And,
+        b"or" => BitOp::Or,
+        b"xor" => BitOp::Xor,
+        b"not" => BitOp::Not,
+        b"diff" => BitOp::Diff,
+        b"one" => BitOp::One,
+        _ => return Err(RedisError::Syntax)
+    };
+    let (dest, keys) = (params[1], &params[2..]);
+    if op == BitOp::Not && keys.len() != 1 {
+        return Err(RedisError::Other(String::from("BITOP NOT must be called with a single source key.")));
+    }
+    if op == BitOp::Diff && keys.len() < 2 {
+        return Err(RedisError::Other(String::from("BITOP DIFF must be called with at least two source keys.")));
+    }
+    let mut sources = Vec::with_capacity(keys.len());
+    for key in keys {
+        sources.push(get_bitmap(storage, key)?.to_vec());
+    }
+
+    // shorter strings are zero padded up to the longest one
+    let len = sources.iter().map(|source| {source.len()}).max().unwrap_or(0);
+    let byte = |source: &Vec<u8>, idx: usize| {source.get(idx).copied().unwrap_or(0)};
+    let result = (0..len).map(|idx| {
+        let mut bytes = sources.iter().map(|source| {byte(source, idx)});
+        let first = bytes.next().unwrap();
+        match op {
+            BitOp::And => bytes.fold(first, |acc, byte| {acc & byte}),
+            BitOp::Or => bytes.fold(first, |acc, byte| {acc | byte}),
+            BitOp::Xor => bytes.fold(first, |acc, byte| {acc ^ byte}),
+            BitOp::Not => !first,
+            BitOp::Diff => first & !bytes.fold(0, |acc, byte| {acc | byte}),
+            BitOp::One => {
+                let (once, many) = bytes.fold((first, 0), |(once, many), byte| {(once | byte, many | (once & byte))});
+                once & !many
+            }
+        }
+    }).collect::<Vec<_>>();
+
+    match len {
+        0 => {storage.remove(dest);},
+        _ => {storage.insert(dest, Entry::new(RedisValue::String(result)));}
+    }
+    Ok(len.to_resp())
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Overflow{
+    Wrap,
+    Sat,
+    Fail
+}
+
+#[derive(Clone, Copy)]
+struct FieldType{
+    signed: bool,
+    bits: u32
+}
+
+impl FieldType{
+    // i1 to i64 and u1 to u63, a u64 could not be returned as a redis integer
+    fn parse(arg: &[u8]) -> Result<Self>{
+        let invalid = || {RedisError::Other(String::from(
+            "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is."
+        ))};
+        let (signed, bits) = match arg {
+            [b'i' | b'I', bits @ ..] => (true, bits),
+            [b'u' | b'U', bits @ ..] => (false, bits),
+            _ => return Err(invalid())
+        };
+        let bits = parse_int(bits).map_err(|_| {invalid()})?;
+        let max = if signed {64} else {63};
+        if !(1..=max).contains(&bits) {return Err(invalid());}
+        Ok(Self{signed, bits: bits as u32})
+    }
+
+    fn range(&self) -> (i128, i128){
+        match self.signed {
+            true => (-(1i128 << (self.bits - 1)), (1i128 << (self.bits - 1)) - 1),
+            false => (0, (1i128 << self.bits) - 1)
+        }
+    }
+
+    fn read(&self, bitmap: &[u8], offset: u64) -> i64{
+        let raw = (0..self.bits as u64).fold(0u64, |acc, idx| {(acc << 1) | get_bit(bitmap, offset + idx) as u64});
+        match self.signed && self.bits < 64 && raw >> (self.bits - 1) & 1 == 1 {
+            // sign extend
+            true => (raw | (u64::MAX << self.bits)) as i64,
+            false => raw as i64
+        }
+    }
+
+    fn write(&self, bitmap: &mut [u8], offset: u64, value: i64){
+        let value = value as u64;
+        for idx in 0..self.bits as u64 {
+            set_bit(bitmap, offset + idx, ((value >> (self.bits as u64 - 1 - idx)) & 1) as u8);
+        }
+    }
+
+    // what `value` becomes in this field, None when FAIL rejects it
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64>{
+        let (min, max) = self.range();
+        if (min..=max).contains(&value) {return Some(value as i64);}
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(value.clamp(min, max) as i64),
+            Overflow::Wrap => {
+                let span = 1i128 << self.bits;
+                let wrapped = value.rem_euclid(span);
+                Some(if self.signed && wrapped > max {wrapped - span} else {wrapped} as i64)
+            }
+        }
+    }
+}
+
+enum FieldOp{
+    Get,
+    Set(i64),
+    IncrBy(i64)
+}
+
+/* BITFIELD key [GET type offset] [SET type offset value] [INCRBY type offset increment]
+ *          [OVERFLOW WRAP | SAT | FAIL] ...
+ * OVERFLOW applies to the SET and INCRBY after it. BITFIELD_RO only takes GET
+ * */
+pub fn bitfield(params: Vec<&[u8]>, read_only: bool, max_len: usize, storage: &mut Keyspace) -> Result<RespValue>{
+    let mut ops = vec!();
+    let mut overflow = Overflow::Wrap;
+    let mut idx = 1;
+    while idx < params.len() {
+        let sub = params[idx].to_ascii_lowercase();
+        let arg = |offset: usize| {params.get(idx + offset).copied().ok_or(RedisError::Syntax)};
+        if sub == b"overflow" {
+            overflow = match arg(1)?.to_ascii_lowercase().as_slice() {
+                b"wrap" => Overflow::Wrap,
+                b"sat" => Overflow::Sat,
+                b"fail" => Overflow::Fail,
+                _ => return Err(RedisError::Other(String::from("Invalid OVERFLOW type specified")))
+            };
+            idx += 2;
+            continue;
+        }
+        let (field, offset) = match sub.as_slice() {
+            b"get" | b"set" | b"incrby" => {
+                let field = FieldType::parse(arg(1)?)?;
+                (field, parse_bit_offset(arg(2)?, Some(field.bits), max_len)?)
+            },
+            _ => return Err(RedisError::Syntax)
+        };
+        let op = match sub.as_slice() {
+            b"get" => FieldOp::Get,
+            b"set" => FieldOp::Set(parse_int(arg(3)?)?),
+            _ => FieldOp::IncrBy(parse_int(arg(3)?)?)
+        };
+        if read_only && !matches!(op, FieldOp::Get) {
+            return Err(RedisError::Other(String::from("BITFIELD_RO only supports the GET subcommand")));
+        }
+        idx += if matches!(op, FieldOp::Get) {3} else {4};
+        ops.push((field, offset, op, overflow));
+    }
+
+    // the string is grown for every write up front, even ones FAIL turns down
+    let grow_to = ops.iter()
+                    .filter(|(_, _, op, _)| {!matches!(op, FieldOp::Get)})
+                    .map(|(field, offset, _, _)| {byte_len_for(*offset, field.bits as u64)})
+                    .max();
+    let bitmap = match grow_to {
+        Some(len) => get_bitmap_mut(storage, params[0], len)?,
+        None => {
+            let bitmap = get_bitmap(storage, params[0])?;
+            let replies = ops.iter().map(|(field, offset, _, _)| {field.read(bitmap, *offset).to_resp()}).collect();
+            return Ok(RespValue::Array(replies));
+        }
+    };
+    let replies = ops.into_iter().map(|(field, offset, op, overflow)| {
+        let current = field.read(bitmap, offset);
+        let (updated, reply) = match op {
+            FieldOp::Get => return current.to_resp(),
+            // unsigned SET takes the argument as the 64 bit pattern it is
+            FieldOp::Set(value) if !field.signed => (field.fit(value as u64 as i128, overflow), Some(current)),
+            FieldOp::Set(value) => (field.fit(value as i128, overflow), Some(current)),
+            FieldOp::IncrBy(incr) => (field.fit(current as i128 + incr as i128, overflow), None)
+        };
+        match updated {
+            Some(updated) => {
+                field.write(bitmap, offset, updated);
+                reply.unwrap_or(updated).to_resp()
+            },
+            None => RespValue::Null
+        }
+    }).collect();
+    Ok(RespValue::Array(replies))
+}
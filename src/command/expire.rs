@@ -0,0 +1,148 @@
+/* commands reading or changing the deadline of a key
+ * every deadline is kept as absolute unix milliseconds, the second based
+ * variants only convert on the way in and out
+ * */
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{unix_time_ms, Keyspace};
+
+use super::{parse_int, Propagate};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit{
+    Seconds,
+    Milliseconds
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Deadline{
+    // EXPIRE and PEXPIRE, counted from now
+    Relative,
+    // EXPIREAT and PEXPIREAT, a unix timestamp
+    Absolute
+}
+
+#[derive(Default)]
+pub struct ExpireFlags{
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool
+}
+
+impl ExpireFlags{
+    // whether `when` may replace the current deadline, no deadline counts as infinite for GT and LT
+    pub fn accepts(&self, current: Option<u64>, when: i64) -> bool{
+        match current {
+            Some(_) if self.nx => false,
+            None if self.xx || self.gt => false,
+            Some(deadline) if self.gt => when > deadline as i64,
+            Some(deadline) if self.lt => when < deadline as i64,
+            _ => true
+        }
+    }
+}
+
+pub fn parse_expire_flags(options: &[&[u8]]) -> Result<ExpireFlags>{
+    let mut flags = ExpireFlags::default();
+    for option in options {
+        match option.to_ascii_lowercase().as_slice() {
+            b"nx" => flags.nx = true,
+            b"xx" => flags.xx = true,
+            b"gt" => flags.gt = true,
+            b"lt" => flags.lt = true,
+            _ => return Err(RedisError::Other(format!("Unsupported option {}", String::from_utf8_lossy(option))))
+        }
+    }
+    if flags.nx && (flags.xx || flags.gt || flags.lt) {
+        return Err(RedisError::Other(String::from("NX and XX, GT or LT options at the same time are not compatible")));
+    }
+    if flags.gt && flags.lt {
+        return Err(RedisError::Other(String::from("GT and LT options at the same time are not compatible")));
+    }
+    Ok(flags)
+}
+
+
+// absolute unix ms deadline out of a command argument, overflow is an invalid expire time
+pub fn resolve_deadline(cmd: &str, when: i64, unit: TimeUnit, base: Deadline) -> Result<i64>{
+    let when = match unit {
+        TimeUnit::Seconds => when.checked_mul(1000),
+        TimeUnit::Milliseconds => Some(when)
+    };
+    match base {
+        Deadline::Relative => when.and_then(|ms| {ms.checked_add(unix_time_ms() as i64)}),
+        Deadline::Absolute => when
+    }.ok_or_else(|| {RedisError::invalid_expire_time(cmd)})
+}
+
+
+/* EXPIRE key seconds [NX | XX | GT | LT] and its PEXPIRE, EXPIREAT, PEXPIREAT siblings
+ * replicas get the outcome, PEXPIREAT with the absolute deadline or DEL when it already passed
+ * */
+pub fn expire(
+    cmd: &str, params: Vec<&[u8]>, unit: TimeUnit, base: Deadline, storage: &mut Keyspace
+) -> Result<(RespValue, Propagate)>{
+    let key = params[0];
+    let flags = parse_expire_flags(&params[2..])?;
+    let when = resolve_deadline(cmd, parse_int(params[1])?, unit, base)?;
+    let now = unix_time_ms() as i64;
+
+    let current = match storage.get(key) {
+        Some(entry) => entry.expires_at(),
+        None => return Ok((0.to_resp(), Propagate::nothing()))
+    };
+    if !flags.accepts(current, when) {return Ok((0.to_resp(), Propagate::nothing()));}
+
+    // a deadline already behind us deletes the key right away
+    let propagate = if when <= now {
+        storage.remove(key);
+        Propagate::command(&[b"DEL", key])
+    }else {
+        storage.set_expiry(key, Some(when as u64));
+        Propagate::command(&[b"PEXPIREAT", key, when.to_string().as_bytes()])
+    };
+    Ok((1.to_resp(), propagate))
+}
+
+
+// TTL and PTTL: -2 for a missing key, -1 for a key without deadline
+pub fn ttl(key: &[u8], unit: TimeUnit, storage: &mut Keyspace) -> Result<RespValue>{
+    let remaining = match storage.peek(key) {
+        None => return Ok((-2).to_resp()),
+        Some(entry) => match entry.expires_at() {
+            None => return Ok((-1).to_resp()),
+            Some(deadline) => deadline.saturating_sub(unix_time_ms()) as i64
+        }
+    };
+    match unit {
+        // rounded to the closest second like redis does
+        TimeUnit::Seconds => Ok(((remaining + 500) / 1000).to_resp()),
+        TimeUnit::Milliseconds => Ok(remaining.to_resp())
+    }
+}
+
+// EXPIRETIME and PEXPIRETIME, the absolute deadline as a unix timestamp
+pub fn expire_time(key: &[u8], unit: TimeUnit, storage: &mut Keyspace) -> Result<RespValue>{
+    let deadline = match storage.peek(key) {
+        None => return Ok((-2).to_resp()),
+        Some(entry) => match entry.expires_at() {
+            None => return Ok((-1).to_resp()),
+            Some(deadline) => deadline as i64
+        }
+    };
+    match unit {
+        TimeUnit::Seconds => Ok((deadline / 1000).to_resp()),
+        TimeUnit::Milliseconds => Ok(deadline.to_resp())
+    }
+}
+
+pub fn persist(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    let has_deadline = storage.get(key).is_some_and(|entry| {entry.expires_at().is_some()});
+    if has_deadline {
+        storage.set_expiry(key, None);
+    }
+    Ok((has_deadline as i64).to_resp())
+}
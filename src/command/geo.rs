@@ -0,0 +1,323 @@
+/* geo commands over sorted sets
+ * a point is a member scored with its 52 bit geohash, so everything ZSET does
+ * works on a geo index too. searches scan the score ranges of the cells around
+ * the center and keep what actually falls inside the shape. distances go out
+ * with 4 decimals in the unit asked for, coordinates with full precision
+ * */
+
+mod geohash;
+
+use crate::error::{RedisError, Result};
+use crate::parser::{ProtocolVersion, RespValue, ToResp};
+use crate::persistence::{Entry, Keyspace, RedisValue, SortedSet};
+use crate::persistence::value::ScoreRange;
+
+use self::geohash::{Shape, ShapeKind};
+use super::{parse_float, parse_int};
+use super::zset;
+
+
+fn get_zset<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a SortedSet>>{
+    storage.get(key).map(|entry| {entry.value.as_zset()}).transpose()
+}
+
+// meters per unit
+fn parse_unit(arg: &[u8]) -> Result<f64>{
+    match arg.to_ascii_lowercase().as_slice() {
+        b"m" => Ok(1.0),
+        b"km" => Ok(1000.0),
+        b"ft" => Ok(0.3048),
+        b"mi" => Ok(1609.34),
+        _ => Err(RedisError::Other(String::from("unsupported unit provided. please use M, KM, FT, MI")))
+    }
+}
+
+fn parse_coords(lon: &[u8], lat: &[u8]) -> Result<(f64, f64)>{
+    let (lon, lat) = (parse_float(lon)?, parse_float(lat)?);
+    if !geohash::valid_coords(lon, lat) {
+        return Err(RedisError::Other(format!("invalid longitude,latitude pair {lon:.6},{lat:.6}")));
+    }
+    Ok((lon, lat))
+}
+
+fn parse_size(arg: &[u8], what: &str) -> Result<f64>{
+    parse_float(arg).map_err(|_| {RedisError::Other(format!("need numeric {what}"))})
+}
+
+fn member_coords(zset: &SortedSet, member: &[u8]) -> Option<(f64, f64)>{
+    zset.score(member).map(geohash::decode_score)
+}
+
+fn distance_reply(meters: f64, conversion: f64) -> RespValue{
+    format!("{:.4}", meters / conversion).to_resp()
+}
+
+/* full precision without the trailing zeros, what redis prints for coordinates.
+ * RESP3 clients get them as doubles
+ * */
+fn coord_reply(coord: f64, proto: ProtocolVersion) -> RespValue{
+    if proto == ProtocolVersion::Resp3 {return RespValue::Double(coord);}
+    let repr = format!("{coord:.17}");
+    let repr = repr.trim_end_matches('0').trim_end_matches('.');
+    if repr == "-0" {"0".to_resp()} else {repr.to_resp()}
+}
+
+fn coords_reply((lon, lat): (f64, f64), proto: ProtocolVersion) -> RespValue{
+    RespValue::Array(vec!(coord_reply(lon, proto), coord_reply(lat, proto)))
+}
+
+
+// GEOADD key [NX | XX] [CH] longitude latitude member [longitude latitude member ...]
+pub fn geoadd(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let options = params[1..].iter()
+                    .take_while(|arg| {matches!(arg.to_ascii_lowercase().as_slice(), b"nx" | b"xx" | b"ch")})
+                    .count();
+    let flags = &params[1..1 + options];
+    let has = |flag: &[u8]| {flags.iter().any(|arg| {arg.eq_ignore_ascii_case(flag)})};
+    let points = &params[1 + options..];
+    if !points.len().is_multiple_of(3) || (has(b"nx") && has(b"xx")) {return Err(RedisError::Syntax);}
+
+    // every pair is validated before anything is added
+    let scores = points.chunks(3)
+                    .map(|point| {
+                        let (lon, lat) = parse_coords(point[0], point[1])?;
+                        Ok(geohash::encode(geohash::WGS84, lon, lat, geohash::STEP_MAX).unwrap().bits.to_string())
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+    let mut zadd_params = vec!(params[0]);
+    zadd_params.extend(flags);
+    for (point, score) in points.chunks(3).zip(&scores) {
+        zadd_params.extend([score.as_bytes(), point[2]]);
+    }
+    zset::zadd(zadd_params, storage)
+}
+
+pub fn geopos(params: Vec<&[u8]>, proto: ProtocolVersion, storage: &mut Keyspace) -> Result<RespValue>{
+    let zset = get_zset(storage, params[0])?;
+    let positions = params[1..].iter()
+                    .map(|member| {
+                        match zset.and_then(|zset| {member_coords(zset, member)}) {
+                            Some(coords) => coords_reply(coords, proto),
+                            None => RespValue::NullArray
+                        }
+                    })
+                    .collect();
+    Ok(RespValue::Array(positions))
+}
+
+// GEODIST key member1 member2 [M | KM | FT | MI]
+pub fn geodist(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let conversion = match &params[3..] {
+        [] => 1.0,
+        [unit] => parse_unit(unit)?,
+        _ => return Err(RedisError::Syntax)
+    };
+    let zset = match get_zset(storage, params[0])? {
+        Some(zset) => zset,
+        None => return Ok(RespValue::Null)
+    };
+    match (member_coords(zset, params[1]), member_coords(zset, params[2])) {
+        (Some((lon1, lat1)), Some((lon2, lat2))) => Ok(distance_reply(geohash::distance(lon1, lat1, lon2, lat2), conversion)),
+        _ => Ok(RespValue::Null)
+    }
+}
+
+// standard 11 character geohash strings, re-encoded over the -90..90 latitude range
+pub fn geohash(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    const ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+    let zset = get_zset(storage, params[0])?;
+    let hashes = params[1..].iter()
+                    .map(|member| {
+                        let (lon, lat) = zset.and_then(|zset| {member_coords(zset, member)})?;
+                        let bits = geohash::encode(geohash::STANDARD, lon, lat, geohash::STEP_MAX)?.bits;
+                        // only 52 bits for 11 characters, the last one is always 0
+                        let hash = (0..11)
+                                    .map(|idx| {
+                                        let symbol = if idx == 10 {0} else {(bits >> (52 - (idx + 1) * 5)) & 0x1f};
+                                        ALPHABET[symbol as usize]
+                                    })
+                                    .collect::<Vec<_>>();
+                        Some(hash)
+                    })
+                    .collect::<Vec<_>>();
+    Ok(hashes.to_resp())
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sort{
+    None,
+    Asc,
+    Desc
+}
+
+struct SearchArgs{
+    shape: Shape,
+    sort: Sort,
+    count: Option<usize>,
+    any: bool,
+    withdist: bool,
+    withhash: bool,
+    withcoord: bool,
+    storedist: bool
+}
+
+struct GeoPoint{
+    member: Vec<u8>,
+    score: f64,
+    coords: (f64, f64),
+    dist: f64
+}
+
+/* the GEOSEARCH options after the key. the source zset is needed to resolve
+ * FROMMEMBER, a missing key has no members so like redis that fails to decode
+ * */
+fn parse_search_args(cmd: &str, store: bool, options: &[&[u8]], zset: Option<&SortedSet>) -> Result<SearchArgs>{
+    let mut args = SearchArgs{
+        shape: Shape{lon: 0.0, lat: 0.0, conversion: 1.0, kind: ShapeKind::Radius(0.0)},
+        sort: Sort::None, count: None, any: false,
+        withdist: false, withhash: false, withcoord: false, storedist: false
+    };
+    let (mut from_member, mut from_lonlat, mut by_radius, mut by_box) = (false, false, false, false);
+    let mut idx = 0;
+    while let Some(option) = options.get(idx) {
+        let remaining = options.len() - idx - 1;
+        match option.to_ascii_lowercase().as_slice() {
+            b"withdist" => args.withdist = true,
+            b"withhash" => args.withhash = true,
+            b"withcoord" => args.withcoord = true,
+            b"any" => args.any = true,
+            b"asc" => args.sort = Sort::Asc,
+            b"desc" => args.sort = Sort::Desc,
+            b"storedist" if store => args.storedist = true,
+            b"count" if remaining >= 1 => {
+                let count = parse_int(options[idx + 1])?;
+                if count <= 0 {return Err(RedisError::Other(String::from("COUNT must be > 0")));}
+                args.count = Some(count as usize);
+                idx += 1;
+            },
+            b"frommember" if remaining >= 1 && !(from_member || from_lonlat) => {
+                (args.shape.lon, args.shape.lat) = zset.and_then(|zset| {member_coords(zset, options[idx + 1])})
+                    .ok_or_else(|| {RedisError::Other(String::from("could not decode requested zset member"))})?;
+                from_member = true;
+                idx += 1;
+            },
+            b"fromlonlat" if remaining >= 2 && !(from_member || from_lonlat) => {
+                (args.shape.lon, args.shape.lat) = parse_coords(options[idx + 1], options[idx + 2])?;
+                from_lonlat = true;
+                idx += 2;
+            },
+            b"byradius" if remaining >= 2 && !(by_radius || by_box) => {
+                let radius = parse_size(options[idx + 1], "radius")?;
+                if radius < 0.0 {return Err(RedisError::Other(String::from("radius cannot be negative")));}
+                args.shape.conversion = parse_unit(options[idx + 2])?;
+                args.shape.kind = ShapeKind::Radius(radius);
+                by_radius = true;
+                idx += 2;
+            },
+            b"bybox" if remaining >= 3 && !(by_radius || by_box) => {
+                let width = parse_size(options[idx + 1], "width")?;
+                let height = parse_size(options[idx + 2], "height")?;
+                if width < 0.0 || height < 0.0 {
+                    return Err(RedisError::Other(String::from("height or width cannot be negative")));
+                }
+                args.shape.conversion = parse_unit(options[idx + 3])?;
+                args.shape.kind = ShapeKind::Box{width, height};
+                by_box = true;
+                idx += 3;
+            },
+            _ => return Err(RedisError::Syntax)
+        }
+        idx += 1;
+    }
+
+    if store && (args.withdist || args.withhash || args.withcoord) {
+        return Err(RedisError::Other(String::from(
+            "GEOSEARCHSTORE is not compatible with WITHDIST, WITHHASH and WITHCOORD options"
+        )));
+    }
+    if !from_member && !from_lonlat {
+        return Err(RedisError::Other(format!("exactly one of FROMMEMBER or FROMLONLAT can be specified for {cmd}")));
+    }
+    if !by_radius && !by_box {
+        return Err(RedisError::Other(format!("exactly one of BYRADIUS and BYBOX can be specified for {cmd}")));
+    }
+    if args.any && args.count.is_none() {
+        return Err(RedisError::Other(String::from("the ANY argument requires COUNT argument")));
+    }
+    // the closest N need sorting, only ANY settles for the first N found
+    if args.count.is_some() && args.sort == Sort::None && !args.any {args.sort = Sort::Asc;}
+    Ok(args)
+}
+
+/* the points inside the shape in the order the cells were scanned, with ANY
+ * the scan stops as soon as COUNT of them were found
+ * */
+fn search(zset: &SortedSet, args: &SearchArgs) -> Vec<GeoPoint>{
+    let limit = if args.any {args.count} else {None};
+    let is_full = |found: &Vec<GeoPoint>| {limit.is_some_and(|limit| {!found.is_empty() && found.len() >= limit})};
+    let mut found = vec!();
+    for (min, max) in geohash::search_ranges(&args.shape) {
+        if is_full(&found) {break;}
+        let range = ScoreRange{min: min as f64, max: max as f64, min_exclusive: false, max_exclusive: true};
+        for (member, score) in zset.range_by_score(range, false) {
+            let coords = geohash::decode_score(score);
+            if let Some(dist) = args.shape.distance_to(coords.0, coords.1) {
+                found.push(GeoPoint{member: member.to_vec(), score, coords, dist});
+            }
+            if is_full(&found) {break;}
+        }
+    }
+    match args.sort {
+        Sort::Asc => found.sort_by(|a, b| {a.dist.total_cmp(&b.dist)}),
+        Sort::Desc => found.sort_by(|a, b| {b.dist.total_cmp(&a.dist)}),
+        Sort::None => ()
+    }
+    found.truncate(args.count.unwrap_or(usize::MAX));
+    found
+}
+
+/* GEOSEARCH key <FROMMEMBER member | FROMLONLAT longitude latitude>
+ *   <BYRADIUS radius unit | BYBOX width height unit> [ASC | DESC]
+ *   [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+ * */
+pub fn geosearch(params: Vec<&[u8]>, proto: ProtocolVersion, storage: &mut Keyspace) -> Result<RespValue>{
+    let zset = get_zset(storage, params[0])?;
+    let args = parse_search_args("geosearch", false, &params[1..], zset)?;
+    let points = match zset {
+        Some(zset) => search(zset, &args),
+        None => vec!()
+    };
+    let with_options = args.withdist || args.withhash || args.withcoord;
+    let items = points.into_iter()
+                    .map(|point| {
+                        if !with_options {return point.member.to_resp();}
+                        let mut item = vec!(point.member.to_resp());
+                        if args.withdist {item.push(distance_reply(point.dist, args.shape.conversion));}
+                        if args.withhash {item.push((point.score as i64).to_resp());}
+                        if args.withcoord {item.push(coords_reply(point.coords, proto));}
+                        RespValue::Array(item)
+                    })
+                    .collect();
+    Ok(RespValue::Array(items))
+}
+
+// GEOSEARCHSTORE destination source ... [STOREDIST], an empty result deletes the destination
+pub fn geosearchstore(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let zset = get_zset(storage, params[1])?;
+    let args = parse_search_args("geosearchstore", true, &params[2..], zset)?;
+    let points = match zset {
+        Some(zset) => search(zset, &args),
+        None => vec!()
+    };
+    let mut stored = SortedSet::new();
+    for point in &points {
+        let score = if args.storedist {point.dist / args.shape.conversion} else {point.score};
+        stored.insert(&point.member, score);
+    }
+    match stored.len() {
+        0 => {storage.remove(params[0]);},
+        _ => {storage.insert(params[0], Entry::new(RedisValue::ZSet(stored)));}
+    }
+    Ok(points.len().to_resp())
+}
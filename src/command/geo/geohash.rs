@@ -0,0 +1,286 @@
+/* 52 bit geohashes and the search areas built from them, the same arithmetic
+ * as redis so scores, decoded coordinates and search results line up exactly.
+ * latitude bits sit at the even positions and longitude bits at the odd ones,
+ * the sorted set score of a point is its hash at STEP_MAX
+ * */
+
+pub const STEP_MAX: u8 = 26;
+pub const LONG_MIN: f64 = -180.0;
+pub const LONG_MAX: f64 = 180.0;
+// the mercator projection limits, EPSG:900913
+pub const LAT_MIN: f64 = -85.05112878;
+pub const LAT_MAX: f64 = 85.05112878;
+
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct Range{
+    pub min: f64,
+    pub max: f64
+}
+
+// the indexed coordinate space and the standard one GEOHASH strings are based on
+pub const WGS84: (Range, Range) = (Range{min: LONG_MIN, max: LONG_MAX}, Range{min: LAT_MIN, max: LAT_MAX});
+pub const STANDARD: (Range, Range) = (Range{min: LONG_MIN, max: LONG_MAX}, Range{min: -90.0, max: 90.0});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashBits{
+    pub bits: u64,
+    pub step: u8
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Area{
+    pub longitude: Range,
+    pub latitude: Range
+}
+
+fn interleave(lat: u32, lon: u32) -> u64{
+    (0..32).fold(0, |bits, idx| {
+        bits | (((lat >> idx) & 1) as u64) << (2 * idx) | (((lon >> idx) & 1) as u64) << (2 * idx + 1)
+    })
+}
+
+fn deinterleave(bits: u64) -> (u32, u32){
+    (0..32).fold((0, 0), |(lat, lon), idx| {
+        (lat | (((bits >> (2 * idx)) & 1) as u32) << idx, lon | (((bits >> (2 * idx + 1)) & 1) as u32) << idx)
+    })
+}
+
+pub fn valid_coords(lon: f64, lat: f64) -> bool{
+    (LONG_MIN..=LONG_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+pub fn encode(ranges: (Range, Range), lon: f64, lat: f64, step: u8) -> Option<HashBits>{
+    let (long_range, lat_range) = ranges;
+    if !valid_coords(lon, lat) {return None;}
+    if lat < lat_range.min || lat > lat_range.max || lon < long_range.min || lon > long_range.max {
+        return None;
+    }
+    let lat_offset = (lat - lat_range.min) / (lat_range.max - lat_range.min) * (1u64 << step) as f64;
+    let long_offset = (lon - long_range.min) / (long_range.max - long_range.min) * (1u64 << step) as f64;
+    Some(HashBits{bits: interleave(lat_offset as u32, long_offset as u32), step})
+}
+
+pub fn decode(ranges: (Range, Range), hash: HashBits) -> Area{
+    let (long_range, lat_range) = ranges;
+    let (lat, lon) = deinterleave(hash.bits);
+    let cells = (1u64 << hash.step) as f64;
+    let (lat_scale, long_scale) = (lat_range.max - lat_range.min, long_range.max - long_range.min);
+    Area{
+        latitude: Range{
+            min: lat_range.min + (lat as f64 / cells) * lat_scale,
+            max: lat_range.min + ((lat as f64 + 1.0) / cells) * lat_scale
+        },
+        longitude: Range{
+            min: long_range.min + (lon as f64 / cells) * long_scale,
+            max: long_range.min + ((lon as f64 + 1.0) / cells) * long_scale
+        }
+    }
+}
+
+// the center of the cell, the coordinates GEOPOS reports for a score
+pub fn decode_score(score: f64) -> (f64, f64){
+    let area = decode(WGS84, HashBits{bits: score as u64, step: STEP_MAX});
+    let lon = ((area.longitude.min + area.longitude.max) / 2.0).clamp(LONG_MIN, LONG_MAX);
+    let lat = ((area.latitude.min + area.latitude.max) / 2.0).clamp(LAT_MIN, LAT_MAX);
+    (lon, lat)
+}
+
+// the hash widened to a score, the lower bound of the cell's score range
+fn align_52bits(hash: HashBits) -> u64{
+    hash.bits << (52 - hash.step as u32 * 2)
+}
+
+fn move_x(hash: &mut HashBits, d: i8){
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0x5555555555555555u64 >> (64 - hash.step as u32 * 2);
+    let x = match d > 0 {
+        true => x.wrapping_add(zz + 1),
+        false => (x | zz).wrapping_sub(zz + 1)
+    } & (0xaaaaaaaaaaaaaaaa >> (64 - hash.step as u32 * 2));
+    hash.bits = x | y;
+}
+
+fn move_y(hash: &mut HashBits, d: i8){
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2);
+    let y = match d > 0 {
+        true => y.wrapping_add(zz + 1),
+        false => (y | zz).wrapping_sub(zz + 1)
+    } & (0x5555555555555555 >> (64 - hash.step as u32 * 2));
+    hash.bits = x | y;
+}
+
+fn neighbor(hash: HashBits, dx: i8, dy: i8) -> HashBits{
+    let mut moved = hash;
+    if dx != 0 {move_x(&mut moved, dx);}
+    if dy != 0 {move_y(&mut moved, dy);}
+    moved
+}
+
+
+fn deg_rad(deg: f64) -> f64{
+    deg * (std::f64::consts::PI / 180.0)
+}
+
+fn rad_deg(rad: f64) -> f64{
+    rad / (std::f64::consts::PI / 180.0)
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64{
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+// haversine distance in meters
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64{
+    let v = ((deg_rad(lon2) - deg_rad(lon1)) / 2.0).sin();
+    // on the same meridian only the latitudes matter
+    if v == 0.0 {return lat_distance(lat1, lat2);}
+    let (lat1, lat2) = (deg_rad(lat1), deg_rad(lat2));
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub enum ShapeKind{
+    Radius(f64),
+    Box{width: f64, height: f64}
+}
+
+// what GEOSEARCH looks through, sizes are in the unit given and `conversion` turns them into meters
+#[derive(Clone, Copy, Debug)]
+pub struct Shape{
+    pub lon: f64,
+    pub lat: f64,
+    pub conversion: f64,
+    pub kind: ShapeKind
+}
+
+impl Shape{
+    // the distance to a point in meters, None when it falls outside
+    pub fn distance_to(&self, lon: f64, lat: f64) -> Option<f64>{
+        match self.kind {
+            ShapeKind::Radius(radius) => {
+                let dist = distance(self.lon, self.lat, lon, lat);
+                (dist <= radius * self.conversion).then_some(dist)
+            },
+            ShapeKind::Box{width, height} => {
+                // the latitude check is the cheaper one
+                if lat_distance(lat, self.lat) > height * self.conversion / 2.0 {return None;}
+                if distance(lon, lat, self.lon, lat) > width * self.conversion / 2.0 {return None;}
+                Some(distance(self.lon, self.lat, lon, lat))
+            }
+        }
+    }
+
+    // min lon, min lat, max lon, max lat
+    fn bounding_box(&self) -> [f64; 4]{
+        let (half_width, half_height) = match self.kind {
+            ShapeKind::Radius(radius) => (radius, radius),
+            ShapeKind::Box{width, height} => (width / 2.0, height / 2.0)
+        };
+        let (width, height) = (half_width * self.conversion, half_height * self.conversion);
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.lat + lat_delta).cos());
+        let long_delta_bottom = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.lat - lat_delta).cos());
+        // the widest edge is the one closer to the equator
+        let long_delta = if self.lat < 0.0 {long_delta_bottom} else {long_delta_top};
+        [self.lon - long_delta, self.lat - lat_delta, self.lon + long_delta, self.lat + lat_delta]
+    }
+
+    fn radius_meters(&self) -> f64{
+        let radius = match self.kind {
+            ShapeKind::Radius(radius) => radius,
+            ShapeKind::Box{width, height} => ((width / 2.0) * (width / 2.0) + (height / 2.0) * (height / 2.0)).sqrt()
+        };
+        radius * self.conversion
+    }
+}
+
+fn estimate_steps(mut range_meters: f64, lat: f64) -> u8{
+    if range_meters == 0.0 {return STEP_MAX;}
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    // make sure the range is included in most of the base cases
+    step -= 2;
+    // cells get narrower towards the poles
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {step -= 1;}
+    }
+    step.clamp(1, STEP_MAX as i32) as u8
+}
+
+/* the cell holding the center plus its 8 neighbors, in the order they are
+ * searched: center, north, south, east, west, north east, north west, south
+ * east, south west. neighbors that can not hold anything inside the shape are
+ * None. the cell size is chosen from the radius and made one step coarser when
+ * the neighbors do not reach the edges of the bounding box
+ * */
+fn search_cells(shape: &Shape) -> [Option<HashBits>; 9]{
+    let [min_lon, min_lat, max_lon, max_lat] = shape.bounding_box();
+    let mut steps = estimate_steps(shape.radius_meters(), shape.lat);
+    let cells = |steps: u8| {
+        let hash = encode(WGS84, shape.lon, shape.lat, steps).unwrap();
+        let around = [(0, 1), (0, -1), (1, 0), (-1, 0), (1, 1), (-1, 1), (1, -1), (-1, -1)]
+                        .map(|(dx, dy)| {neighbor(hash, dx, dy)});
+        (hash, around)
+    };
+    let (mut hash, mut around) = cells(steps);
+    let [north, south, east, west, ..] = around.map(|cell| {decode(WGS84, cell)});
+    let decrease_step = north.latitude.max < max_lat
+                        || south.latitude.min > min_lat
+                        || east.longitude.max < max_lon
+                        || west.longitude.min > min_lon;
+    if steps > 1 && decrease_step {
+        steps -= 1;
+        (hash, around) = cells(steps);
+    }
+
+    let mut searched = [true; 8];
+    if steps >= 2 {
+        let area = decode(WGS84, hash);
+        // north, south, east, west, ne, nw, se, sw
+        let mut exclude = |cells: [usize; 3]| {cells.iter().for_each(|idx| {searched[*idx] = false})};
+        if area.latitude.min < min_lat {exclude([1, 6, 7]);}
+        if area.latitude.max > max_lat {exclude([0, 4, 5]);}
+        if area.longitude.min < min_lon {exclude([3, 5, 7]);}
+        if area.longitude.max > max_lon {exclude([2, 4, 6]);}
+    }
+    let mut result = [Some(hash); 9];
+    for (idx, cell) in around.into_iter().enumerate() {
+        result[idx + 1] = searched[idx].then_some(cell);
+    }
+    result
+}
+
+/* the score ranges to scan for the shape, [min, max) each. a huge radius can
+ * make a neighbor the same cell as the one searched right before it, those are
+ * only scanned once
+ * */
+pub fn search_ranges(shape: &Shape) -> Vec<(u64, u64)>{
+    let cells = search_cells(shape);
+    let mut ranges = vec!();
+    let mut last_processed = 0;
+    for (idx, cell) in cells.iter().enumerate() {
+        let cell = match cell {
+            Some(cell) => *cell,
+            None => continue
+        };
+        if last_processed != 0 && cells[last_processed] == Some(cell) {continue;}
+        let next = HashBits{bits: cell.bits + 1, ..cell};
+        ranges.push((align_52bits(cell), align_52bits(next)));
+        last_processed = idx;
+    }
+    ranges
+}
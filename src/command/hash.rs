@@ -0,0 +1,335 @@
+/* hash commands, field level expiration included
+ * fields past their deadline are purged whenever the hash is looked up and a
+ * hash left without fields is removed from the keyspace
+ * */
+
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{unix_time_ms, Keyspace, RedisValue};
+use crate::persistence::value::HashObject;
+
+use super::expire::{parse_expire_flags, resolve_deadline, Deadline, TimeUnit};
+use super::scan::{parse_scan_args, scan_cursor, ScanKind};
+use super::{format_float, parse_float, parse_int, to_args, Propagate};
+
+
+// the live hash under `key` with expired fields already dropped
+fn get_hash<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut HashObject>>{
+    let emptied = match storage.get_mut(key) {
+        Some(entry) => {
+            let hash = entry.value.as_hash_mut()?;
+            hash.purge_expired(unix_time_ms());
+            hash.is_empty()
+        },
+        None => return Ok(None)
+    };
+    if emptied {
+        storage.remove(key);
+        return Ok(None);
+    }
+    storage.get_mut(key).map(|entry| {entry.value.as_hash_mut()}).transpose()
+}
+
+fn get_or_create_hash<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<&'a mut HashObject>{
+    get_hash(storage, key)?;
+    storage.get_or_insert_with(key, || {RedisValue::Hash(HashObject::new())}).value.as_hash_mut()
+}
+
+
+// HSET key field value [field value ...] and the deprecated HMSET
+pub fn hset(cmd: &str, params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    if params.len().is_multiple_of(2) {return Err(RedisError::wrong_arity(cmd));}
+    let hash = get_or_create_hash(storage, params[0])?;
+    let created = params[1..].chunks(2).filter(|pair| {hash.insert(pair[0], pair[1].to_vec())}).count();
+    match cmd {
+        "hmset" => Ok(RespValue::ok()),
+        _ => Ok(created.to_resp())
+    }
+}
+
+pub fn hsetnx(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let hash = get_or_create_hash(storage, params[0])?;
+    if hash.contains(params[1]) {return Ok(0.to_resp());}
+    hash.insert(params[1], params[2].to_vec());
+    Ok(1.to_resp())
+}
+
+pub fn hget(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_hash(storage, params[0])?.and_then(|hash| {hash.get(params[1])}).to_resp())
+}
+
+pub fn hmget(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let hash = get_hash(storage, params[0])?;
+    let values = params[1..].iter()
+                    .map(|field| {hash.as_ref().and_then(|hash| {hash.get(field)}).to_resp()})
+                    .collect();
+    Ok(RespValue::Array(values))
+}
+
+pub fn hdel(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let removed = match get_hash(storage, params[0])? {
+        Some(hash) => params[1..].iter().filter(|field| {hash.remove(field)}).count(),
+        None => 0
+    };
+    storage.remove_if_empty(params[0]);
+    Ok(removed.to_resp())
+}
+
+pub fn hlen(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_hash(storage, key)?.map_or(0, |hash| {hash.len()}).to_resp())
+}
+
+pub fn hstrlen(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let hash = get_hash(storage, params[0])?;
+    Ok(hash.and_then(|hash| {hash.get(params[1])}).map_or(0, |value| {value.len()}).to_resp())
+}
+
+pub fn hexists(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let hash = get_hash(storage, params[0])?;
+    Ok((hash.is_some_and(|hash| {hash.contains(params[1])}) as i64).to_resp())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HashPart{
+    Keys,
+    Values,
+    Both
+}
+
+// HKEYS, HVALS and HGETALL, the latter as a map under RESP3
+pub fn hgetall(key: &[u8], part: HashPart, storage: &mut Keyspace) -> Result<RespValue>{
+    let hash = match get_hash(storage, key)? {
+        Some(hash) => hash,
+        None if part == HashPart::Both => return Ok(RespValue::Map(vec!())),
+        None => return Ok(RespValue::Array(vec!()))
+    };
+    Ok(match part {
+        HashPart::Keys => RespValue::Array(hash.iter().map(|(field, _)| {field.to_resp()}).collect()),
+        HashPart::Values => RespValue::Array(hash.iter().map(|(_, value)| {value.to_resp()}).collect()),
+        HashPart::Both => RespValue::Map(hash.iter().map(|(field, value)| {(field.to_resp(), value.to_resp())}).collect())
+    })
+}
+
+pub fn hincrby(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let incr = parse_int(params[2])?;
+    let hash = get_or_create_hash(storage, params[0])?;
+    let current = match hash.get(params[1]) {
+        Some(value) => parse_int(value).map_err(|_| {RedisError::Other(String::from("hash value is not an integer"))})?,
+        None => 0
+    };
+    let updated = current.checked_add(incr)
+                    .ok_or_else(|| {RedisError::Other(String::from("increment or decrement would overflow"))})?;
+    hash.update(params[1], updated.to_string().into_bytes());
+    Ok(updated.to_resp())
+}
+
+// replicas get the final value as HSET, followed by the field's deadline since HSET clears it
+pub fn hincrbyfloat(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<(RespValue, Propagate)>{
+    let incr = parse_float(params[2])?;
+    let hash = get_or_create_hash(storage, params[0])?;
+    let current = match hash.get(params[1]) {
+        Some(value) => parse_float(value).map_err(|_| {RedisError::Other(String::from("hash value is not a float"))})?,
+        None => 0.0
+    };
+    let updated = current + incr;
+    if !updated.is_finite() {
+        return Err(RedisError::Other(String::from("increment would produce NaN or Infinity")));
+    }
+    let repr = format_float(updated).into_bytes();
+    hash.update(params[1], repr.clone());
+    let mut commands = vec!(to_args(&[b"HSET", params[0], params[1], &repr]));
+    if let Some(deadline) = hash.expires_at(params[1]) {
+        commands.push(field_expiry_command(params[0], deadline as i64, &params[1..2]));
+    }
+    Ok((repr.to_resp(), Propagate::Rewritten(commands)))
+}
+
+// HRANDFIELD key [count [WITHVALUES]], a negative count may repeat fields
+pub fn hrandfield(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let count = params.get(1).map(|count| {parse_int(count)}).transpose()?;
+    let with_values = match params.get(2) {
+        Some(option) if option.eq_ignore_ascii_case(b"withvalues") => true,
+        Some(_) => return Err(RedisError::Syntax),
+        None => false
+    };
+    if params.len() > 3 {return Err(RedisError::Syntax);}
+    if count.is_some_and(|count| {count.unsigned_abs() > (i64::MAX / 2) as u64}) {
+        return Err(RedisError::Other(String::from("value is out of range")));
+    }
+
+    let mut rng = rand::thread_rng();
+    let hash = match (get_hash(storage, params[0])?, count) {
+        (Some(hash), _) => hash,
+        (None, None) => return Ok(RespValue::Null),
+        (None, Some(_)) => return Ok(RespValue::Array(vec!()))
+    };
+    let picked: Vec<_> = match count {
+        None => return Ok(hash.iter().choose(&mut rng).map(|(field, _)| {field}).to_resp()),
+        Some(count) if count >= 0 => hash.iter().choose_multiple(&mut rng, count as usize),
+        Some(count) => {
+            let fields = hash.iter().collect::<Vec<_>>();
+            (0..count.unsigned_abs()).filter_map(|_| {fields.choose(&mut rng).copied()}).collect()
+        }
+    };
+    let mut reply = vec!();
+    for (field, value) in picked {
+        reply.push(field.to_resp());
+        if with_values {reply.push(value.to_resp());}
+    }
+    Ok(RespValue::Array(reply))
+}
+
+// HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]
+pub fn hscan(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let args = parse_scan_args(params[1], &params[2..], ScanKind::Hash)?;
+    let hash = match get_hash(storage, params[0])? {
+        Some(hash) => hash,
+        None => return Ok(("0", RespValue::Array(vec!())).to_resp())
+    };
+    let (next, page) = scan_cursor(&args, |cursor, page| {
+        hash.scan(cursor, |field, value| {page.push((field, value))})
+    });
+    let mut items = vec!();
+    for (field, value) in page.into_iter().filter(|(field, _)| {args.matches(field)}) {
+        items.push(field.to_resp());
+        if !args.novalues {items.push(value.to_resp());}
+    }
+    Ok((next.to_string(), RespValue::Array(items)).to_resp())
+}
+
+
+/* field expiration, redis 7.4
+ * every command answers with one integer per requested field
+ * */
+const FIELD_MISSING: i64 = -2;
+const FIELD_NO_TTL: i64 = -1;
+// deadlines are capped to 48 bits of milliseconds like redis does
+const FIELD_EXPIRE_MAX: i64 = (1 << 48) - 1;
+
+// FIELDS numfields field [field ...]
+fn parse_fields<'a>(params: &[&'a [u8]]) -> Result<Vec<&'a [u8]>>{
+    match params {
+        [keyword, numfields, fields @ ..] if keyword.eq_ignore_ascii_case(b"fields") => {
+            let numfields = parse_int(numfields)?;
+            if numfields <= 0 {
+                return Err(RedisError::Other(String::from("Parameter `numFields` should be greater than 0")));
+            }
+            if numfields as usize != fields.len() {
+                return Err(RedisError::Other(String::from("The `numfields` parameter must match the number of arguments")));
+            }
+            Ok(fields.to_vec())
+        },
+        _ => Err(RedisError::Other(String::from("Mandatory argument FIELDS is missing or not at the right position")))
+    }
+}
+
+// HPEXPIREAT key unix-ms FIELDS numfields field [field ...], how field deadlines reach replicas
+fn field_expiry_command(key: &[u8], deadline: i64, fields: &[&[u8]]) -> Vec<Vec<u8>>{
+    let numfields = fields.len().to_string();
+    let mut args = to_args(&[b"HPEXPIREAT", key, deadline.to_string().as_bytes(), b"FIELDS", numfields.as_bytes()]);
+    args.extend(fields.iter().map(|field| {field.to_vec()}));
+    args
+}
+
+// HEXPIRE key seconds [NX | XX | GT | LT] FIELDS numfields field [field ...] and siblings
+pub fn hexpire(
+    cmd: &str, params: Vec<&[u8]>, unit: TimeUnit, base: Deadline, storage: &mut Keyspace
+) -> Result<(RespValue, Propagate)>{
+    let when = parse_int(params[1])?;
+    if when < 0 {return Err(RedisError::Other(String::from("invalid expire time, must be >= 0")));}
+    let when = resolve_deadline(cmd, when, unit, base)?;
+    if when > FIELD_EXPIRE_MAX {return Err(RedisError::invalid_expire_time(cmd));}
+
+    let has_condition = params.get(2).is_some_and(|option| {
+        [&b"nx"[..], b"xx", b"gt", b"lt"].iter().any(|flag| {option.eq_ignore_ascii_case(flag)})
+    });
+    let flags = parse_expire_flags(if has_condition {&params[2..3]} else {&[]})?;
+    let fields = parse_fields(&params[2 + has_condition as usize..])?;
+
+    let now = unix_time_ms() as i64;
+    let hash = match get_hash(storage, params[0])? {
+        Some(hash) => hash,
+        None => return Ok((vec!(FIELD_MISSING; fields.len()).to_resp(), Propagate::nothing()))
+    };
+    let replies = fields.iter().map(|field| {
+        if !hash.contains(field) {return FIELD_MISSING;}
+        if !flags.accepts(hash.expires_at(field), when) {return 0;}
+        // a deadline already behind us deletes the field
+        if when <= now {
+            hash.remove(field);
+            return 2;
+        }
+        hash.set_expiry(field, Some(when as u64));
+        1
+    }).collect::<Vec<_>>();
+    storage.remove_if_empty(params[0]);
+
+    // replicas get the outcome, the new deadline or HDEL for the fields it already deleted
+    let touched = fields.iter().zip(&replies).filter(|(_, reply)| {**reply > 0}).map(|(field, _)| {*field}).collect::<Vec<_>>();
+    let propagate = match (touched.is_empty(), when <= now) {
+        (true, _) => Propagate::nothing(),
+        (false, true) => {
+            let mut args = to_args(&[b"HDEL", params[0]]);
+            args.extend(touched.iter().map(|field| {field.to_vec()}));
+            Propagate::Rewritten(vec!(args))
+        },
+        (false, false) => Propagate::Rewritten(vec!(field_expiry_command(params[0], when, &touched)))
+    };
+    Ok((replies.to_resp(), propagate))
+}
+
+fn per_field(
+    params: &[&[u8]], storage: &mut Keyspace, mut reply: impl FnMut(&mut HashObject, &[u8]) -> i64
+) -> Result<RespValue>{
+    let fields = parse_fields(&params[1..])?;
+    let hash = match get_hash(storage, params[0])? {
+        Some(hash) => hash,
+        None => return Ok(vec!(FIELD_MISSING; fields.len()).to_resp())
+    };
+    let replies = fields.iter().map(|field| {
+        if !hash.contains(field) {FIELD_MISSING} else {reply(hash, field)}
+    }).collect::<Vec<_>>();
+    Ok(replies.to_resp())
+}
+
+// HTTL and HPTTL key FIELDS numfields field [field ...]
+pub fn httl(params: Vec<&[u8]>, unit: TimeUnit, storage: &mut Keyspace) -> Result<RespValue>{
+    per_field(&params, storage, |hash, field| {
+        match hash.expires_at(field) {
+            None => FIELD_NO_TTL,
+            Some(deadline) => {
+                let remaining = deadline.saturating_sub(unix_time_ms()) as i64;
+                match unit {
+                    TimeUnit::Seconds => (remaining + 500) / 1000,
+                    TimeUnit::Milliseconds => remaining
+                }
+            }
+        }
+    })
+}
+
+// HEXPIRETIME and HPEXPIRETIME key FIELDS numfields field [field ...]
+pub fn hexpiretime(params: Vec<&[u8]>, unit: TimeUnit, storage: &mut Keyspace) -> Result<RespValue>{
+    per_field(&params, storage, |hash, field| {
+        match (hash.expires_at(field), unit) {
+            (None, _) => FIELD_NO_TTL,
+            (Some(deadline), TimeUnit::Seconds) => deadline as i64 / 1000,
+            (Some(deadline), TimeUnit::Milliseconds) => deadline as i64
+        }
+    })
+}
+
+// HPERSIST key FIELDS numfields field [field ...]
+pub fn hpersist(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    per_field(&params, storage, |hash, field| {
+        match hash.expires_at(field) {
+            None => FIELD_NO_TTL,
+            Some(_) => {
+                hash.set_expiry(field, None);
+                1
+            }
+        }
+    })
+}
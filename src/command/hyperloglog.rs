@@ -0,0 +1,116 @@
+/* PFADD, PFCOUNT, PFMERGE and PFDEBUG
+ * the sketches are plain strings in the redis layout (see value/hyperloglog),
+ * any string that does not carry the header is refused with its own WRONGTYPE
+ * message while other types get the usual one
+ * */
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{Entry, Keyspace, RedisValue};
+use crate::persistence::value::hyperloglog::{self as hll, Opcode, REGISTERS};
+
+
+fn get_hll<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a Vec<u8>>>{
+    match storage.get_string(key)? {
+        Some(value) if !hll::is_hll(value) => Err(RedisError::NotHll),
+        value => Ok(value)
+    }
+}
+
+fn get_hll_mut<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut Vec<u8>>>{
+    get_hll(storage, key)?;
+    storage.get_mut(key).map(|entry| {entry.value.as_string_mut()}).transpose()
+}
+
+
+// PFADD key [element ...], 1 when the key was created or any register changed
+pub fn pfadd(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let key = params[0];
+    let mut updated = false;
+    if get_hll(storage, key)?.is_none() {
+        storage.insert(key, Entry::new(RedisValue::String(hll::new_hll())));
+        updated = true;
+    }
+    let sketch = get_hll_mut(storage, key)?.unwrap();
+    for element in &params[1..] {
+        updated |= hll::add(sketch, element)?;
+    }
+    Ok((updated as i64).to_resp())
+}
+
+/* PFCOUNT key [key ...], a single key answers from its cached cardinality and
+ * refreshes the cache when stale. several keys are merged into a scratch set of
+ * registers that is counted and thrown away
+ * */
+pub fn pfcount(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    if let [key] = params[..] {
+        return Ok(match get_hll_mut(storage, key)? {
+            Some(sketch) => hll::count(sketch)?,
+            None => 0
+        }.to_resp());
+    }
+    let mut max = vec!(0; REGISTERS);
+    for key in params {
+        if let Some(sketch) = get_hll(storage, key)? {
+            hll::merge_into(&mut max, sketch)?;
+        }
+    }
+    Ok(hll::count_raw(&max).to_resp())
+}
+
+/* PFMERGE destkey [sourcekey ...], the destination takes part in the union too.
+ * it ends up dense when any of the inputs was
+ * */
+pub fn pfmerge(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let mut max = vec!(0; REGISTERS);
+    let mut dense = false;
+    for key in &params {
+        if let Some(sketch) = get_hll(storage, key)? {
+            dense |= !hll::is_sparse(sketch);
+            hll::merge_into(&mut max, sketch)?;
+        }
+    }
+    let dest = params[0];
+    if get_hll(storage, dest)?.is_none() {
+        storage.insert(dest, Entry::new(RedisValue::String(hll::new_hll())));
+    }
+    hll::store_registers(get_hll_mut(storage, dest)?.unwrap(), &max, dense)?;
+    Ok(RespValue::ok())
+}
+
+// PFDEBUG GETREG|DECODE|ENCODING|TODENSE key, introspection for tests
+pub fn pfdebug(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let sub = params[0].to_ascii_lowercase();
+    let sketch = get_hll_mut(storage, params[1])?
+                    .ok_or_else(|| {RedisError::Other(String::from("The specified key does not exist"))})?;
+    let known = matches!(sub.as_slice(), b"getreg" | b"decode" | b"encoding" | b"todense");
+    if known && params.len() != 2 {return Err(RedisError::wrong_arity("pfdebug"));}
+    match sub.as_slice() {
+        b"getreg" => {
+            hll::sparse_to_dense(sketch)?;
+            let registers = hll::dense_registers(sketch).into_iter().map(|value| {value as i64}).collect::<Vec<_>>();
+            Ok(registers.to_resp())
+        },
+        b"decode" => {
+            if !hll::is_sparse(sketch) {
+                return Err(RedisError::Other(String::from("HLL encoding is not sparse")));
+            }
+            let decoded = hll::sparse_opcodes(sketch).into_iter()
+                            .map(|opcode| {
+                                match opcode {
+                                    Opcode::Zero(len) => format!("z:{len}"),
+                                    Opcode::XZero(len) => format!("Z:{len}"),
+                                    Opcode::Val(value, len) => format!("v:{value},{len}")
+                                }
+                            })
+                            .collect::<Vec<_>>();
+            Ok(RespValue::SimpleStr(decoded.join(" ")))
+        },
+        b"encoding" => {
+            let encoding = if hll::is_sparse(sketch) {"sparse"} else {"dense"};
+            Ok(RespValue::SimpleStr(String::from(encoding)))
+        },
+        b"todense" => Ok((hll::sparse_to_dense(sketch)? as i64).to_resp()),
+        _ => Err(RedisError::Other(format!("Unknown PFDEBUG subcommand '{}'", String::from_utf8_lossy(params[0]))))
+    }
+}
@@ -0,0 +1,141 @@
+/* generic key management, the commands that work on a key regardless of what
+ * it holds. there is a single database, so COPY only accepts DB 0 and FLUSHDB
+ * and FLUSHALL are the same thing
+ * */
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{lazyfree, unix_time_ms, Entry, Keyspace};
+
+use super::parse_int;
+use super::scan::{parse_scan_args, scan_cursor, string_match, ScanKind};
+
+
+// DEL key [key ...], UNLINK frees big values in the background instead
+pub fn del(params: Vec<&[u8]>, lazy: bool, storage: &mut Keyspace) -> Result<RespValue>{
+    let mut removed = 0;
+    for key in params {
+        let entry = match storage.remove(key) {
+            Some(entry) => entry,
+            None => continue
+        };
+        removed += 1;
+        if lazy {
+            let effort = entry.value.free_effort();
+            lazyfree::free(entry, effort);
+        }
+    }
+    Ok(removed.to_resp())
+}
+
+// a key given several times is counted every time
+pub fn exists(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(params.iter().filter(|key| {storage.contains_key(key)}).count().to_resp())
+}
+
+// like EXISTS, but the lookups count as accesses
+pub fn touch(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(params.iter().filter(|key| {storage.get(key).is_some()}).count().to_resp())
+}
+
+/* RENAME key newkey and RENAMENX, the entry moves over whole with its ttl and
+ * whatever was under newkey is dropped
+ * */
+pub fn rename(params: Vec<&[u8]>, only_new: bool, storage: &mut Keyspace) -> Result<RespValue>{
+    let (src, dst) = (params[0], params[1]);
+    if !storage.contains_key(src) {return Err(RedisError::NoSuchKey);}
+    let done = if only_new {1.to_resp()} else {RespValue::ok()};
+    if src == dst {
+        return Ok(if only_new {0.to_resp()} else {done});
+    }
+    if only_new && storage.contains_key(dst) {return Ok(0.to_resp());}
+    let entry = storage.remove(src).unwrap();
+    storage.insert(dst, entry);
+    Ok(done)
+}
+
+// COPY source destination [DB destination-db] [REPLACE]
+pub fn copy(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (src, dst) = (params[0], params[1]);
+    let mut replace = false;
+    let mut options = params[2..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"replace" => replace = true,
+            b"db" if options.len() > 0 => {
+                if parse_int(options.next().unwrap())? != 0 {
+                    return Err(RedisError::Other(String::from("DB index is out of range")));
+                }
+            },
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+    if src == dst {
+        return Err(RedisError::Other(String::from("source and destination objects are the same")));
+    }
+    let copied = match storage.get(src) {
+        Some(entry) => Entry::with_expiry(entry.value.clone(), entry.expires_at()),
+        None => return Ok(0.to_resp())
+    };
+    if !replace && storage.contains_key(dst) {return Ok(0.to_resp());}
+    storage.insert(dst, copied);
+    Ok(1.to_resp())
+}
+
+pub fn keys(pattern: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    let all = pattern == b"*";
+    let keys = storage.keys()
+                .filter(|key| {all || string_match(pattern, key, false)})
+                .map(|key| {key.to_resp()})
+                .collect();
+    Ok(RespValue::Array(keys))
+}
+
+/* SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+ * the cursor walks the keyspace dict a few buckets per call, so unlike KEYS it
+ * never holds the lock for long. keys present during the whole walk come back
+ * at least once, MATCH and TYPE only filter what a call collected
+ * */
+pub fn scan(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let args = parse_scan_args(params[0], &params[1..], ScanKind::Keys)?;
+    let now = unix_time_ms();
+    let (next, page) = scan_cursor(&args, |cursor, page| {
+        storage.scan(cursor, |key, entry| {page.push((key, entry))})
+    });
+    let keys = page.into_iter()
+                .filter(|(key, entry)| {
+                    !entry.is_expired(now)
+                        && args.matches(key)
+                        && args.type_filter.as_ref().is_none_or(|kind| {kind == entry.value.type_name().as_bytes()})
+                })
+                .map(|(key, _)| {key.to_resp()})
+                .collect();
+    Ok((next.to_string(), RespValue::Array(keys)).to_resp())
+}
+
+pub fn randomkey(storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(storage.random_key().to_resp())
+}
+
+// keys that expired but were not reclaimed yet are still counted, as in redis
+pub fn dbsize(storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(storage.len().to_resp())
+}
+
+/* FLUSHDB [ASYNC | SYNC] and FLUSHALL, with ASYNC the keyspace is swapped for
+ * an empty one under the lock and the old entries are freed in the background
+ * */
+pub fn flush(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let lazy = match &params[..] {
+        [] => false,
+        [mode] if mode.eq_ignore_ascii_case(b"sync") => false,
+        [mode] if mode.eq_ignore_ascii_case(b"async") => true,
+        _ => return Err(RedisError::Syntax)
+    };
+    let entries = storage.flush();
+    match lazy {
+        true => lazyfree::free_async(entries),
+        false => drop(entries)
+    }
+    Ok(RespValue::ok())
+}
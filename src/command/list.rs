@@ -0,0 +1,415 @@
+/* list commands
+ * a list is a VecDeque so both ends are O(1), an emptied list is removed from
+ * the keyspace and a missing key behaves like an empty list
+ * */
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{Keyspace, RedisStorage, RedisValue};
+
+use super::{clamp_range, parse_bounded, parse_int, parse_positive, Propagate};
+
+
+type List = VecDeque<Vec<u8>>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum End{
+    Left,
+    Right
+}
+
+impl End{
+    pub fn name(self) -> &'static [u8]{
+        match self {
+            End::Left => b"LEFT",
+            End::Right => b"RIGHT"
+        }
+    }
+
+    // the plain pop a blocking pop from this end is replicated as
+    pub fn pop_command(self) -> &'static [u8]{
+        match self {
+            End::Left => b"LPOP",
+            End::Right => b"RPOP"
+        }
+    }
+}
+
+pub fn parse_end(arg: &[u8]) -> Result<End>{
+    match arg.to_ascii_lowercase().as_slice() {
+        b"left" => Ok(End::Left),
+        b"right" => Ok(End::Right),
+        _ => Err(RedisError::Syntax)
+    }
+}
+
+fn get_list<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a List>>{
+    storage.get(key).map(|entry| {entry.value.as_list()}).transpose()
+}
+
+fn get_list_mut<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut List>>{
+    storage.get_mut(key).map(|entry| {entry.value.as_list_mut()}).transpose()
+}
+
+fn push_at(list: &mut List, end: End, elem: Vec<u8>){
+    match end {
+        End::Left => list.push_front(elem),
+        End::Right => list.push_back(elem)
+    }
+}
+
+fn pop_at(list: &mut List, end: End) -> Option<Vec<u8>>{
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back()
+    }
+}
+
+
+// pop up to `count` elements from one end, None when the key holds no list elements
+pub fn pop_elements(storage: &mut Keyspace, key: &[u8], end: End, count: usize) -> Result<Option<Vec<Vec<u8>>>>{
+    let popped = match get_list_mut(storage, key)? {
+        Some(list) => (0..count.min(list.len())).filter_map(|_| {pop_at(list, end)}).collect(),
+        None => return Ok(None)
+    };
+    storage.remove_if_empty(key);
+    Ok(Some(popped))
+}
+
+// atomically pop from `src` and push onto `dst`, the two may be the same list
+pub fn move_element(
+    storage: &mut Keyspace, src: &[u8], dst: &[u8], from: End, to: End
+) -> Result<Option<Vec<u8>>>{
+    if get_list(storage, src)?.is_none() {return Ok(None);}
+    // the destination type is checked before anything is popped
+    get_list(storage, dst)?;
+    let elem = match pop_elements(storage, src, from, 1)?.and_then(|mut popped| {popped.pop()}) {
+        Some(elem) => elem,
+        None => return Ok(None)
+    };
+    let entry = storage.get_or_insert_with(dst, || {RedisValue::List(List::new())});
+    push_at(entry.value.as_list_mut()?, to, elem.clone());
+    Ok(Some(elem))
+}
+
+
+// LPUSH, RPUSH and their X variants which only touch an existing list
+pub fn push(params: Vec<&[u8]>, end: End, only_existing: bool, storage: &mut Keyspace) -> Result<RespValue>{
+    let key = params[0];
+    if get_list(storage, key)?.is_none() && only_existing {
+        return Ok(0.to_resp());
+    }
+    let list = storage.get_or_insert_with(key, || {RedisValue::List(List::new())}).value.as_list_mut()?;
+    params[1..].iter().for_each(|elem| {push_at(list, end, elem.to_vec())});
+    Ok(list.len().to_resp())
+}
+
+// LPOP key [count] and RPOP key [count]
+pub fn pop(params: Vec<&[u8]>, end: End, storage: &mut Keyspace) -> Result<RespValue>{
+    if params.len() > 2 {
+        return Err(RedisError::wrong_arity(if end == End::Left {"lpop"} else {"rpop"}));
+    }
+    let count = params.get(1).map(|arg| {parse_positive(arg)}).transpose()?;
+    let popped = pop_elements(storage, params[0], end, count.unwrap_or(1) as usize)?;
+    match (count, popped) {
+        (None, popped) => Ok(popped.and_then(|mut popped| {popped.pop()}).to_resp()),
+        (Some(_), None) => Ok(RespValue::NullArray),
+        (Some(_), Some(popped)) => Ok(popped.to_resp())
+    }
+}
+
+pub fn llen(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_list(storage, key)?.map_or(0, |list| {list.len()}).to_resp())
+}
+
+pub fn lrange(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (start, stop) = (parse_int(params[1])?, parse_int(params[2])?);
+    let list = match get_list(storage, params[0])? {
+        Some(list) => list,
+        None => return Ok(RespValue::Array(vec!()))
+    };
+    match clamp_range(start, stop, list.len()) {
+        Some((start, stop)) => Ok(list.range(start..=stop).collect::<Vec<_>>().to_resp()),
+        None => Ok(RespValue::Array(vec!()))
+    }
+}
+
+// negative indexes count from the tail, -1 being the last element
+fn resolve_index(index: i64, len: usize) -> Option<usize>{
+    let index = if index < 0 {len as i64 + index} else {index};
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+pub fn lindex(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let index = parse_int(params[1])?;
+    let list = get_list(storage, params[0])?;
+    Ok(list.and_then(|list| {resolve_index(index, list.len()).map(|i| {&list[i]})}).to_resp())
+}
+
+pub fn lset(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let index = parse_int(params[1])?;
+    let list = get_list_mut(storage, params[0])?.ok_or(RedisError::NoSuchKey)?;
+    let index = resolve_index(index, list.len()).ok_or_else(|| {RedisError::Other(String::from("index out of range"))})?;
+    list[index] = params[2].to_vec();
+    Ok(RespValue::ok())
+}
+
+// LINSERT key BEFORE|AFTER pivot element
+pub fn linsert(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let after = match params[1].to_ascii_lowercase().as_slice() {
+        b"before" => false,
+        b"after" => true,
+        _ => return Err(RedisError::Syntax)
+    };
+    let list = match get_list_mut(storage, params[0])? {
+        Some(list) => list,
+        None => return Ok(0.to_resp())
+    };
+    match list.iter().position(|elem| {elem == params[2]}) {
+        Some(pos) => {
+            list.insert(pos + after as usize, params[3].to_vec());
+            Ok(list.len().to_resp())
+        },
+        None => Ok((-1).to_resp())
+    }
+}
+
+// LREM key count element: from the head for count > 0, the tail for count < 0, all for 0
+pub fn lrem(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let count = parse_int(params[1])?;
+    let list = match get_list_mut(storage, params[0])? {
+        Some(list) => list,
+        None => return Ok(0.to_resp())
+    };
+    let limit = if count == 0 {usize::MAX} else {count.unsigned_abs() as usize};
+    let mut removed = 0;
+    if count >= 0 {
+        let mut i = 0;
+        while i < list.len() && removed < limit {
+            if list[i] == params[2] {
+                list.remove(i);
+                removed += 1;
+            }else {i += 1;}
+        }
+    }else {
+        let mut i = list.len();
+        while i > 0 && removed < limit {
+            i -= 1;
+            if list[i] == params[2] {
+                list.remove(i);
+                removed += 1;
+            }
+        }
+    }
+    storage.remove_if_empty(params[0]);
+    Ok(removed.to_resp())
+}
+
+pub fn ltrim(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (start, stop) = (parse_int(params[1])?, parse_int(params[2])?);
+    let list = match get_list_mut(storage, params[0])? {
+        Some(list) => list,
+        None => return Ok(RespValue::ok())
+    };
+    match clamp_range(start, stop, list.len()) {
+        Some((start, stop)) => {
+            list.truncate(stop + 1);
+            list.drain(..start);
+        },
+        None => list.clear()
+    }
+    storage.remove_if_empty(params[0]);
+    Ok(RespValue::ok())
+}
+
+// LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]
+pub fn lpos(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (mut rank, mut count, mut maxlen) = (1i64, None, 0usize);
+    let mut options = params[2..].iter();
+    while let Some(option) = options.next() {
+        let arg = match options.next() {
+            Some(arg) => arg,
+            None => return Err(RedisError::Syntax)
+        };
+        match option.to_ascii_lowercase().as_slice() {
+            b"rank" => {
+                rank = parse_int(arg)?;
+                if rank == 0 || rank == i64::MIN {
+                    return Err(RedisError::Other(String::from(
+                        "RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list"
+                    )));
+                }
+            },
+            b"count" => {
+                count = Some(parse_bounded(arg, 0, i64::MAX, "COUNT can't be negative")? as usize);
+            },
+            b"maxlen" => {
+                maxlen = parse_bounded(arg, 0, i64::MAX, "MAXLEN can't be negative")? as usize;
+            },
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+
+    let list = match get_list(storage, params[0])? {
+        Some(list) => list,
+        None if count.is_some() => return Ok(RespValue::Array(vec!())),
+        None => return Ok(RespValue::Null)
+    };
+    let limit = if maxlen == 0 {list.len()} else {maxlen.min(list.len())};
+    let wanted = match count {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => 1
+    };
+    let candidates: Box<dyn Iterator<Item = (usize, &Vec<u8>)>> = if rank > 0 {
+        Box::new(list.iter().enumerate().take(limit))
+    }else {
+        Box::new(list.iter().enumerate().rev().take(limit))
+    };
+    let matches = candidates.filter(|(_, elem)| {*elem == params[1]})
+                    .skip(rank.unsigned_abs() as usize - 1)
+                    .take(wanted)
+                    .map(|(pos, _)| {pos})
+                    .collect::<Vec<_>>();
+    match count {
+        Some(_) => Ok(matches.to_resp()),
+        None => Ok(matches.first().copied().to_resp())
+    }
+}
+
+// LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+pub fn lmove(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (from, to) = (parse_end(params[2])?, parse_end(params[3])?);
+    Ok(move_element(storage, params[0], params[1], from, to)?.to_resp())
+}
+
+pub fn rpoplpush(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(move_element(storage, params[0], params[1], End::Right, End::Left)?.to_resp())
+}
+
+
+pub struct MultiPop{
+    pub keys: Vec<Vec<u8>>,
+    pub end: End,
+    pub count: usize
+}
+
+// numkeys key [key ...] LEFT|RIGHT [COUNT count], shared with BLMPOP
+pub fn parse_multi_pop(params: &[&[u8]]) -> Result<MultiPop>{
+    let numkeys = parse_bounded(params[0], 1, i64::MAX, "numkeys should be greater than 0")? as usize;
+    if numkeys + 1 >= params.len() {return Err(RedisError::Syntax);}
+    let keys = params[1..=numkeys].iter().map(|key| {key.to_vec()}).collect();
+    let end = parse_end(params[numkeys+1])?;
+    let count = match &params[numkeys+2..] {
+        [] => 1,
+        [option, count] if option.eq_ignore_ascii_case(b"count") => {
+            parse_bounded(count, 1, i64::MAX, "count should be greater than 0")? as usize
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    Ok(MultiPop{keys, end, count})
+}
+
+// the key popped from and the elements, in pop order
+pub type Popped<'a> = (&'a [u8], Vec<Vec<u8>>);
+
+// pop from the first non empty list
+pub fn pop_first_non_empty<'a>(request: &'a MultiPop, storage: &mut Keyspace) -> Result<Option<Popped<'a>>>{
+    for key in &request.keys {
+        if let Some(popped) = pop_elements(storage, key, request.end, request.count)? {
+            return Ok(Some((key, popped)));
+        }
+    }
+    Ok(None)
+}
+
+// LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count], replying [key, [elements]]
+pub fn lmpop(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let request = parse_multi_pop(&params)?;
+    Ok(pop_first_non_empty(&request, storage)?.map_or(RespValue::NullArray, |popped| {popped.to_resp()}))
+}
+
+
+
+/* blocking variants
+ * the operation is tried right away and, failing that, handed to the keyspace
+ * which runs it again each time one of the keys is created. the connection
+ * thread sleeps on the reply until then or until the timeout passes.
+ * replicas never block, they get the plain pop or move that was actually made
+ * */
+
+// timeout in seconds with decimals, 0 blocks forever
+pub fn parse_timeout(arg: &[u8]) -> Result<Option<Duration>>{
+    let secs = std::str::from_utf8(arg).ok()
+                .and_then(|s| {s.parse::<f64>().ok()})
+                .filter(|secs| {secs.is_finite() && *secs < (i64::MAX / 1000) as f64})
+                .ok_or_else(|| {RedisError::Other(String::from("timeout is not a float or out of range"))})?;
+    if secs < 0.0 {return Err(RedisError::Other(String::from("timeout is negative")));}
+    Ok((secs > 0.0).then(|| {Duration::from_secs_f64(secs)}))
+}
+
+fn owned_keys(keys: &[&[u8]]) -> Vec<Box<[u8]>>{
+    keys.iter().map(|key| {Box::from(*key)}).collect()
+}
+
+// BLPOP key [key ...] timeout and BRPOP, replying [key, element]
+pub fn blpop(
+    params: Vec<&[u8]>, end: End, client_id: u64, storage: &RedisStorage
+) -> Result<(RespValue, Propagate)>{
+    let timeout = parse_timeout(params[params.len()-1])?;
+    let keys = owned_keys(&params[..params.len()-1]);
+    let watched = keys.clone();
+    let op = Box::new(move |db: &mut Keyspace| {
+        for key in &watched {
+            if let Some(elem) = pop_elements(db, key, end, 1)?.and_then(|mut popped| {popped.pop()}) {
+                let propagate = Propagate::command(&[end.pop_command(), key]);
+                return Ok(Some(((&key[..], elem).to_resp(), propagate)));
+            }
+        }
+        Ok(None)
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::NullArray)
+}
+
+// BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout
+pub fn blmove(params: Vec<&[u8]>, client_id: u64, storage: &RedisStorage) -> Result<(RespValue, Propagate)>{
+    let (from, to) = (parse_end(params[2])?, parse_end(params[3])?);
+    block_move(params[0], params[1], from, to, params[4], client_id, storage)
+}
+
+// BRPOPLPUSH source destination timeout
+pub fn brpoplpush(params: Vec<&[u8]>, client_id: u64, storage: &RedisStorage) -> Result<(RespValue, Propagate)>{
+    block_move(params[0], params[1], End::Right, End::Left, params[2], client_id, storage)
+}
+
+fn block_move(
+    src: &[u8], dst: &[u8], from: End, to: End, timeout: &[u8], client_id: u64, storage: &RedisStorage
+) -> Result<(RespValue, Propagate)>{
+    let timeout = parse_timeout(timeout)?;
+    let (src, dst) = (Box::<[u8]>::from(src), Box::<[u8]>::from(dst));
+    let keys = vec!(src.clone());
+    let op = Box::new(move |db: &mut Keyspace| {
+        Ok(move_element(db, &src, &dst, from, to)?.map(|elem| {
+            (elem.to_resp(), Propagate::command(&[b"LMOVE", &src, &dst, from.name(), to.name()]))
+        }))
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::Null)
+}
+
+// BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count]
+pub fn blmpop(params: Vec<&[u8]>, client_id: u64, storage: &RedisStorage) -> Result<(RespValue, Propagate)>{
+    let timeout = parse_timeout(params[0])?;
+    let request = parse_multi_pop(&params[1..])?;
+    let keys = request.keys.iter().map(|key| {Box::from(&key[..])}).collect();
+    let op = Box::new(move |db: &mut Keyspace| {
+        Ok(pop_first_non_empty(&request, db)?.map(|(key, popped)| {
+            let count = popped.len().to_string();
+            let propagate = Propagate::command(&[request.end.pop_command(), key, count.as_bytes()]);
+            ((key, popped).to_resp(), propagate)
+        }))
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::NullArray)
+}
@@ -0,0 +1,176 @@
+/* shared pieces of KEYS and the SCAN family
+ * glob style matching as implemented by redis' stringmatchlen, the
+ * MATCH / COUNT / TYPE / NOVALUES argument parsing and the cursor loop
+ * */
+
+use crate::error::{RedisError, Result};
+
+use super::parse_int;
+
+
+/* supports * ? [abc] [^abc] [a-z] and backslash escapes
+ * once the tail after a '*' failed to match anywhere in the remaining input,
+ * earlier stars can not do better by consuming more, which keeps patterns like
+ * a*a*a*a*b from going exponential
+ * */
+pub fn string_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool{
+    let mut skip_longer_matches = false;
+    string_match_impl(pattern, string, nocase, &mut skip_longer_matches, 0)
+}
+
+fn string_match_impl(
+    mut pattern: &[u8], mut string: &[u8], nocase: bool, skip_longer_matches: &mut bool, nesting: usize
+) -> bool{
+    // protection against abusive patterns
+    if nesting > 1000 {return false;}
+    let same = |a: u8, b: u8| {
+        if nocase {a.eq_ignore_ascii_case(&b)} else {a == b}
+    };
+
+    while !pattern.is_empty() && !string.is_empty() {
+        match pattern[0] {
+            b'*' => {
+                while pattern.len() > 1 && pattern[1] == b'*' {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {return true;}
+                while !string.is_empty() {
+                    if string_match_impl(&pattern[1..], string, nocase, skip_longer_matches, nesting + 1) {
+                        return true;
+                    }
+                    if *skip_longer_matches {return false;}
+                    string = &string[1..];
+                }
+                *skip_longer_matches = true;
+                return false;
+            },
+            b'?' => string = &string[1..],
+            b'[' => {
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {pattern = &pattern[1..];}
+                let c = string[0];
+                let mut matched = false;
+                loop {
+                    match pattern {
+                        [b'\\', escaped, ..] => {
+                            pattern = &pattern[1..];
+                            if *escaped == c {matched = true;}
+                        },
+                        [b']', ..] => break,
+                        // unterminated class, treat the end of the pattern as its end
+                        [] => break,
+                        [start, b'-', end, ..] => {
+                            let (mut start, mut end, mut c) = (*start, *end, c);
+                            if start > end {std::mem::swap(&mut start, &mut end);}
+                            if nocase {
+                                start = start.to_ascii_lowercase();
+                                end = end.to_ascii_lowercase();
+                                c = c.to_ascii_lowercase();
+                            }
+                            pattern = &pattern[2..];
+                            if (start..=end).contains(&c) {matched = true;}
+                        },
+                        [other, ..] => {
+                            if same(*other, c) {matched = true;}
+                        }
+                    }
+                    pattern = &pattern[1..];
+                }
+                if matched == negate {return false;}
+                string = &string[1..];
+                if pattern.is_empty() {
+                    // the class ran to the end of the pattern
+                    return string.is_empty();
+                }
+            },
+            b'\\' if pattern.len() >= 2 => {
+                pattern = &pattern[1..];
+                if !same(pattern[0], string[0]) {return false;}
+                string = &string[1..];
+            },
+            c => {
+                if !same(c, string[0]) {return false;}
+                string = &string[1..];
+            }
+        }
+        pattern = &pattern[1..];
+        if string.is_empty() {
+            while pattern.first() == Some(&b'*') {
+                pattern = &pattern[1..];
+            }
+            break;
+        }
+    }
+    pattern.is_empty() && string.is_empty()
+}
+
+
+pub struct ScanArgs{
+    pub cursor: u64,
+    pub pattern: Option<Vec<u8>>,
+    pub count: usize,
+    // SCAN only
+    pub type_filter: Option<Vec<u8>>,
+    // HSCAN only
+    pub novalues: bool
+}
+
+impl ScanArgs{
+    pub fn matches(&self, item: &[u8]) -> bool{
+        self.pattern.as_ref().is_none_or(|pattern| {string_match(pattern, item, false)})
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ScanKind{
+    Keys,
+    Hash,
+    Members
+}
+
+// cursor [MATCH pattern] [COUNT count] plus TYPE for SCAN and NOVALUES for HSCAN
+pub fn parse_scan_args(cursor: &[u8], options: &[&[u8]], kind: ScanKind) -> Result<ScanArgs>{
+    let cursor = std::str::from_utf8(cursor).ok()
+                    .and_then(|s| {s.parse::<u64>().ok()})
+                    .ok_or_else(|| {RedisError::Other(String::from("invalid cursor"))})?;
+    let mut args = ScanArgs{cursor, pattern: None, count: 10, type_filter: None, novalues: false};
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"match" => {
+                let pattern = options.next().ok_or(RedisError::Syntax)?;
+                // a lone star matches everything, skip the matcher entirely
+                args.pattern = (*pattern != b"*").then(|| {pattern.to_vec()});
+            },
+            b"count" => {
+                let count = parse_int(options.next().ok_or(RedisError::Syntax)?)?;
+                if count < 1 {return Err(RedisError::Syntax);}
+                args.count = count as usize;
+            },
+            b"type" if kind == ScanKind::Keys => {
+                args.type_filter = Some(options.next().ok_or(RedisError::Syntax)?.to_ascii_lowercase());
+            },
+            b"novalues" if kind == ScanKind::Hash => args.novalues = true,
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+    Ok(args)
+}
+
+/* one call worth of a cursor walk, `step` visits the bucket(s) under the
+ * cursor and returns the next one. like redis a call stops once COUNT items
+ * were collected or after COUNT * 10 steps, so sparse tables still answer
+ * quickly, possibly with an empty page. 0 as next cursor once the walk is over
+ * */
+pub fn scan_cursor<T>(args: &ScanArgs, mut step: impl FnMut(u64, &mut Vec<T>) -> u64) -> (u64, Vec<T>){
+    let mut page = vec!();
+    let mut cursor = args.cursor;
+    let mut max_steps = args.count * 10;
+    loop {
+        cursor = step(cursor, &mut page);
+        if cursor == 0 || max_steps == 0 || page.len() >= args.count {break;}
+        max_steps -= 1;
+    }
+    (cursor, page)
+}
@@ -0,0 +1,277 @@
+/* set commands
+ * a missing key behaves like the empty set, an emptied set is removed from the
+ * keyspace. member listings go out as RESP3 sets which RESP2 clients see as arrays
+ * */
+
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{Entry, Keyspace, RedisValue};
+use crate::persistence::dict::DictSet;
+
+use super::scan::{parse_scan_args, scan_cursor, ScanKind};
+use super::{parse_bounded, parse_int, parse_positive, to_args, Propagate};
+
+
+type Set = DictSet<Vec<u8>>;
+
+fn get_set<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a Set>>{
+    storage.get(key).map(|entry| {entry.value.as_set()}).transpose()
+}
+
+fn get_set_mut<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut Set>>{
+    storage.get_mut(key).map(|entry| {entry.value.as_set_mut()}).transpose()
+}
+
+fn members_reply<'a>(members: impl Iterator<Item = &'a Vec<u8>>) -> RespValue{
+    RespValue::Set(members.map(|member| {member.to_resp()}).collect())
+}
+
+
+pub fn sadd(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    get_set(storage, params[0])?;
+    let set = storage.get_or_insert_with(params[0], || {RedisValue::Set(Set::new())}).value.as_set_mut()?;
+    Ok(params[1..].iter().filter(|member| {set.insert(member.to_vec())}).count().to_resp())
+}
+
+pub fn srem(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let removed = match get_set_mut(storage, params[0])? {
+        Some(set) => params[1..].iter().filter(|member| {set.remove(**member)}).count(),
+        None => 0
+    };
+    storage.remove_if_empty(params[0]);
+    Ok(removed.to_resp())
+}
+
+pub fn sismember(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let set = get_set(storage, params[0])?;
+    Ok((set.is_some_and(|set| {set.contains(params[1])}) as i64).to_resp())
+}
+
+pub fn smismember(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let set = get_set(storage, params[0])?;
+    let replies = params[1..].iter()
+                    .map(|member| {set.is_some_and(|set| {set.contains(*member)}) as i64})
+                    .collect::<Vec<_>>();
+    Ok(replies.to_resp())
+}
+
+pub fn smembers(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(match get_set(storage, key)? {
+        Some(set) => members_reply(set.iter()),
+        None => RespValue::Set(vec!())
+    })
+}
+
+pub fn scard(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_set(storage, key)?.map_or(0, |set| {set.len()}).to_resp())
+}
+
+// SMOVE source destination member
+pub fn smove(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (src, dst, member) = (params[0], params[1], params[2]);
+    // the destination type is checked before anything is removed
+    get_set(storage, dst)?;
+    // moving a member onto its own set changes nothing, the key and its ttl stay
+    if src == dst {
+        let present = get_set(storage, src)?.is_some_and(|set| {set.contains(member)});
+        return Ok((present as i64).to_resp());
+    }
+    let moved = match get_set_mut(storage, src)? {
+        Some(set) => set.remove(member),
+        None => false
+    };
+    if !moved {return Ok(0.to_resp());}
+    storage.remove_if_empty(src);
+    storage.get_or_insert_with(dst, || {RedisValue::Set(Set::new())}).value.as_set_mut()?.insert(member.to_vec());
+    Ok(1.to_resp())
+}
+
+// past count * SPOP_MOVE_STRATEGY_MUL members SPOP removes them one by one, below it keeps a sample instead
+const SPOP_MOVE_STRATEGY_MUL: usize = 5;
+
+/* SPOP key [count], members are removed at random
+ * like redis only the popped members are copied when few of them are asked
+ * for, and when most of the set goes the few survivors move to a new set
+ * while the old one is handed out whole. replicas get SREM of what was popped
+ * */
+pub fn spop(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<(RespValue, Propagate)>{
+    if params.len() > 2 {return Err(RedisError::Syntax);}
+    let count = params.get(1).map(|count| {parse_positive(count)}).transpose()?;
+    let set = match (get_set_mut(storage, params[0])?, count) {
+        (Some(set), _) => set,
+        (None, None) => return Ok((RespValue::Null, Propagate::nothing())),
+        (None, Some(_)) => return Ok((RespValue::Set(vec!()), Propagate::nothing()))
+    };
+    let count = match count {
+        Some(count) => count as usize,
+        None => {
+            let member = set.random().cloned().unwrap();
+            set.remove(&member);
+            storage.remove_if_empty(params[0]);
+            let propagate = Propagate::command(&[b"SREM", params[0], &member]);
+            return Ok((member.to_resp(), propagate));
+        }
+    };
+    let popped = if count >= set.len() {
+        std::mem::take(set)
+    }else if count * SPOP_MOVE_STRATEGY_MUL > set.len() {
+        let kept = set.iter().choose_multiple(&mut rand::thread_rng(), set.len() - count);
+        let kept = kept.into_iter().cloned().collect::<Set>();
+        kept.iter().for_each(|member| {set.remove(member);});
+        std::mem::replace(set, kept)
+    }else {
+        (0..count).map(|_| {
+            let member = set.random().cloned().unwrap();
+            set.remove(&member);
+            member
+        }).collect()
+    };
+    storage.remove_if_empty(params[0]);
+    let mut srem = to_args(&[b"SREM", params[0]]);
+    srem.extend(popped.iter().map(|member| {member.to_vec()}));
+    let propagate = match popped.is_empty() {
+        true => Propagate::nothing(),
+        false => Propagate::Rewritten(vec!(srem))
+    };
+    Ok((members_reply(popped.iter()), propagate))
+}
+
+// SRANDMEMBER key [count], a negative count may repeat members
+pub fn srandmember(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    if params.len() > 2 {return Err(RedisError::Syntax);}
+    let count = params.get(1).map(|count| {parse_int(count)}).transpose()?;
+    if count.is_some_and(|count| {count.unsigned_abs() > (i64::MAX / 2) as u64}) {
+        return Err(RedisError::Other(String::from("value is out of range")));
+    }
+    let mut rng = rand::thread_rng();
+    let set = match (get_set(storage, params[0])?, count) {
+        (Some(set), _) => set,
+        (None, None) => return Ok(RespValue::Null),
+        (None, Some(_)) => return Ok(RespValue::Array(vec!()))
+    };
+    let picked: Vec<_> = match count {
+        None => return Ok(set.random().to_resp()),
+        Some(count) if count >= 0 => set.iter().choose_multiple(&mut rng, count as usize),
+        Some(count) => {
+            let members = set.iter().collect::<Vec<_>>();
+            (0..count.unsigned_abs()).filter_map(|_| {members.choose(&mut rng).copied()}).collect()
+        }
+    };
+    Ok(picked.to_resp())
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SetOp{
+    Inter,
+    Union,
+    Diff
+}
+
+/* the algebra is done one key at a time so only the result is ever copied
+ * every key is type checked up front, a wrong type anywhere fails the command
+ * */
+fn combine(storage: &mut Keyspace, keys: &[&[u8]], op: SetOp) -> Result<Set>{
+    let mut sizes = Vec::with_capacity(keys.len());
+    for key in keys {
+        sizes.push(get_set(storage, key)?.map_or(0, |set| {set.len()}));
+    }
+    match op {
+        SetOp::Inter => {
+            // walk the smallest set, any missing key empties the result
+            let mut order = (0..keys.len()).collect::<Vec<_>>();
+            order.sort_by_key(|idx| {sizes[*idx]});
+            if sizes[order[0]] == 0 {return Ok(Set::new());}
+            let mut result = get_set(storage, keys[order[0]])?.cloned().unwrap_or_default();
+            for idx in &order[1..] {
+                match get_set(storage, keys[*idx])? {
+                    Some(other) => result.retain(|member| {other.contains(member)}),
+                    None => return Ok(Set::new())
+                }
+            }
+            Ok(result)
+        },
+        SetOp::Union => {
+            let mut result = Set::new();
+            for key in keys {
+                if let Some(set) = get_set(storage, key)? {
+                    result.extend(set.iter().cloned());
+                }
+            }
+            Ok(result)
+        },
+        SetOp::Diff => {
+            let mut result = get_set(storage, keys[0])?.cloned().unwrap_or_default();
+            for key in &keys[1..] {
+                if result.is_empty() {break;}
+                if let Some(set) = get_set(storage, key)? {
+                    result.retain(|member| {!set.contains(member)});
+                }
+            }
+            Ok(result)
+        }
+    }
+}
+
+// SINTER, SUNION and SDIFF key [key ...]
+pub fn set_algebra(params: Vec<&[u8]>, op: SetOp, storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(members_reply(combine(storage, &params, op)?.iter()))
+}
+
+// SINTERSTORE, SUNIONSTORE and SDIFFSTORE destination key [key ...], an empty result deletes the destination
+pub fn set_algebra_store(params: Vec<&[u8]>, op: SetOp, storage: &mut Keyspace) -> Result<RespValue>{
+    let result = combine(storage, &params[1..], op)?;
+    let len = result.len();
+    match len {
+        0 => {storage.remove(params[0]);},
+        _ => {storage.insert(params[0], Entry::new(RedisValue::Set(result)));}
+    }
+    Ok(len.to_resp())
+}
+
+// SINTERCARD numkeys key [key ...] [LIMIT limit], LIMIT 0 means no limit
+pub fn sintercard(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let numkeys = parse_bounded(params[0], 1, i64::MAX, "numkeys should be greater than 0")? as usize;
+    if numkeys >= params.len() {
+        return Err(RedisError::Other(String::from("Number of keys can't be greater than number of args")));
+    }
+    let keys = &params[1..=numkeys];
+    let limit = match &params[numkeys+1..] {
+        [] => 0,
+        [option, limit] if option.eq_ignore_ascii_case(b"limit") => {
+            parse_bounded(limit, 0, i64::MAX, "LIMIT can't be negative")? as usize
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    // every key is type checked first, a missing or empty one makes the answer 0
+    for key in keys {
+        if get_set(storage, key)?.is_none_or(|set| {set.is_empty()}) {return Ok(0.to_resp());}
+    }
+    let mut sets = keys.iter()
+                    .filter_map(|key| {storage.lookup(key).and_then(|entry| {entry.value.as_set().ok()})})
+                    .collect::<Vec<_>>();
+    sets.sort_by_key(|set| {set.len()});
+    // walk the smallest set and stop counting at the limit
+    let limit = if limit == 0 {usize::MAX} else {limit};
+    let card = sets[0].iter()
+                .filter(|member| {sets[1..].iter().all(|other| {other.contains(*member)})})
+                .take(limit)
+                .count();
+    Ok(card.to_resp())
+}
+
+// SSCAN key cursor [MATCH pattern] [COUNT count]
+pub fn sscan(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let args = parse_scan_args(params[1], &params[2..], ScanKind::Members)?;
+    let set = match get_set(storage, params[0])? {
+        Some(set) => set,
+        None => return Ok(("0", RespValue::Array(vec!())).to_resp())
+    };
+    let (next, page) = scan_cursor(&args, |cursor, page| {
+        set.scan(cursor, |member| {page.push(member)})
+    });
+    let members = page.into_iter().filter(|member| {args.matches(member)}).collect::<Vec<_>>();
+    Ok((next.to_string(), members).to_resp())
+}
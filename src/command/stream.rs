@@ -0,0 +1,382 @@
+/* stream commands
+ * unlike the other aggregates a stream stays in the keyspace once emptied, it
+ * still carries its last id. XADD wakes clients parked in XREAD BLOCK by
+ * signalling the key even though it already existed
+ * */
+
+use std::time::Duration;
+
+use crate::error::{RedisError, Result};
+use crate::parser::{ProtocolVersion, RespValue, ToResp};
+use crate::persistence::{unix_time_ms, Keyspace, RedisStorage, RedisValue, Stream};
+use crate::persistence::value::{StreamEntry, StreamId, TrimStrategy, BLOCK_MAX_ENTRIES};
+
+use super::{parse_int, Propagate};
+
+mod group;
+pub use group::{xack, xautoclaim, xclaim, xgroup, xinfo, xpending, xreadgroup};
+
+
+fn get_stream<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a Stream>>{
+    storage.get(key).map(|entry| {entry.value.as_stream()}).transpose()
+}
+
+fn get_stream_mut<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut Stream>>{
+    storage.get_mut(key).map(|entry| {entry.value.as_stream_mut()}).transpose()
+}
+
+fn invalid_id() -> RedisError{
+    RedisError::Other(String::from("Invalid stream ID specified as stream command argument"))
+}
+
+fn parse_u64(arg: &[u8]) -> Option<u64>{
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// <ms>-<seq>, or a bare <ms> taking `missing_seq` as its sequence
+pub fn parse_id(arg: &[u8], missing_seq: u64) -> Result<StreamId>{
+    let mut parts = arg.splitn(2, |c| {*c == b'-'});
+    let ms = parts.next().and_then(parse_u64).ok_or_else(invalid_id)?;
+    let seq = match parts.next() {
+        Some(seq) => parse_u64(seq).ok_or_else(invalid_id)?,
+        None => missing_seq
+    };
+    Ok(StreamId::new(ms, seq))
+}
+
+// interval ends for XRANGE, - and + included, ( makes the end exclusive
+fn parse_range_end(arg: &[u8], is_start: bool) -> Result<StreamId>{
+    match arg {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => {
+            let id = parse_id(id, if is_start {0} else {u64::MAX})?;
+            let bound = if is_start {id.successor()} else {id.predecessor()};
+            bound.ok_or_else(|| {
+                RedisError::Other(format!("invalid {} ID for the interval", if is_start {"start"} else {"end"}))
+            })
+        },
+        id => parse_id(id, if is_start {0} else {u64::MAX})
+    }
+}
+
+pub fn entry_reply((id, fields): StreamEntry) -> RespValue{
+    let fields = fields.into_iter().flat_map(|(field, value)| {[field.to_resp(), value.to_resp()]}).collect();
+    (id.to_string(), RespValue::Array(fields)).to_resp()
+}
+
+pub fn entries_reply(entries: Vec<StreamEntry>) -> RespValue{
+    RespValue::Array(entries.into_iter().map(entry_reply).collect())
+}
+
+
+#[derive(Default)]
+pub struct TrimArgs{
+    pub strategy: Option<TrimStrategy>,
+    pub approx: bool,
+    pub limit: Option<i64>
+}
+
+impl TrimArgs{
+    /* MAXLEN | MINID [= | ~] threshold [LIMIT count], one option at `params[*idx]`
+     * moves past it and returns false when the token there is not one of them
+     * */
+    pub fn parse_option(&mut self, params: &[&[u8]], idx: &mut usize) -> Result<bool>{
+        let option = params[*idx].to_ascii_lowercase();
+        let mut next = || {
+            *idx += 1;
+            params.get(*idx).copied().ok_or(RedisError::Syntax)
+        };
+        match option.as_slice() {
+            b"maxlen" | b"minid" => {
+                let mut threshold = next()?;
+                self.approx = threshold == b"~";
+                if threshold == b"~" || threshold == b"=" {threshold = next()?;}
+                self.strategy = Some(match option.as_slice() {
+                    b"maxlen" => {
+                        let max = parse_int(threshold)?;
+                        if max < 0 {return Err(RedisError::Other(String::from("The MAXLEN argument must be >= 0.")));}
+                        TrimStrategy::MaxLen(max as usize)
+                    },
+                    _ => TrimStrategy::MinId(parse_id(threshold, 0)?)
+                });
+            },
+            b"limit" => {
+                let limit = parse_int(next()?)?;
+                if limit < 0 {return Err(RedisError::Other(String::from("The LIMIT argument must be >= 0.")));}
+                self.limit = Some(limit);
+            },
+            _ => return Ok(false)
+        }
+        *idx += 1;
+        Ok(true)
+    }
+
+    pub fn validate(&self) -> Result<()>{
+        if self.limit.is_some() && !self.approx {
+            return Err(RedisError::Other(String::from("syntax error, LIMIT cannot be used without the special ~ option")));
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, stream: &mut Stream) -> usize{
+        let strategy = match self.strategy {
+            Some(strategy) => strategy,
+            None => return 0
+        };
+        // the approximate form does at most this much work by default
+        let limit = self.limit.unwrap_or(if self.approx {100 * BLOCK_MAX_ENTRIES as i64} else {0});
+        stream.trim(strategy, self.approx, limit as usize)
+    }
+}
+
+
+enum IdSpec{
+    Auto,
+    // <ms>-*
+    AutoSeq(u64),
+    Explicit(StreamId)
+}
+
+fn parse_id_spec(arg: &[u8]) -> Result<IdSpec>{
+    if arg == b"*" {return Ok(IdSpec::Auto);}
+    match arg.strip_suffix(b"-*") {
+        Some(ms) => Ok(IdSpec::AutoSeq(parse_u64(ms).ok_or_else(invalid_id)?)),
+        None => Ok(IdSpec::Explicit(parse_id(arg, 0)?))
+    }
+}
+
+// the id XADD assigns, strictly greater than anything the stream has seen
+fn next_id(stream: &Stream, spec: IdSpec) -> Result<StreamId>{
+    let last = stream.last_id();
+    let too_small = || {
+        RedisError::Other(String::from("The ID specified in XADD is equal or smaller than the target stream top item"))
+    };
+    match spec {
+        IdSpec::Auto => {
+            let now = unix_time_ms();
+            if now > last.ms {return Ok(StreamId::new(now, 0));}
+            last.successor().ok_or_else(|| {
+                RedisError::Other(String::from("The stream has exhausted the last possible ID, unable to add more items"))
+            })
+        },
+        IdSpec::AutoSeq(ms) if ms > last.ms => Ok(StreamId::new(ms, 0)),
+        IdSpec::AutoSeq(ms) if ms == last.ms => {
+            last.seq.checked_add(1).map(|seq| {StreamId::new(ms, seq)}).ok_or_else(too_small)
+        },
+        IdSpec::AutoSeq(_) => Err(too_small()),
+        IdSpec::Explicit(id) if id == StreamId::MIN => {
+            Err(RedisError::Other(String::from("The ID specified in XADD must be greater than 0-0")))
+        },
+        IdSpec::Explicit(id) if id > last => Ok(id),
+        IdSpec::Explicit(_) => Err(too_small())
+    }
+}
+
+// XADD key [NOMKSTREAM] [MAXLEN | MINID [= | ~] threshold [LIMIT count]] * | id field value [field value ...]
+pub fn xadd(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let key = params[0];
+    let (mut nomkstream, mut trim) = (false, TrimArgs::default());
+    let mut idx = 1;
+    while idx < params.len() {
+        if params[idx].eq_ignore_ascii_case(b"nomkstream") {
+            nomkstream = true;
+            idx += 1;
+        } else if !trim.parse_option(&params, &mut idx)? {
+            break;
+        }
+    }
+    trim.validate()?;
+    let fields = match params.get(idx+1..) {
+        Some(fields) if !fields.is_empty() && fields.len().is_multiple_of(2) => fields,
+        _ => return Err(RedisError::wrong_arity("xadd"))
+    };
+    let spec = parse_id_spec(params[idx])?;
+
+    if get_stream(storage, key)?.is_none() && nomkstream {return Ok(RespValue::Null);}
+    // the id is validated before an empty stream would be created for it
+    let id = next_id(get_stream(storage, key)?.unwrap_or(&Stream::new()), spec)?;
+    let stream = storage.get_or_insert_with(key, || {RedisValue::Stream(Stream::new())}).value.as_stream_mut()?;
+    let fields = fields.chunks(2).map(|pair| {(pair[0].to_vec(), pair[1].to_vec())}).collect();
+    stream.append(id, fields);
+    trim.apply(stream);
+    storage.blocked.signal_ready(key);
+    Ok(id.to_string().to_resp())
+}
+
+pub fn xlen(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_stream(storage, key)?.map_or(0, |stream| {stream.len()}).to_resp())
+}
+
+// XRANGE key start end [COUNT count] and XREVRANGE key end start [COUNT count]
+pub fn xrange(params: Vec<&[u8]>, reverse: bool, storage: &mut Keyspace) -> Result<RespValue>{
+    let (start, end) = if reverse {(params[2], params[1])} else {(params[1], params[2])};
+    let (start, end) = (parse_range_end(start, true)?, parse_range_end(end, false)?);
+    let count = match &params[3..] {
+        [] => usize::MAX,
+        [option, count] if option.eq_ignore_ascii_case(b"count") => {
+            // a negative count returns nothing, like COUNT 0
+            usize::try_from(parse_int(count)?).unwrap_or(0)
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    let entries = match get_stream(storage, params[0])? {
+        Some(stream) if count > 0 => stream.range(start, end, reverse, count),
+        _ => vec!()
+    };
+    Ok(entries_reply(entries))
+}
+
+pub fn xdel(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let ids = params[1..].iter().map(|id| {parse_id(id, 0)}).collect::<Result<Vec<_>>>()?;
+    let deleted = match get_stream_mut(storage, params[0])? {
+        Some(stream) => ids.into_iter().filter(|id| {stream.delete(*id)}).count(),
+        None => 0
+    };
+    Ok(deleted.to_resp())
+}
+
+// XTRIM key MAXLEN | MINID [= | ~] threshold [LIMIT count]
+pub fn xtrim(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (mut idx, mut trim) = (1, TrimArgs::default());
+    while idx < params.len() {
+        if !trim.parse_option(&params, &mut idx)? {return Err(RedisError::Syntax);}
+    }
+    if trim.strategy.is_none() {return Err(RedisError::Syntax);}
+    trim.validate()?;
+    Ok(match get_stream_mut(storage, params[0])? {
+        Some(stream) => trim.apply(stream),
+        None => 0
+    }.to_resp())
+}
+
+
+struct ReadArgs<'a>{
+    count: usize,
+    // None without BLOCK, Some(None) blocks for good
+    block: Option<Option<Duration>>,
+    group: Option<(&'a [u8], &'a [u8])>,
+    noack: bool,
+    keys: &'a [&'a [u8]],
+    ids: &'a [&'a [u8]]
+}
+
+// [COUNT count] [BLOCK milliseconds] [GROUP group consumer] [NOACK] STREAMS key [key ...] id [id ...]
+fn parse_read_args<'a>(cmd: &str, params: &'a [&'a [u8]]) -> Result<ReadArgs<'a>>{
+    let grouped = cmd == "xreadgroup";
+    let mut args = ReadArgs{count: usize::MAX, block: None, group: None, noack: false, keys: &[], ids: &[]};
+    let arg = |idx: usize| {params.get(idx).copied().ok_or(RedisError::Syntax)};
+    let mut idx = 0;
+    loop {
+        let option = arg(idx)?.to_ascii_lowercase();
+        match option.as_slice() {
+            b"count" => {
+                args.count = usize::try_from(parse_int(arg(idx+1)?)?).unwrap_or(usize::MAX);
+                idx += 2;
+            },
+            b"block" => {
+                let ms = parse_int(arg(idx+1)?)?;
+                if ms < 0 {return Err(RedisError::Other(String::from("timeout is negative")));}
+                args.block = Some((ms > 0).then(|| {Duration::from_millis(ms as u64)}));
+                idx += 2;
+            },
+            b"group" if grouped => {
+                args.group = Some((arg(idx+1)?, arg(idx+2)?));
+                idx += 3;
+            },
+            b"group" => {
+                return Err(RedisError::Other(String::from(
+                    "The GROUP option is only supported by XREADGROUP. You called XREAD instead."
+                )));
+            },
+            b"noack" if grouped => {
+                args.noack = true;
+                idx += 1;
+            },
+            b"streams" => break,
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+    let rest = &params[idx+1..];
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(RedisError::Other(format!(
+            "Unbalanced '{cmd}' list of streams: for each stream key an ID or '$' must be specified."
+        )));
+    }
+    if grouped && args.group.is_none() {
+        return Err(RedisError::Other(String::from("Missing GROUP option for XREADGROUP")));
+    }
+    (args.keys, args.ids) = rest.split_at(rest.len() / 2);
+    if args.count == 0 {args.count = usize::MAX;}
+    Ok(args)
+}
+
+// [key, entries] pairs, a map on RESP3
+fn streams_reply(replies: Vec<(RespValue, RespValue)>, proto: ProtocolVersion) -> RespValue{
+    match proto {
+        ProtocolVersion::Resp2 => RespValue::Array(replies.into_iter().map(|pair| {pair.to_resp()}).collect()),
+        ProtocolVersion::Resp3 => RespValue::Map(replies)
+    }
+}
+
+struct ReadRequest{
+    count: usize,
+    // entries strictly after the id are read from each key
+    streams: Vec<(Vec<u8>, StreamId)>,
+    proto: ProtocolVersion
+}
+
+// only the keys that had something are part of the reply
+fn read_streams(request: &ReadRequest, storage: &mut Keyspace) -> Result<Option<RespValue>>{
+    let mut replies = vec!();
+    for (key, after) in &request.streams {
+        let stream = match get_stream(storage, key)? {
+            Some(stream) => stream,
+            None => continue
+        };
+        let entries = match after.successor() {
+            Some(start) => stream.range(start, StreamId::MAX, false, request.count),
+            None => vec!()
+        };
+        if !entries.is_empty() {
+            replies.push((key.to_resp(), entries_reply(entries)));
+        }
+    }
+    if replies.is_empty() {return Ok(None);}
+    Ok(Some(streams_reply(replies, request.proto)))
+}
+
+// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+pub fn xread(
+    params: Vec<&[u8]>, proto: ProtocolVersion, client_id: u64, storage: &RedisStorage
+) -> Result<RespValue>{
+    let args = parse_read_args("xread", &params)?;
+    let mut data = storage.lock();
+    let mut streams = vec!();
+    for (key, id) in args.keys.iter().zip(args.ids) {
+        let stream = get_stream(&mut data, key)?;
+        let after = match *id {
+            // only what arrives from now on
+            b"$" => stream.map_or(StreamId::MIN, |stream| {stream.last_id()}),
+            // the last entry itself
+            b"+" => stream.and_then(|stream| {stream.last_entry()})
+                        .and_then(|(id, _)| {id.predecessor()})
+                        .unwrap_or(StreamId::MAX),
+            id => parse_id(id, 0)?
+        };
+        streams.push((key.to_vec(), after));
+    }
+    let request = ReadRequest{count: args.count, streams, proto};
+    if let Some(reply) = read_streams(&request, &mut data)? {return Ok(reply);}
+    drop(data);
+
+    let timeout = match args.block {
+        Some(timeout) => timeout,
+        None => return Ok(RespValue::NullArray)
+    };
+    let keys = args.keys.iter().map(|key| {Box::<[u8]>::from(*key)}).collect();
+    let op = Box::new(move |db: &mut Keyspace| {
+        Ok(read_streams(&request, db)?.map(|reply| {(reply, Propagate::Verbatim)}))
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::NullArray).map(|(reply, _)| {reply})
+}
@@ -0,0 +1,508 @@
+/* consumer group commands
+ * the PEL bookkeeping lives with the stream itself, these only parse and shape
+ * replies. XREADGROUP parks on the same blocking path as XREAD, but only when
+ * every id is > since reading a consumer's history never waits
+ * */
+
+use crate::error::{RedisError, Result};
+use crate::parser::{ProtocolVersion, RespValue, ToResp};
+use crate::persistence::{unix_time_ms, Keyspace, RedisStorage, RedisValue, Stream};
+use crate::persistence::value::{Claimed, ClaimOptions, ConsumerGroup, StreamId};
+
+use super::{
+    entries_reply, entry_reply, get_stream, get_stream_mut, parse_id, parse_range_end, parse_read_args, streams_reply
+};
+use super::super::{parse_bounded, parse_int, Propagate};
+
+
+fn no_group(key: &[u8], group: &[u8]) -> RedisError{
+    RedisError::NoGroup(format!(
+        "No such key '{}' or consumer group '{}'", String::from_utf8_lossy(key), String::from_utf8_lossy(group)
+    ))
+}
+
+fn no_group_for_key(key: &[u8], group: &[u8]) -> RedisError{
+    RedisError::NoGroup(format!(
+        "No such consumer group '{}' for key name '{}'", String::from_utf8_lossy(group), String::from_utf8_lossy(key)
+    ))
+}
+
+fn sub_arity(cmd: &str, sub: &[u8]) -> RedisError{
+    RedisError::Other(format!("wrong number of arguments for '{cmd}|{}' command", String::from_utf8_lossy(sub)))
+}
+
+// the stream and group the command works on, NOGROUP when either is missing
+fn get_group_stream<'a>(storage: &'a mut Keyspace, key: &[u8], group: &[u8]) -> Result<&'a mut Stream>{
+    match get_stream_mut(storage, key)? {
+        Some(stream) if stream.group(group).is_some() => Ok(stream),
+        _ => Err(no_group(key, group))
+    }
+}
+
+// -1 stands for an unknown count
+fn parse_entries_read(arg: &[u8]) -> Result<Option<u64>>{
+    match parse_int(arg)? {
+        -1 => Ok(None),
+        read if read >= 0 => Ok(Some(read as u64)),
+        _ => Err(RedisError::Other(String::from("value for ENTRIESREAD must be positive or -1")))
+    }
+}
+
+
+/* XGROUP CREATE key group id | $ [MKSTREAM] [ENTRIESREAD entries-read]
+ *      | SETID key group id | $ [ENTRIESREAD entries-read]
+ *      | DESTROY key group
+ *      | CREATECONSUMER key group consumer
+ *      | DELCONSUMER key group consumer
+ * */
+pub fn xgroup(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let sub = params[0].to_ascii_lowercase();
+    match (sub.as_slice(), &params[1..]) {
+        (b"create" | b"setid", [_, _, _, ..]) | (b"destroy", [_, _]) | (b"createconsumer" | b"delconsumer", [_, _, _]) => (),
+        (b"create" | b"setid" | b"destroy" | b"createconsumer" | b"delconsumer", _) => {
+            return Err(sub_arity("xgroup", &sub));
+        },
+        _ => return Err(RedisError::unknown_subcommand("xgroup", params[0]))
+    }
+    let (key, group) = (params[1], params[2]);
+    // checked before MKSTREAM gets to create anything
+    let id = match (sub.as_slice(), params.get(3)) {
+        (b"create" | b"setid", Some(id)) if *id != b"$" => Some(parse_id(id, 0)?),
+        _ => None
+    };
+
+    let (mut mkstream, mut entries_read) = (false, None);
+    if matches!(sub.as_slice(), b"create" | b"setid") {
+        let mut idx = 4;
+        while idx < params.len() {
+            let option = params[idx].to_ascii_lowercase();
+            match option.as_slice() {
+                b"mkstream" if sub == b"create" => mkstream = true,
+                b"entriesread" if idx + 1 < params.len() => {
+                    idx += 1;
+                    entries_read = parse_entries_read(params[idx])?;
+                },
+                _ => return Err(RedisError::Syntax)
+            }
+            idx += 1;
+        }
+    }
+
+    let now = unix_time_ms();
+    if get_stream(storage, key)?.is_none() {
+        if !mkstream {
+            return Err(RedisError::Other(String::from(
+                "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+            )));
+        }
+        storage.get_or_insert_with(key, || {RedisValue::Stream(Stream::new())});
+    }
+    let stream = get_stream_mut(storage, key)?.unwrap();
+    match sub.as_slice() {
+        b"create" | b"setid" => {
+            let id = id.unwrap_or(stream.last_id());
+            if sub == b"create" {
+                if !stream.create_group(group, id, entries_read) {return Err(RedisError::BusyGroup);}
+            } else {
+                let group = stream.group_mut(group).ok_or_else(|| {no_group_for_key(key, group)})?;
+                group.last_id = id;
+                group.entries_read = entries_read;
+            }
+            Ok(RespValue::ok())
+        },
+        b"destroy" => Ok((stream.destroy_group(group) as i64).to_resp()),
+        _ => {
+            let group = stream.group_mut(group).ok_or_else(|| {no_group_for_key(key, group)})?;
+            Ok(match sub.as_slice() {
+                b"createconsumer" => (group.create_consumer(params[3], now) as i64).to_resp(),
+                _ => group.delete_consumer(params[3]).unwrap_or(0).to_resp()
+            })
+        }
+    }
+}
+
+
+struct GroupReadRequest{
+    group: Vec<u8>,
+    consumer: Vec<u8>,
+    count: usize,
+    noack: bool,
+    // None reads new entries (>), an id reads the consumer's pending entries after it
+    streams: Vec<(Vec<u8>, Option<StreamId>)>,
+    proto: ProtocolVersion
+}
+
+// history reads always show up in the reply, even with nothing pending
+fn read_group_streams(request: &GroupReadRequest, storage: &mut Keyspace) -> Result<Option<RespValue>>{
+    let now = unix_time_ms();
+    let mut replies = vec!();
+    for (key, after) in &request.streams {
+        let missing = || {RedisError::NoGroup(format!(
+            "No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+            String::from_utf8_lossy(key), String::from_utf8_lossy(&request.group)
+        ))};
+        let stream = get_stream_mut(storage, key)?.ok_or_else(missing)?;
+        let (group, consumer) = (&request.group, &request.consumer);
+        match after {
+            None => {
+                let entries = stream.read_group(group, consumer, request.count, request.noack, now).ok_or_else(missing)?;
+                if !entries.is_empty() {
+                    replies.push((key.to_resp(), entries_reply(entries)));
+                }
+            },
+            Some(after) => {
+                let pending = stream.read_pending(group, consumer, *after, request.count, now).ok_or_else(missing)?;
+                let entries = pending.into_iter().map(|(id, fields)| {
+                    match fields {
+                        Some(fields) => entry_reply((id, fields)),
+                        // deleted since it was delivered
+                        None => (id.to_string(), RespValue::NullArray).to_resp()
+                    }
+                }).collect();
+                replies.push((key.to_resp(), RespValue::Array(entries)));
+            }
+        }
+    }
+    if replies.is_empty() {return Ok(None);}
+    Ok(Some(streams_reply(replies, request.proto)))
+}
+
+// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...] id [id ...]
+pub fn xreadgroup(
+    params: Vec<&[u8]>, proto: ProtocolVersion, client_id: u64, storage: &RedisStorage
+) -> Result<RespValue>{
+    let args = parse_read_args("xreadgroup", &params)?;
+    let (group, consumer) = args.group.unwrap();
+    let mut streams = vec!();
+    for (key, id) in args.keys.iter().zip(args.ids) {
+        let after = match *id {
+            b">" => None,
+            b"$" => {
+                return Err(RedisError::Other(String::from(
+                    "The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set."
+                )));
+            },
+            id => Some(parse_id(id, 0)?)
+        };
+        streams.push((key.to_vec(), after));
+    }
+    let request = GroupReadRequest{
+        group: group.to_vec(), consumer: consumer.to_vec(), count: args.count, noack: args.noack, streams, proto
+    };
+    if let Some(reply) = read_group_streams(&request, &mut storage.lock())? {return Ok(reply);}
+
+    let timeout = match args.block {
+        Some(timeout) => timeout,
+        None => return Ok(RespValue::NullArray)
+    };
+    let keys = args.keys.iter().map(|key| {Box::<[u8]>::from(*key)}).collect();
+    let op = Box::new(move |db: &mut Keyspace| {
+        Ok(read_group_streams(&request, db)?.map(|reply| {(reply, Propagate::Verbatim)}))
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::NullArray).map(|(reply, _)| {reply})
+}
+
+// XACK key group id [id ...]
+pub fn xack(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let ids = params[2..].iter().map(|id| {parse_id(id, 0)}).collect::<Result<Vec<_>>>()?;
+    let acked = match get_stream_mut(storage, params[0])?.and_then(|stream| {stream.group_mut(params[1])}) {
+        Some(group) => ids.into_iter().filter(|id| {group.ack(*id)}).count(),
+        None => 0
+    };
+    Ok(acked.to_resp())
+}
+
+fn pending_summary(group: &ConsumerGroup) -> RespValue{
+    let (first, last) = match (group.pending.keys().next(), group.pending.keys().next_back()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return RespValue::Array(vec!(0.to_resp(), RespValue::Null, RespValue::Null, RespValue::NullArray))
+    };
+    let consumers = group.consumers.iter()
+                        .filter(|(_, consumer)| {!consumer.pending.is_empty()})
+                        .map(|(name, consumer)| {(name, consumer.pending.len().to_string()).to_resp()})
+                        .collect();
+    RespValue::Array(vec!(
+        group.pending.len().to_resp(), first.to_string().to_resp(), last.to_string().to_resp(), RespValue::Array(consumers)
+    ))
+}
+
+// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+pub fn xpending(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (key, group) = (params[0], params[1]);
+    let (min_idle, rest) = match &params[2..] {
+        [option, min_idle, rest @ ..] if option.eq_ignore_ascii_case(b"idle") => (parse_int(min_idle)?.max(0) as u64, rest),
+        rest => (0, rest)
+    };
+    let extended = match rest {
+        [] if params.len() == 2 => None,
+        [start, end, count] => Some((start, end, count, None)),
+        [start, end, count, consumer] => Some((start, end, count, Some(*consumer))),
+        _ => return Err(RedisError::Syntax)
+    };
+    let extended = extended.map(|(start, end, count, consumer)| -> Result<_> {
+        let count = parse_int(count)?.max(0) as usize;
+        Ok((parse_range_end(start, true)?, parse_range_end(end, false)?, count, consumer))
+    }).transpose()?;
+
+    let stream = get_group_stream(storage, key, group)?;
+    let group = stream.group(group).unwrap();
+    let (start, end, count, consumer) = match extended {
+        Some(extended) => extended,
+        None => return Ok(pending_summary(group))
+    };
+    if start > end {return Ok(RespValue::Array(vec!()));}
+    let now = unix_time_ms();
+    let entries = group.pending.range(start..=end)
+                    .filter(|(_, entry)| {consumer.is_none_or(|consumer| {entry.consumer == consumer})})
+                    .filter(|(_, entry)| {now.saturating_sub(entry.delivery_time) >= min_idle})
+                    .take(count)
+                    .map(|(id, entry)| {
+                        (id.to_string(), &entry.consumer, now.saturating_sub(entry.delivery_time), entry.delivery_count).to_resp()
+                    })
+                    .collect();
+    Ok(RespValue::Array(entries))
+}
+
+fn claimed_reply(entries: Vec<(StreamId, Option<RespValue>)>, justid: bool) -> RespValue{
+    RespValue::Array(entries.into_iter().map(|(id, entry)| {
+        match justid {
+            true => id.to_string().to_resp(),
+            false => entry.unwrap()
+        }
+    }).collect())
+}
+
+/* XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-milliseconds]
+ *        [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID lastid]
+ * */
+pub fn xclaim(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (key, group, consumer) = (params[0], params[1], params[2]);
+    let min_idle = parse_int(params[3])
+                    .map_err(|_| {RedisError::Other(String::from("Invalid min-idle-time argument for XCLAIM"))})?
+                    .max(0) as u64;
+    // ids run up to the first argument that does not parse as one
+    let mut idx = 4;
+    let mut ids = vec!();
+    while let Some(id) = params.get(idx).and_then(|id| {parse_id(id, 0).ok()}) {
+        ids.push(id);
+        idx += 1;
+    }
+
+    let now = unix_time_ms();
+    let mut options = ClaimOptions{min_idle, delivery_time: now, retry_count: None, force: false, justid: false};
+    let mut last_id = None;
+    while idx < params.len() {
+        let option = params[idx].to_ascii_lowercase();
+        let value = |name: &str| -> Result<i64> {
+            params.get(idx+1)
+                .and_then(|value| {parse_int(value).ok()})
+                .ok_or_else(|| {RedisError::Other(format!("Invalid {name} option argument for XCLAIM"))})
+        };
+        match option.as_slice() {
+            b"force" => options.force = true,
+            b"justid" => options.justid = true,
+            b"idle" => {
+                options.delivery_time = now.saturating_sub(value("IDLE")?.max(0) as u64);
+                idx += 1;
+            },
+            b"time" => {
+                options.delivery_time = value("TIME")?.max(0) as u64;
+                idx += 1;
+            },
+            b"retrycount" => {
+                options.retry_count = Some(value("RETRYCOUNT")?.max(0) as u64);
+                idx += 1;
+            },
+            b"lastid" if idx + 1 < params.len() => {
+                last_id = Some(parse_id(params[idx+1], 0)?);
+                idx += 1;
+            },
+            _ => {
+                return Err(RedisError::Other(format!(
+                    "Unrecognized XCLAIM option '{}'", String::from_utf8_lossy(params[idx])
+                )));
+            }
+        }
+        idx += 1;
+    }
+    // a delivery time in the future is taken as now
+    options.delivery_time = options.delivery_time.min(now);
+
+    let stream = get_group_stream(storage, key, group)?;
+    if let Some(last_id) = last_id {
+        let group = stream.group_mut(group).unwrap();
+        group.last_id = group.last_id.max(last_id);
+    }
+    let mut claimed = vec!();
+    for id in ids {
+        if let Claimed::Entry(entry) = stream.claim(group, consumer, id, &options, now) {
+            claimed.push((id, Some(entry_reply(entry))));
+        }
+    }
+    Ok(claimed_reply(claimed, options.justid))
+}
+
+// XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]
+pub fn xautoclaim(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (key, group, consumer) = (params[0], params[1], params[2]);
+    let min_idle = parse_int(params[3])
+                    .map_err(|_| {RedisError::Other(String::from("Invalid min-idle-time argument for XAUTOCLAIM"))})?
+                    .max(0) as u64;
+    let start = parse_range_end(params[4], true)?;
+    let (mut count, mut justid) = (100, false);
+    let mut idx = 5;
+    while idx < params.len() {
+        let option = params[idx].to_ascii_lowercase();
+        match option.as_slice() {
+            b"justid" => justid = true,
+            b"count" if idx + 1 < params.len() => {
+                count = parse_bounded(params[idx+1], 1, i64::MAX / 10, "COUNT must be > 0")? as usize;
+                idx += 1;
+            },
+            _ => return Err(RedisError::Syntax)
+        }
+        idx += 1;
+    }
+
+    let now = unix_time_ms();
+    let options = ClaimOptions{min_idle, delivery_time: now, retry_count: None, force: false, justid};
+    let stream = get_group_stream(storage, key, group)?;
+    // every pending entry looked at counts as an attempt, claimed or not
+    let mut attempts = count * 10;
+    let mut candidates = stream.group(group).unwrap().pending
+                            .range(start..)
+                            .map(|(id, _)| {*id})
+                            .take(attempts + 1)
+                            .collect::<Vec<_>>()
+                            .into_iter();
+    let (mut claimed, mut deleted) = (vec!(), vec!());
+    while attempts > 0 && count > 0 {
+        let id = match candidates.next() {
+            Some(id) => id,
+            None => break
+        };
+        attempts -= 1;
+        match stream.claim(group, consumer, id, &options, now) {
+            Claimed::Entry(entry) => claimed.push((id, Some(entry_reply(entry)))),
+            Claimed::Deleted => deleted.push(id.to_string()),
+            Claimed::Skipped => continue
+        }
+        count -= 1;
+    }
+    let next = candidates.next().unwrap_or(StreamId::MIN);
+    Ok(RespValue::Array(vec!(next.to_string().to_resp(), claimed_reply(claimed, justid), deleted.to_resp())))
+}
+
+
+fn group_info(stream: &Stream, name: &[u8], group: &ConsumerGroup) -> RespValue{
+    RespValue::Map(vec!(
+        ("name".to_resp(), name.to_resp()),
+        ("consumers".to_resp(), group.consumers.len().to_resp()),
+        ("pending".to_resp(), group.pending.len().to_resp()),
+        ("last-delivered-id".to_resp(), group.last_id.to_string().to_resp()),
+        ("entries-read".to_resp(), group.entries_read.to_resp()),
+        ("lag".to_resp(), stream.lag(group).to_resp())
+    ))
+}
+
+// the consumer's own PEL nested in each consumer, at most `count` entries of each
+fn full_group_info(stream: &Stream, name: &[u8], group: &ConsumerGroup, count: usize) -> RespValue{
+    let pel = group.pending.iter().take(count).map(|(id, entry)| {
+        (id.to_string(), &entry.consumer, entry.delivery_time, entry.delivery_count).to_resp()
+    }).collect();
+    let consumers = group.consumers.iter().map(|(consumer_name, consumer)| {
+        let pel = consumer.pending.iter().take(count).map(|id| {
+            let entry = &group.pending[id];
+            (id.to_string(), entry.delivery_time, entry.delivery_count).to_resp()
+        }).collect();
+        RespValue::Map(vec!(
+            ("name".to_resp(), consumer_name.to_resp()),
+            ("seen-time".to_resp(), consumer.seen_time.to_resp()),
+            ("active-time".to_resp(), consumer.active_time.map_or(-1, |time| {time as i64}).to_resp()),
+            ("pel-count".to_resp(), consumer.pending.len().to_resp()),
+            ("pending".to_resp(), RespValue::Array(pel))
+        ))
+    }).collect();
+    RespValue::Map(vec!(
+        ("name".to_resp(), name.to_resp()),
+        ("last-delivered-id".to_resp(), group.last_id.to_string().to_resp()),
+        ("entries-read".to_resp(), group.entries_read.to_resp()),
+        ("lag".to_resp(), stream.lag(group).to_resp()),
+        ("pel-count".to_resp(), group.pending.len().to_resp()),
+        ("pending".to_resp(), RespValue::Array(pel)),
+        ("consumers".to_resp(), RespValue::Array(consumers))
+    ))
+}
+
+// FULL lists entries and groups in place of the first and last entry
+fn stream_info(stream: &Stream, full: Option<usize>) -> RespValue{
+    let first_id = stream.first_id().unwrap_or(StreamId::MIN);
+    // blocks hang off a btree rather than a radix tree, so keys and nodes are both the block count
+    let mut info = vec!(
+        ("length".to_resp(), stream.len().to_resp()),
+        ("radix-tree-keys".to_resp(), stream.num_blocks().to_resp()),
+        ("radix-tree-nodes".to_resp(), stream.num_blocks().to_resp()),
+        ("last-generated-id".to_resp(), stream.last_id().to_string().to_resp()),
+        ("max-deleted-entry-id".to_resp(), stream.max_deleted_id().to_string().to_resp()),
+        ("entries-added".to_resp(), stream.entries_added().to_resp()),
+        ("recorded-first-entry-id".to_resp(), first_id.to_string().to_resp())
+    );
+    let entry_or_null = |entry: Option<_>| {entry.map_or(RespValue::Null, entry_reply)};
+    match full {
+        None => info.extend([
+            ("groups".to_resp(), stream.groups().len().to_resp()),
+            ("first-entry".to_resp(), entry_or_null(stream.first_entry())),
+            ("last-entry".to_resp(), entry_or_null(stream.last_entry()))
+        ]),
+        Some(count) => {
+            let groups = stream.groups().iter()
+                            .map(|(name, group)| {full_group_info(stream, name, group, count)})
+                            .collect();
+            info.extend([
+                ("entries".to_resp(), entries_reply(stream.range(StreamId::MIN, StreamId::MAX, false, count))),
+                ("groups".to_resp(), RespValue::Array(groups))
+            ]);
+        }
+    }
+    RespValue::Map(info)
+}
+
+// XINFO STREAM key [FULL [COUNT count]] | GROUPS key | CONSUMERS key group
+pub fn xinfo(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let sub = params[0].to_ascii_lowercase();
+    match (sub.as_slice(), &params[1..]) {
+        (b"stream", [_, ..]) | (b"groups", [_]) | (b"consumers", [_, _]) => (),
+        (b"stream" | b"groups" | b"consumers", _) => return Err(sub_arity("xinfo", &sub)),
+        _ => return Err(RedisError::unknown_subcommand("xinfo", params[0]))
+    }
+    let full = match (sub.as_slice(), &params[2..]) {
+        (b"stream", []) | (b"groups" | b"consumers", _) => None,
+        (b"stream", [full]) if full.eq_ignore_ascii_case(b"full") => Some(10),
+        (b"stream", [full, option, count]) if full.eq_ignore_ascii_case(b"full") && option.eq_ignore_ascii_case(b"count") => {
+            // COUNT 0 lists everything
+            Some(usize::try_from(parse_int(count)?).ok().filter(|count| {*count > 0}).unwrap_or(usize::MAX))
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    let stream = get_stream(storage, params[1])?.ok_or(RedisError::NoSuchKey)?;
+    match sub.as_slice() {
+        b"stream" => Ok(stream_info(stream, full)),
+        b"groups" => {
+            Ok(RespValue::Array(stream.groups().iter().map(|(name, group)| {group_info(stream, name, group)}).collect()))
+        },
+        _ => {
+            let group = stream.group(params[2]).ok_or_else(|| {no_group_for_key(params[1], params[2])})?;
+            let now = unix_time_ms();
+            let consumers = group.consumers.iter().map(|(name, consumer)| {
+                let inactive = consumer.active_time.map_or(-1, |time| {now.saturating_sub(time) as i64});
+                RespValue::Map(vec!(
+                    ("name".to_resp(), name.to_resp()),
+                    ("pending".to_resp(), consumer.pending.len().to_resp()),
+                    ("idle".to_resp(), now.saturating_sub(consumer.seen_time).to_resp()),
+                    ("inactive".to_resp(), inactive.to_resp())
+                ))
+            }).collect();
+            Ok(RespValue::Array(consumers))
+        }
+    }
+}
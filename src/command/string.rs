@@ -0,0 +1,292 @@
+/* string commands besides SET and GET
+ * updates in place keep the key's ttl like redis does, only the commands that
+ * replace the value wholesale (MSET) drop it. numbers are kept as their decimal
+ * representation, there is no integer encoding
+ * */
+
+use crate::error::{RedisError, Result};
+use crate::parser::{RespValue, ToResp};
+use crate::persistence::{Entry, Keyspace, RedisValue};
+
+use super::{clamp_range, format_float, parse_expiry, parse_float, parse_int, Propagate};
+
+
+fn get_string_mut<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut Vec<u8>>>{
+    storage.get_mut(key).map(|entry| {entry.value.as_string_mut()}).transpose()
+}
+
+// a fresh value for the key that keeps whatever ttl it had
+fn replace_value(storage: &mut Keyspace, key: &[u8], value: Vec<u8>) -> Result<()>{
+    match get_string_mut(storage, key)? {
+        Some(current) => *current = value,
+        None => {storage.insert(key, Entry::new(RedisValue::String(value)));}
+    }
+    Ok(())
+}
+
+// `max_len` is the configured proto-max-bulk-len, no command builds a longer string
+fn check_string_length(len: usize, max_len: usize) -> Result<()>{
+    if len > max_len {
+        return Err(RedisError::Other(String::from("string exceeds maximum allowed size (proto-max-bulk-len)")));
+    }
+    Ok(())
+}
+
+// the existing value in place, or an empty string under a new key
+fn get_or_create_string<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<&'a mut Vec<u8>>{
+    storage.get_string(key)?;
+    storage.get_or_insert_with(key, || {RedisValue::String(vec!())}).value.as_string_mut()
+}
+
+
+// INCR key, DECR key, INCRBY key increment and DECRBY key decrement
+pub fn incrby(cmd: &str, params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let delta = match cmd {
+        "incr" => 1,
+        "decr" => -1,
+        "incrby" => parse_int(params[1])?,
+        _ => {
+            let decrement = parse_int(params[1])?;
+            decrement.checked_neg().ok_or_else(|| {RedisError::Other(String::from("decrement would overflow"))})?
+        }
+    };
+    let current = storage.get_string(params[0])?.map(|value| {parse_int(value)}).transpose()?.unwrap_or(0);
+    let updated = current.checked_add(delta)
+                    .ok_or_else(|| {RedisError::Other(String::from("increment or decrement would overflow"))})?;
+    replace_value(storage, params[0], updated.to_string().into_bytes())?;
+    Ok(updated.to_resp())
+}
+
+// replicas get the final value as SET ... KEEPTTL, float rounding can not drift apart that way
+pub fn incrbyfloat(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<(RespValue, Propagate)>{
+    let incr = parse_float(params[1])?;
+    let current = storage.get_string(params[0])?.map(|value| {parse_float(value)}).transpose()?.unwrap_or(0.0);
+    let updated = current + incr;
+    if !updated.is_finite() {
+        return Err(RedisError::Other(String::from("increment would produce NaN or Infinity")));
+    }
+    let repr = format_float(updated).into_bytes();
+    replace_value(storage, params[0], repr.clone())?;
+    let propagate = Propagate::command(&[b"SET", params[0], &repr, b"KEEPTTL"]);
+    Ok((repr.to_resp(), propagate))
+}
+
+pub fn append(params: Vec<&[u8]>, max_len: usize, storage: &mut Keyspace) -> Result<RespValue>{
+    let current = storage.get_string(params[0])?.map_or(0, |value| {value.len()});
+    check_string_length(current.saturating_add(params[1].len()), max_len)?;
+    let value = get_or_create_string(storage, params[0])?;
+    value.extend_from_slice(params[1]);
+    Ok(value.len().to_resp())
+}
+
+pub fn strlen(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(storage.get_string(key)?.map_or(0, |value| {value.len()}).to_resp())
+}
+
+// GETRANGE key start end, negative offsets count from the end
+pub fn getrange(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (start, end) = (parse_int(params[1])?, parse_int(params[2])?);
+    let value = storage.get_string(params[0])?.map_or(&[][..], |value| {value.as_slice()});
+    Ok(match clamp_range(start, end, value.len()) {
+        Some((start, end)) => value[start..=end].to_resp(),
+        None => b"".to_resp()
+    })
+}
+
+// SETRANGE key offset value, the string is zero padded up to the offset
+pub fn setrange(params: Vec<&[u8]>, max_len: usize, storage: &mut Keyspace) -> Result<RespValue>{
+    let (key, patch) = (params[0], params[2]);
+    let offset = parse_int(params[1])?;
+    if offset < 0 {return Err(RedisError::Other(String::from("offset is out of range")));}
+    let offset = offset as usize;
+    // an empty patch never creates the key, nor is it checked against the size limit
+    if patch.is_empty() {
+        return Ok(storage.get_string(key)?.map_or(0, |value| {value.len()}).to_resp());
+    }
+    check_string_length(offset.saturating_add(patch.len()), max_len)?;
+    let value = get_or_create_string(storage, key)?;
+    if value.len() < offset + patch.len() {
+        value.resize(offset + patch.len(), 0);
+    }
+    value[offset..offset + patch.len()].copy_from_slice(patch);
+    Ok(value.len().to_resp())
+}
+
+// keys holding something other than a string read as nil
+pub fn mget(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let values = params.iter()
+                    .map(|key| {storage.get_string(key).ok().flatten().cloned()})
+                    .collect::<Vec<_>>();
+    Ok(values.to_resp())
+}
+
+/* MSET key value [key value ...] and MSETNX, the whole batch is applied under
+ * the one keyspace lock the command runs with. MSETNX sets nothing when any of
+ * the keys already exists
+ * */
+pub fn mset(params: Vec<&[u8]>, only_new: bool, storage: &mut Keyspace) -> Result<RespValue>{
+    if !params.len().is_multiple_of(2) {
+        return Err(RedisError::wrong_arity(if only_new {"msetnx"} else {"mset"}));
+    }
+    if only_new && params.chunks(2).any(|pair| {storage.contains_key(pair[0])}) {
+        return Ok(0.to_resp());
+    }
+    for pair in params.chunks(2) {
+        storage.insert(pair[0], Entry::new(RedisValue::from(pair[1])));
+    }
+    Ok(if only_new {1.to_resp()} else {RespValue::ok()})
+}
+
+pub fn getdel(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    let value = storage.get_string(key)?.cloned();
+    if value.is_some() {storage.remove(key);}
+    Ok(value.to_resp())
+}
+
+// GETEX key [EX seconds | PX ms | EXAT unix-s | PXAT unix-ms | PERSIST]
+// a new deadline goes to replicas as PEXPIREAT, PERSIST as is, a plain read not at all
+pub fn getex(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<(RespValue, Propagate)>{
+    let expiry = match &params[1..] {
+        [] => None,
+        [option] if option.eq_ignore_ascii_case(b"persist") => Some(None),
+        [option, arg] => {
+            let option = option.to_ascii_lowercase();
+            match option.as_slice() {
+                b"ex" | b"px" | b"exat" | b"pxat" => Some(Some(parse_expiry(&option, arg, "getex")?)),
+                _ => return Err(RedisError::Syntax)
+            }
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    let value = storage.get_string(params[0])?.cloned();
+    let propagate = match (&value, expiry) {
+        (Some(_), Some(expires_at)) => {
+            storage.set_expiry(params[0], expires_at);
+            match expires_at {
+                Some(deadline) => Propagate::command(&[b"PEXPIREAT", params[0], deadline.to_string().as_bytes()]),
+                None => Propagate::command(&[b"PERSIST", params[0]])
+            }
+        },
+        _ => Propagate::nothing()
+    };
+    Ok((value.to_resp(), propagate))
+}
+
+
+// dp[i][j] is the LCS length of a[..i] and b[..j]
+struct LcsTable{
+    cols: usize,
+    cells: Vec<u32>
+}
+
+impl LcsTable{
+    fn build(a: &[u8], b: &[u8]) -> Self{
+        let cols = b.len() + 1;
+        let mut cells = vec!(0u32; (a.len() + 1) * cols);
+        for i in 1..=a.len() {
+            for j in 1..=b.len() {
+                cells[i * cols + j] = match a[i-1] == b[j-1] {
+                    true => cells[(i-1) * cols + j - 1] + 1,
+                    false => cells[(i-1) * cols + j].max(cells[i * cols + j - 1])
+                };
+            }
+        }
+        Self{cols, cells}
+    }
+
+    fn at(&self, i: usize, j: usize) -> u32{
+        self.cells[i * self.cols + j]
+    }
+}
+
+// matching ranges, inclusive and from the end of the strings backwards
+type LcsMatch = ((usize, usize), (usize, usize));
+
+/* walks the table back from the bottom right corner, collecting the common
+ * subsequence and the contiguous ranges it was matched in, the way redis does
+ * */
+fn lcs_backtrack(a: &[u8], b: &[u8], table: &LcsTable) -> (Vec<u8>, Vec<LcsMatch>){
+    let (mut i, mut j) = (a.len(), b.len());
+    let mut common = vec!();
+    let mut matches = vec!();
+    let mut current: Option<LcsMatch> = None;
+    while i > 0 && j > 0 {
+        let mut emit = false;
+        if a[i-1] == b[j-1] {
+            common.push(a[i-1]);
+            match current.as_mut() {
+                None => current = Some(((i-1, i-1), (j-1, j-1))),
+                // extend the running range backwards while it stays contiguous
+                Some(range) if range.0.0 == i && range.1.0 == j => {
+                    range.0.0 -= 1;
+                    range.1.0 -= 1;
+                },
+                Some(_) => emit = true
+            }
+            // the first byte of either string ends the walk
+            if current.is_some_and(|range| {range.0.0 == 0 || range.1.0 == 0}) {emit = true;}
+            i -= 1;
+            j -= 1;
+        } else {
+            if table.at(i-1, j) > table.at(i, j-1) {i -= 1;} else {j -= 1;}
+            emit = current.is_some();
+        }
+        if emit {
+            matches.extend(current.take());
+        }
+    }
+    common.reverse();
+    (common, matches)
+}
+
+// LCS key1 key2 [LEN] [IDX] [MINMATCHLEN min-match-len] [WITHMATCHLEN]
+pub fn lcs(params: Vec<&[u8]>, max_len: usize, storage: &mut Keyspace) -> Result<RespValue>{
+    let (mut len_only, mut idx, mut min_match_len, mut with_match_len) = (false, false, 0, false);
+    let mut options = params[2..].iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_lowercase();
+        match option.as_slice() {
+            b"len" => len_only = true,
+            b"idx" => idx = true,
+            b"withmatchlen" => with_match_len = true,
+            b"minmatchlen" => {
+                let arg = options.next().ok_or(RedisError::Syntax)?;
+                min_match_len = parse_int(arg)?.max(0) as usize;
+            },
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+    if len_only && idx {
+        return Err(RedisError::Other(String::from("If you want both the length and indexes, please just use IDX.")));
+    }
+
+    let mut fetch = |key: &[u8]| {
+        storage.get_string(key)
+            .map_err(|_| {RedisError::Other(String::from("The specified keys must contain string values"))})
+            .map(|value| {value.cloned().unwrap_or_default()})
+    };
+    let (a, b) = (fetch(params[0])?, fetch(params[1])?);
+    let cells = (a.len() + 1).checked_mul(b.len() + 1).and_then(|cells| {cells.checked_mul(size_of::<u32>())});
+    if cells.is_none_or(|bytes| {bytes > max_len}) {
+        return Err(RedisError::Other(String::from(
+            "Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len"
+        )));
+    }
+
+    let table = LcsTable::build(&a, &b);
+    let (common, matches) = lcs_backtrack(&a, &b, &table);
+    if len_only {return Ok(common.len().to_resp());}
+    if !idx {return Ok(common.to_resp());}
+    let matches = matches.into_iter()
+                    .filter(|((start, end), _)| {end - start + 1 >= min_match_len})
+                    .map(|((a_start, a_end), (b_start, b_end))| {
+                        let mut item = vec!((a_start, a_end).to_resp(), (b_start, b_end).to_resp());
+                        if with_match_len {item.push((a_end - a_start + 1).to_resp());}
+                        RespValue::Array(item)
+                    })
+                    .collect();
+    Ok(RespValue::Map(vec!(
+        ("matches".to_resp(), RespValue::Array(matches)),
+        ("len".to_resp(), common.len().to_resp())
+    )))
+}
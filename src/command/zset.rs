@@ -0,0 +1,721 @@
+/* sorted set commands
+ * a missing key behaves like the empty sorted set, an emptied one is removed
+ * from the keyspace. scores go out as doubles, which RESP2 clients receive as
+ * bulk strings, and scored listings turn into [member, score] pairs on RESP3
+ * */
+
+use std::collections::HashMap;
+
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use crate::error::{RedisError, Result};
+use crate::parser::{ProtocolVersion, RespValue, ToResp};
+use crate::parser::encrypt::format_double;
+use crate::persistence::{Entry, Keyspace, RedisStorage, RedisValue, SortedSet};
+use crate::persistence::value::{LexBound, LexRange, ScoreRange};
+use crate::persistence::dict::DictSet;
+
+use super::list::parse_timeout;
+use super::scan::{parse_scan_args, scan_cursor, ScanKind};
+use super::{clamp_range, parse_bounded, parse_float, parse_int, parse_positive, Propagate};
+
+
+type Scored = (Vec<u8>, f64);
+
+fn get_zset<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a SortedSet>>{
+    storage.get(key).map(|entry| {entry.value.as_zset()}).transpose()
+}
+
+fn get_zset_mut<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<&'a mut SortedSet>>{
+    storage.get_mut(key).map(|entry| {entry.value.as_zset_mut()}).transpose()
+}
+
+fn owned<'a>(items: impl Iterator<Item = (&'a [u8], f64)>) -> Vec<Scored>{
+    items.map(|(member, score)| {(member.to_vec(), score)}).collect()
+}
+
+// members alone, or interleaved with their scores
+fn scored_reply(items: Vec<Scored>, withscores: bool, proto: ProtocolVersion) -> RespValue{
+    let items = items.into_iter();
+    RespValue::Array(match (withscores, proto) {
+        (false, _) => items.map(|(member, _)| {member.to_resp()}).collect(),
+        (true, ProtocolVersion::Resp2) => items.flat_map(|(member, score)| {[member.to_resp(), score.to_resp()]}).collect(),
+        (true, ProtocolVersion::Resp3) => items.map(|pair| {pair.to_resp()}).collect()
+    })
+}
+
+// write `items` under `dst` as a fresh sorted set, an empty result deletes it
+fn store(storage: &mut Keyspace, dst: &[u8], items: Vec<Scored>) -> usize{
+    let mut zset = SortedSet::new();
+    items.into_iter().for_each(|(member, score)| {zset.insert(&member, score);});
+    let len = zset.len();
+    match len {
+        0 => {storage.remove(dst);},
+        _ => {storage.insert(dst, Entry::new(RedisValue::ZSet(zset)));}
+    }
+    len
+}
+
+
+// ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]
+pub fn zadd(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) = (false, false, false, false, false, false);
+    let mut idx = 1;
+    while let Some(option) = params.get(idx) {
+        match option.to_ascii_lowercase().as_slice() {
+            b"nx" => nx = true,
+            b"xx" => xx = true,
+            b"gt" => gt = true,
+            b"lt" => lt = true,
+            b"ch" => ch = true,
+            b"incr" => incr = true,
+            _ => break
+        }
+        idx += 1;
+    }
+    let pairs = &params[idx..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {return Err(RedisError::Syntax);}
+    if nx && xx {
+        return Err(RedisError::Other(String::from("XX and NX options at the same time are not compatible")));
+    }
+    if (nx && (gt || lt)) || (gt && lt) {
+        return Err(RedisError::Other(String::from("GT, LT, and/or NX options at the same time are not compatible")));
+    }
+    if incr && pairs.len() > 2 {
+        return Err(RedisError::Other(String::from("INCR option supports a single increment-element pair")));
+    }
+    // every score is validated before the set is touched
+    let scores = pairs.chunks(2).map(|pair| {parse_float(pair[0])}).collect::<Result<Vec<_>>>()?;
+
+    let key = params[0];
+    if get_zset(storage, key)?.is_none() && xx {
+        return Ok(if incr {RespValue::Null} else {0.to_resp()});
+    }
+    let zset = storage.get_or_insert_with(key, || {RedisValue::ZSet(SortedSet::new())}).value.as_zset_mut()?;
+    let (mut added, mut updated) = (0, 0);
+    let mut incr_reply = RespValue::Null;
+    for (pair, score) in pairs.chunks(2).zip(scores) {
+        let member = pair[1];
+        match zset.score(member) {
+            Some(_) if nx => continue,
+            Some(current) => {
+                let score = if incr {current + score} else {score};
+                if score.is_nan() {
+                    return Err(RedisError::Other(String::from("resulting score is not a number (NaN)")));
+                }
+                if (gt && score <= current) || (lt && score >= current) {continue;}
+                if score != current {
+                    zset.insert(member, score);
+                    updated += 1;
+                }
+                incr_reply = score.to_resp();
+            },
+            None if xx => continue,
+            None => {
+                zset.insert(member, score);
+                added += 1;
+                incr_reply = score.to_resp();
+            }
+        }
+    }
+    storage.remove_if_empty(key);
+    match (incr, ch) {
+        (true, _) => Ok(incr_reply),
+        (false, true) => Ok((added + updated).to_resp()),
+        (false, false) => Ok(added.to_resp())
+    }
+}
+
+// ZINCRBY key increment member
+pub fn zincrby(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    zadd(vec!(params[0], b"INCR", params[1], params[2]), storage)
+}
+
+pub fn zrem(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let removed = match get_zset_mut(storage, params[0])? {
+        Some(zset) => params[1..].iter().filter(|member| {zset.remove(member)}).count(),
+        None => 0
+    };
+    storage.remove_if_empty(params[0]);
+    Ok(removed.to_resp())
+}
+
+pub fn zcard(key: &[u8], storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_zset(storage, key)?.map_or(0, |zset| {zset.len()}).to_resp())
+}
+
+pub fn zscore(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    Ok(get_zset(storage, params[0])?.and_then(|zset| {zset.score(params[1])}).to_resp())
+}
+
+pub fn zmscore(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let zset = get_zset(storage, params[0])?;
+    let scores = params[1..].iter().map(|member| {zset.and_then(|zset| {zset.score(member)})}).collect::<Vec<_>>();
+    Ok(scores.to_resp())
+}
+
+// ZRANK key member [WITHSCORE] and ZREVRANK
+pub fn zrank(params: Vec<&[u8]>, reverse: bool, storage: &mut Keyspace) -> Result<RespValue>{
+    let withscore = match params.get(2) {
+        Some(option) if option.eq_ignore_ascii_case(b"withscore") => true,
+        Some(_) => return Err(RedisError::Syntax),
+        None => false
+    };
+    if params.len() > 3 {return Err(RedisError::Syntax);}
+    let zset = get_zset(storage, params[0])?;
+    let found = zset.and_then(|zset| {Some((zset.rank(params[1], reverse)?, zset.score(params[1])?))});
+    Ok(match (found, withscore) {
+        (Some((rank, score)), true) => (rank, score).to_resp(),
+        (Some((rank, _)), false) => rank.to_resp(),
+        (None, true) => RespValue::NullArray,
+        (None, false) => RespValue::Null
+    })
+}
+
+
+fn parse_score_bound(arg: &[u8]) -> Result<(f64, bool)>{
+    let (arg, exclusive) = match arg.strip_prefix(b"(") {
+        Some(rest) => (rest, true),
+        None => (arg, false)
+    };
+    let bound = parse_float(arg).map_err(|_| {RedisError::Other(String::from("min or max is not a float"))})?;
+    Ok((bound, exclusive))
+}
+
+pub fn parse_score_range(min: &[u8], max: &[u8]) -> Result<ScoreRange>{
+    let ((min, min_exclusive), (max, max_exclusive)) = (parse_score_bound(min)?, parse_score_bound(max)?);
+    Ok(ScoreRange{min, max, min_exclusive, max_exclusive})
+}
+
+fn parse_lex_bound(arg: &[u8]) -> Result<LexBound>{
+    match arg {
+        b"-" => Ok(LexBound::NegInf),
+        b"+" => Ok(LexBound::PosInf),
+        [b'[', rest @ ..] => Ok(LexBound::Inclusive(rest.to_vec())),
+        [b'(', rest @ ..] => Ok(LexBound::Exclusive(rest.to_vec())),
+        _ => Err(RedisError::Other(String::from("min or max not valid string range item")))
+    }
+}
+
+pub fn parse_lex_range(min: &[u8], max: &[u8]) -> Result<LexRange>{
+    Ok(LexRange{min: parse_lex_bound(min)?, max: parse_lex_bound(max)?})
+}
+
+// ZCOUNT key min max
+pub fn zcount(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let range = parse_score_range(params[1], params[2])?;
+    Ok(get_zset(storage, params[0])?.map_or(0, |zset| {zset.count_by_score(range)}).to_resp())
+}
+
+// ZLEXCOUNT key min max
+pub fn zlexcount(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let range = parse_lex_range(params[1], params[2])?;
+    Ok(get_zset(storage, params[0])?.map_or(0, |zset| {zset.count_by_lex(&range)}).to_resp())
+}
+
+
+enum Bounds{
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange)
+}
+
+struct RangeQuery{
+    bounds: Bounds,
+    rev: bool,
+    // offset and count, a negative count takes everything past the offset
+    limit: Option<(i64, i64)>,
+    withscores: bool
+}
+
+/* the unified ZRANGE syntax and the older commands it absorbed
+ * ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+ * the legacy commands come with their mode preset and only take the options
+ * they always had. with REV the score and lex bounds are given max first
+ * */
+fn parse_range_query(cmd: &str, min: &[u8], max: &[u8], options: &[&[u8]]) -> Result<RangeQuery>{
+    let unified = matches!(cmd, "zrange" | "zrangestore");
+    let mut by_score = matches!(cmd, "zrangebyscore" | "zrevrangebyscore");
+    let mut by_lex = matches!(cmd, "zrangebylex" | "zrevrangebylex");
+    let mut rev = cmd.starts_with("zrev");
+    let (mut limit, mut withscores) = (None, false);
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"withscores" if cmd != "zrangestore" && !matches!(cmd, "zrangebylex" | "zrevrangebylex") => withscores = true,
+            b"limit" if cmd != "zrevrange" => {
+                let (offset, count) = match (options.next(), options.next()) {
+                    (Some(offset), Some(count)) => (parse_int(offset)?, parse_int(count)?),
+                    _ => return Err(RedisError::Syntax)
+                };
+                limit = Some((offset, count));
+            },
+            b"byscore" if unified => by_score = true,
+            b"bylex" if unified => by_lex = true,
+            b"rev" if unified => rev = true,
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+    if by_score && by_lex {return Err(RedisError::Syntax);}
+    if limit.is_some() && !by_score && !by_lex {
+        return Err(RedisError::Other(String::from(
+            "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+        )));
+    }
+    if withscores && by_lex {
+        return Err(RedisError::Other(String::from("syntax error, WITHSCORES not supported in combination with BYLEX")));
+    }
+
+    let (min, max) = if rev && (by_score || by_lex) {(max, min)} else {(min, max)};
+    let bounds = match (by_score, by_lex) {
+        (true, _) => Bounds::Score(parse_score_range(min, max)?),
+        (_, true) => Bounds::Lex(parse_lex_range(min, max)?),
+        _ => Bounds::Rank(parse_int(min)?, parse_int(max)?)
+    };
+    Ok(RangeQuery{bounds, rev, limit, withscores})
+}
+
+fn run_range_query(zset: &SortedSet, query: &RangeQuery) -> Vec<Scored>{
+    let (offset, count) = match query.limit {
+        Some((offset, _)) if offset < 0 => return vec!(),
+        Some((offset, count)) => (offset as usize, if count < 0 {usize::MAX} else {count as usize}),
+        None => (0, usize::MAX)
+    };
+    match &query.bounds {
+        Bounds::Rank(start, stop) => match clamp_range(*start, *stop, zset.len()) {
+            Some((start, stop)) => owned(zset.range_by_rank(start, stop, query.rev)),
+            None => vec!()
+        },
+        Bounds::Score(range) => owned(zset.range_by_score(*range, query.rev).skip(offset).take(count)),
+        Bounds::Lex(range) => owned(zset.range_by_lex(range, query.rev).skip(offset).take(count))
+    }
+}
+
+// ZRANGE and the legacy ZREVRANGE, ZRANGEBYSCORE, ZREVRANGEBYSCORE, ZRANGEBYLEX, ZREVRANGEBYLEX
+pub fn zrange(cmd: &str, params: Vec<&[u8]>, proto: ProtocolVersion, storage: &mut Keyspace) -> Result<RespValue>{
+    let query = parse_range_query(cmd, params[1], params[2], &params[3..])?;
+    let items = match get_zset(storage, params[0])? {
+        Some(zset) => run_range_query(zset, &query),
+        None => vec!()
+    };
+    Ok(scored_reply(items, query.withscores, proto))
+}
+
+// ZRANGESTORE dst src min max [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+pub fn zrangestore(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let query = parse_range_query("zrangestore", params[2], params[3], &params[4..])?;
+    let items = match get_zset(storage, params[1])? {
+        Some(zset) => run_range_query(zset, &query),
+        None => vec!()
+    };
+    Ok(store(storage, params[0], items).to_resp())
+}
+
+// ZREMRANGEBYRANK, ZREMRANGEBYSCORE and ZREMRANGEBYLEX key min max
+pub fn zremrange(cmd: &str, params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let range_cmd = match cmd {
+        "zremrangebyscore" => "zrangebyscore",
+        "zremrangebylex" => "zrangebylex",
+        _ => "zrange"
+    };
+    let query = parse_range_query(range_cmd, params[1], params[2], &[])?;
+    let removed = match get_zset_mut(storage, params[0])? {
+        Some(zset) => {
+            let doomed = run_range_query(zset, &query);
+            doomed.iter().for_each(|(member, _)| {zset.remove(member);});
+            doomed.len()
+        },
+        None => 0
+    };
+    storage.remove_if_empty(params[0]);
+    Ok(removed.to_resp())
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ZSetOp{
+    Union,
+    Inter,
+    Diff
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Aggregate{
+    Sum,
+    Min,
+    Max
+}
+
+impl Aggregate{
+    fn apply(self, acc: f64, score: f64) -> f64{
+        match self {
+            // inf + -inf is NaN, redis settles on 0
+            Self::Sum => Some(acc + score).filter(|sum| {!sum.is_nan()}).unwrap_or(0.0),
+            Self::Min => acc.min(score),
+            Self::Max => acc.max(score)
+        }
+    }
+}
+
+fn weighted(score: f64, weight: f64) -> f64{
+    Some(score * weight).filter(|product| {!product.is_nan()}).unwrap_or(0.0)
+}
+
+struct Combine<'a>{
+    keys: Vec<&'a [u8]>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    withscores: bool
+}
+
+// numkeys key [key ...] [WEIGHTS weight ...] [AGGREGATE SUM | MIN | MAX] [WITHSCORES]
+fn parse_combine<'a>(cmd: &str, params: &[&'a [u8]], op: ZSetOp, store: bool) -> Result<Combine<'a>>{
+    let numkeys = parse_int(params[0])?;
+    if numkeys < 1 {
+        return Err(RedisError::Other(format!("at least 1 input key is needed for '{cmd}' command")));
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > params.len() - 1 {return Err(RedisError::Syntax);}
+    let mut combine = Combine{
+        keys: params[1..=numkeys].to_vec(),
+        weights: vec!(1.0; numkeys),
+        aggregate: Aggregate::Sum,
+        withscores: false
+    };
+
+    let mut options = params[numkeys+1..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"weights" if op != ZSetOp::Diff => {
+                for weight in combine.weights.iter_mut() {
+                    let arg = options.next().ok_or(RedisError::Syntax)?;
+                    *weight = parse_float(arg).map_err(|_| {RedisError::Other(String::from("weight value is not a float"))})?;
+                }
+            },
+            b"aggregate" if op != ZSetOp::Diff => {
+                combine.aggregate = match options.next().map(|arg| {arg.to_ascii_lowercase()}).as_deref() {
+                    Some(b"sum") => Aggregate::Sum,
+                    Some(b"min") => Aggregate::Min,
+                    Some(b"max") => Aggregate::Max,
+                    _ => return Err(RedisError::Syntax)
+                };
+            },
+            b"withscores" if !store => combine.withscores = true,
+            _ => return Err(RedisError::Syntax)
+        }
+    }
+    Ok(combine)
+}
+
+// plain sets take part in the algebra with every score at 1
+enum Input<'a>{
+    Set(&'a DictSet<Vec<u8>>),
+    ZSet(&'a SortedSet)
+}
+
+impl<'a> Input<'a>{
+    fn len(&self) -> usize{
+        match self {
+            Self::Set(set) => set.len(),
+            Self::ZSet(zset) => zset.len()
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<f64>{
+        match self {
+            Self::Set(set) => set.contains(member).then_some(1.0),
+            Self::ZSet(zset) => zset.score(member)
+        }
+    }
+
+    fn items(&self) -> Box<dyn Iterator<Item = (&'a [u8], f64)> + 'a>{
+        match *self {
+            Self::Set(set) => Box::new(set.iter().map(|member| {(member.as_slice(), 1.0)})),
+            Self::ZSet(zset) => Box::new(zset.iter())
+        }
+    }
+}
+
+fn as_input(value: &RedisValue) -> Result<Input<'_>>{
+    match value {
+        RedisValue::Set(set) => Ok(Input::Set(set)),
+        RedisValue::ZSet(zset) => Ok(Input::ZSet(zset)),
+        _ => Err(RedisError::WrongType)
+    }
+}
+
+fn get_input<'a>(storage: &'a mut Keyspace, key: &[u8]) -> Result<Option<Input<'a>>>{
+    storage.get(key).map(|entry| {as_input(&entry.value)}).transpose()
+}
+
+/* one input at a time so only the result is ever copied, every key is type
+ * checked before any work is done. the result comes back in score order
+ * */
+fn combine(storage: &mut Keyspace, request: &Combine, op: ZSetOp) -> Result<Vec<Scored>>{
+    let mut sizes = Vec::with_capacity(request.keys.len());
+    for key in &request.keys {
+        sizes.push(get_input(storage, key)?.map_or(0, |input| {input.len()}));
+    }
+    let mut result: HashMap<Vec<u8>, f64> = HashMap::new();
+    match op {
+        ZSetOp::Union => {
+            for (key, weight) in request.keys.iter().zip(&request.weights) {
+                let input = match get_input(storage, key)? {
+                    Some(input) => input,
+                    None => continue
+                };
+                for (member, score) in input.items() {
+                    let score = weighted(score, *weight);
+                    result.entry(member.to_vec())
+                        .and_modify(|acc| {*acc = request.aggregate.apply(*acc, score)})
+                        .or_insert(score);
+                }
+            }
+        },
+        ZSetOp::Inter => {
+            // start from the smallest input, any missing key empties the result
+            let mut order = (0..request.keys.len()).collect::<Vec<_>>();
+            order.sort_by_key(|idx| {sizes[*idx]});
+            if sizes[order[0]] == 0 {return Ok(vec!());}
+            if let Some(input) = get_input(storage, request.keys[order[0]])? {
+                let weight = request.weights[order[0]];
+                result.extend(input.items().map(|(member, score)| {(member.to_vec(), weighted(score, weight))}));
+            }
+            for idx in &order[1..] {
+                let input = match get_input(storage, request.keys[*idx])? {
+                    Some(input) => input,
+                    None => return Ok(vec!())
+                };
+                let weight = request.weights[*idx];
+                result.retain(|member, acc| {
+                    match input.score(member) {
+                        Some(score) => {
+                            *acc = request.aggregate.apply(*acc, weighted(score, weight));
+                            true
+                        },
+                        None => false
+                    }
+                });
+            }
+        },
+        ZSetOp::Diff => {
+            if let Some(input) = get_input(storage, request.keys[0])? {
+                result.extend(input.items().map(|(member, score)| {(member.to_vec(), score)}));
+            }
+            for key in &request.keys[1..] {
+                if result.is_empty() {break;}
+                if let Some(input) = get_input(storage, key)? {
+                    result.retain(|member, _| {input.score(member).is_none()});
+                }
+            }
+        }
+    }
+    let mut items = result.into_iter().collect::<Vec<_>>();
+    items.sort_by(|a, b| {a.1.total_cmp(&b.1).then_with(|| {a.0.cmp(&b.0)})});
+    Ok(items)
+}
+
+// ZUNION, ZINTER and ZDIFF numkeys key [key ...] [WEIGHTS ...] [AGGREGATE ...] [WITHSCORES]
+pub fn zcombine(cmd: &str, params: Vec<&[u8]>, op: ZSetOp, proto: ProtocolVersion, storage: &mut Keyspace) -> Result<RespValue>{
+    let request = parse_combine(cmd, &params, op, false)?;
+    let items = combine(storage, &request, op)?;
+    Ok(scored_reply(items, request.withscores, proto))
+}
+
+// ZUNIONSTORE, ZINTERSTORE and ZDIFFSTORE destination numkeys key [key ...] ...
+pub fn zcombine_store(cmd: &str, params: Vec<&[u8]>, op: ZSetOp, storage: &mut Keyspace) -> Result<RespValue>{
+    let request = parse_combine(cmd, &params[1..], op, true)?;
+    let items = combine(storage, &request, op)?;
+    Ok(store(storage, params[0], items).to_resp())
+}
+
+// ZINTERCARD numkeys key [key ...] [LIMIT limit], LIMIT 0 means no limit
+pub fn zintercard(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let numkeys = parse_bounded(params[0], 1, i64::MAX, "numkeys should be greater than 0")? as usize;
+    if numkeys >= params.len() {
+        return Err(RedisError::Other(String::from("Number of keys can't be greater than number of args")));
+    }
+    let limit = match &params[numkeys+1..] {
+        [] => 0,
+        [option, limit] if option.eq_ignore_ascii_case(b"limit") => {
+            parse_bounded(limit, 0, i64::MAX, "LIMIT can't be negative")? as usize
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    // every key is type checked first, a missing or empty one makes the answer 0
+    let keys = &params[1..=numkeys];
+    for key in keys {
+        if get_input(storage, key)?.is_none_or(|input| {input.len() == 0}) {return Ok(0.to_resp());}
+    }
+    let mut inputs = keys.iter()
+                        .filter_map(|key| {storage.lookup(key).and_then(|entry| {as_input(&entry.value).ok()})})
+                        .collect::<Vec<_>>();
+    inputs.sort_by_key(|input| {input.len()});
+    // walk the smallest input and stop counting at the limit, scores play no part
+    let limit = if limit == 0 {usize::MAX} else {limit};
+    let card = inputs[0].items()
+                .filter(|(member, _)| {inputs[1..].iter().all(|other| {other.score(member).is_some()})})
+                .take(limit)
+                .count();
+    Ok(card.to_resp())
+}
+
+
+// ZRANDMEMBER key [count [WITHSCORES]], a negative count may repeat members
+pub fn zrandmember(params: Vec<&[u8]>, proto: ProtocolVersion, storage: &mut Keyspace) -> Result<RespValue>{
+    let count = params.get(1).map(|count| {parse_int(count)}).transpose()?;
+    let withscores = match params.get(2) {
+        Some(option) if option.eq_ignore_ascii_case(b"withscores") => true,
+        Some(_) => return Err(RedisError::Syntax),
+        None => false
+    };
+    if params.len() > 3 {return Err(RedisError::Syntax);}
+    if count.is_some_and(|count| {count.unsigned_abs() > (i64::MAX / 2) as u64}) {
+        return Err(RedisError::Other(String::from("value is out of range")));
+    }
+    let mut rng = rand::thread_rng();
+    let zset = match (get_zset(storage, params[0])?, count) {
+        (Some(zset), _) => zset,
+        (None, None) => return Ok(RespValue::Null),
+        (None, Some(_)) => return Ok(RespValue::Array(vec!()))
+    };
+    let picked = match count {
+        None => return Ok(zset.iter().choose(&mut rng).map(|(member, _)| {member}).to_resp()),
+        Some(count) if count >= 0 => zset.iter().choose_multiple(&mut rng, count as usize),
+        Some(count) => {
+            let members = zset.iter().collect::<Vec<_>>();
+            (0..count.unsigned_abs()).filter_map(|_| {members.choose(&mut rng).copied()}).collect()
+        }
+    };
+    Ok(scored_reply(owned(picked.into_iter()), withscores, proto))
+}
+
+// ZSCAN key cursor [MATCH pattern] [COUNT count], scores are sent as bulk strings
+pub fn zscan(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let args = parse_scan_args(params[1], &params[2..], ScanKind::Members)?;
+    let zset = match get_zset(storage, params[0])? {
+        Some(zset) => zset,
+        None => return Ok(("0", RespValue::Array(vec!())).to_resp())
+    };
+    let (next, page) = scan_cursor(&args, |cursor, page| {
+        zset.scan(cursor, |member, score| {page.push((member, score))})
+    });
+    let mut items = vec!();
+    for (member, score) in page.into_iter().filter(|(member, _)| {args.matches(member)}) {
+        items.push(member.to_resp());
+        items.push(format_double(score).to_resp());
+    }
+    Ok((next.to_string(), RespValue::Array(items)).to_resp())
+}
+
+
+
+/* popping, plain and blocking
+ * `reverse` pops the highest scores first, ZPOPMAX style
+ * */
+
+// up to `count` elements from one end, None when the key holds no sorted set
+pub fn pop_members(storage: &mut Keyspace, key: &[u8], reverse: bool, count: usize) -> Result<Option<Vec<Scored>>>{
+    let popped = match get_zset_mut(storage, key)? {
+        Some(zset) => (0..count).map_while(|_| {zset.pop(reverse)}).collect(),
+        None => return Ok(None)
+    };
+    storage.remove_if_empty(key);
+    Ok(Some(popped))
+}
+
+// ZPOPMIN key [count] and ZPOPMAX
+pub fn zpop(params: Vec<&[u8]>, reverse: bool, proto: ProtocolVersion, storage: &mut Keyspace) -> Result<RespValue>{
+    if params.len() > 2 {return Err(RedisError::Syntax);}
+    let count = params.get(1).map(|count| {parse_positive(count)}).transpose()?;
+    let popped = pop_members(storage, params[0], reverse, count.unwrap_or(1) as usize)?.unwrap_or_default();
+    Ok(match count {
+        // a single pop stays a flat [member, score] on every protocol
+        None => scored_reply(popped, true, ProtocolVersion::Resp2),
+        Some(_) => scored_reply(popped, true, proto)
+    })
+}
+
+pub struct ZMultiPop{
+    pub keys: Vec<Vec<u8>>,
+    pub reverse: bool,
+    pub count: usize
+}
+
+// numkeys key [key ...] MIN|MAX [COUNT count], shared with BZMPOP
+pub fn parse_zmulti_pop(params: &[&[u8]]) -> Result<ZMultiPop>{
+    let numkeys = parse_bounded(params[0], 1, i64::MAX, "numkeys should be greater than 0")? as usize;
+    if numkeys + 1 >= params.len() {return Err(RedisError::Syntax);}
+    let keys = params[1..=numkeys].iter().map(|key| {key.to_vec()}).collect();
+    let reverse = match params[numkeys+1].to_ascii_lowercase().as_slice() {
+        b"min" => false,
+        b"max" => true,
+        _ => return Err(RedisError::Syntax)
+    };
+    let count = match &params[numkeys+2..] {
+        [] => 1,
+        [option, count] if option.eq_ignore_ascii_case(b"count") => {
+            parse_bounded(count, 1, i64::MAX, "count should be greater than 0")? as usize
+        },
+        _ => return Err(RedisError::Syntax)
+    };
+    Ok(ZMultiPop{keys, reverse, count})
+}
+
+// pop from the first non empty sorted set, handing back the key and what was popped
+fn pop_first_non_empty<'a>(
+    request: &'a ZMultiPop, storage: &mut Keyspace
+) -> Result<Option<(&'a [u8], Vec<Scored>)>>{
+    for key in &request.keys {
+        if let Some(popped) = pop_members(storage, key, request.reverse, request.count)? {
+            return Ok(Some((key, popped)));
+        }
+    }
+    Ok(None)
+}
+
+// the plain pop a blocking sorted set pop is replicated as
+fn pop_command(reverse: bool) -> &'static [u8]{
+    if reverse {b"ZPOPMAX"} else {b"ZPOPMIN"}
+}
+
+// ZMPOP numkeys key [key ...] MIN|MAX [COUNT count], replying [key, [[member, score] ...]]
+pub fn zmpop(params: Vec<&[u8]>, storage: &mut Keyspace) -> Result<RespValue>{
+    let request = parse_zmulti_pop(&params)?;
+    Ok(pop_first_non_empty(&request, storage)?.map_or(RespValue::NullArray, |popped| {popped.to_resp()}))
+}
+
+// BZPOPMIN key [key ...] timeout and BZPOPMAX, replying [key, member, score]
+pub fn bzpop(
+    params: Vec<&[u8]>, reverse: bool, client_id: u64, storage: &RedisStorage
+) -> Result<(RespValue, Propagate)>{
+    let timeout = parse_timeout(params[params.len()-1])?;
+    let keys = params[..params.len()-1].iter().map(|key| {Box::<[u8]>::from(*key)}).collect::<Vec<_>>();
+    let watched = keys.clone();
+    let op = Box::new(move |db: &mut Keyspace| {
+        for key in &watched {
+            if let Some((member, score)) = pop_members(db, key, reverse, 1)?.and_then(|mut popped| {popped.pop()}) {
+                let propagate = Propagate::command(&[pop_command(reverse), key]);
+                return Ok(Some(((&key[..], member, score).to_resp(), propagate)));
+            }
+        }
+        Ok(None)
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::NullArray)
+}
+
+// BZMPOP timeout numkeys key [key ...] MIN|MAX [COUNT count]
+pub fn bzmpop(params: Vec<&[u8]>, client_id: u64, storage: &RedisStorage) -> Result<(RespValue, Propagate)>{
+    let timeout = parse_timeout(params[0])?;
+    let request = parse_zmulti_pop(&params[1..])?;
+    let keys = request.keys.iter().map(|key| {Box::from(&key[..])}).collect();
+    let op = Box::new(move |db: &mut Keyspace| {
+        Ok(pop_first_non_empty(&request, db)?.map(|(key, popped)| {
+            let count = popped.len().to_string();
+            let propagate = Propagate::command(&[pop_command(request.reverse), key, count.as_bytes()]);
+            ((key, popped).to_resp(), propagate)
+        }))
+    });
+    storage.block_on_keys(client_id, keys, timeout, op, RespValue::NullArray)
+}
@@ -0,0 +1,118 @@
+use thiserror::Error;
+
+use crate::parser::{RespValue, ToResp};
+
+
+pub type Result<T> = std::result::Result<T, RedisError>;
+
+
+/* every failure a client can trigger
+ * the Display form is the exact error line sent back, code prefix included
+ * */
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RedisError{
+    // malformed input, the connection is closed once the reply is flushed
+    #[error("ERR Protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+
+    #[error("ERR unknown command '{cmd}', with args beginning with: {args}")]
+    UnknownCommand{cmd: String, args: String},
+
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+
+    #[error("ERR syntax error")]
+    Syntax,
+
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+
+    #[error("ERR value is out of range, must be positive")]
+    NotPositive,
+
+    #[error("ERR no such key")]
+    NoSuchKey,
+
+    #[error("UNBLOCKED client unblocked via CLIENT UNBLOCK")]
+    Unblocked,
+
+    #[error("NOAUTH {0}")]
+    NoAuth(&'static str),
+
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+
+    #[error("NOGROUP {0}")]
+    NoGroup(String),
+
+    #[error("BUSYGROUP Consumer Group name already exists")]
+    BusyGroup,
+
+    // a string that does not carry the hyperloglog header
+    #[error("WRONGTYPE Key is not a valid HyperLogLog string value.")]
+    NotHll,
+
+    #[error("INVALIDOBJ Corrupted HLL object detected")]
+    CorruptHll,
+
+    // a decoded reply did not have the shape the caller asked for
+    #[error("ERR unexpected reply: {0}")]
+    UnexpectedReply(&'static str),
+
+    // any other plain ERR reply
+    #[error("ERR {0}")]
+    Other(String)
+}
+
+impl RedisError{
+    pub fn unknown_command(cmd: &[u8], params: &[&[u8]]) -> Self{
+        // same shape as redis: 'arg1' 'arg2' ... each quoted and followed by a space
+        let args = params.iter()
+                    .map(|arg| {format!("'{}' ", String::from_utf8_lossy(&arg[..arg.len().min(128)]))})
+                    .collect::<String>();
+        Self::UnknownCommand{cmd: String::from_utf8_lossy(cmd).into_owned(), args}
+    }
+
+    pub fn unknown_subcommand(cmd: &str, sub: &[u8]) -> Self{
+        Self::Other(format!(
+            "unknown subcommand '{}'. Try {} HELP.", String::from_utf8_lossy(sub), cmd.to_uppercase()
+        ))
+    }
+
+    pub fn wrong_arity(cmd: &str) -> Self{
+        Self::WrongArity(cmd.to_lowercase())
+    }
+
+    pub fn invalid_expire_time(cmd: &str) -> Self{
+        Self::Other(format!("invalid expire time in '{cmd}' command"))
+    }
+
+    // the stream can not be trusted anymore after these
+    pub fn is_fatal(&self) -> bool{
+        matches!(self, Self::Protocol(_))
+    }
+}
+
+impl ToResp for RedisError{
+    fn to_resp(self) -> RespValue{
+        RespValue::Error(self.to_string())
+    }
+}
+
+impl<T: ToResp> ToResp for Result<T>{
+    fn to_resp(self) -> RespValue{
+        match self {
+            Ok(val) => val.to_resp(),
+            Err(e) => e.to_resp()
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod server;
+pub mod error;
 
 pub mod parser;
 pub mod command;
@@ -7,9 +8,69 @@ pub mod persistence;
 
 #[cfg(test)]
 mod tests{
-    use crate::parser::decrypt::parse_resp;
-    use crate::parser::{SimpleRESPObject, AggrRESPObject, RESPObject, AtomicItem};
-    use std::str; 
+    use crate::parser::decrypt::{parse_resp, parse_inline, StreamDecoder, ProtoLimits};
+    use crate::parser::{SimpleRESPObject, AggrRESPObject, RESPObject, AtomicItem, ProtocolVersion};
+    use crate::parser::{RespValue, ToResp, FromResp};
+    use crate::parser::encrypt::{as_map, as_bulk_str, as_double, as_null, write_int, write_value, format_double, IoSink};
+    use crate::server::{ClientSession, LaunchConfig, SharedGlobalState, serve_one_connection, parse_memory};
+    use crate::command::{hello, set, get, expire, ttl, persist, key_type, TimeUnit, Deadline, Propagate};
+    use crate::command::{del, exists, touch, rename, copy, keys, scan, randomkey, dbsize, flush};
+    use crate::command::list::{self, End};
+    use crate::command::hash::{self, HashPart};
+    use crate::command::scan::string_match;
+    use crate::command::set::SetOp;
+    use crate::command::zset::{self, ZSetOp};
+    use crate::command::stream;
+    use crate::command::string;
+    use crate::command::bitmap;
+    use crate::command::hyperloglog;
+    use crate::command::geo;
+    use crate::persistence::{RedisStorage, Keyspace, Entry, RedisValue, SortedSet, unix_time_ms, active_expire_cycle};
+    use crate::persistence::dict::Dict;
+    use crate::error::RedisError;
+    use bytes::BytesMut;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+
+    // serve every accepted connection on an ephemeral port, sharing one keyspace
+    fn spawn_test_server() -> SocketAddr{
+        spawn_test_master().0
+    }
+
+    // same, also handing out what the master propagates to its replicas
+    fn spawn_test_master() -> (SocketAddr, mpsc::Receiver<Box<[u8]>>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let storage = RedisStorage::new();
+        let (tx, rx) = mpsc::channel();
+        let shared_state = SharedGlobalState{slave_hub: Arc::new(Mutex::new(HashSet::new())), comm_channels: tx};
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (storage, shared_state) = (storage.clone(), shared_state.clone());
+                thread::spawn(move || {serve_one_connection(stream, storage, LaunchConfig::new(), shared_state)});
+            }
+        });
+        (addr, rx)
+    }
+
+    // send raw bytes and read until `expected` bytes arrived, the server hangs up or it goes quiet
+    fn roundtrip(stream: &mut TcpStream, payload: &[u8], expected: usize) -> Vec<u8>{
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+        stream.write_all(payload).unwrap();
+        let mut reply = vec!();
+        let mut chunk = [0u8; 4096];
+        while reply.len() < expected {
+            match stream.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => reply.extend_from_slice(&chunk[..n])
+            }
+        }
+        reply
+    }
     
     #[test]
     fn parse_simple_str(){
@@ -19,7 +80,7 @@ mod tests{
             RESPObject::Simple(SimpleRESPObject::Str(content)) => {
                 assert_eq!(content, "PING");
             },
-            _ => {}
+            _ => panic!("expect simple string")
         }
     }
     
@@ -31,7 +92,7 @@ mod tests{
             RESPObject::Aggregate(AggrRESPObject::BulkStr(content)) => {
                 assert_eq!(content, b"PING");
             },
-            _ => {}
+            _ => panic!("expect bulk string")
         }
     }
 
@@ -43,11 +104,10 @@ mod tests{
         let unpacked = match parsed_res {
             RESPObject::Aggregate(AggrRESPObject::Array(ref v)) => {
                 v.iter().take_while(|item|{
-                    match item {
-                        AtomicItem::SimpleItem(SimpleRESPObject::Str(_)) => true,
-                        AtomicItem::AggrItem(AggrRESPObject::BulkStr(_)) => true,
-                        _ => false
-                    }
+                    matches!(
+                        item,
+                        AtomicItem::SimpleItem(SimpleRESPObject::Str(_)) | AtomicItem::AggrItem(AggrRESPObject::BulkStr(_))
+                    )
                 }).map(|item| {item.as_bytes().unwrap()}).collect()
             },
             _ => {vec!()}
@@ -56,5 +116,1335 @@ mod tests{
     }
 
 
-    
+    #[test]
+    fn stream_decoder_waits_for_partial_frame(){
+        let mut decoder = StreamDecoder::new();
+        let mut buf = BytesMut::from(&b"*2\r\n$5\r\nhel"[..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"lo\r\n$5\r\nworld\r\n");
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&frame[..], b"*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n");
+        assert!(buf.is_empty());
+    }
+
+
+    #[test]
+    fn stream_decoder_splits_pipelined_frames(){
+        let mut decoder = StreamDecoder::new();
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n+PING\r\n*2\r\n$3\r\nGET\r\n$1\r\nk"[..]);
+        let frames = decoder.decode_all(&mut buf).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(&frames[0][..], b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(&frames[1][..], b"+PING\r\n");
+
+        buf.extend_from_slice(b"\r\n");
+        let frames = decoder.decode_all(&mut buf).unwrap();
+        assert_eq!(&frames[0][..], b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+    }
+
+
+    #[test]
+    fn parse_resp3_map(){
+        let map_str = b"%2\r\n+first\r\n,1.5\r\n$6\r\nsecond\r\n~2\r\n#t\r\n_\r\n";
+        let pairs = match parse_resp(map_str).unwrap() {
+            RESPObject::Aggregate(AggrRESPObject::Map(pairs)) => pairs,
+            _ => panic!("expect map")
+        };
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].0.as_bytes().unwrap(), b"first");
+        assert!(matches!(pairs[0].1, AtomicItem::SimpleItem(SimpleRESPObject::Double(d)) if d == 1.5));
+        match &pairs[1].1 {
+            AtomicItem::AggrItem(AggrRESPObject::Set(items)) => {
+                assert!(matches!(items[0], AtomicItem::SimpleItem(SimpleRESPObject::Boolean(true))));
+                assert!(matches!(items[1], AtomicItem::SimpleItem(SimpleRESPObject::Null)));
+            },
+            _ => panic!("expect set")
+        }
+    }
+
+
+    #[test]
+    fn parse_resp3_scalars(){
+        let verbatim = parse_resp(b"=15\r\ntxt:Some string\r\n").unwrap();
+        assert!(matches!(verbatim, RESPObject::Aggregate(AggrRESPObject::VerbatimStr("txt", b"Some string"))));
+        let big = parse_resp(b"(-3492890328409238509324850943850943825024385\r\n").unwrap();
+        assert!(matches!(big, RESPObject::Simple(SimpleRESPObject::BigNumber(_))));
+        let inf = parse_resp(b",-inf\r\n").unwrap();
+        assert!(matches!(inf, RESPObject::Simple(SimpleRESPObject::Double(d)) if d == f64::NEG_INFINITY));
+        assert!(parse_resp(b"#x\r\n").is_err());
+    }
+
+
+    #[test]
+    fn encode_resp3_downgrades_to_resp2(){
+        let pairs = || {vec!((as_bulk_str(Some(b"k")), as_double(2.5, ProtocolVersion::Resp3)))};
+        assert_eq!(&as_map(pairs(), ProtocolVersion::Resp3)[..], b"%1\r\n$1\r\nk\r\n,2.5\r\n");
+        let pairs = vec!((as_bulk_str(Some(b"k")), as_double(2.5, ProtocolVersion::Resp2)));
+        assert_eq!(&as_map(pairs, ProtocolVersion::Resp2)[..], b"*2\r\n$1\r\nk\r\n$3\r\n2.5\r\n");
+        assert_eq!(&as_null(ProtocolVersion::Resp2)[..], b"$-1\r\n");
+        assert_eq!(&as_null(ProtocolVersion::Resp3)[..], b"_\r\n");
+    }
+
+
+    #[test]
+    fn hello_switches_protocol(){
+        let server_state = LaunchConfig::new();
+        let mut session = ClientSession::new(&server_state);
+        let reply = hello(vec!(b"3", b"SETNAME", b"worker"), &mut session, &server_state).unwrap();
+        assert!(reply.encode(session.protocol).starts_with(b"%7\r\n$6\r\nserver\r\n"));
+        assert_eq!(session.protocol, ProtocolVersion::Resp3);
+        assert_eq!(session.name.as_deref(), Some(&b"worker"[..]));
+
+        let reply = hello(vec!(b"4"), &mut session, &server_state);
+        assert_eq!(reply, Err(RedisError::NoProto));
+        assert_eq!(session.protocol, ProtocolVersion::Resp3);
+    }
+
+
+    #[test]
+    fn parse_errors_and_integers(){
+        let err = parse_resp(b"-ERR unknown command 'foo'\r\n").unwrap();
+        assert!(matches!(err, RESPObject::Simple(SimpleRESPObject::Error("ERR unknown command 'foo'"))));
+        let int = parse_resp(b":-9223372036854775808\r\n").unwrap();
+        assert!(matches!(int, RESPObject::Simple(SimpleRESPObject::Integer(i64::MIN))));
+        assert!(parse_resp(b":12a\r\n").is_err());
+    }
+
+
+    #[test]
+    fn parse_nulls_and_empty_aggregates(){
+        assert!(matches!(parse_resp(b"$-1\r\n").unwrap(), RESPObject::Aggregate(AggrRESPObject::NullBulkStr)));
+        assert!(matches!(parse_resp(b"*-1\r\n").unwrap(), RESPObject::Aggregate(AggrRESPObject::NullArray)));
+        assert!(matches!(parse_resp(b"$0\r\n\r\n").unwrap(), RESPObject::Aggregate(AggrRESPObject::BulkStr(b""))));
+        match parse_resp(b"*0\r\n").unwrap() {
+            RESPObject::Aggregate(AggrRESPObject::Array(items)) => assert!(items.is_empty()),
+            _ => panic!("expect empty array")
+        }
+    }
+
+
+    #[test]
+    fn parse_nested_array(){
+        let nested = b"*3\r\n:1\r\n*2\r\n$-1\r\n*0\r\n-ERR inner\r\n";
+        let items = match parse_resp(nested).unwrap() {
+            RESPObject::Aggregate(AggrRESPObject::Array(items)) => items,
+            _ => panic!("expect array")
+        };
+        assert_eq!(items.len(), 3);
+        assert!(matches!(items[0], AtomicItem::SimpleItem(SimpleRESPObject::Integer(1))));
+        match &items[1] {
+            AtomicItem::AggrItem(AggrRESPObject::Array(inner)) => {
+                assert!(matches!(inner[0], AtomicItem::AggrItem(AggrRESPObject::NullBulkStr)));
+                assert!(matches!(&inner[1], AtomicItem::AggrItem(AggrRESPObject::Array(v)) if v.is_empty()));
+            },
+            _ => panic!("expect nested array")
+        }
+        assert!(matches!(items[2], AtomicItem::SimpleItem(SimpleRESPObject::Error("ERR inner"))));
+        // mixed arrays can not be flattened into strings
+        assert!(parse_resp(nested).unwrap().to_vec().is_none());
+    }
+
+
+    #[test]
+    fn binary_bulk_str_round_trip(){
+        let blob: &[u8] = b"\x08\x96\x01\r\n\xff\x00$3\r\n";
+        let mut storage = Keyspace::new();
+        let set_cmd = [&b"*3\r\n$3\r\nSET\r\n$4\r\nblob\r\n$11\r\n"[..], blob, b"\r\n"].concat();
+        let args = parse_resp(&set_cmd).unwrap().to_vec().unwrap();
+        assert_eq!(args[2], blob);
+        set(args[1..].to_vec(), &mut storage).unwrap();
+
+        let reply = get(b"blob", &mut storage).unwrap().encode(ProtocolVersion::Resp2);
+        assert_eq!(&reply[..], &[&b"$11\r\n"[..], blob, b"\r\n"].concat()[..]);
+        assert!(matches!(parse_resp(&reply).unwrap(), RESPObject::Aggregate(AggrRESPObject::BulkStr(v)) if v == blob));
+    }
+
+
+    #[test]
+    fn resp_value_encodes_per_protocol(){
+        let mut fields = HashMap::new();
+        fields.insert("count", Some(3i64));
+        let reply = (fields, vec!(Some(&b"a"[..]), None), 1.5f64, true).to_resp();
+        // only one field, so map ordering is deterministic
+        assert_eq!(
+            &reply.encode(ProtocolVersion::Resp3)[..],
+            &b"*4\r\n%1\r\n$5\r\ncount\r\n:3\r\n*2\r\n$1\r\na\r\n_\r\n,1.5\r\n#t\r\n"[..]
+        );
+        assert_eq!(
+            &reply.encode(ProtocolVersion::Resp2)[..],
+            &b"*4\r\n*2\r\n$5\r\ncount\r\n:3\r\n*2\r\n$1\r\na\r\n$-1\r\n$3\r\n1.5\r\n:1\r\n"[..]
+        );
+    }
+
+
+    #[test]
+    fn resp_value_converts_back(){
+        let decoded = RespValue::from(parse_resp(b"*2\r\n$2\r\n42\r\n$-1\r\n").unwrap());
+        let (num, missing) = <(i64, Option<String>)>::from_resp(decoded).unwrap();
+        assert_eq!((num, missing), (42, None));
+
+        let flat_map = RespValue::from(parse_resp(b"*2\r\n$5\r\nproto\r\n:3\r\n").unwrap());
+        let info = HashMap::<String, i64>::from_resp(flat_map).unwrap();
+        assert_eq!(info["proto"], 3);
+        assert!(Vec::<u8>::from_resp(RespValue::Integer(1)).is_err());
+    }
+
+
+    #[test]
+    fn parse_inline_quoting(){
+        let args = parse_inline(b"set  key \"a\\x41\\n b\" 'it\\'s'\r\n").unwrap();
+        assert_eq!(args, vec!(b"set".to_vec(), b"key".to_vec(), b"aA\n b".to_vec(), b"it's".to_vec()));
+        assert_eq!(parse_inline(b"\"\"\n").unwrap(), vec!(Vec::<u8>::new()));
+        assert!(parse_inline(b"\r\n").unwrap().is_empty());
+        assert!(parse_inline(b"get \"key\r\n").is_err());
+        assert!(parse_inline(b"get \"key\"x\r\n").is_err());
+    }
+
+
+    #[test]
+    fn stream_decoder_frames_inline_commands(){
+        let mut decoder = StreamDecoder::new();
+        let mut buf = BytesMut::from(&b"PING\r\nECHO hi\n*1\r\n$4\r\nPING\r\nGET"[..]);
+        let frames = decoder.decode_all(&mut buf).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(&frames[0][..], b"PING\r\n");
+        assert_eq!(&frames[1][..], b"ECHO hi\n");
+        assert_eq!(&buf[..], b"GET");
+    }
+
+
+    #[test]
+    fn command_errors_keep_connection(){
+        let mut stream = TcpStream::connect(spawn_test_server()).unwrap();
+        let expected = b"-ERR wrong number of arguments for 'get' command\r\n";
+        let reply = roundtrip(&mut stream, b"*1\r\n$3\r\nGET\r\n", expected.len());
+        assert_eq!(&reply[..], expected);
+        let expected = b"-ERR unknown command 'FOO', with args beginning with: 'bar' \r\n+PONG\r\n";
+        let reply = roundtrip(&mut stream, b"FOO bar\r\nPING\r\n", expected.len());
+        assert_eq!(&reply[..], expected);
+    }
+
+    #[test]
+    fn master_propagates_successful_writes(){
+        let (addr, propagated) = spawn_test_master();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let expected = b"+OK\r\n$1\r\nv\r\n:1\r\n:0\r\n+OK\r\n-WRONGTYPE Operation against a key holding the wrong kind of value\r\n+OK\r\n";
+        let reply = roundtrip(&mut stream, b"SET k v\r\nGET k\r\nDEL k\r\nexists k\r\nSET l x\r\nLPUSH l a\r\nflushall\r\n", expected.len());
+        assert_eq!(&reply[..], expected);
+        // reads and failed writes stay on the master, inline frames go out as multibulk
+        let feed = propagated.try_iter().collect::<Vec<_>>();
+        assert_eq!(feed, [
+            &b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n"[..],
+            b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n",
+            b"*3\r\n$3\r\nSET\r\n$1\r\nl\r\n$1\r\nx\r\n",
+            b"*1\r\n$8\r\nflushall\r\n"
+        ].map(Box::from));
+    }
+
+    #[test]
+    fn master_rewrites_what_replicas_can_not_replay(){
+        let (addr, propagated) = spawn_test_master();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // SPOP goes out as SREM of the member it picked
+        let expected = b":1\r\n$1\r\na\r\n$-1\r\n";
+        assert_eq!(roundtrip(&mut stream, b"SADD s a\r\nSPOP s\r\nSPOP s\r\n", expected.len()), expected);
+        // a blocking pop that times out changed nothing and sends nothing
+        assert_eq!(roundtrip(&mut stream, b"BLPOP q 0.05\r\n", 5), b"*-1\r\n");
+        // float increments go out as their final value
+        assert_eq!(roundtrip(&mut stream, b"INCRBYFLOAT f 1.5\r\nHINCRBYFLOAT h x 0.5\r\n", 16), b"$3\r\n1.5\r\n$3\r\n0.5\r\n");
+        assert_eq!(propagated.try_iter().collect::<Vec<_>>(), [
+            &b"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n"[..],
+            b"*3\r\n$4\r\nSREM\r\n$1\r\ns\r\n$1\r\na\r\n",
+            b"*4\r\n$3\r\nSET\r\n$1\r\nf\r\n$3\r\n1.5\r\n$7\r\nKEEPTTL\r\n",
+            b"*4\r\n$4\r\nHSET\r\n$1\r\nh\r\n$1\r\nx\r\n$3\r\n0.5\r\n"
+        ].map(Box::from));
+
+        // SPOP with a count removes exactly the members it replied with
+        assert_eq!(roundtrip(&mut stream, b"SADD t a b c\r\n", 4), b":3\r\n");
+        let reply = roundtrip(&mut stream, b"SPOP t 2\r\n", 18);
+        let feed = propagated.try_iter().collect::<Vec<_>>();
+        assert_eq!(feed.len(), 2);
+        assert_eq!(feed[1], [&b"*4\r\n$4\r\nSREM\r\n$1\r\nt\r\n"[..], &reply[4..]].concat().into_boxed_slice());
+
+        // relative deadlines go out as absolute ones
+        assert_eq!(roundtrip(&mut stream, b"EXPIRE f 100\r\nSET g v PX 100000\r\n", 9), b":1\r\n+OK\r\n");
+        let feed = propagated.try_iter().collect::<Vec<_>>();
+        assert!(feed[0].starts_with(b"*3\r\n$9\r\nPEXPIREAT\r\n$1\r\nf\r\n$13\r\n"));
+        assert!(feed[1].starts_with(b"*5\r\n$3\r\nSET\r\n$1\r\ng\r\n$1\r\nv\r\n$4\r\nPXAT\r\n"));
+
+        // a served blocking pop follows the push that fed it, as a plain pop
+        let mut blocked = TcpStream::connect(addr).unwrap();
+        let handle = thread::spawn(move || {roundtrip(&mut blocked, b"BLPOP q 5\r\n", 18)});
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(roundtrip(&mut stream, b"RPUSH q x\r\n", 4), b":1\r\n");
+        assert_eq!(handle.join().unwrap(), b"*2\r\n$1\r\nq\r\n$1\r\nx\r\n");
+        assert_eq!(propagated.try_iter().collect::<Vec<_>>(), [
+            &b"*3\r\n$5\r\nRPUSH\r\n$1\r\nq\r\n$1\r\nx\r\n"[..],
+            b"*2\r\n$4\r\nLPOP\r\n$1\r\nq\r\n"
+        ].map(Box::from));
+    }
+
+
+    #[test]
+    fn protocol_error_closes_connection(){
+        let mut stream = TcpStream::connect(spawn_test_server()).unwrap();
+        let reply = roundtrip(&mut stream, b"PING\r\n*1\r\n$x\r\nPING\r\n", usize::MAX);
+        assert_eq!(&reply[..], b"+PONG\r\n-ERR Protocol error: invalid bulk length\r\n");
+    }
+
+
+    #[test]
+    fn encoder_writes_into_reused_sink(){
+        let mut out = BytesMut::new();
+        for num in [0, 7, -1, 1234567890, i64::MAX, i64::MIN] {
+            out.clear();
+            write_int(&mut out, num);
+            assert_eq!(&out[..], format!(":{num}\r\n").as_bytes());
+        }
+        out.clear();
+        let reply = RespValue::Array(vec!(RespValue::Integer(-42), RespValue::SimpleStr("a\r\nb".into())));
+        write_value(&mut out, &reply, ProtocolVersion::Resp2);
+        assert_eq!(&out[..], b"*2\r\n:-42\r\n+a  b\r\n");
+
+        let mut sink = IoSink::new(vec!());
+        write_value(&mut sink, &RespValue::Double(-0.25), ProtocolVersion::Resp3);
+        assert_eq!(sink.finish().unwrap(), b",-0.25\r\n");
+        assert_eq!(format_double(1e300), "1e+300");
+        assert_eq!(format_double(3.0), "3");
+    }
+
+
+    #[test]
+    fn stream_decoder_enforces_limits(){
+        let limits = ProtoLimits{max_bulk_len: 8, max_multibulk_len: 2, max_query_buffer: 64, max_inline_len: 16};
+        let decode = |input: &[u8]| {StreamDecoder::with_limits(limits).decode(&mut BytesMut::from(input))};
+        assert!(decode(b"*2\r\n$8\r\n12345678\r\n$1\r\na\r\n").unwrap().is_some());
+        assert_eq!(decode(b"*1\r\n$9\r\n"), Err(RedisError::Protocol("invalid bulk length")));
+        assert_eq!(decode(b"*3\r\n"), Err(RedisError::Protocol("invalid multibulk length")));
+        // only -1 may carry a sign
+        assert_eq!(decode(b"*1\r\n$+3\r\nabc\r\n"), Err(RedisError::Protocol("invalid bulk length")));
+        assert_eq!(decode(b"*+1\r\n$3\r\nabc\r\n"), Err(RedisError::Protocol("invalid multibulk length")));
+        assert_eq!(decode(b"*-0\r\n"), Err(RedisError::Protocol("invalid multibulk length")));
+        assert_eq!(parse_resp(b"$+3\r\nabc\r\n").err(), Some(RedisError::Protocol("invalid bulk length")));
+        assert_eq!(decode(b"SET key aaaaaaaaaaaaa"), Err(RedisError::Protocol("too big inline request")));
+        // a partial frame may not grow the buffer past the query buffer limit
+        let limits = ProtoLimits{max_query_buffer: 16, ..ProtoLimits::default()};
+        let mut partial = BytesMut::from(&b"*1\r\n$32\r\n0123456789abcdef"[..]);
+        assert_eq!(
+            StreamDecoder::with_limits(limits).decode(&mut partial),
+            Err(RedisError::Protocol("client query buffer limit exceeded"))
+        );
+
+        assert_eq!(parse_memory("512mb"), Some(512*1024*1024));
+        assert_eq!(parse_memory("1g"), Some(1_000_000_000));
+        assert_eq!(parse_memory("12xb"), None);
+    }
+
+
+    #[test]
+    fn set_options_and_lazy_expiry(){
+        let mut storage = Keyspace::new();
+        let mut run = |args: &[&str]| {
+            reply_of(set(args.iter().map(|arg| {arg.as_bytes()}).collect(), &mut storage))
+        };
+        assert_eq!(run(&["k", "v1", "XX"]), Ok(RespValue::Null));
+        assert_eq!(run(&["k", "v1", "NX", "GET"]), Ok(RespValue::Null));
+        assert_eq!(run(&["k", "v2", "NX"]), Ok(RespValue::Null));
+        assert_eq!(run(&["k", "v2", "XX", "GET", "PX", "100000"]), Ok(b"v1".to_resp()));
+        assert_eq!(run(&["k", "v3", "KEEPTTL"]), Ok(RespValue::ok()));
+        assert_eq!(run(&["k", "v", "NX", "XX"]), Err(RedisError::Syntax));
+        assert_eq!(run(&["k", "v", "EX", "10", "KEEPTTL"]), Err(RedisError::Syntax));
+        assert_eq!(run(&["k", "v", "EX"]), Err(RedisError::Syntax));
+        assert_eq!(run(&["k", "v", "EX", "1.5"]), Err(RedisError::NotInteger));
+        assert_eq!(run(&["k", "v", "PX", "0"]), Err(RedisError::invalid_expire_time("set")));
+        assert_eq!(run(&["k", "v", "EX", "9223372036854775807"]), Err(RedisError::invalid_expire_time("set")));
+        let deadline = storage.get(b"k").unwrap().expires_at().unwrap();
+        assert!(deadline > unix_time_ms() && deadline <= unix_time_ms() + 100000);
+
+        // a deadline in the past makes the key vanish on the next lookup
+        let past = (unix_time_ms() - 1).to_string();
+        set(vec!(b"k", b"v4", b"PXAT", past.as_bytes()), &mut storage).unwrap();
+        assert_eq!(get(b"k", &mut storage), Ok(RespValue::Null));
+        assert!(storage.is_empty());
+    }
+
+
+    #[test]
+    fn expire_flags_and_ttl_family(){
+        let mut storage = Keyspace::new();
+        storage.insert(b"k", Entry::new(RedisValue::from(&b"v"[..])));
+        let mut run = |args: &[&str]| {
+            let params = args.iter().map(|arg| {arg.as_bytes()}).collect();
+            reply_of(expire("expire", params, TimeUnit::Seconds, Deadline::Relative, &mut storage))
+        };
+        assert_eq!(run(&["missing", "10"]), Ok(RespValue::Integer(0)));
+        assert_eq!(run(&["k", "10", "XX"]), Ok(RespValue::Integer(0)));
+        assert_eq!(run(&["k", "10", "GT"]), Ok(RespValue::Integer(0)));
+        assert_eq!(run(&["k", "100", "NX"]), Ok(RespValue::Integer(1)));
+        assert_eq!(run(&["k", "50", "GT"]), Ok(RespValue::Integer(0)));
+        assert_eq!(run(&["k", "50", "LT"]), Ok(RespValue::Integer(1)));
+        assert_eq!(run(&["k", "10", "NX", "LT"]).unwrap_err().to_string(),
+                   "ERR NX and XX, GT or LT options at the same time are not compatible");
+        assert_eq!(run(&["k", "10", "FOO"]).unwrap_err().to_string(), "ERR Unsupported option FOO");
+        assert_eq!(run(&["k", "9223372036854775807"]), Err(RedisError::invalid_expire_time("expire")));
+
+        assert_eq!(ttl(b"k", TimeUnit::Seconds, &mut storage), Ok(RespValue::Integer(50)));
+        assert_eq!(ttl(b"missing", TimeUnit::Milliseconds, &mut storage), Ok(RespValue::Integer(-2)));
+        assert_eq!(persist(b"k", &mut storage), Ok(RespValue::Integer(1)));
+        assert_eq!(persist(b"k", &mut storage), Ok(RespValue::Integer(0)));
+        assert_eq!(ttl(b"k", TimeUnit::Seconds, &mut storage), Ok(RespValue::Integer(-1)));
+        assert_eq!(storage.volatile_len(), 0);
+
+        // a negative ttl deletes the key on the spot
+        let params = vec!(&b"k"[..], b"-1");
+        assert_eq!(reply_of(expire("pexpire", params, TimeUnit::Milliseconds, Deadline::Relative, &mut storage)), Ok(RespValue::Integer(1)));
+        assert!(storage.is_empty());
+    }
+
+
+    #[test]
+    fn active_expire_reclaims_untouched_keys(){
+        let storage = RedisStorage::new();
+        {
+            let mut data = storage.data.lock().unwrap();
+            let past = unix_time_ms() - 1;
+            for i in 0..200 {
+                let key = format!("gone:{i}");
+                data.insert(key.as_bytes(), Entry::with_expiry(RedisValue::from(&b"v"[..]), Some(past)));
+            }
+            data.insert(b"kept", Entry::with_expiry(RedisValue::from(&b"v"[..]), Some(unix_time_ms() + 60000)));
+        }
+        let mut expired = 0;
+        while storage.data.lock().unwrap().len() > 1 {
+            expired += active_expire_cycle(&storage, std::time::Duration::from_millis(25));
+        }
+        assert_eq!(expired, 200);
+        assert_eq!(storage.data.lock().unwrap().volatile_len(), 1);
+    }
+
+
+    #[test]
+    fn typed_values_report_wrongtype(){
+        let mut storage = Keyspace::new();
+        storage.insert(b"list", Entry::new(RedisValue::List(vec!(b"a".to_vec()).into())));
+        storage.insert(b"str", Entry::new(RedisValue::from(&b"v"[..])));
+        assert_eq!(key_type(b"list", &mut storage), Ok(RespValue::SimpleStr("list".into())));
+        assert_eq!(key_type(b"str", &mut storage), Ok(RespValue::SimpleStr("string".into())));
+        assert_eq!(key_type(b"nope", &mut storage), Ok(RespValue::SimpleStr("none".into())));
+
+        assert_eq!(get(b"list", &mut storage), Err(RedisError::WrongType));
+        let params = vec!(&b"list"[..], b"v", b"GET");
+        assert_eq!(reply_of(set(params, &mut storage)), Err(RedisError::WrongType));
+        assert_eq!(key_type(b"list", &mut storage), Ok(RespValue::SimpleStr("list".into())));
+        // a plain SET replaces whatever type was there
+        assert_eq!(reply_of(set(vec!(&b"list"[..], b"v"), &mut storage)), Ok(RespValue::ok()));
+        assert_eq!(get(b"list", &mut storage), Ok(b"v".to_resp()));
+
+        let entry = storage.get(b"str").unwrap();
+        assert!(entry.idle_secs() <= 1 && entry.lfu_frequency() >= 5);
+    }
+
+
+    // build a params vector out of a whitespace separated command tail
+    fn args(line: &str) -> Vec<&[u8]>{
+        line.split_whitespace().map(|arg| {arg.as_bytes()}).collect()
+    }
+
+    fn bulks(items: &[&str]) -> RespValue{
+        items.to_vec().to_resp()
+    }
+
+    #[test]
+    fn list_commands(){
+        let mut db = Keyspace::new();
+        assert_eq!(list::push(args("l c b a"), End::Left, false, &mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(list::push(args("l d e a"), End::Right, false, &mut db), Ok(RespValue::Integer(6)));
+        assert_eq!(list::push(args("nope x"), End::Right, true, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(list::lrange(args("l 0 -1"), &mut db), Ok(bulks(&["a", "b", "c", "d", "e", "a"])));
+        assert_eq!(list::lrange(args("l -100 1"), &mut db), Ok(bulks(&["a", "b"])));
+        assert_eq!(list::lrange(args("l 5 2"), &mut db), Ok(bulks(&[])));
+        assert_eq!(list::lindex(args("l -2"), &mut db), Ok(b"e".to_resp()));
+        assert_eq!(list::lindex(args("l 6"), &mut db), Ok(RespValue::Null));
+        assert_eq!(list::lset(args("l 9 x"), &mut db).unwrap_err().to_string(), "ERR index out of range");
+        assert_eq!(list::lset(args("nope 0 x"), &mut db), Err(RedisError::NoSuchKey));
+
+        assert_eq!(list::lpos(args("l a"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(list::lpos(args("l a RANK -1"), &mut db), Ok(RespValue::Integer(5)));
+        assert_eq!(list::lpos(args("l a COUNT 0"), &mut db), Ok(vec!(0, 5).to_resp()));
+        assert_eq!(list::lpos(args("l a COUNT 0 MAXLEN 3"), &mut db), Ok(vec!(0).to_resp()));
+        assert_eq!(list::lpos(args("l z"), &mut db), Ok(RespValue::Null));
+        assert!(list::lpos(args("l a RANK 0"), &mut db).unwrap_err().to_string().starts_with("ERR RANK can't be zero"));
+        // i64::MIN has no positive counterpart, it is refused before any negation
+        assert_eq!(list::lpos(args("l a RANK -9223372036854775808"), &mut db).unwrap_err().to_string(),
+                   "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list");
+
+        assert_eq!(list::linsert(args("l BEFORE c x"), &mut db), Ok(RespValue::Integer(7)));
+        assert_eq!(list::linsert(args("l AFTER zz x"), &mut db), Ok(RespValue::Integer(-1)));
+        assert_eq!(list::lrem(args("l -1 a"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(list::ltrim(args("l 1 -2"), &mut db), Ok(RespValue::ok()));
+        assert_eq!(list::lrange(args("l 0 -1"), &mut db), Ok(bulks(&["b", "x", "c", "d"])));
+
+        assert_eq!(list::pop(args("l"), End::Left, &mut db), Ok(b"b".to_resp()));
+        assert_eq!(list::pop(args("l 2"), End::Right, &mut db), Ok(bulks(&["d", "c"])));
+        assert_eq!(list::pop(args("l -1"), End::Right, &mut db), Err(RedisError::NotPositive));
+        assert_eq!(list::pop(args("nope 2"), End::Right, &mut db), Ok(RespValue::NullArray));
+        assert_eq!(list::lmove(args("l dst LEFT RIGHT"), &mut db), Ok(b"x".to_resp()));
+        // the source went empty and disappears
+        assert_eq!(key_type(b"l", &mut db), Ok(RespValue::SimpleStr("none".into())));
+        assert_eq!(list::lmove(args("l dst LEFT RIGHT"), &mut db), Ok(RespValue::Null));
+
+        list::push(args("other 1 2 3"), End::Right, false, &mut db).unwrap();
+        assert_eq!(list::lmpop(args("2 l other RIGHT COUNT 2"), &mut db), Ok(("other", bulks(&["3", "2"])).to_resp()));
+        assert_eq!(list::lmpop(args("1 l LEFT"), &mut db), Ok(RespValue::NullArray));
+        assert_eq!(list::lmpop(args("0 l LEFT"), &mut db).unwrap_err().to_string(), "ERR numkeys should be greater than 0");
+        assert_eq!(list::lmpop(args("1 l LEFT COUNT 0"), &mut db).unwrap_err().to_string(), "ERR count should be greater than 0");
+        set(args("str v"), &mut db).unwrap();
+        assert_eq!(list::push(args("str x"), End::Left, false, &mut db), Err(RedisError::WrongType));
+        assert_eq!(list::lmove(args("dst str LEFT LEFT"), &mut db), Err(RedisError::WrongType));
+    }
+
+
+    #[test]
+    fn blocking_pops_wake_in_fifo_order(){
+        let addr = spawn_test_server();
+        let waiter = |payload: &'static [u8], expected: &'static [u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let handle = thread::spawn(move || {roundtrip(&mut stream, payload, expected.len())});
+            // give the waiter time to park before the next one queues up
+            thread::sleep(std::time::Duration::from_millis(100));
+            (handle, expected)
+        };
+        let first = waiter(b"BLPOP q 5\r\n", b"*2\r\n$1\r\nq\r\n$1\r\na\r\n");
+        let second = waiter(b"BLMOVE q dst LEFT LEFT 5\r\n", b"$1\r\nb\r\n");
+
+        let mut writer = TcpStream::connect(addr).unwrap();
+        assert_eq!(roundtrip(&mut writer, b"RPUSH q a b c\r\n", 4), b":3\r\n");
+        for (handle, expected) in [first, second] {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+        // the leftover element stays put and BLMOVE pushed to its destination
+        assert_eq!(roundtrip(&mut writer, b"LRANGE q 0 -1\r\nLLEN dst\r\n", 15), b"*1\r\n$1\r\nc\r\n:1\r\n");
+        assert_eq!(roundtrip(&mut writer, b"BRPOP empty 0.05\r\n", 5), b"*-1\r\n");
+        assert_eq!(roundtrip(&mut writer, b"BLPOP q -1\r\n", 26), b"-ERR timeout is negative\r\n");
+
+        // CLIENT UNBLOCK releases a client parked forever
+        let mut blocked = TcpStream::connect(addr).unwrap();
+        let id_reply = roundtrip(&mut blocked, b"CLIENT ID\r\n", 3);
+        let id = String::from_utf8_lossy(&id_reply[1..id_reply.len()-2]).into_owned();
+        let expected = b"-UNBLOCKED client unblocked via CLIENT UNBLOCK\r\n";
+        let handle = thread::spawn(move || {roundtrip(&mut blocked, b"BLMPOP 0 1 q2 LEFT\r\n", expected.len())});
+        thread::sleep(std::time::Duration::from_millis(100));
+        let unblock = format!("CLIENT UNBLOCK {id} ERROR\r\nCLIENT UNBLOCK {id}\r\n");
+        assert_eq!(roundtrip(&mut writer, unblock.as_bytes(), 8), b":1\r\n:0\r\n");
+        assert_eq!(handle.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn disconnected_blocked_client_does_not_take_the_element(){
+        let addr = spawn_test_server();
+        let mut gone = TcpStream::connect(addr).unwrap();
+        gone.write_all(b"BLPOP hungup 0\r\n").unwrap();
+        let mut waiting = TcpStream::connect(addr).unwrap();
+        let handle = thread::spawn(move || {roundtrip(&mut waiting, b"BLPOP hungup 5\r\n", 24)});
+        thread::sleep(std::time::Duration::from_millis(100));
+        // the first in line hangs up, the push goes to the next waiter instead of a closed socket
+        drop(gone);
+        thread::sleep(std::time::Duration::from_millis(50));
+
+        let mut writer = TcpStream::connect(addr).unwrap();
+        assert_eq!(roundtrip(&mut writer, b"RPUSH hungup a\r\n", 4), b":1\r\n");
+        assert_eq!(handle.join().unwrap(), b"*2\r\n$6\r\nhungup\r\n$1\r\na\r\n");
+        assert_eq!(roundtrip(&mut writer, b"RPUSH hungup b\r\nLRANGE hungup 0 -1\r\n", 15), b":1\r\n*1\r\n$1\r\nb\r\n");
+    }
+
+
+    #[test]
+    fn string_commands(){
+        let mut db = Keyspace::new();
+        let max_len = ProtoLimits::default().max_bulk_len;
+        assert_eq!(string::incrby("incr", args("n"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(string::incrby("decrby", args("n 11"), &mut db), Ok(RespValue::Integer(-10)));
+        assert_eq!(string::incrby("decrby", args("n -9223372036854775808"), &mut db).unwrap_err().to_string(),
+                   "ERR decrement would overflow");
+        set(args("big 9223372036854775807 EX 100"), &mut db).unwrap();
+        assert_eq!(string::incrby("incr", args("big"), &mut db).unwrap_err().to_string(),
+                   "ERR increment or decrement would overflow");
+        assert_eq!(string::incrby("incrby", args("big -7"), &mut db), Ok(RespValue::Integer(9223372036854775800)));
+        // updating in place keeps the ttl
+        assert!(db.peek(b"big").unwrap().expires_at().is_some());
+        set(args("s hello"), &mut db).unwrap();
+        assert_eq!(string::incrby("incr", args("s"), &mut db), Err(RedisError::NotInteger));
+        assert_eq!(reply_of(string::incrbyfloat(args("f 10.5"), &mut db)), Ok(b"10.5".to_resp()));
+        assert_eq!(reply_of(string::incrbyfloat(args("f 0.1"), &mut db)), Ok(b"10.6".to_resp()));
+        assert_eq!(reply_of(string::incrbyfloat(args("s 1"), &mut db)), Err(RedisError::NotFloat));
+        // formatted like redis' long double, without the noise of the binary addition
+        set(args("p 0.1"), &mut db).unwrap();
+        assert_eq!(reply_of(string::incrbyfloat(args("p 0.2"), &mut db)), Ok(b"0.3".to_resp()));
+        assert_eq!(get(b"p", &mut db), Ok(b"0.3".to_resp()));
+        assert_eq!(reply_of(string::incrbyfloat(args("p -0.3"), &mut db)), Ok(b"0".to_resp()));
+        assert_eq!(reply_of(string::incrbyfloat(args("p 1e20"), &mut db)), Ok(b"100000000000000000000".to_resp()));
+        assert_eq!(reply_of(string::incrbyfloat(args("p 1.5e-10"), &mut db)), Ok(b"100000000000000000000".to_resp()));
+
+        assert_eq!(string::append(args("s _world"), max_len, &mut db), Ok(RespValue::Integer(11)));
+        assert_eq!(string::strlen(b"s", &mut db), Ok(RespValue::Integer(11)));
+        assert_eq!(string::getrange(args("s -5 -1"), &mut db), Ok(b"world".to_resp()));
+        assert_eq!(string::getrange(args("s 3 1"), &mut db), Ok(b"".to_resp()));
+        assert_eq!(string::setrange(args("pad 3 ab"), max_len, &mut db), Ok(RespValue::Integer(5)));
+        assert_eq!(get(b"pad", &mut db), Ok(b"\0\0\0ab".to_resp()));
+        assert_eq!(string::setrange(vec!(b"none", b"0", b""), max_len, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(string::setrange(args("pad -1 x"), max_len, &mut db).unwrap_err().to_string(), "ERR offset is out of range");
+        // proto-max-bulk-len bounds every string a command builds
+        let too_long = "ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+        assert_eq!(string::setrange(args("pad 3 abc"), 5, &mut db).unwrap_err().to_string(), too_long);
+        assert_eq!(string::append(args("pad x"), 5, &mut db).unwrap_err().to_string(), too_long);
+        assert_eq!(string::setrange(vec!(b"pad", b"100", b""), 5, &mut db), Ok(RespValue::Integer(5)));
+        assert_eq!(string::setrange(vec!(b"none", b"100", b""), 5, &mut db), Ok(RespValue::Integer(0)));
+
+        assert_eq!(string::mset(args("a 1 b 2"), false, &mut db), Ok(RespValue::ok()));
+        assert_eq!(string::mset(args("b 3 c 4"), true, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(string::mset(args("a 1 b"), false, &mut db), Err(RedisError::wrong_arity("mset")));
+        hash::hset("hset", args("h f v"), &mut db).unwrap();
+        assert_eq!(string::mget(args("a c h b"), &mut db),
+                   Ok(vec!(Some("1"), None, None, Some("2")).to_resp()));
+        assert_eq!(string::getdel(b"a", &mut db), Ok(b"1".to_resp()));
+        assert_eq!(get(b"a", &mut db), Ok(RespValue::Null));
+        assert_eq!(reply_of(string::getex(args("b PX 100000"), &mut db)), Ok(b"2".to_resp()));
+        assert!(db.peek(b"b").unwrap().expires_at().is_some());
+        assert_eq!(reply_of(string::getex(args("b PERSIST"), &mut db)), Ok(b"2".to_resp()));
+        assert!(db.peek(b"b").unwrap().expires_at().is_none());
+        assert_eq!(reply_of(string::getex(args("b EX 0"), &mut db)), Err(RedisError::invalid_expire_time("getex")));
+
+        set(args("k1 ohmytext"), &mut db).unwrap();
+        set(args("k2 mynewtext"), &mut db).unwrap();
+        assert_eq!(string::lcs(args("k1 k2"), max_len, &mut db), Ok(b"mytext".to_resp()));
+        assert_eq!(string::lcs(args("k1 k2 LEN"), max_len, &mut db), Ok(RespValue::Integer(6)));
+        let range = |a: (i64, i64), b: (i64, i64)| {vec!(a.to_resp(), b.to_resp())};
+        assert_eq!(string::lcs(args("k1 k2 IDX"), max_len, &mut db), Ok(RespValue::Map(vec!(
+            ("matches".to_resp(), vec!(range((4, 7), (5, 8)), range((2, 3), (0, 1))).to_resp()),
+            ("len".to_resp(), 6.to_resp())
+        ))));
+        let mut longest = range((4, 7), (5, 8));
+        longest.push(4.to_resp());
+        assert_eq!(string::lcs(args("k1 k2 IDX MINMATCHLEN 4 WITHMATCHLEN"), max_len, &mut db), Ok(RespValue::Map(vec!(
+            ("matches".to_resp(), vec!(longest).to_resp()),
+            ("len".to_resp(), 6.to_resp())
+        ))));
+        assert_eq!(string::lcs(args("k1 h"), max_len, &mut db).unwrap_err().to_string(),
+                   "ERR The specified keys must contain string values");
+        assert_eq!(string::lcs(args("k1 k2"), 64, &mut db).unwrap_err().to_string(),
+                   "ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len");
+    }
+
+    #[test]
+    fn bitmap_commands(){
+        let mut db = Keyspace::new();
+        let max_len = ProtoLimits::default().max_bulk_len;
+        assert_eq!(bitmap::setbit(args("b 7 1"), max_len, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(bitmap::setbit(args("b 7 1"), max_len, &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(bitmap::getbit(args("b 7"), max_len, &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(bitmap::getbit(args("b 100"), max_len, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(bitmap::setbit(args("b 23 1"), max_len, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(get(b"b", &mut db), Ok(b"\x01\x00\x01".to_resp()));
+        assert_eq!(bitmap::setbit(args("b 4294967296 1"), max_len, &mut db).unwrap_err().to_string(),
+                   "ERR bit offset is not an integer or out of range");
+        // the configured proto-max-bulk-len caps offsets too
+        assert_eq!(bitmap::setbit(args("b 32 1"), 4, &mut db).unwrap_err().to_string(),
+                   "ERR bit offset is not an integer or out of range");
+        assert_eq!(bitmap::bitfield(args("b GET u8 #4"), true, 4, &mut db).unwrap_err().to_string(),
+                   "ERR bit offset is not an integer or out of range");
+        assert_eq!(bitmap::setbit(args("b 1 2"), max_len, &mut db).unwrap_err().to_string(),
+                   "ERR bit is not an integer or out of range");
+
+        set(args("s foobar"), &mut db).unwrap();
+        assert_eq!(bitmap::bitcount(args("s"), &mut db), Ok(RespValue::Integer(26)));
+        assert_eq!(bitmap::bitcount(args("s 1 1"), &mut db), Ok(RespValue::Integer(6)));
+        assert_eq!(bitmap::bitcount(args("s -2 -1 BYTE"), &mut db), Ok(RespValue::Integer(7)));
+        assert_eq!(bitmap::bitcount(args("s 5 30 BIT"), &mut db), Ok(RespValue::Integer(17)));
+        assert_eq!(bitmap::bitcount(args("s 1"), &mut db), Err(RedisError::Syntax));
+
+        set(vec!(b"p", b"\xff\xf0\x00"), &mut db).unwrap();
+        assert_eq!(bitmap::bitpos(args("p 0"), &mut db), Ok(RespValue::Integer(12)));
+        set(vec!(b"p", b"\x00\xff\xf0"), &mut db).unwrap();
+        assert_eq!(bitmap::bitpos(args("p 1 2"), &mut db), Ok(RespValue::Integer(16)));
+        assert_eq!(bitmap::bitpos(args("p 1 7 15 BIT"), &mut db), Ok(RespValue::Integer(8)));
+        set(vec!(b"p", b"\xff\xff"), &mut db).unwrap();
+        // clear bits past the end only count when no end was given
+        assert_eq!(bitmap::bitpos(args("p 0"), &mut db), Ok(RespValue::Integer(16)));
+        assert_eq!(bitmap::bitpos(args("p 0 0 -1"), &mut db), Ok(RespValue::Integer(-1)));
+        assert_eq!(bitmap::bitpos(args("missing 0"), &mut db), Ok(RespValue::Integer(0)));
+
+        set(args("k1 foobar"), &mut db).unwrap();
+        set(args("k2 abcdef"), &mut db).unwrap();
+        assert_eq!(bitmap::bitop(args("AND dest k1 k2"), &mut db), Ok(RespValue::Integer(6)));
+        assert_eq!(get(b"dest", &mut db), Ok(b"`bc`ab".to_resp()));
+        set(vec!(b"x", b"\x0c"), &mut db).unwrap();
+        set(vec!(b"y", b"\x0a"), &mut db).unwrap();
+        set(vec!(b"z", b"\x09\xff"), &mut db).unwrap();
+        bitmap::bitop(args("ONE dest x y z"), &mut db).unwrap();
+        assert_eq!(get(b"dest", &mut db), Ok(b"\x07\xff".to_resp()));
+        bitmap::bitop(args("DIFF dest x y z"), &mut db).unwrap();
+        assert_eq!(get(b"dest", &mut db), Ok(b"\x04\x00".to_resp()));
+        bitmap::bitop(args("NOT dest x"), &mut db).unwrap();
+        assert_eq!(get(b"dest", &mut db), Ok(b"\xf3".to_resp()));
+        assert_eq!(bitmap::bitop(args("NOT dest x y"), &mut db).unwrap_err().to_string(),
+                   "ERR BITOP NOT must be called with a single source key.");
+        assert_eq!(bitmap::bitop(args("OR dest nothing"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(get(b"dest", &mut db), Ok(RespValue::Null));
+
+        let ints = |values: &[i64]| {Ok(RespValue::Array(values.iter().map(|value| {value.to_resp()}).collect()))};
+        assert_eq!(bitmap::bitfield(args("f INCRBY i5 100 1 GET u4 0"), false, max_len, &mut db), ints(&[1, 0]));
+        for expected in [[1, 1], [2, 2], [3, 3], [0, 3]] {
+            let reply = bitmap::bitfield(args("f INCRBY u2 200 1 OVERFLOW SAT INCRBY u2 202 1"), false, max_len, &mut db);
+            assert_eq!(reply, ints(&expected));
+        }
+        assert_eq!(bitmap::bitfield(args("f OVERFLOW FAIL INCRBY u2 202 1"), false, max_len, &mut db),
+                   Ok(RespValue::Array(vec!(RespValue::Null))));
+        assert_eq!(bitmap::bitfield(args("f SET i8 #1 -128 INCRBY i8 #1 -1 GET u8 8"), false, max_len, &mut db), ints(&[0, 127, 127]));
+        // an unsigned SET sees a negative value as a huge one
+        assert_eq!(bitmap::bitfield(args("f OVERFLOW SAT SET u8 0 -1 GET u8 0 GET i16 0"), false, max_len, &mut db), ints(&[0, 255, -129]));
+        assert_eq!(bitmap::bitfield(args("f GET u64 0"), true, max_len, &mut db).unwrap_err().to_string(),
+                   "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.");
+        assert_eq!(bitmap::bitfield(args("f SET u8 0 1"), true, max_len, &mut db).unwrap_err().to_string(),
+                   "ERR BITFIELD_RO only supports the GET subcommand");
+        assert_eq!(bitmap::bitfield(args("nothing GET u8 0"), true, max_len, &mut db), ints(&[0]));
+        assert_eq!(key_type(b"nothing", &mut db), Ok(RespValue::SimpleStr("none".into())));
+    }
+
+    #[test]
+    fn hyperloglog_commands(){
+        let mut db = Keyspace::new();
+        let count = |keys: &str, db: &mut Keyspace| {hyperloglog::pfcount(args(keys), db).unwrap()};
+        assert_eq!(hyperloglog::pfadd(args("h"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(count("h", &mut db), RespValue::Integer(0));
+        assert_eq!(hyperloglog::pfdebug(args("DECODE h"), &mut db), Ok(RespValue::SimpleStr("Z:16384".into())));
+        assert_eq!(hyperloglog::pfadd(args("h a b c d e f g"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(hyperloglog::pfadd(args("h a b c"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(count("h", &mut db), RespValue::Integer(7));
+        assert_eq!(hyperloglog::pfdebug(args("ENCODING h"), &mut db), Ok(RespValue::SimpleStr("sparse".into())));
+        // the stored bytes are the redis layout, header first
+        assert!(matches!(get(b"h", &mut db), Ok(RespValue::BulkStr(value)) if value.starts_with(b"HYLL\x01")));
+
+        for (key, range) in [("x", 0..500), ("y", 250..6000)] {
+            let elements = range.map(|n| {format!("e{n}")}).collect::<Vec<_>>();
+            let mut params = vec!(key.as_bytes());
+            params.extend(elements.iter().map(|element| {element.as_bytes()}));
+            hyperloglog::pfadd(params, &mut db).unwrap();
+        }
+        assert_eq!(hyperloglog::pfdebug(args("ENCODING y"), &mut db), Ok(RespValue::SimpleStr("dense".into())));
+        let sparse_x = count("x", &mut db);
+        assert_eq!(hyperloglog::pfdebug(args("TODENSE x"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(hyperloglog::pfdebug(args("TODENSE x"), &mut db), Ok(RespValue::Integer(0)));
+        hyperloglog::pfadd(args("x e0"), &mut db).unwrap();
+        assert_eq!(count("x", &mut db), sparse_x);
+        let union = match count("x y missing", &mut db) {
+            RespValue::Integer(union) => union,
+            reply => panic!("{reply:?}")
+        };
+        assert!((5900..6100).contains(&union), "{union}");
+        assert_eq!(hyperloglog::pfmerge(args("u x y"), &mut db), Ok(RespValue::ok()));
+        assert_eq!(count("u", &mut db), RespValue::Integer(union));
+        let registers = match hyperloglog::pfdebug(args("GETREG u"), &mut db) {
+            Ok(RespValue::Array(registers)) => registers,
+            reply => panic!("{reply:?}")
+        };
+        assert_eq!(registers.len(), 16384);
+
+        /* promotion happens as soon as an update would take the whole value,
+         * header included, past hll-sparse-max-bytes (3000). one update grows
+         * the sparse form by 3 bytes at most, so the last sparse value sits
+         * right under the limit
+         * */
+        let (mut n, mut last_sparse) = (0, 0);
+        loop {
+            hyperloglog::pfadd(vec!(b"grow", format!("g{n}").as_bytes()), &mut db).unwrap();
+            let Ok(RespValue::BulkStr(value)) = get(b"grow", &mut db) else {panic!("not a string")};
+            if value[4] == 0 {break;}
+            last_sparse = value.len();
+            n += 1;
+        }
+        assert!((2998..=3000).contains(&last_sparse), "{last_sparse} bytes when promoted after {n} elements");
+
+        set(args("s plain"), &mut db).unwrap();
+        assert_eq!(hyperloglog::pfadd(args("s a"), &mut db).unwrap_err().to_string(),
+                   "WRONGTYPE Key is not a valid HyperLogLog string value.");
+        assert_eq!(hyperloglog::pfdebug(args("DECODE u"), &mut db).unwrap_err().to_string(),
+                   "ERR HLL encoding is not sparse");
+        // a sparse body that does not cover every register
+        let mut corrupt = b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80".to_vec();
+        corrupt.extend(b"\x7f\xfe");
+        set(vec!(b"c", &corrupt), &mut db).unwrap();
+        assert_eq!(hyperloglog::pfcount(args("c"), &mut db).unwrap_err().to_string(),
+                   "INVALIDOBJ Corrupted HLL object detected");
+    }
+
+    #[test]
+    fn geo_commands(){
+        let mut db = Keyspace::new();
+        let proto = ProtocolVersion::Resp2;
+        assert_eq!(geo::geoadd(args("Sicily 13.361389 38.115556 Palermo 15.087269 37.502669 Catania"), &mut db),
+                   Ok(RespValue::Integer(2)));
+        // the scores and every reply below are what redis gives for the same points
+        assert_eq!(zset::zscore(args("Sicily Palermo"), &mut db), Ok(RespValue::Double(3479099956230698.0)));
+        assert_eq!(geo::geodist(args("Sicily Palermo Catania"), &mut db), Ok(b"166274.1516".to_resp()));
+        assert_eq!(geo::geodist(args("Sicily Palermo Catania km"), &mut db), Ok(b"166.2742".to_resp()));
+        assert_eq!(geo::geodist(args("Sicily Palermo Catania MI"), &mut db), Ok(b"103.3182".to_resp()));
+        assert_eq!(geo::geodist(args("Sicily Palermo Nowhere"), &mut db), Ok(RespValue::Null));
+        assert_eq!(geo::geopos(args("Sicily Palermo Nowhere"), proto, &mut db), Ok(RespValue::Array(vec!(
+            bulks(&["13.36138933897018433", "38.11555639549629859"]),
+            RespValue::NullArray
+        ))));
+        assert_eq!(geo::geohash(args("Sicily Palermo Catania"), &mut db), Ok(bulks(&["sqc8b49rny0", "sqdtr74hyu0"])));
+
+        geo::geoadd(args("Sicily 12.758489 38.788135 edge1 17.241510 38.788135 edge2"), &mut db).unwrap();
+        assert_eq!(geo::geosearch(args("Sicily FROMLONLAT 15 37 BYRADIUS 200 km ASC"), proto, &mut db),
+                   Ok(bulks(&["Catania", "Palermo"])));
+        let reply = geo::geosearch(args("Sicily FROMLONLAT 15 37 BYBOX 400 400 km ASC WITHCOORD WITHDIST"), proto, &mut db);
+        let item = |member: &str, dist: &str, lon: &str, lat: &str| {
+            RespValue::Array(vec!(member.to_resp(), dist.to_resp(), bulks(&[lon, lat])))
+        };
+        assert_eq!(reply, Ok(RespValue::Array(vec!(
+            item("Catania", "56.4413", "15.08726745843887329", "37.50266842333162032"),
+            item("Palermo", "190.4424", "13.36138933897018433", "38.11555639549629859"),
+            item("edge2", "279.7403", "17.24151045083999634", "38.78813451624225195"),
+            item("edge1", "279.7405", "12.7584877610206604", "38.78813451624225195")
+        ))));
+        assert_eq!(geo::geosearch(args("Sicily FROMMEMBER Palermo BYRADIUS 200 km DESC COUNT 1 WITHHASH"), proto, &mut db),
+                   Ok(RespValue::Array(vec!(RespValue::Array(vec!("Catania".to_resp(), 3479447370796909i64.to_resp()))))));
+        let any = geo::geosearch(args("Sicily FROMLONLAT 15 37 BYRADIUS 500 km COUNT 2 ANY"), proto, &mut db);
+        assert!(matches!(any, Ok(RespValue::Array(ref found)) if found.len() == 2), "{any:?}");
+
+        assert_eq!(geo::geosearchstore(args("near Sicily FROMLONLAT 15 37 BYRADIUS 100 km STOREDIST"), &mut db),
+                   Ok(RespValue::Integer(1)));
+        // STOREDIST keeps the distance in the unit of the search
+        assert!(matches!(zset::zscore(args("near Catania"), &mut db), Ok(RespValue::Double(dist)) if format!("{dist:.4}") == "56.4413"));
+        assert_eq!(geo::geosearchstore(args("near Sicily FROMLONLAT 0 0 BYRADIUS 1 m"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(key_type(b"near", &mut db), Ok(RespValue::SimpleStr("none".into())));
+
+        assert_eq!(geo::geoadd(args("Sicily 200 100 bad"), &mut db).unwrap_err().to_string(),
+                   "ERR invalid longitude,latitude pair 200.000000,100.000000");
+        assert_eq!(geo::geoadd(args("Sicily NX XX 1 1 m"), &mut db), Err(RedisError::Syntax));
+        // only NX, XX and CH are options, anything else starts the points
+        assert_eq!(geo::geoadd(args("Sicily GT 1 2 m"), &mut db), Err(RedisError::Syntax));
+        assert_eq!(geo::geoadd(args("Sicily CH GT 1 2 m"), &mut db), Err(RedisError::Syntax));
+        assert_eq!(geo::geosearch(args("Sicily BYRADIUS 1 km"), proto, &mut db).unwrap_err().to_string(),
+                   "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch");
+        assert_eq!(geo::geosearch(args("Sicily FROMLONLAT 15 37 BYRADIUS 1 km ANY"), proto, &mut db).unwrap_err().to_string(),
+                   "ERR the ANY argument requires COUNT argument");
+        assert_eq!(geo::geosearch(args("Sicily FROMMEMBER Nowhere BYRADIUS 1 km"), proto, &mut db).unwrap_err().to_string(),
+                   "ERR could not decode requested zset member");
+        assert_eq!(geo::geosearch(args("missing FROMMEMBER Palermo BYRADIUS 1 km"), proto, &mut db).unwrap_err().to_string(),
+                   "ERR could not decode requested zset member");
+        assert_eq!(geo::geosearchstore(args("dst missing FROMMEMBER Palermo BYRADIUS 1 km"), &mut db).unwrap_err().to_string(),
+                   "ERR could not decode requested zset member");
+        assert_eq!(geo::geosearch(args("missing FROMLONLAT 15 37 BYRADIUS 1 km"), proto, &mut db), Ok(RespValue::Array(vec!())));
+        // an origin or a shape given twice is a syntax error, even of the same kind
+        for line in [
+            "Sicily FROMLONLAT 15 37 FROMLONLAT 13 38 BYRADIUS 1 km",
+            "Sicily FROMMEMBER Palermo FROMMEMBER Catania BYRADIUS 1 km",
+            "Sicily FROMLONLAT 15 37 BYRADIUS 1 km BYRADIUS 2 km",
+            "Sicily FROMLONLAT 15 37 BYBOX 1 1 km BYBOX 2 2 km"
+        ] {
+            assert_eq!(geo::geosearch(args(line), proto, &mut db), Err(RedisError::Syntax), "{line}");
+        }
+        assert_eq!(geo::geodist(args("Sicily Palermo Catania yd"), &mut db).unwrap_err().to_string(),
+                   "ERR unsupported unit provided. please use M, KM, FT, MI");
+    }
+
+    #[test]
+    fn generic_keyspace_commands(){
+        let mut db = Keyspace::new();
+        set(args("a 1"), &mut db).unwrap();
+        set(args("b 2"), &mut db).unwrap();
+        list::push(args("l x y z"), End::Right, false, &mut db).unwrap();
+        assert_eq!(exists(args("a a missing l"), &mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(touch(args("a missing"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(dbsize(&mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(sorted_members(keys(b"*", &mut db)), ["a", "b", "l"]);
+        assert_eq!(sorted_members(keys(b"[ab]", &mut db)), ["a", "b"]);
+        assert!(matches!(randomkey(&mut db), Ok(RespValue::BulkStr(_))));
+
+        // the ttl travels with the renamed entry
+        expire("expire", args("a 100"), TimeUnit::Seconds, Deadline::Relative, &mut db).unwrap();
+        assert_eq!(rename(args("a c"), false, &mut db), Ok(RespValue::ok()));
+        assert!(matches!(ttl(b"c", TimeUnit::Seconds, &mut db), Ok(RespValue::Integer(secs)) if secs > 0));
+        assert_eq!(rename(args("a c"), false, &mut db), Err(RedisError::NoSuchKey));
+        assert_eq!(rename(args("c b"), true, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(rename(args("c c"), true, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(rename(args("c b"), false, &mut db), Ok(RespValue::ok()));
+        assert_eq!(get(b"b", &mut db), Ok(b"1".to_resp()));
+
+        assert_eq!(copy(args("l l2"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(copy(args("b l2"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(copy(args("b l2 DB 0 REPLACE"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(key_type(b"l2", &mut db), Ok(RespValue::SimpleStr("string".into())));
+        assert_eq!(list::llen(b"l", &mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(copy(args("b b"), &mut db).unwrap_err().to_string(), "ERR source and destination objects are the same");
+        assert_eq!(copy(args("b x DB 1"), &mut db).unwrap_err().to_string(), "ERR DB index is out of range");
+
+        assert_eq!(del(args("b missing b"), false, &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(del(args("l l2"), true, &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(randomkey(&mut db), Ok(RespValue::Null));
+
+        let big = (0..1000).map(|n| {n.to_string()}).collect::<Vec<_>>();
+        let mut params = vec!(&b"big"[..]);
+        params.extend(big.iter().map(|member| {member.as_bytes()}));
+        list::push(params, End::Right, false, &mut db).unwrap();
+        set(args("a 1"), &mut db).unwrap();
+        assert_eq!(flush(args("ASYNC"), &mut db), Ok(RespValue::ok()));
+        assert_eq!(dbsize(&mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(exists(args("big a"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(flush(args("LAZY"), &mut db), Err(RedisError::Syntax));
+    }
+
+    // next cursor and the items of a SCAN family reply
+    fn scan_reply(reply: Result<RespValue, RedisError>) -> (String, Vec<String>){
+        let RespValue::Array(mut parts) = reply.unwrap() else {panic!("not a scan reply")};
+        let items = sorted_members(Ok(parts.pop().unwrap()));
+        (String::from_resp(parts.pop().unwrap()).unwrap(), items)
+    }
+
+    #[test]
+    fn scan_cursors_survive_rehashing(){
+        // the table grows then shrinks again under the walk, the original keys must all come back
+        let mut dict = Dict::new();
+        dict.extend((0..200).map(|n| {(n, n)}));
+        let (mut cursor, mut seen, mut extra) = (0, HashSet::new(), 200);
+        loop {
+            cursor = dict.scan(cursor, |key, _| {seen.insert(*key);});
+            if cursor == 0 {break;}
+            match extra < 3000 {
+                true => dict.extend((extra..extra + 50).map(|n| {(n, n)})),
+                false => dict.retain(|key, _| {*key < 200})
+            }
+            extra += 50;
+        }
+        assert!((0..200).all(|n| {seen.contains(&n)}));
+
+        let mut db = Keyspace::new();
+        for n in 0..100 {
+            set(args(&format!("key:{n} v")), &mut db).unwrap();
+        }
+        hash::hset("hset", args("h f v"), &mut db).unwrap();
+        let (mut cursor, mut seen, mut calls) = (String::from("0"), HashSet::new(), 0);
+        loop {
+            let (next, items) = scan_reply(scan(args(&format!("{cursor} MATCH key:* COUNT 7")), &mut db));
+            assert!(items.iter().all(|key| {key.starts_with("key:")}));
+            seen.extend(items);
+            set(args(&format!("new:{calls} v")), &mut db).unwrap();
+            calls += 1;
+            if next == "0" {break;}
+            cursor = next;
+        }
+        assert!((0..100).all(|n| {seen.contains(&format!("key:{n}"))}));
+        assert!(calls > 1);
+        assert_eq!(scan_reply(scan(args("0 COUNT 1000 TYPE hash"), &mut db)), (String::from("0"), vec!(String::from("h"))));
+        assert_eq!(scan(args("0 TYPE"), &mut db), Err(RedisError::Syntax));
+        assert_eq!(scan(args("x"), &mut db).unwrap_err().to_string(), "ERR invalid cursor");
+
+        // the per value dicts hand out cursors the same way
+        let fields = (0..300).map(|n| {format!("f{n} {n}")}).collect::<Vec<_>>().join(" ");
+        hash::hset("hset", args(&format!("big {fields}")), &mut db).unwrap();
+        let members = (0..300).map(|n| {n.to_string()}).collect::<Vec<_>>().join(" ");
+        set::sadd(args(&format!("s {members}")), &mut db).unwrap();
+        zset::zadd(args("z 1.5 a 2 b"), &mut db).unwrap();
+        for (key, scan_fn, expected) in [
+            ("big", hash::hscan as fn(Vec<&[u8]>, &mut Keyspace) -> _, 600),
+            ("s", set::sscan, 300)
+        ] {
+            let (mut cursor, mut items) = (String::from("0"), vec!());
+            loop {
+                let (next, page) = scan_reply(scan_fn(args(&format!("{key} {cursor}")), &mut db));
+                items.extend(page);
+                if next == "0" {break;}
+                cursor = next;
+            }
+            items.sort();
+            items.dedup();
+            assert_eq!(items.len(), expected);
+        }
+        assert_eq!(scan_reply(zset::zscan(args("z 0"), &mut db)), (String::from("0"), vec!("1.5", "2", "a", "b").into_iter().map(String::from).collect()));
+    }
+
+    #[test]
+    fn hash_commands_and_field_ttl(){
+        let mut db = Keyspace::new();
+        assert_eq!(hash::hset("hset", args("h a 1 b 2"), &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(hash::hset("hmset", args("h a 3 c x"), &mut db), Ok(RespValue::ok()));
+        assert_eq!(hash::hset("hset", args("h a"), &mut db), Err(RedisError::wrong_arity("hset")));
+        assert_eq!(hash::hsetnx(args("h a 9"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(hash::hmget(args("h a nope"), &mut db), Ok(vec!(Some("3"), None).to_resp()));
+        assert_eq!(hash::hstrlen(args("h c"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(hash::hincrby(args("h a 4"), &mut db), Ok(RespValue::Integer(7)));
+        assert_eq!(hash::hincrby(args("h c 1"), &mut db).unwrap_err().to_string(), "ERR hash value is not an integer");
+        assert_eq!(reply_of(hash::hincrbyfloat(args("h f 1.5"), &mut db)), Ok(b"1.5".to_resp()));
+        hash::hset("hset", args("h p 0.1"), &mut db).unwrap();
+        assert_eq!(reply_of(hash::hincrbyfloat(args("h p 0.2"), &mut db)), Ok(b"0.3".to_resp()));
+        assert_eq!(hash::hmget(args("h p"), &mut db), Ok(vec!(Some("0.3")).to_resp()));
+        hash::hdel(args("h p"), &mut db).unwrap();
+        assert_eq!(hash::hdel(args("h f c nope"), &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(hash::hgetall(b"h", HashPart::Both, &mut db).map(|reply| {
+            let RespValue::Map(mut pairs) = reply else {panic!()};
+            pairs.sort_by_key(|pair| {format!("{pair:?}")});
+            pairs
+        }), Ok(vec!((b"a".to_resp(), b"7".to_resp()), (b"b".to_resp(), b"2".to_resp()))));
+        assert_eq!(hash::hrandfield(args("h -5"), &mut db).map(|reply| {
+            let RespValue::Array(fields) = reply else {panic!()};
+            fields.len()
+        }), Ok(5));
+        assert_eq!(hash::hscan(args("h 0 MATCH a NOVALUES"), &mut db), Ok(("0", bulks(&["a"])).to_resp()));
+
+        // per field ttl, replies come back one per requested field
+        assert_eq!(reply_of(hash::hexpire("hexpire", args("h 100 FIELDS 2 a nope"), TimeUnit::Seconds, Deadline::Relative, &mut db)),
+                   Ok(vec!(1, -2).to_resp()));
+        assert_eq!(reply_of(hash::hexpire("hexpire", args("h 50 GT FIELDS 1 a"), TimeUnit::Seconds, Deadline::Relative, &mut db)),
+                   Ok(vec!(0).to_resp()));
+        assert_eq!(hash::httl(args("h FIELDS 2 a b"), TimeUnit::Seconds, &mut db), Ok(vec!(100, -1).to_resp()));
+        // increments update the field in place and keep its ttl
+        assert_eq!(hash::hincrby(args("h a 1"), &mut db), Ok(RespValue::Integer(8)));
+        assert_eq!(reply_of(hash::hincrbyfloat(args("h a 0.5"), &mut db)), Ok(b"8.5".to_resp()));
+        assert_eq!(hash::httl(args("h FIELDS 1 a"), TimeUnit::Seconds, &mut db), Ok(vec!(100).to_resp()));
+        assert_eq!(hash::hpersist(args("h FIELDS 2 a b"), &mut db), Ok(vec!(1, -1).to_resp()));
+        assert_eq!(reply_of(hash::hexpire("hexpire", args("h 1 FIELDS 2 a"), TimeUnit::Seconds, Deadline::Relative, &mut db))
+                   .unwrap_err().to_string(), "ERR The `numfields` parameter must match the number of arguments");
+        assert_eq!(reply_of(hash::hexpire("hexpire", args("h 1 a"), TimeUnit::Seconds, Deadline::Relative, &mut db))
+                   .unwrap_err().to_string(), "ERR Mandatory argument FIELDS is missing or not at the right position");
+        // a deadline in the past deletes the field, then the emptied key
+        assert_eq!(reply_of(hash::hexpire("hpexpireat", args("h 1 FIELDS 2 a b"), TimeUnit::Milliseconds, Deadline::Absolute, &mut db)),
+                   Ok(vec!(2, 2).to_resp()));
+        assert_eq!(key_type(b"h", &mut db), Ok(RespValue::SimpleStr("none".into())));
+
+        hash::hset("hset", args("g x 1"), &mut db).unwrap();
+        hash::hexpire("hpexpire", args("g 1 FIELDS 1 x"), TimeUnit::Milliseconds, Deadline::Relative, &mut db).unwrap();
+        thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(hash::hlen(b"g", &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(key_type(b"g", &mut db), Ok(RespValue::SimpleStr("none".into())));
+
+        assert!(string_match(b"h[^e]llo*", b"hallo world", false));
+        assert!(string_match(b"H?LLO", b"hello", true));
+        assert!(!string_match(b"a*a*a*a*a*a*a*a*b", &[b'a'; 64], false));
+    }
+
+
+    // member order is unspecified, compare set replies sorted
+    // the reply of a write handler, leaving out what it hands to replicas
+    fn reply_of(served: Result<(RespValue, Propagate), RedisError>) -> Result<RespValue, RedisError>{
+        served.map(|(reply, _)| {reply})
+    }
+
+    fn sorted_members(reply: Result<RespValue, RedisError>) -> Vec<String>{
+        let (RespValue::Set(items) | RespValue::Array(items)) = reply.unwrap() else {panic!("not a collection")};
+        let mut members = items.into_iter().map(|item| {String::from_resp(item).unwrap()}).collect::<Vec<_>>();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn set_commands_and_algebra(){
+        let mut db = Keyspace::new();
+        assert_eq!(set::sadd(args("a 1 2 3 3"), &mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(set::sadd(args("b 2 3 4"), &mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(set::smismember(args("a 1 4"), &mut db), Ok(vec!(1, 0).to_resp()));
+        assert_eq!(sorted_members(set::set_algebra(args("a b"), SetOp::Inter, &mut db)), ["2", "3"]);
+        assert_eq!(sorted_members(set::set_algebra(args("a b nope"), SetOp::Union, &mut db)), ["1", "2", "3", "4"]);
+        assert_eq!(sorted_members(set::set_algebra(args("a b"), SetOp::Diff, &mut db)), ["1"]);
+        assert_eq!(set::set_algebra(args("a nope"), SetOp::Inter, &mut db), Ok(RespValue::Set(vec!())));
+        assert_eq!(set::sintercard(args("2 a b LIMIT 1"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(set::sintercard(args("2 a b LIMIT 0"), &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(set::sintercard(args("3 a b nope"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(set::sintercard(args("3 a b"), &mut db).unwrap_err().to_string(),
+                   "ERR Number of keys can't be greater than number of args");
+        assert_eq!(set::sintercard(args("2 a b LIMIT -1"), &mut db).unwrap_err().to_string(), "ERR LIMIT can't be negative");
+
+        // the STORE variants replace whatever lived under the destination
+        set(args("dst v"), &mut db).unwrap();
+        assert_eq!(set::set_algebra_store(args("dst a b"), SetOp::Union, &mut db), Ok(RespValue::Integer(4)));
+        assert_eq!(set::scard(b"dst", &mut db), Ok(RespValue::Integer(4)));
+        assert_eq!(set::set_algebra_store(args("dst a nope"), SetOp::Inter, &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(key_type(b"dst", &mut db), Ok(RespValue::SimpleStr("none".into())));
+
+        assert_eq!(set::smove(args("a b 1"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(set::smove(args("a b 1"), &mut db), Ok(RespValue::Integer(0)));
+        // a move onto the same set leaves the key and its ttl alone
+        set::sadd(args("one x"), &mut db).unwrap();
+        expire("expire", args("one 100"), TimeUnit::Seconds, Deadline::Relative, &mut db).unwrap();
+        assert_eq!(set::smove(args("one one x"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(set::smove(args("one one y"), &mut db), Ok(RespValue::Integer(0)));
+        assert!(matches!(ttl(b"one", TimeUnit::Seconds, &mut db), Ok(RespValue::Integer(secs)) if secs > 0));
+        assert_eq!(sorted_members(set::srandmember(args("b -6"), &mut db)).len(), 6);
+        assert_eq!(sorted_members(set::srandmember(args("b 10"), &mut db)), ["1", "2", "3", "4"]);
+        assert_eq!(reply_of(set::spop(args("b -1"), &mut db)), Err(RedisError::NotPositive));
+        assert_eq!(sorted_members(reply_of(set::spop(args("b 3"), &mut db))).len(), 3);
+        assert_eq!(set::scard(b"b", &mut db), Ok(RespValue::Integer(1)));
+        set::spop(args("b"), &mut db).unwrap();
+        assert_eq!(key_type(b"b", &mut db), Ok(RespValue::SimpleStr("none".into())));
+        assert_eq!(reply_of(set::spop(args("b"), &mut db)), Ok(RespValue::Null));
+        // few members popped one by one, most of them by keeping a sample, all of them at once
+        let members = (0..100).map(|n| {n.to_string()}).collect::<Vec<_>>().join(" ");
+        set::sadd(args(&format!("big {members}")), &mut db).unwrap();
+        let mut popped = sorted_members(reply_of(set::spop(args("big 10"), &mut db)));
+        popped.extend(sorted_members(reply_of(set::spop(args("big 80"), &mut db))));
+        assert!(matches!(reply_of(set::spop(args("big"), &mut db)), Ok(RespValue::BulkStr(_))));
+        popped.extend(sorted_members(reply_of(set::spop(args("big 100"), &mut db))));
+        popped.sort();
+        popped.dedup();
+        assert_eq!(popped.len(), 99);
+        assert_eq!(key_type(b"big", &mut db), Ok(RespValue::SimpleStr("none".into())));
+
+        assert_eq!(set::sscan(args("a 0 MATCH [23]"), &mut db).map(|reply| {
+            let RespValue::Array(mut parts) = reply else {panic!()};
+            sorted_members(Ok(parts.pop().unwrap()))
+        }), Ok(vec!(String::from("2"), String::from("3"))));
+        set(args("str v"), &mut db).unwrap();
+        assert_eq!(set::set_algebra(args("a str"), SetOp::Union, &mut db), Err(RedisError::WrongType));
+    }
+
+
+    #[test]
+    fn sorted_set_keeps_order_and_ranks(){
+        // random inserts, score moves and removals checked against a sorted vec
+        let mut zset = SortedSet::new();
+        let mut model: Vec<(i64, Vec<u8>)> = vec!();
+        for step in 0..2000u32 {
+            let member = format!("m{}", rand::random::<u8>() % 64).into_bytes();
+            let score = (rand::random::<u8>() % 16) as i64;
+            model.retain(|(_, existing)| {*existing != member});
+            if step % 5 == 0 {
+                zset.remove(&member);
+            } else {
+                zset.insert(&member, score as f64);
+                model.push((score, member));
+            }
+        }
+        model.sort();
+        let listed = zset.iter().map(|(member, score)| {(score as i64, member.to_vec())}).collect::<Vec<_>>();
+        assert_eq!(listed, model);
+        for (rank, (_, member)) in model.iter().enumerate() {
+            assert_eq!(zset.rank(member, false), Some(rank));
+            assert_eq!(zset.rank(member, true), Some(model.len() - 1 - rank));
+        }
+        let middle = zset.range_by_rank(1, 3, true).map(|(member, _)| {member.to_vec()}).collect::<Vec<_>>();
+        let expected = model.iter().rev().skip(1).take(3).map(|(_, member)| {member.clone()}).collect::<Vec<_>>();
+        assert_eq!(middle, expected);
+    }
+
+    #[test]
+    fn sorted_set_commands(){
+        let mut db = Keyspace::new();
+        let resp2 = ProtocolVersion::Resp2;
+        assert_eq!(zset::zadd(args("z 1 a 2 b 3 c"), &mut db), Ok(RespValue::Integer(3)));
+        assert_eq!(zset::zadd(args("z XX CH 5 a 9 new"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(zset::zadd(args("z GT 1 a"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(zset::zadd(args("z LT INCR -1 b"), &mut db), Ok(RespValue::Double(1.0)));
+        assert_eq!(zset::zadd(args("z NX INCR 1 b"), &mut db), Ok(RespValue::Null));
+        assert_eq!(zset::zadd(args("z NX XX 1 b"), &mut db).unwrap_err().to_string(),
+                   "ERR XX and NX options at the same time are not compatible");
+        assert_eq!(zset::zadd(args("z GT LT 1 b"), &mut db).unwrap_err().to_string(),
+                   "ERR GT, LT, and/or NX options at the same time are not compatible");
+        assert_eq!(zset::zadd(args("z 1 b x"), &mut db), Err(RedisError::Syntax));
+        assert_eq!(zset::zadd(args("z nan b"), &mut db), Err(RedisError::NotFloat));
+        // b 1, c 3, a 5
+        assert_eq!(zset::zrange("zrange", args("z 0 -1 WITHSCORES"), resp2, &mut db),
+                   Ok(vec!(b"b".to_resp(), 1.0.to_resp(), b"c".to_resp(), 3.0.to_resp(), b"a".to_resp(), 5.0.to_resp()).to_resp()));
+        assert_eq!(zset::zrange("zrange", args("z 0 0 WITHSCORES"), ProtocolVersion::Resp3, &mut db),
+                   Ok(vec!((b"b".to_resp(), 1.0)).to_resp()));
+        assert_eq!(zset::zrange("zrange", args("z (5 1 BYSCORE REV"), resp2, &mut db), Ok(bulks(&["c", "b"])));
+        assert_eq!(zset::zrange("zrange", args("z -inf +inf BYSCORE LIMIT 1 1"), resp2, &mut db), Ok(bulks(&["c"])));
+        assert_eq!(zset::zrange("zrevrangebyscore", args("z +inf 2"), resp2, &mut db), Ok(bulks(&["a", "c"])));
+        assert_eq!(zset::zrange("zrange", args("z 0 -1 LIMIT 0 1"), resp2, &mut db).unwrap_err().to_string(),
+                   "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX");
+        assert_eq!(zset::zrange("zrange", args("z x 1 BYSCORE"), resp2, &mut db).unwrap_err().to_string(),
+                   "ERR min or max is not a float");
+        assert_eq!(zset::zcount(args("z (1 5"), &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(zset::zrank(args("z a WITHSCORE"), false, &mut db), Ok((2, 5.0).to_resp()));
+        assert_eq!(zset::zrank(args("z a"), true, &mut db), Ok(RespValue::Integer(0)));
+
+        zset::zadd(args("lex 0 a 0 b 0 c 0 d"), &mut db).unwrap();
+        assert_eq!(zset::zrange("zrange", args("lex [b (d BYLEX"), resp2, &mut db), Ok(bulks(&["b", "c"])));
+        assert_eq!(zset::zrange("zrange", args("lex + (b BYLEX REV LIMIT 0 2"), resp2, &mut db), Ok(bulks(&["d", "c"])));
+        assert_eq!(zset::zlexcount(args("lex - +"), &mut db), Ok(RespValue::Integer(4)));
+        assert_eq!(zset::zrangestore(args("dst lex [c + BYLEX"), &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(zset::zrangestore(args("dst lex [x + BYLEX"), &mut db), Ok(RespValue::Integer(0)));
+        assert_eq!(key_type(b"dst", &mut db), Ok(RespValue::SimpleStr("none".into())));
+
+        // weighted algebra, plain sets count with score 1
+        set::sadd(args("s a b"), &mut db).unwrap();
+        assert_eq!(zset::zcombine("zunion", args("2 z s WEIGHTS 2 10 WITHSCORES"), ZSetOp::Union, resp2, &mut db),
+                   Ok(vec!(b"c".to_resp(), 6.0.to_resp(), b"b".to_resp(), 12.0.to_resp(), b"a".to_resp(), 20.0.to_resp()).to_resp()));
+        assert_eq!(zset::zcombine_store("zinterstore", args("out 2 z s AGGREGATE MAX"), ZSetOp::Inter, &mut db),
+                   Ok(RespValue::Integer(2)));
+        assert_eq!(zset::zrange("zrange", args("out 0 -1 WITHSCORES"), resp2, &mut db),
+                   Ok(vec!(b"b".to_resp(), 1.0.to_resp(), b"a".to_resp(), 5.0.to_resp()).to_resp()));
+        assert_eq!(zset::zcombine("zdiff", args("2 z s"), ZSetOp::Diff, resp2, &mut db), Ok(bulks(&["c"])));
+        assert_eq!(zset::zcombine("zunion", args("0 z"), ZSetOp::Union, resp2, &mut db).unwrap_err().to_string(),
+                   "ERR at least 1 input key is needed for 'zunion' command");
+        assert_eq!(zset::zintercard(args("2 z s LIMIT 1"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(zset::zintercard(args("2 z s LIMIT 0"), &mut db), Ok(RespValue::Integer(2)));
+        assert_eq!(zset::zintercard(args("3 z s nope"), &mut db), Ok(RespValue::Integer(0)));
+
+        assert_eq!(zset::zpop(args("z"), false, resp2, &mut db), Ok(("b", 1.0).to_resp()));
+        assert_eq!(zset::zpop(args("z 5"), true, ProtocolVersion::Resp3, &mut db),
+                   Ok(vec!((b"a".to_resp(), 5.0), (b"c".to_resp(), 3.0)).to_resp()));
+        assert_eq!(key_type(b"z", &mut db), Ok(RespValue::SimpleStr("none".into())));
+        assert_eq!(zset::zmpop(args("2 z lex MAX COUNT 2"), &mut db),
+                   Ok(("lex", vec!((b"d".to_resp(), 0.0), (b"c".to_resp(), 0.0))).to_resp()));
+        assert_eq!(zset::zmpop(args("1 z MIN"), &mut db), Ok(RespValue::NullArray));
+        set(args("str v"), &mut db).unwrap();
+        assert_eq!(zset::zcombine("zunion", args("2 lex str"), ZSetOp::Union, resp2, &mut db), Err(RedisError::WrongType));
+    }
+
+    #[test]
+    fn blocking_zset_pops(){
+        let addr = spawn_test_server();
+        let mut blocked = TcpStream::connect(addr).unwrap();
+        let expected = b"*3\r\n$1\r\nz\r\n$1\r\na\r\n$1\r\n2\r\n";
+        let handle = thread::spawn(move || {roundtrip(&mut blocked, b"BZPOPMAX none z 5\r\n", expected.len())});
+        thread::sleep(std::time::Duration::from_millis(100));
+        let mut writer = TcpStream::connect(addr).unwrap();
+        assert_eq!(roundtrip(&mut writer, b"ZADD z 1 b 2 a\r\n", 4), b":2\r\n");
+        assert_eq!(handle.join().unwrap(), expected);
+        assert_eq!(roundtrip(&mut writer, b"BZMPOP 0.05 1 z MIN COUNT 3\r\n", 29), b"*2\r\n$1\r\nz\r\n*1\r\n*2\r\n$1\r\nb\r\n$1\r\n1\r\n");
+        assert_eq!(roundtrip(&mut writer, b"BZMPOP 0.05 1 z MIN\r\n", 5), b"*-1\r\n");
+    }
+
+
+    fn stream_ids(reply: Result<RespValue, RedisError>) -> Vec<String>{
+        let RespValue::Array(entries) = reply.unwrap() else {panic!("not an array")};
+        entries.into_iter().map(|entry| {
+            let RespValue::Array(mut parts) = entry else {panic!("not an entry")};
+            String::from_resp(parts.remove(0)).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn stream_ids_ranges_and_trimming(){
+        let mut db = Keyspace::new();
+        assert_eq!(stream::xadd(args("s 1-1 f v"), &mut db), Ok(b"1-1".to_resp()));
+        assert_eq!(stream::xadd(args("s 1-* f v"), &mut db), Ok(b"1-2".to_resp()));
+        assert_eq!(stream::xadd(args("s 5-* f v other x"), &mut db), Ok(b"5-0".to_resp()));
+        assert_eq!(stream::xadd(args("s 5-0 f v"), &mut db).unwrap_err().to_string(),
+                   "ERR The ID specified in XADD is equal or smaller than the target stream top item");
+        assert_eq!(stream::xadd(args("fresh 0-0 f v"), &mut db).unwrap_err().to_string(),
+                   "ERR The ID specified in XADD must be greater than 0-0");
+        assert_eq!(stream::xadd(args("s 9-x f v"), &mut db).unwrap_err().to_string(),
+                   "ERR Invalid stream ID specified as stream command argument");
+        assert_eq!(stream::xadd(args("s * f"), &mut db), Err(RedisError::wrong_arity("xadd")));
+        assert_eq!(stream::xadd(args("none NOMKSTREAM * f v"), &mut db), Ok(RespValue::Null));
+        assert_eq!(key_type(b"none", &mut db), Ok(RespValue::SimpleStr("none".into())));
+        let auto = stream::xadd(args("s * f v"), &mut db).unwrap();
+        assert!(String::from_resp(auto).unwrap().ends_with("-0"));
+
+        assert_eq!(stream_ids(stream::xrange(args("s - +"), false, &mut db)).len(), 4);
+        assert_eq!(stream_ids(stream::xrange(args("s (1-1 5"), false, &mut db)), ["1-2", "5-0"]);
+        assert_eq!(stream_ids(stream::xrange(args("s + - COUNT 2"), true, &mut db))[1], "5-0");
+        assert_eq!(stream::xrange(args("s - + COUNT -1"), false, &mut db), Ok(RespValue::Array(vec!())));
+        assert_eq!(stream::xrange(args("s 1-2 1-2"), false, &mut db),
+                   Ok(RespValue::Array(vec!(("1-2", bulks(&["f", "v"])).to_resp()))));
+        assert_eq!(stream::xrange(args("s 5 5"), false, &mut db),
+                   Ok(RespValue::Array(vec!(("5-0", bulks(&["f", "v", "other", "x"])).to_resp()))));
+        assert_eq!(stream::xdel(args("s 1-2 1-2 7-7"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(stream::xlen(b"s", &mut db), Ok(RespValue::Integer(3)));
+
+        // exact trimming goes entry by entry, ~ only drops whole blocks
+        for seq in 0..250 {
+            stream::xadd(vec!(b"big", format!("1-{}", seq + 1).as_bytes(), b"f", b"v"), &mut db).unwrap();
+        }
+        assert_eq!(stream::xtrim(args("big MAXLEN ~ 120"), &mut db), Ok(RespValue::Integer(100)));
+        assert_eq!(stream::xtrim(args("big MAXLEN = 120"), &mut db), Ok(RespValue::Integer(30)));
+        assert_eq!(stream_ids(stream::xrange(args("big - + COUNT 1"), false, &mut db)), ["1-131"]);
+        assert_eq!(stream::xtrim(args("big MINID 1-200"), &mut db), Ok(RespValue::Integer(69)));
+        assert_eq!(stream::xtrim(args("big MAXLEN 1 LIMIT 10"), &mut db).unwrap_err().to_string(),
+                   "ERR syntax error, LIMIT cannot be used without the special ~ option");
+        assert!(stream::xadd(args("big MAXLEN 2 * f v"), &mut db).is_ok());
+        assert_eq!(stream::xlen(b"big", &mut db), Ok(RespValue::Integer(2)));
+        // a stream emptied by deletion keeps its key and last id
+        stream::xtrim(args("big MAXLEN 0"), &mut db).unwrap();
+        assert_eq!(key_type(b"big", &mut db), Ok(RespValue::SimpleStr("stream".into())));
+        assert!(stream::xadd(args("big 1-5 f v"), &mut db).is_err());
+    }
+
+    #[test]
+    fn consumer_groups_track_pending_entries(){
+        let storage = RedisStorage::new();
+        {
+            let mut db = storage.lock();
+            assert!(stream::xgroup(args("CREATE s g $"), &mut db).unwrap_err().to_string().contains("requires the key to exist"));
+            assert_eq!(stream::xgroup(args("CREATE s g $ MKSTREAM"), &mut db), Ok(RespValue::ok()));
+            assert_eq!(stream::xgroup(args("CREATE s g 0"), &mut db), Err(RedisError::BusyGroup));
+            for id in ["1-1", "1-2", "1-3"] {
+                stream::xadd(vec!(b"s", id.as_bytes(), b"f", b"v"), &mut db).unwrap();
+            }
+        }
+        // the entries handed out for the single stream read
+        let read = |line: &str| -> Result<Vec<String>, RedisError> {
+            match stream::xreadgroup(args(line), ProtocolVersion::Resp2, 1, &storage)? {
+                RespValue::Array(mut streams) => {
+                    let RespValue::Array(mut pair) = streams.remove(0) else {panic!("not a pair")};
+                    Ok(stream_ids(Ok(pair.remove(1))))
+                },
+                _ => Ok(vec!())
+            }
+        };
+        assert_eq!(read("GROUP g alice COUNT 2 STREAMS s >"), Ok(vec!(String::from("1-1"), String::from("1-2"))));
+        assert_eq!(read("GROUP g bob STREAMS s >"), Ok(vec!(String::from("1-3"))));
+        assert_eq!(read("GROUP g bob STREAMS s >"), Ok(vec!()));
+        // history is the consumer's own pending entries
+        assert_eq!(read("GROUP g alice STREAMS s 0"), Ok(vec!(String::from("1-1"), String::from("1-2"))));
+        assert_eq!(read("GROUP nope alice STREAMS s >").unwrap_err().to_string(),
+                   "NOGROUP No such key 's' or consumer group 'nope' in XREADGROUP with GROUP option");
+
+        let mut db = storage.lock();
+        assert_eq!(stream::xack(args("s g 1-1 1-1 9-9"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(stream::xpending(args("s g"), &mut db), Ok(RespValue::Array(vec!(
+            2.to_resp(), "1-2".to_resp(), "1-3".to_resp(),
+            RespValue::Array(vec!(("alice", "1").to_resp(), ("bob", "1").to_resp()))
+        ))));
+        assert_eq!(stream::xclaim(args("s g bob 3600000 1-2"), &mut db), Ok(RespValue::Array(vec!())));
+        assert_eq!(stream_ids(stream::xclaim(args("s g bob 0 1-2"), &mut db)), ["1-2"]);
+        let RespValue::Array(pending) = stream::xpending(args("s g - + 10 bob"), &mut db).unwrap() else {panic!()};
+        let RespValue::Array(first) = &pending[0] else {panic!()};
+        assert_eq!((pending.len(), &first[3]), (2, &RespValue::Integer(2)));
+
+        // a deleted entry is dropped from the PEL instead of being claimed
+        stream::xdel(args("s 1-3"), &mut db).unwrap();
+        // not before its min-idle-time has passed though
+        assert_eq!(stream::xautoclaim(args("s g carol 3600000 - JUSTID"), &mut db),
+                   Ok(vec!("0-0".to_resp(), RespValue::Array(vec!()), RespValue::Array(vec!())).to_resp()));
+        assert_eq!(stream::xpending(args("s g"), &mut db).map(|reply| {
+            let RespValue::Array(summary) = reply else {panic!()};
+            summary[0].clone()
+        }), Ok(RespValue::Integer(2)));
+        let RespValue::Array(reply) = stream::xautoclaim(args("s g carol 0 - JUSTID"), &mut db).unwrap() else {panic!()};
+        assert_eq!(reply, vec!("0-0".to_resp(), RespValue::Array(vec!("1-2".to_resp())), RespValue::Array(vec!("1-3".to_resp()))));
+        let RespValue::Array(groups) = stream::xinfo(args("GROUPS s"), &mut db).unwrap() else {panic!()};
+        assert_eq!(groups, vec!(RespValue::Map(vec!(
+            ("name".to_resp(), "g".to_resp()), ("consumers".to_resp(), 3.to_resp()), ("pending".to_resp(), 1.to_resp()),
+            ("last-delivered-id".to_resp(), "1-3".to_resp()), ("entries-read".to_resp(), 3.to_resp()), ("lag".to_resp(), 0.to_resp())
+        ))));
+        assert_eq!(stream::xgroup(args("DELCONSUMER s g carol"), &mut db), Ok(RespValue::Integer(1)));
+        assert_eq!(stream::xpending(args("s g"), &mut db), Ok(RespValue::Array(vec!(
+            0.to_resp(), RespValue::Null, RespValue::Null, RespValue::NullArray
+        ))));
+        assert_eq!(stream::xinfo(args("CONSUMERS s nope"), &mut db).unwrap_err().to_string(),
+                   "NOGROUP No such consumer group 'nope' for key name 's'");
+    }
+
+    #[test]
+    fn xread_blocks_until_xadd(){
+        let addr = spawn_test_server();
+        let mut writer = TcpStream::connect(addr).unwrap();
+        assert_eq!(roundtrip(&mut writer, b"XADD s 1-1 a 1\r\n", 9), b"$3\r\n1-1\r\n");
+        let expected = b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n";
+        assert_eq!(roundtrip(&mut writer, b"XREAD COUNT 5 STREAMS s 0\r\n", expected.len()), expected);
+        let unbalanced = b"-ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.\r\n";
+        assert_eq!(roundtrip(&mut writer, b"XREAD STREAMS s t 0\r\n", unbalanced.len()), unbalanced);
+
+        let mut blocked = TcpStream::connect(addr).unwrap();
+        let expected = b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n2-1\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n";
+        let handle = thread::spawn(move || {roundtrip(&mut blocked, b"XREAD BLOCK 5000 STREAMS s $\r\n", expected.len())});
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(roundtrip(&mut writer, b"XADD s 2-1 b 2\r\n", 9), b"$3\r\n2-1\r\n");
+        assert_eq!(handle.join().unwrap(), expected);
+        assert_eq!(roundtrip(&mut writer, b"XREAD BLOCK 50 STREAMS s $\r\n", 5), b"*-1\r\n");
+    }
 }
@@ -1,5 +1,5 @@
-use std::collections::{HashMap, HashSet};
-use std::net::{TcpListener, SocketAddr};
+use std::collections::HashSet;
+use std::net::TcpListener;
 use std::thread;
 
 use std::sync::{mpsc, Arc, Mutex};
@@ -25,6 +25,8 @@ fn main() {
     };
 
     let tsafe_hash_map = persistence::RedisStorage::new();
+    // reclaim keys that expired but are never read again
+    persistence::spawn_active_expire(tsafe_hash_map.clone());
 
     // launch a dispatch thread to handle modification if the server is launched in master mode
     if let ServerType::Master = launch_config.server_type {
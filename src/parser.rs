@@ -6,9 +6,28 @@
  * */
 
 
+pub mod value;
+pub use value::{RespValue, ToResp, FromResp};
+
+
+// wire protocol negotiated per connection through HELLO, RESP2 unless told otherwise
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion{
+    #[default]
+    Resp2,
+    Resp3
+}
+
+
 pub enum SimpleRESPObject<'a>{
-    Integer(i32),
+    Integer(i64),
     Str(&'a str),
+    Error(&'a str),
+    // RESP3 only
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(&'a str)
 }
 
 
@@ -18,7 +37,17 @@ pub enum AtomicItem<'a>{
 }
 pub enum AggrRESPObject<'a>{
    BulkStr(&'a [u8]),
-   Array(Vec<AtomicItem<'a>>)
+   Array(Vec<AtomicItem<'a>>),
+   // RESP2 nil replies, $-1 and *-1
+   NullBulkStr,
+   NullArray,
+   // RESP3 only
+   BulkError(&'a [u8]),
+   // three bytes encoding hint, e.g. txt / mkd, followed by the payload
+   VerbatimStr(&'a str, &'a [u8]),
+   Map(Vec<(AtomicItem<'a>, AtomicItem<'a>)>),
+   Set(Vec<AtomicItem<'a>>),
+   Push(Vec<AtomicItem<'a>>)
 }
 
 
@@ -28,36 +57,46 @@ pub enum RESPObject<'a>{
 }
 
 impl<'a> RESPObject<'a>{
+    // flatten an array made only of strings, None for any other shape
     pub fn to_vec(self) -> Option<Vec<&'a [u8]>>{
         if let Self::Aggregate(AggrRESPObject::Array(items)) = self{
-           let unpacked = items.into_iter().map(|item| {item.as_bytes().unwrap()})
-                .collect::<Vec<_>>();
-           Some(unpacked)
+           items.iter().map(|item| {item.as_bytes()}).collect()
         }else {None}
     }
 }
 
+impl<'a> From<AtomicItem<'a>> for RESPObject<'a>{
+    fn from(item: AtomicItem<'a>) -> Self{
+        match item{
+            AtomicItem::SimpleItem(object) => Self::Simple(object),
+            AtomicItem::AggrItem(object) => Self::Aggregate(object)
+        }
+    }
+}
+
 
 impl<'a> SimpleRESPObject<'a>{
-    pub fn as_bytes(self) -> Option<&'a [u8]>{
+    pub fn as_bytes(&self) -> Option<&'a [u8]>{
        match self{
            Self::Str(s) => Some(s.as_bytes()),
+           Self::BigNumber(s) => Some(s.as_bytes()),
            _ => None
        } 
     }
 }
 
 impl<'a> AggrRESPObject<'a>{
-    pub fn as_bytes(self) -> Option<&'a [u8]>{
+    pub fn as_bytes(&self) -> Option<&'a [u8]>{
         match self{
-            Self::BulkStr(s) => Some(s),
+            Self::BulkStr(s) => Some(*s),
+            Self::VerbatimStr(_, s) => Some(*s),
             _ => None
         }
     }
 }
 
 impl<'a> AtomicItem<'a>{
-    pub fn as_bytes(self) -> Option<&'a [u8]>{
+    pub fn as_bytes(&self) -> Option<&'a [u8]>{
         match self{
             Self::SimpleItem(object) => object.as_bytes(),
             Self::AggrItem(object) => object.as_bytes()
@@ -70,151 +109,827 @@ impl<'a> AtomicItem<'a>{
 
 pub mod decrypt{
     use super::{RESPObject, AggrRESPObject, SimpleRESPObject, AtomicItem};
+    use crate::error::RedisError::{self, Protocol};
+    use bytes::{Bytes, BytesMut};
     use std::str;
 
-    fn extract_simple_object(content: &[u8]) -> Result<SimpleRESPObject, &'static str>{
-       match content[0] {
+    /* every extractor below receives the content starting at the type byte and
+     * returns the decoded object along with the number of bytes it consumed,
+     * so aggregates can walk their elements back to back
+     * */
+
+    // split off <type><line>\r\n, yielding the line and the consumed length
+    fn read_line(content: &[u8]) -> Result<(&[u8], usize), RedisError>{
+        let line_end = find_crlf(content, 1).ok_or(Protocol("line is not terminated by CRLF"))?;
+        Ok((&content[1..line_end], line_end + 2))
+    }
+
+    fn extract_simple_object(content: &[u8]) -> Result<(SimpleRESPObject<'_>, usize), RedisError>{
+       let (line, consumed) = read_line(content)?;
+       let object = match content[0] {
            b'+' => {
-               let str_content = str::from_utf8(&content[1..]).unwrap()
-                                    .strip_suffix("\r\n").unwrap();
-               Ok(SimpleRESPObject::Str(str_content))
+               let str_content = str::from_utf8(line).map_err(|_| {Protocol("simple string is not utf-8")})?;
+               SimpleRESPObject::Str(str_content)
+           },
+           b'-' => {
+               let str_content = str::from_utf8(line).map_err(|_| {Protocol("error message is not utf-8")})?;
+               SimpleRESPObject::Error(str_content)
+           },
+           b':' => {
+               let num = str::from_utf8(line).ok()
+                            .and_then(|s| {s.parse::<i64>().ok()})
+                            .ok_or(Protocol("malformed integer"))?;
+               SimpleRESPObject::Integer(num)
            },
-           _ => {todo!()}
-       }
+           b'_' => {
+               if !line.is_empty() {return Err(Protocol("malformed null"));}
+               SimpleRESPObject::Null
+           },
+           b'#' => {
+               match line {
+                   b"t" => SimpleRESPObject::Boolean(true),
+                   b"f" => SimpleRESPObject::Boolean(false),
+                   _ => return Err(Protocol("malformed boolean"))
+               }
+           },
+           b',' => {
+               let double = str::from_utf8(line).ok()
+                                .and_then(|s| {s.parse::<f64>().ok()})
+                                .ok_or(Protocol("malformed double"))?;
+               SimpleRESPObject::Double(double)
+           },
+           b'(' => {
+               let digits = line.strip_prefix(b"-").or(line.strip_prefix(b"+")).unwrap_or(line);
+               if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                   return Err(Protocol("malformed big number"));
+               }
+               SimpleRESPObject::BigNumber(str::from_utf8(line).unwrap())
+           },
+           _ => return Err(Protocol("unrecognized simple type"))
+       };
+       Ok((object, consumed))
     }
     
     
-    fn extract_single_aggregate_object(content: &[u8]) -> Result<AggrRESPObject, &'static str>{
-        // including types of bulkstr, bulkerror 
-        match content[0] {
-           b'$' => {
-               // bulk str: $<length>\r\n<data>\r\n
-               let mut sstream = str::from_utf8(&content[1..]).unwrap().split("\r\n");
-               let bstr_len = sstream.next().unwrap().parse::<i32>().unwrap();
-               let bstr_content: &[u8] = sstream.next().unwrap().as_bytes(); 
-               
-               assert_eq!(bstr_len as usize, bstr_content.len());
-               Ok(AggrRESPObject::BulkStr(bstr_content))
-           },
-           _ => {todo!()}
+    fn extract_single_aggregate_object(content: &[u8]) -> Result<(AggrRESPObject<'_>, usize), RedisError>{
+        // including types of bulkstr, bulkerror, verbatim str
+        // all of them share the layout <type><length>\r\n<data>\r\n
+        let (header, header_len) = read_line(content)?;
+        let bstr_len = parse_len(header, INVALID_BULK_LEN)?;
+        if bstr_len < 0 {
+            return match content[0] {
+                b'$' => Ok((AggrRESPObject::NullBulkStr, header_len)),
+                _ => Err(Protocol("negative length on non bulk string"))
+            };
+        }
+        let end = header_len + bstr_len as usize;
+        if content.len() < end + 2 || &content[end..end+2] != b"\r\n" {
+            return Err(Protocol("bulk string length mismatch"));
         }
+        let bstr_content = &content[header_len..end];
+
+        let object = match content[0] {
+           b'$' => AggrRESPObject::BulkStr(bstr_content),
+           b'!' => AggrRESPObject::BulkError(bstr_content),
+           b'=' => {
+               // verbatim str: =<length>\r\n<fmt>:<data>\r\n
+               if bstr_content.len() < 4 || bstr_content[3] != b':' {
+                   return Err(Protocol("malformed verbatim string"));
+               }
+               let format = str::from_utf8(&bstr_content[..3]).map_err(|_| {Protocol("malformed verbatim string")})?;
+               AggrRESPObject::VerbatimStr(format, &bstr_content[4..])
+           },
+           _ => return Err(Protocol("unrecognized aggregate type"))
+        };
+        Ok((object, end + 2))
     }
     
     
-    fn extract_nested_object(content: &[u8]) -> Result<AggrRESPObject, &'static str>{
-        match content[0] {
-            b'*' => {
-                // Array: *<num-items>\r\n<element-1>...<element-n>
-                let sstream = str::from_utf8(&content[1..]).unwrap();
-                let (num_item, sstream) = sstream.split_once("\r\n").unwrap();
-                // sstream: <type><data>\r\n || <type><data>\r\n<data>\r\n
-                
-                
+    fn extract_nested_object(content: &[u8]) -> Result<(AggrRESPObject<'_>, usize), RedisError>{
+        // Array: *<num-items>\r\n<element-1>...<element-n>
+        // Set and Push share the layout, Map carries <num-items> key value pairs
+        let (header, mut consumed) = read_line(content)?;
+        let num_item = parse_len(header, INVALID_MULTIBULK_LEN)?;
+        if num_item < 0 {
+            return match content[0] {
+                b'*' => Ok((AggrRESPObject::NullArray, consumed)),
+                _ => Err(Protocol("negative length on non array aggregate"))
+            };
+        }
+
+        let next_item = |consumed: &mut usize| {
+            let (item, item_len) = extract_item(&content[*consumed..])?;
+            *consumed += item_len;
+            Ok::<_, RedisError>(item)
+        };
+        let object = match content[0] {
+            b'%' => {
+                let mut pairs = vec!();
+                for _ in 0..num_item {
+                    let key = next_item(&mut consumed)?;
+                    let val = next_item(&mut consumed)?;
+                    pairs.push((key, val));
+                }
+                AggrRESPObject::Map(pairs)
+            },
+            category => {
                 let mut objects_arr = vec!();
-                let mut sstream_iter = sstream.chars().enumerate();
-                while let Some((start, category)) = sstream_iter.next(){
-                    let (expect_num_terminator, generator): (i32, Box<dyn Fn(&[u8]) -> AtomicItem>) = match category {
-                        '$' => (2, Box::new(|chunk|{
-                            AtomicItem::AggrItem(extract_single_aggregate_object(chunk).unwrap())
-                        })),
-                         _  => (1, Box::new(|chunk|{
-                             AtomicItem::SimpleItem(extract_simple_object(chunk).unwrap())
-                         }))
-                    };
-                    
-                    let mut num_terminator = 0i32;
-                    while let Some((_, c)) = sstream_iter.next(){
-                        if c == '\r' {
-                            if let Some((next_index, next_c)) = sstream_iter.next(){
-                               if next_c == '\n'{
-                                   num_terminator += 1;
-                               } 
-                               if num_terminator == expect_num_terminator{
-                                   objects_arr.push(generator(&sstream[start..next_index+1].as_bytes()));
-                                   break;
-                               }
-                            }
-                        }
-                    }
+                for _ in 0..num_item {
+                    objects_arr.push(next_item(&mut consumed)?);
+                }
+                match category {
+                    b'*' => AggrRESPObject::Array(objects_arr),
+                    b'~' => AggrRESPObject::Set(objects_arr),
+                    b'>' => AggrRESPObject::Push(objects_arr),
+                    _ => return Err(Protocol("unrecognized nested type"))
+                }
+            }
+        };
+        Ok((object, consumed))
+    }
+
+
+    fn extract_item(content: &[u8]) -> Result<(AtomicItem<'_>, usize), RedisError>{
+        match content.first() {
+            Some(b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(') => {
+                let (object, consumed) = extract_simple_object(content)?;
+                Ok((AtomicItem::SimpleItem(object), consumed))
+            },
+            Some(b'$' | b'!' | b'=') => {
+                let (object, consumed) = extract_single_aggregate_object(content)?;
+                Ok((AtomicItem::AggrItem(object), consumed))
+            },
+            Some(b'*' | b'%' | b'~' | b'>') => {
+                let (object, consumed) = extract_nested_object(content)?;
+                Ok((AtomicItem::AggrItem(object), consumed))
+            },
+            Some(b'|') => {
+                // attributes are out-of-band metadata, drop them and keep the annotated value
+                let (header, mut consumed) = read_line(content)?;
+                for _ in 0..2*parse_len(header, INVALID_MULTIBULK_LEN)?.max(0) {
+                    consumed += extract_item(&content[consumed..])?.1;
                 }
-                assert_eq!(str::parse::<usize>(num_item).unwrap(), objects_arr.len());
-                Ok(AggrRESPObject::Array(objects_arr))
-                
+                let (item, item_len) = extract_item(&content[consumed..])?;
+                Ok((item, consumed + item_len))
             },
-            _ => {todo!()}
+            Some(_) => Err(Protocol("unrecognized category indicator")),
+            None => Err(Protocol("incomplete frame"))
         }
     }
     
     
-    pub fn parse_resp(content: &[u8]) -> Result<RESPObject, &'static str>{
-        
-        let serialized_res = match content[0]{
-           b':' | b'+' => {
-               let simple_object = extract_simple_object(content)?;
-               Ok(RESPObject::Simple(simple_object))
-           },
-           b'$' => {
-               let single_aggr_object = extract_single_aggregate_object(content)?;
-               Ok(RESPObject::Aggregate(single_aggr_object))
-           }, 
-           b'*' => {
-               let aggr_object = extract_nested_object(content)?;
-               Ok(RESPObject::Aggregate(aggr_object))
-           },
-            _ => {Err("unrecognized category indicator")}
-        };
-        serialized_res
+    pub fn parse_resp(content: &[u8]) -> Result<RESPObject<'_>, RedisError>{
+        let (item, _) = extract_item(content)?;
+        Ok(item.into())
+    }
+
+
+    /* incremental framing over a growing read buffer
+     * bytes are appended by the connection loop as they arrive, the decoder
+     * walks RESP headers and only hands back a frame once every element of it
+     * is buffered. scanning progress is kept across calls so a large frame
+     * split over many reads is not re-walked from its start every time
+     * */
+    /* bounds on what a single client may send, anything beyond them is
+     * answered with a protocol error and the connection is dropped
+     * */
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ProtoLimits{
+        // proto-max-bulk-len
+        pub max_bulk_len: usize,
+        // element count of a single multibulk header
+        pub max_multibulk_len: usize,
+        // client-query-buffer-limit, unparsed bytes held for one client
+        pub max_query_buffer: usize,
+        // inline commands and RESP headers must find their line end within this
+        pub max_inline_len: usize
+    }
+
+    impl Default for ProtoLimits{
+        fn default() -> Self{
+            Self{
+                max_bulk_len: 512*1024*1024,
+                max_multibulk_len: 1024*1024,
+                max_query_buffer: 1024*1024*1024,
+                max_inline_len: 64*1024
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct StreamDecoder{
+        // offset into the buffer up to which the in-flight frame is validated
+        scanned: usize,
+        // number of elements still owed by each aggregate opened so far
+        pending: Vec<usize>,
+        limits: ProtoLimits
+    }
+
+    impl StreamDecoder{
+        pub fn new() -> Self{
+            Self::default()
+        }
+
+        pub fn with_limits(limits: ProtoLimits) -> Self{
+            Self{limits, ..Self::default()}
+        }
+
+        // Ok(None) means more data is needed, the buffer is left untouched
+        pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, RedisError>{
+            let frame = self.decode_frame(buf)?;
+            // whatever is still buffered belongs to one unfinished frame
+            if frame.is_none() && buf.len() > self.limits.max_query_buffer {
+                return Err(Protocol("client query buffer limit exceeded"));
+            }
+            Ok(frame)
+        }
+
+        fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, RedisError>{
+            if self.pending.is_empty() && is_inline(buf) {
+                // inline commands end at LF, the CR before it is optional
+                return match buf.iter().position(|c| {*c == b'\n'}) {
+                    Some(line_end) => Ok(Some(buf.split_to(line_end + 1).freeze())),
+                    None if buf.len() > self.limits.max_inline_len => Err(Protocol("too big inline request")),
+                    None => Ok(None)
+                };
+            }
+            loop {
+                if self.scanned >= buf.len() {return Ok(None);}
+                let line_end = match find_crlf(buf, self.scanned){
+                    Some(index) => index,
+                    None if buf.len() - self.scanned > self.limits.max_inline_len => {
+                        return Err(Protocol("too big count string"));
+                    },
+                    None => return Ok(None)
+                };
+                let header = &buf[self.scanned+1..line_end];
+                let mut next = line_end + 2;
+
+                let completes_element = match buf[self.scanned]{
+                    b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => true,
+                    b'$' | b'!' | b'=' => {
+                        // bulk str: $<length>\r\n<data>\r\n, $-1 for nil
+                        let len = parse_len(header, INVALID_BULK_LEN)?;
+                        if len > self.limits.max_bulk_len as i64 {return Err(INVALID_BULK_LEN);}
+                        if len >= 0 {
+                            next += len as usize + 2;
+                            if next > buf.len() {return Ok(None);}
+                            if &buf[next-2..next] != b"\r\n" {
+                                return Err(Protocol("bulk string is not terminated by CRLF"));
+                            }
+                        }
+                        true
+                    },
+                    b'*' | b'~' | b'>' | b'%' => {
+                        let mut len = parse_len(header, INVALID_MULTIBULK_LEN)?;
+                        if len > self.limits.max_multibulk_len as i64 {return Err(INVALID_MULTIBULK_LEN);}
+                        if buf[self.scanned] == b'%' {len *= 2;}
+                        if len > 0 {
+                            self.pending.push(len as usize);
+                            false
+                        }else {true}
+                    },
+                    b'|' => {
+                        // attribute pairs plus the value they annotate count as one element
+                        let len = parse_len(header, INVALID_MULTIBULK_LEN)?.max(0);
+                        if len > self.limits.max_multibulk_len as i64 {return Err(INVALID_MULTIBULK_LEN);}
+                        self.pending.push(2*len as usize + 1);
+                        false
+                    },
+                    _ => return Err(Protocol("unrecognized category indicator"))
+                };
+
+                self.scanned = next;
+                if completes_element && self.close_element(){
+                    let frame = buf.split_to(self.scanned).freeze();
+                    self.scanned = 0;
+                    return Ok(Some(frame));
+                }
+            }
+        }
+
+        // drain every complete frame currently sitting in the buffer
+        pub fn decode_all(&mut self, buf: &mut BytesMut) -> Result<Vec<Bytes>, RedisError>{
+            let mut frames = vec!();
+            while let Some(frame) = self.decode(buf)? {
+                frames.push(frame);
+            }
+            Ok(frames)
+        }
+
+        // returns true once the outermost element of the frame is complete
+        fn close_element(&mut self) -> bool{
+            while let Some(remaining) = self.pending.last_mut(){
+                *remaining -= 1;
+                if *remaining > 0 {return false;}
+                self.pending.pop();
+            }
+            true
+        }
+    }
+
+    // a frame not led by any RESP type byte is an inline command typed by hand,
+    // e.g. through telnet or `printf 'PING\r\n' | nc`
+    pub fn is_inline(frame: &[u8]) -> bool{
+        !matches!(
+            frame.first(),
+            None | Some(b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b'#' | b',' | b'(' | b'!' | b'=' | b'%' | b'~' | b'>' | b'|')
+        )
+    }
+
+    fn unhex(c: u8) -> Option<u8>{
+        (c as char).to_digit(16).map(|digit| {digit as u8})
+    }
+
+    /* split an inline command into arguments following the quoting rules of redis
+     * "double quoted" arguments understand \n \r \t \b \a \\ \" and \xHH escapes,
+     * 'single quoted' ones only \' and a closing quote must be followed by a space
+     * */
+    pub fn parse_inline(line: &[u8]) -> Result<Vec<Vec<u8>>, RedisError>{
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let is_blank = |c: u8| {matches!(c, b' ' | b'\n' | b'\r' | b'\t' | b'\0')};
+        let mut args = vec!();
+        let mut i = 0;
+
+        loop {
+            while i < line.len() && is_blank(line[i]) {i += 1;}
+            if i == line.len() {return Ok(args);}
+
+            let mut current = vec!();
+            let (mut in_double_quotes, mut in_single_quotes) = (false, false);
+            loop {
+                let c = line.get(i).copied();
+                let next = line.get(i+1).copied();
+                if in_double_quotes {
+                    match (c, next) {
+                        (None, _) => return Err(Protocol("unbalanced quotes in request")),
+                        (Some(b'\\'), Some(b'x')) if line.len() > i+3 && unhex(line[i+2]).is_some() && unhex(line[i+3]).is_some() => {
+                            current.push(unhex(line[i+2]).unwrap()*16 + unhex(line[i+3]).unwrap());
+                            i += 3;
+                        },
+                        (Some(b'\\'), Some(escaped)) => {
+                            current.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => other
+                            });
+                            i += 1;
+                        },
+                        (Some(b'"'), next) => {
+                            // closing quote must be followed by a space or nothing at all
+                            if next.is_some_and(|c| {!is_blank(c)}) {
+                                return Err(Protocol("unbalanced quotes in request"));
+                            }
+                            i += 1;
+                            break;
+                        },
+                        (Some(c), _) => current.push(c)
+                    }
+                }else if in_single_quotes {
+                    match (c, next) {
+                        (None, _) => return Err(Protocol("unbalanced quotes in request")),
+                        (Some(b'\\'), Some(b'\'')) => {
+                            current.push(b'\'');
+                            i += 1;
+                        },
+                        (Some(b'\''), next) => {
+                            if next.is_some_and(|c| {!is_blank(c)}) {
+                                return Err(Protocol("unbalanced quotes in request"));
+                            }
+                            i += 1;
+                            break;
+                        },
+                        (Some(c), _) => current.push(c)
+                    }
+                }else {
+                    match c {
+                        None => break,
+                        Some(c) if is_blank(c) => {
+                            i += 1;
+                            break;
+                        },
+                        Some(b'"') => in_double_quotes = true,
+                        Some(b'\'') => in_single_quotes = true,
+                        Some(c) => current.push(c)
+                    }
+                }
+                i += 1;
+            }
+            args.push(current);
+        }
+    }
+
+    fn find_crlf(buf: &[u8], from: usize) -> Option<usize>{
+        buf[from..].windows(2).position(|w| {w == b"\r\n"}).map(|i| {i + from})
+    }
+
+    const INVALID_BULK_LEN: RedisError = Protocol("invalid bulk length");
+    const INVALID_MULTIBULK_LEN: RedisError = Protocol("invalid multibulk length");
+
+    // plain digits, or -1 for the null forms. any other sign ($+3, *-0) is refused like redis does
+    fn parse_len(header: &[u8], invalid: RedisError) -> Result<i64, RedisError>{
+        if header == b"-1" {return Ok(-1);}
+        if header.is_empty() || !header.iter().all(u8::is_ascii_digit) {return Err(invalid);}
+        str::from_utf8(header).ok()
+            .and_then(|s| {s.parse::<i64>().ok()})
+            .filter(|len| {*len >= -1})
+            .ok_or(invalid)
     }
 }
 
 
 
 pub mod encrypt{
-    use std::str;
-    use super::{AtomicItem, AggrRESPObject};
+    use super::{AtomicItem, AggrRESPObject, ProtocolVersion, RespValue};
+    use bytes::BytesMut;
+    use std::io::Write;
+
+    /* replies are written straight into a byte sink owned by the connection
+     * instead of being assembled from temporary Strings, numbers are formatted
+     * on the stack so a reply costs no allocation besides the sink growing
+     * */
+    pub trait RespSink{
+        fn put(&mut self, bytes: &[u8]);
+
+        fn put_byte(&mut self, byte: u8){
+            self.put(&[byte]);
+        }
+    }
+
+    impl RespSink for BytesMut{
+        fn put(&mut self, bytes: &[u8]){
+            self.extend_from_slice(bytes);
+        }
+    }
+
+    impl RespSink for Vec<u8>{
+        fn put(&mut self, bytes: &[u8]){
+            self.extend_from_slice(bytes);
+        }
+
+        fn put_byte(&mut self, byte: u8){
+            self.push(byte);
+        }
+    }
+
+    // adapter over any io::Write, the first failure is kept and later writes are skipped
+    pub struct IoSink<W: Write>{
+        inner: W,
+        error: Option<std::io::Error>
+    }
+
+    impl<W: Write> IoSink<W>{
+        pub fn new(inner: W) -> Self{
+            Self{inner, error: None}
+        }
+
+        pub fn finish(self) -> Result<W, std::io::Error>{
+            match self.error {
+                Some(e) => Err(e),
+                None => Ok(self.inner)
+            }
+        }
+    }
+
+    impl<W: Write> RespSink for IoSink<W>{
+        fn put(&mut self, bytes: &[u8]){
+            if self.error.is_none() {
+                self.error = self.inner.write_all(bytes).err();
+            }
+        }
+    }
+
+
+    // itoa style, digits are produced backwards into a stack buffer
+    pub fn write_decimal<S: RespSink + ?Sized>(out: &mut S, num: i64){
+        let mut buf = [0u8; 20];
+        let mut pos = buf.len();
+        let mut magnitude = num.unsigned_abs();
+        loop {
+            pos -= 1;
+            buf[pos] = b'0' + (magnitude % 10) as u8;
+            magnitude /= 10;
+            if magnitude == 0 {break;}
+        }
+        if num < 0 {
+            out.put_byte(b'-');
+        }
+        out.put(&buf[pos..]);
+    }
+
+    fn write_header<S: RespSink + ?Sized>(out: &mut S, category: u8, len: usize){
+        out.put_byte(category);
+        write_decimal(out, len as i64);
+        out.put(b"\r\n");
+    }
+
+    // simple strings and errors can not carry CR or LF, those are blanked out
+    fn write_line<S: RespSink + ?Sized>(out: &mut S, category: u8, msg: &[u8]){
+        out.put_byte(category);
+        let mut rest = msg;
+        while let Some(pos) = rest.iter().position(|c| {matches!(c, b'\r' | b'\n')}) {
+            out.put(&rest[..pos]);
+            out.put_byte(b' ');
+            rest = &rest[pos+1..];
+        }
+        out.put(rest);
+        out.put(b"\r\n");
+    }
+
+    pub fn write_simple_str<S: RespSink + ?Sized>(out: &mut S, msg: &[u8]){
+        write_line(out, b'+', msg);
+    }
+
+    pub fn write_error<S: RespSink + ?Sized>(out: &mut S, msg: &[u8]){
+        write_line(out, b'-', msg);
+    }
+
+    pub fn write_int<S: RespSink + ?Sized>(out: &mut S, num: i64){
+        out.put_byte(b':');
+        write_decimal(out, num);
+        out.put(b"\r\n");
+    }
+
+    // prefix the payload with its length, the payload itself is copied verbatim
+    // so binary values and embedded CRLF survive the round trip
+    pub fn write_bulk_str<S: RespSink + ?Sized>(out: &mut S, msg: Option<&[u8]>){
+        match msg {
+            Some(msg) => {
+                write_header(out, b'$', msg.len());
+                out.put(msg);
+                out.put(b"\r\n");
+            },
+            None => out.put(b"$-1\r\n")
+        }
+    }
+
+    pub fn write_array_header<S: RespSink + ?Sized>(out: &mut S, len: usize){
+        write_header(out, b'*', len);
+    }
+
+
+    /* RESP3 types
+     * each of them degrades to the closest RESP2 shape when the connection
+     * has not negotiated protocol 3, the same way redis does
+     * */
+
+    pub fn write_null<S: RespSink + ?Sized>(out: &mut S, proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => out.put(b"$-1\r\n"),
+            ProtocolVersion::Resp3 => out.put(b"_\r\n")
+        }
+    }
+
+    pub fn write_null_array<S: RespSink + ?Sized>(out: &mut S, proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => out.put(b"*-1\r\n"),
+            ProtocolVersion::Resp3 => out.put(b"_\r\n")
+        }
+    }
+
+    pub fn write_boolean<S: RespSink + ?Sized>(out: &mut S, flag: bool, proto: ProtocolVersion){
+        match (proto, flag) {
+            (ProtocolVersion::Resp2, true) => out.put(b":1\r\n"),
+            (ProtocolVersion::Resp2, false) => out.put(b":0\r\n"),
+            (ProtocolVersion::Resp3, true) => out.put(b"#t\r\n"),
+            (ProtocolVersion::Resp3, false) => out.put(b"#f\r\n")
+        }
+    }
+
+    // fixed buffer for fmt::Write, large enough for any shortest f64 representation
+    struct DoubleBuf{
+        buf: [u8; 40],
+        len: usize
+    }
+
+    impl std::fmt::Write for DoubleBuf{
+        fn write_str(&mut self, s: &str) -> std::fmt::Result{
+            let end = self.len + s.len();
+            if end > self.buf.len() {return Err(std::fmt::Error);}
+            self.buf[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    // shortest round trip digits, switching to exponent form at the same bounds as %.17g
+    fn with_double_repr<T>(num: f64, f: impl FnOnce(&[u8]) -> T) -> T{
+        use std::fmt::Write as _;
+        if num.is_nan() {return f(b"nan");}
+        if num.is_infinite() {return f(if num > 0.0 {b"inf"} else {b"-inf"});}
+
+        let mut repr = DoubleBuf{buf: [0u8; 40], len: 0};
+        let magnitude = num.abs();
+        if magnitude != 0.0 && !(1e-4..1e17).contains(&magnitude) {
+            let _ = write!(repr, "{:e}", num);
+            // C prints an explicit sign on positive exponents
+            if let Some(pos) = repr.buf[..repr.len].iter().position(|c| {*c == b'e'}) {
+                if repr.buf[pos+1] != b'-' {
+                    repr.buf.copy_within(pos+1..repr.len, pos+2);
+                    repr.buf[pos+1] = b'+';
+                    repr.len += 1;
+                }
+            }
+        }else {
+            let _ = write!(repr, "{}", num);
+        }
+        f(&repr.buf[..repr.len])
+    }
+
+    pub fn format_double(num: f64) -> String{
+        with_double_repr(num, |repr| {String::from_utf8_lossy(repr).into_owned()})
+    }
+
+    pub fn write_double<S: RespSink + ?Sized>(out: &mut S, num: f64, proto: ProtocolVersion){
+        with_double_repr(num, |repr| {
+            match proto {
+                ProtocolVersion::Resp2 => write_bulk_str(out, Some(repr)),
+                ProtocolVersion::Resp3 => {
+                    out.put_byte(b',');
+                    out.put(repr);
+                    out.put(b"\r\n");
+                }
+            }
+        });
+    }
+
+    pub fn write_big_number<S: RespSink + ?Sized>(out: &mut S, digits: &[u8], proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => write_bulk_str(out, Some(digits)),
+            ProtocolVersion::Resp3 => write_line(out, b'(', digits)
+        }
+    }
+
+    pub fn write_verbatim_str<S: RespSink + ?Sized>(out: &mut S, format: &[u8], msg: &[u8], proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => write_bulk_str(out, Some(msg)),
+            ProtocolVersion::Resp3 => {
+                // =<length>\r\n<fmt>:<data>\r\n
+                write_header(out, b'=', format.len() + 1 + msg.len());
+                out.put(format);
+                out.put_byte(b':');
+                out.put(msg);
+                out.put(b"\r\n");
+            }
+        }
+    }
+
+    pub fn write_set_header<S: RespSink + ?Sized>(out: &mut S, len: usize, proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => write_header(out, b'*', len),
+            ProtocolVersion::Resp3 => write_header(out, b'~', len)
+        }
+    }
+
+    pub fn write_push_header<S: RespSink + ?Sized>(out: &mut S, len: usize, proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => write_header(out, b'*', len),
+            ProtocolVersion::Resp3 => write_header(out, b'>', len)
+        }
+    }
+
+    // RESP2 has no map, pairs are flattened into key value key value ...
+    pub fn write_map_header<S: RespSink + ?Sized>(out: &mut S, num_pairs: usize, proto: ProtocolVersion){
+        match proto {
+            ProtocolVersion::Resp2 => write_header(out, b'*', 2*num_pairs),
+            ProtocolVersion::Resp3 => write_header(out, b'%', num_pairs)
+        }
+    }
+
+    pub fn write_value<S: RespSink + ?Sized>(out: &mut S, value: &RespValue, proto: ProtocolVersion){
+        match value {
+            RespValue::SimpleStr(s) => write_simple_str(out, s.as_bytes()),
+            RespValue::Error(s) => write_error(out, s.as_bytes()),
+            RespValue::Integer(num) => write_int(out, *num),
+            RespValue::BulkStr(s) => write_bulk_str(out, Some(s)),
+            RespValue::Null => write_null(out, proto),
+            RespValue::NullArray => write_null_array(out, proto),
+            RespValue::Boolean(flag) => write_boolean(out, *flag, proto),
+            RespValue::Double(num) => write_double(out, *num, proto),
+            RespValue::BigNumber(digits) => write_big_number(out, digits.as_bytes(), proto),
+            RespValue::Verbatim(format, s) => write_verbatim_str(out, format.as_bytes(), s, proto),
+            RespValue::Array(items) => {
+                write_array_header(out, items.len());
+                items.iter().for_each(|item| {write_value(out, item, proto)});
+            },
+            RespValue::Set(items) => {
+                write_set_header(out, items.len(), proto);
+                items.iter().for_each(|item| {write_value(out, item, proto)});
+            },
+            RespValue::Push(items) => {
+                write_push_header(out, items.len(), proto);
+                items.iter().for_each(|item| {write_value(out, item, proto)});
+            },
+            RespValue::Map(pairs) => {
+                write_map_header(out, pairs.len(), proto);
+                for (key, val) in pairs {
+                    write_value(out, key, proto);
+                    write_value(out, val, proto);
+                }
+            }
+        }
+    }
+
+
+    /* one-shot helpers returning an owned frame
+     * handy for tests and handshakes, the hot path writes into a sink instead
+     * */
 
     pub trait AsRESPItem{
-        fn as_item(&self) -> AtomicItem;
+        fn as_item(&self) -> AtomicItem<'_>;
     }
 
-    impl<T: AsRef<str>> AsRESPItem for T{
-       fn as_item(&self) -> AtomicItem{
-           AtomicItem::AggrItem(AggrRESPObject::BulkStr(self.as_ref().as_bytes()))
+    // anything viewable as raw bytes, str and String included, goes out as a bulk string
+    impl<T: AsRef<[u8]>> AsRESPItem for T{
+       fn as_item(&self) -> AtomicItem<'_>{
+           AtomicItem::AggrItem(AggrRESPObject::BulkStr(self.as_ref()))
        } 
     }
 
+    fn collect(f: impl FnOnce(&mut Vec<u8>)) -> Box<[u8]>{
+        let mut out = vec!();
+        f(&mut out);
+        out.into_boxed_slice()
+    }
+
     pub fn as_bulk_str(msg: Option<&[u8]>) -> Box<[u8]>{
-       match msg{
-          Some(msg) => {
-            let size = msg.len();
-            let s = format!("${size}").to_string() + "\r\n" + str::from_utf8(msg).unwrap() + "\r\n";
-            s.into_bytes().into_boxed_slice()
-          },
-          None => {
-            ("-1".to_string() + "\r\n").into_bytes().into_boxed_slice()
-          }
-       }
+        collect(|out| {write_bulk_str(out, msg)})
     }
 
     pub fn as_simple_str(msg: &[u8]) -> Box<[u8]>{
-        let s = '+'.to_string() + str::from_utf8(msg).unwrap() + "\r\n"; 
-        s.into_bytes().into_boxed_slice()
+        collect(|out| {write_simple_str(out, msg)})
     }
 
-    pub fn as_int(num: i32) -> Box<[u8]>{
-        let s = ':'.to_string() + num.to_string().as_str() + "\r\n";
-        s.into_bytes().into_boxed_slice()
+    pub fn as_error(msg: &[u8]) -> Box<[u8]>{
+        collect(|out| {write_error(out, msg)})
     }
 
-   pub fn as_array<T: AsRESPItem>(items: Vec<T>) -> Box<[u8]>{
-       let mut header = ("*".to_string() + items.len().to_string().as_str() + "\r\n").into_bytes();
-       let encoed_items = items.into_iter()
-                            .map(|item| {as_bulk_str(item.as_item().as_bytes())})
-                            .collect::<Vec<_>>().concat();
-       header.extend(&encoed_items[..]);
-       header.into_boxed_slice()
-   }
+    pub fn as_int(num: i64) -> Box<[u8]>{
+        collect(|out| {write_int(out, num)})
+    }
 
-    
-}
+    pub fn as_array<T: AsRESPItem>(items: Vec<T>) -> Box<[u8]>{
+        collect(|out| {
+            write_array_header(out, items.len());
+            items.iter().for_each(|item| {write_bulk_str(out, item.as_item().as_bytes())});
+        })
+    }
+
+    pub fn as_null(proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {write_null(out, proto)})
+    }
+
+    pub fn as_boolean(flag: bool, proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {write_boolean(out, flag, proto)})
+    }
+
+    pub fn as_double(num: f64, proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {write_double(out, num, proto)})
+    }
 
+    pub fn as_big_number(digits: &str, proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {write_big_number(out, digits.as_bytes(), proto)})
+    }
+
+    pub fn as_verbatim_str(format: &str, msg: &[u8], proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {write_verbatim_str(out, format.as_bytes(), msg, proto)})
+    }
+
+    // items are expected to be RESP encoded already, allowing mixed element types
+    pub fn as_nested_array(items: Vec<Box<[u8]>>) -> Box<[u8]>{
+        collect(|out| {
+            write_array_header(out, items.len());
+            items.iter().for_each(|item| {out.put(item)});
+        })
+    }
+
+    pub fn as_set(items: Vec<Box<[u8]>>, proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {
+            write_set_header(out, items.len(), proto);
+            items.iter().for_each(|item| {out.put(item)});
+        })
+    }
+
+    pub fn as_push(items: Vec<Box<[u8]>>, proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {
+            write_push_header(out, items.len(), proto);
+            items.iter().for_each(|item| {out.put(item)});
+        })
+    }
+
+    pub type EncodedPair = (Box<[u8]>, Box<[u8]>);
+
+    pub fn as_map(pairs: Vec<EncodedPair>, proto: ProtocolVersion) -> Box<[u8]>{
+        collect(|out| {
+            write_map_header(out, pairs.len(), proto);
+            for (key, val) in &pairs {
+                out.put(key);
+                out.put(val);
+            }
+        })
+    }
+}
@@ -0,0 +1,331 @@
+/* owned counterpart of RESPObject
+ * commands build a RespValue tree and leave the wire format to the connection,
+ * which knows whether the client negotiated RESP2 or RESP3
+ * */
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use crate::error::RedisError::{self, UnexpectedReply};
+use super::encrypt::{write_value, RespSink};
+use super::{AggrRESPObject, AtomicItem, ProtocolVersion, RESPObject, SimpleRESPObject};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue{
+    SimpleStr(String),
+    Error(String),
+    Integer(i64),
+    BulkStr(Vec<u8>),
+    Array(Vec<RespValue>),
+    // nil bulk string in RESP2
+    Null,
+    // nil multi bulk in RESP2, both nils collapse into `_` under RESP3
+    NullArray,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    Verbatim(String, Vec<u8>),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    Push(Vec<RespValue>)
+}
+
+impl RespValue{
+    pub fn ok() -> Self{
+        Self::SimpleStr(String::from("OK"))
+    }
+
+    pub fn error<T: Into<String>>(msg: T) -> Self{
+        Self::Error(msg.into())
+    }
+
+    pub fn is_error(&self) -> bool{
+        matches!(self, Self::Error(_))
+    }
+
+    pub fn encode(&self, proto: ProtocolVersion) -> Box<[u8]>{
+        let mut out = vec!();
+        self.write_to(&mut out, proto);
+        out.into_boxed_slice()
+    }
+
+    pub fn write_to<S: RespSink + ?Sized>(&self, out: &mut S, proto: ProtocolVersion){
+        write_value(out, self, proto);
+    }
+}
+
+
+impl<'a> From<AtomicItem<'a>> for RespValue{
+    fn from(item: AtomicItem<'a>) -> Self{
+        match item {
+            AtomicItem::SimpleItem(object) => match object {
+                SimpleRESPObject::Integer(num) => Self::Integer(num),
+                SimpleRESPObject::Str(s) => Self::SimpleStr(s.to_string()),
+                SimpleRESPObject::Error(s) => Self::Error(s.to_string()),
+                SimpleRESPObject::Null => Self::Null,
+                SimpleRESPObject::Boolean(flag) => Self::Boolean(flag),
+                SimpleRESPObject::Double(num) => Self::Double(num),
+                SimpleRESPObject::BigNumber(digits) => Self::BigNumber(digits.to_string())
+            },
+            AtomicItem::AggrItem(object) => match object {
+                AggrRESPObject::BulkStr(s) => Self::BulkStr(s.to_vec()),
+                AggrRESPObject::Array(items) => Self::Array(items.into_iter().map(Self::from).collect()),
+                AggrRESPObject::NullBulkStr => Self::Null,
+                AggrRESPObject::NullArray => Self::NullArray,
+                AggrRESPObject::BulkError(s) => Self::Error(String::from_utf8_lossy(s).into_owned()),
+                AggrRESPObject::VerbatimStr(format, s) => Self::Verbatim(format.to_string(), s.to_vec()),
+                AggrRESPObject::Map(pairs) => Self::Map(
+                    pairs.into_iter().map(|(key, val)| {(Self::from(key), Self::from(val))}).collect()
+                ),
+                AggrRESPObject::Set(items) => Self::Set(items.into_iter().map(Self::from).collect()),
+                AggrRESPObject::Push(items) => Self::Push(items.into_iter().map(Self::from).collect())
+            }
+        }
+    }
+}
+
+impl<'a> From<RESPObject<'a>> for RespValue{
+    fn from(object: RESPObject<'a>) -> Self{
+        match object {
+            RESPObject::Simple(object) => AtomicItem::SimpleItem(object).into(),
+            RESPObject::Aggregate(object) => AtomicItem::AggrItem(object).into()
+        }
+    }
+}
+
+
+
+/* conversion into a reply
+ * byte containers become bulk strings, Option maps None to nil,
+ * sequences and tuples become arrays and hash maps become RESP3 maps
+ * */
+pub trait ToResp{
+    fn to_resp(self) -> RespValue;
+}
+
+impl ToResp for RespValue{
+    fn to_resp(self) -> RespValue{self}
+}
+
+impl ToResp for (){
+    fn to_resp(self) -> RespValue{RespValue::ok()}
+}
+
+macro_rules! int_to_resp {
+    ($($t:ty),*) => {$(
+        impl ToResp for $t{
+            fn to_resp(self) -> RespValue{RespValue::Integer(self as i64)}
+        }
+    )*};
+}
+int_to_resp!(i32, i64, u32, u64, usize);
+
+impl ToResp for bool{
+    fn to_resp(self) -> RespValue{RespValue::Boolean(self)}
+}
+
+impl ToResp for f64{
+    fn to_resp(self) -> RespValue{RespValue::Double(self)}
+}
+
+impl ToResp for Vec<u8>{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self)}
+}
+
+impl ToResp for Box<[u8]>{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self.into_vec())}
+}
+
+impl ToResp for &[u8]{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self.to_vec())}
+}
+
+impl ToResp for &Box<[u8]>{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self.to_vec())}
+}
+
+impl ToResp for &Vec<u8>{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self.clone())}
+}
+
+impl ToResp for String{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self.into_bytes())}
+}
+
+impl ToResp for &str{
+    fn to_resp(self) -> RespValue{RespValue::BulkStr(self.as_bytes().to_vec())}
+}
+
+impl<T: ToResp> ToResp for Option<T>{
+    fn to_resp(self) -> RespValue{
+        match self {
+            Some(val) => val.to_resp(),
+            None => RespValue::Null
+        }
+    }
+}
+
+impl<T: ToResp> ToResp for Vec<T>{
+    fn to_resp(self) -> RespValue{
+        RespValue::Array(self.into_iter().map(ToResp::to_resp).collect())
+    }
+}
+
+impl<K: ToResp, V: ToResp> ToResp for HashMap<K, V>{
+    fn to_resp(self) -> RespValue{
+        RespValue::Map(self.into_iter().map(|(key, val)| {(key.to_resp(), val.to_resp())}).collect())
+    }
+}
+
+impl<K: ToResp, V: ToResp> ToResp for BTreeMap<K, V>{
+    fn to_resp(self) -> RespValue{
+        RespValue::Map(self.into_iter().map(|(key, val)| {(key.to_resp(), val.to_resp())}).collect())
+    }
+}
+
+macro_rules! tuple_to_resp {
+    ($($name:ident),+) => {
+        impl<$($name: ToResp),+> ToResp for ($($name,)+){
+            #[allow(non_snake_case)]
+            fn to_resp(self) -> RespValue{
+                let ($($name,)+) = self;
+                RespValue::Array(vec!($($name.to_resp()),+))
+            }
+        }
+    };
+}
+tuple_to_resp!(A, B);
+tuple_to_resp!(A, B, C);
+tuple_to_resp!(A, B, C, D);
+
+
+
+/* conversion out of a decoded reply, mostly for the replica and tooling side
+ * integers and doubles are also accepted in their bulk string form
+ * */
+pub trait FromResp: Sized{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>;
+}
+
+impl FromResp for RespValue{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{Ok(value)}
+}
+
+impl FromResp for i64{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        match value {
+            RespValue::Integer(num) => Ok(num),
+            RespValue::Boolean(flag) => Ok(flag as i64),
+            RespValue::BulkStr(s) | RespValue::Verbatim(_, s) => {
+                std::str::from_utf8(&s).ok()
+                    .and_then(|s| {s.parse::<i64>().ok()})
+                    .ok_or(UnexpectedReply("value is not an integer"))
+            },
+            _ => Err(UnexpectedReply("value is not an integer"))
+        }
+    }
+}
+
+impl FromResp for f64{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        match value {
+            RespValue::Double(num) => Ok(num),
+            RespValue::Integer(num) => Ok(num as f64),
+            RespValue::BulkStr(s) => {
+                std::str::from_utf8(&s).ok()
+                    .and_then(|s| {s.parse::<f64>().ok()})
+                    .ok_or(UnexpectedReply("value is not a valid float"))
+            },
+            _ => Err(UnexpectedReply("value is not a valid float"))
+        }
+    }
+}
+
+impl FromResp for bool{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        match value {
+            RespValue::Boolean(flag) => Ok(flag),
+            RespValue::Integer(num) => Ok(num != 0),
+            _ => Err(UnexpectedReply("value is not a boolean"))
+        }
+    }
+}
+
+impl FromResp for Vec<u8>{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        match value {
+            RespValue::BulkStr(s) | RespValue::Verbatim(_, s) => Ok(s),
+            RespValue::SimpleStr(s) | RespValue::BigNumber(s) => Ok(s.into_bytes()),
+            _ => Err(UnexpectedReply("value is not a string"))
+        }
+    }
+}
+
+impl FromResp for String{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        String::from_utf8(Vec::<u8>::from_resp(value)?).map_err(|_| {UnexpectedReply("value is not utf-8")})
+    }
+}
+
+impl<T: FromResp> FromResp for Option<T>{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        match value {
+            RespValue::Null | RespValue::NullArray => Ok(None),
+            value => T::from_resp(value).map(Some)
+        }
+    }
+}
+
+fn into_items(value: RespValue) -> Result<Vec<RespValue>, RedisError>{
+    match value {
+        RespValue::Array(items) | RespValue::Set(items) | RespValue::Push(items) => Ok(items),
+        _ => Err(UnexpectedReply("value is not an aggregate"))
+    }
+}
+
+impl<T: FromResp> FromResp for Vec<T>{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        into_items(value)?.into_iter().map(T::from_resp).collect()
+    }
+}
+
+impl<K: FromResp + Eq + Hash, V: FromResp> FromResp for HashMap<K, V>{
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        let pairs = match value {
+            RespValue::Map(pairs) => pairs,
+            // RESP2 servers send maps as flat arrays
+            value => {
+                let items = into_items(value)?;
+                if items.len() % 2 != 0 {return Err(UnexpectedReply("odd number of elements for a map"));}
+                let mut items = items.into_iter();
+                let mut pairs = vec!();
+                while let (Some(key), Some(val)) = (items.next(), items.next()) {
+                    pairs.push((key, val));
+                }
+                pairs
+            }
+        };
+        pairs.into_iter().map(|(key, val)| {Ok((K::from_resp(key)?, V::from_resp(val)?))}).collect()
+    }
+}
+
+impl<A: FromResp, B: FromResp> FromResp for (A, B){
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        let mut items = into_items(value)?.into_iter();
+        match (items.next(), items.next(), items.next()) {
+            (Some(a), Some(b), None) => Ok((A::from_resp(a)?, B::from_resp(b)?)),
+            _ => Err(UnexpectedReply("tuple arity mismatch"))
+        }
+    }
+}
+
+impl<A: FromResp, B: FromResp, C: FromResp> FromResp for (A, B, C){
+    fn from_resp(value: RespValue) -> Result<Self, RedisError>{
+        let mut items = into_items(value)?.into_iter();
+        match (items.next(), items.next(), items.next(), items.next()) {
+            (Some(a), Some(b), Some(c), None) => Ok((A::from_resp(a)?, B::from_resp(b)?, C::from_resp(c)?)),
+            _ => Err(UnexpectedReply("tuple arity mismatch"))
+        }
+    }
+}
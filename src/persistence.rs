@@ -1,25 +1,447 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::ops::{Deref, DerefMut};
 use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-type ThreadSafeHmap<K, V> = Arc<Mutex<HashMap<K, V>>>;
+use rand::Rng;
 
+use crate::command::Propagate;
+use crate::error::Result;
+use crate::parser::RespValue;
 
+pub mod value;
+pub use value::{RedisValue, SortedSet, Stream};
+pub mod blocking;
+use blocking::{BlockedClients, BlockedOp, Waiter};
+pub mod lazyfree;
+pub mod dict;
+use dict::Dict;
 
-#[derive(Clone)]
+type ThreadSafeKeyspace = Arc<Mutex<Keyspace>>;
+
+
+// wall clock in unix milliseconds, the unit every expiry is kept in
+pub fn unix_time_ms() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|elapsed| {elapsed.as_millis() as u64})
+        .unwrap_or(0)
+}
+
+
+/* access clocks kept per entry for eviction, following redis
+ * LRU: 24 bit clock in seconds of the last access
+ * LFU: logarithmic access counter plus the minute it was last decayed
+ * */
+const LRU_CLOCK_MAX: u64 = (1 << 24) - 1;
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+// minutes of inactivity that halve nothing but take one off the counter
+const LFU_DECAY_TIME: u64 = 1;
+
+pub fn lru_clock() -> u32{
+    ((unix_time_ms() / 1000) & LRU_CLOCK_MAX) as u32
+}
+
+fn lfu_minutes() -> u16{
+    ((unix_time_ms() / 60000) & 0xffff) as u16
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry{
+    pub value: RedisValue,
+    // absolute deadline in unix milliseconds, None for persistent keys
+    // only changed through the keyspace so the volatile index stays in sync
+    expires_at: Option<u64>,
+    lru: u32,
+    lfu_counter: u8,
+    lfu_decr_time: u16
+}
+
+impl Entry{
+    pub fn new(value: RedisValue) -> Self{
+        Self::with_expiry(value, None)
+    }
+
+    pub fn with_expiry(value: RedisValue, expires_at: Option<u64>) -> Self{
+        Self{value, expires_at, lru: lru_clock(), lfu_counter: LFU_INIT_VAL, lfu_decr_time: lfu_minutes()}
+    }
+
+    pub fn expires_at(&self) -> Option<u64>{
+        self.expires_at
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool{
+        self.expires_at.is_some_and(|deadline| {deadline <= now})
+    }
+
+    // seconds since the entry was last accessed, OBJECT IDLETIME
+    pub fn idle_secs(&self) -> u64{
+        let now = lru_clock() as u64;
+        let lru = self.lru as u64;
+        // the clock wraps every 194 days
+        if now >= lru {now - lru} else {LRU_CLOCK_MAX - lru + now}
+    }
+
+    // decayed access counter, OBJECT FREQ
+    pub fn lfu_frequency(&self) -> u8{
+        let elapsed = lfu_minutes().wrapping_sub(self.lfu_decr_time) as u64;
+        let periods = elapsed / LFU_DECAY_TIME;
+        self.lfu_counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    // record one access on both clocks
+    pub fn touch(&mut self){
+        self.lru = lru_clock();
+        let mut counter = self.lfu_frequency();
+        if counter < u8::MAX {
+            let baseval = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            let p = 1.0 / (baseval * LFU_LOG_FACTOR + 1.0);
+            if rand::thread_rng().gen::<f64>() < p {counter += 1;}
+        }
+        self.lfu_counter = counter;
+        self.lfu_decr_time = lfu_minutes();
+    }
+}
+
+
+/* key to entry map with lazy expiration
+ * a key past its deadline is dropped the first time it is looked up,
+ * so callers never observe an expired entry. keys carrying a ttl are also
+ * kept in a dense vector so the active cycle can sample them at random
+ * */
+#[derive(Debug, Default)]
+pub struct Keyspace{
+    entries: Dict<Box<[u8]>, Entry>,
+    volatile: Vec<Box<[u8]>>,
+    // position of every volatile key inside `volatile`
+    volatile_pos: HashMap<Box<[u8]>, usize>,
+    pub blocked: BlockedClients
+}
+
+impl Keyspace{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    fn track_volatile(&mut self, key: &[u8], volatile: bool){
+        match (volatile, self.volatile_pos.contains_key(key)) {
+            (true, false) => {
+                self.volatile_pos.insert(Box::from(key), self.volatile.len());
+                self.volatile.push(Box::from(key));
+            },
+            (false, true) => {
+                let pos = self.volatile_pos.remove(key).unwrap();
+                self.volatile.swap_remove(pos);
+                if let Some(moved) = self.volatile.get(pos) {
+                    self.volatile_pos.insert(moved.clone(), pos);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // returns true if the key was present but already expired, and removes it
+    fn expire_if_needed(&mut self, key: &[u8]) -> bool{
+        let expired = self.entries.get(key).is_some_and(|entry| {entry.is_expired(unix_time_ms())});
+        if expired {
+            self.entries.remove(key);
+            self.track_volatile(key, false);
+        }
+        expired
+    }
+
+    // lookups count as an access for the eviction clocks
+    pub fn get(&mut self, key: &[u8]) -> Option<&Entry>{
+        self.get_mut(key).map(|entry| {&*entry})
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry>{
+        self.expire_if_needed(key);
+        let entry = self.entries.get_mut(key)?;
+        entry.touch();
+        Some(entry)
+    }
+
+    // same as get but leaves the access clocks alone, for TYPE, TTL and friends
+    pub fn peek(&mut self, key: &[u8]) -> Option<&Entry>{
+        self.expire_if_needed(key);
+        self.entries.get(key)
+    }
+
+    /* shared lookup for commands reading several keys at once, an expired entry
+     * reads as missing but stays for the expiry paths, the clocks are left alone
+     * */
+    pub fn lookup(&self, key: &[u8]) -> Option<&Entry>{
+        self.entries.get(key).filter(|entry| {!entry.is_expired(unix_time_ms())})
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> bool{
+        self.peek(key).is_some()
+    }
+
+    // typed lookups, Ok(None) for a missing key and WRONGTYPE for another type
+    pub fn get_string(&mut self, key: &[u8]) -> Result<Option<&Vec<u8>>>{
+        self.get(key).map(|entry| {entry.value.as_string()}).transpose()
+    }
+
+    // live entry under the key, a fresh one built by `make` when there is none
+    pub fn get_or_insert_with(&mut self, key: &[u8], make: impl FnOnce() -> RedisValue) -> &mut Entry{
+        if !self.contains_key(key) {
+            self.entries.insert(Box::from(key), Entry::new(make()));
+            self.blocked.signal_ready(key);
+        }
+        self.get_mut(key).unwrap()
+    }
+
+    // aggregates that lost their last element do not linger in the keyspace
+    pub fn remove_if_empty(&mut self, key: &[u8]){
+        if self.entries.get(key).is_some_and(|entry| {entry.value.is_empty_aggregate()}) {
+            self.entries.remove(key);
+            self.track_volatile(key, false);
+        }
+    }
+
+    // returns the live entry previously stored under the key
+    pub fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry>{
+        self.expire_if_needed(key);
+        self.track_volatile(key, entry.expires_at.is_some());
+        self.blocked.signal_ready(key);
+        self.entries.insert(Box::from(key), entry)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry>{
+        self.expire_if_needed(key);
+        self.track_volatile(key, false);
+        self.entries.remove(key)
+    }
+
+    // attach or drop the deadline of a live key, false if there is no such key
+    pub fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> bool{
+        self.expire_if_needed(key);
+        match self.entries.get_mut(key) {
+            Some(entry) => entry.expires_at = expires_at,
+            None => return false
+        }
+        self.track_volatile(key, expires_at.is_some());
+        true
+    }
+
+    // includes keys that expired but were not looked up since
+    pub fn len(&self) -> usize{
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.entries.is_empty()
+    }
+
+    // live keys in no particular order, expired ones are skipped but left for the expiry paths to drop
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]>{
+        let now = unix_time_ms();
+        self.entries.iter().filter(move |(_, entry)| {!entry.is_expired(now)}).map(|(key, _)| {&**key})
+    }
+
+    // a random live key, expired keys hit on the way are dropped
+    pub fn random_key(&mut self) -> Option<Vec<u8>>{
+        while let Some((key, _)) = self.entries.random_entry() {
+            let key = key.to_vec();
+            if !self.expire_if_needed(&key) {return Some(key);}
+        }
+        None
+    }
+
+    /* one step of SCAN over every entry, expired ones included, see Dict::scan.
+     * only takes &self so the caller can keep what it collected borrowed
+     * */
+    pub fn scan<'a>(&'a self, cursor: u64, mut visit: impl FnMut(&'a [u8], &'a Entry)) -> u64{
+        self.entries.scan(cursor, |key, entry| {visit(key, entry)})
+    }
+
+    /* empties the keyspace and hands back what it held, so the caller decides
+     * where the entries are freed. blocked clients stay blocked
+     * */
+    pub fn flush(&mut self) -> Dict<Box<[u8]>, Entry>{
+        self.volatile.clear();
+        self.volatile_pos.clear();
+        std::mem::take(&mut self.entries)
+    }
+
+    // give clients blocked on keys that became available a chance to run,
+    // oldest waiter first. serving one may create keys others wait for
+    pub fn serve_blocked(&mut self){
+        loop {
+            let ready_keys = self.blocked.take_ready_keys();
+            if ready_keys.is_empty() {return;}
+            for key in ready_keys {
+                for client_id in self.blocked.queue_snapshot(&key) {
+                    if !self.contains_key(&key) {break;}
+                    // a client that hung up while parked must not swallow the element, the
+                    // dropped waiter wakes its thread and the next one in line gets served
+                    if !self.blocked.is_connected(client_id) {
+                        self.blocked.forget_peer(client_id);
+                        continue;
+                    }
+                    let mut waiter = match self.blocked.take_waiter(client_id) {
+                        Some(waiter) => waiter,
+                        None => continue
+                    };
+                    let served = (waiter.op)(self);
+                    self.blocked.put_back(client_id, waiter);
+                    // a failing op, e.g. the key now holds another type, leaves the client blocked
+                    if let Ok(Some((reply, propagate))) = served {
+                        let waiter = self.blocked.unblock(client_id).unwrap();
+                        // the rewritten pop has to reach replicas after the push that fed it,
+                        // so it waits for the writer instead of riding back with the reply
+                        let propagate = match propagate {
+                            Propagate::Rewritten(commands) => {
+                                blocking::propagate_served(commands);
+                                Propagate::nothing()
+                            },
+                            verbatim => verbatim
+                        };
+                        let _ = waiter.reply.send((reply, propagate));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn volatile_len(&self) -> usize{
+        self.volatile.len()
+    }
+
+    // look at up to `num_samples` random keys with a ttl, dropping the expired ones
+    // returns how many were sampled and how many of those were expired
+    pub fn sample_expired(&mut self, num_samples: usize) -> (usize, usize){
+        let now = unix_time_ms();
+        let mut rng = rand::thread_rng();
+        let num_samples = num_samples.min(self.volatile.len());
+        let mut num_expired = 0;
+        for _ in 0..num_samples {
+            let key = self.volatile[rng.gen_range(0..self.volatile.len())].clone();
+            if self.entries.get(&key).is_some_and(|entry| {entry.is_expired(now)}) {
+                self.entries.remove(&key);
+                self.track_volatile(&key, false);
+                num_expired += 1;
+            }
+        }
+        (num_samples, num_expired)
+    }
+}
+
+
+/* active expiration, modelled after the slow cycle of redis
+ * every tick the cycle samples batches of volatile keys and keeps going while
+ * more than ACCEPTABLE_STALE percent of a batch turned out expired, bounded by
+ * a slice of the tick so writers are not starved. the lock is released between
+ * batches for the same reason
+ * */
+const ACTIVE_EXPIRE_CYCLE_HZ: u64 = 10;
+const ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP: usize = 20;
+const ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE: usize = 10;
+const ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC: u64 = 25;
+
+// one tick of the cycle, returns the number of keys removed
+pub fn active_expire_cycle(storage: &RedisStorage, time_limit: Duration) -> usize{
+    let start = Instant::now();
+    let mut total_expired = 0;
+    loop {
+        let (sampled, expired) = storage.lock().sample_expired(ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP);
+        total_expired += expired;
+        if sampled == 0 || expired*100 <= sampled*ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE {break;}
+        if start.elapsed() >= time_limit {break;}
+    }
+    total_expired
+}
+
+pub fn spawn_active_expire(storage: RedisStorage) -> thread::JoinHandle<()>{
+    let tick = Duration::from_millis(1000 / ACTIVE_EXPIRE_CYCLE_HZ);
+    let time_limit = tick * ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC as u32 / 100;
+    thread::spawn(move || {
+        loop {
+            thread::sleep(tick);
+            active_expire_cycle(&storage, time_limit);
+        }
+    })
+}
+
+
+#[derive(Clone, Default)]
 pub struct RedisStorage{
-    pub data: ThreadSafeHmap<Box<[u8]>, Box<[u8]>>
+    pub data: ThreadSafeKeyspace
 }
 
 
 impl RedisStorage{
     pub fn new() -> Self{
-        let data = Arc::new(Mutex::new(HashMap::new()));
-        Self {data}
+        Self::default()
+    }
+
+    pub fn lock(&self) -> KeyspaceGuard<'_>{
+        KeyspaceGuard(self.data.lock().unwrap())
+    }
+
+    /* run `op` now, or park the calling client until a write makes it succeed
+     * None as timeout blocks forever. the attempt and the registration happen
+     * under one lock so a push landing in between can not be missed
+     * */
+    pub fn block_on_keys(
+        &self, client_id: u64, keys: Vec<Box<[u8]>>, timeout: Option<Duration>,
+        mut op: BlockedOp, timeout_reply: RespValue
+    ) -> Result<(RespValue, Propagate)>{
+        let rx = {
+            let mut data = self.lock();
+            if let Some(served) = op(&mut data)? {return Ok(served);}
+            let (tx, rx) = mpsc::channel();
+            data.blocked.block(client_id, Waiter{keys, op, timeout_reply: timeout_reply.clone(), reply: tx});
+            rx
+        };
+        let received = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).ok(),
+            None => rx.recv().ok()
+        };
+        if let Some(served) = received {return Ok(served);}
+
+        // timed out, unless a writer served us right before we got the lock back.
+        // nothing changed in the first case, so replicas get nothing either
+        let timed_out = (timeout_reply, Propagate::nothing());
+        let mut data = self.lock();
+        match data.blocked.unblock(client_id) {
+            Some(_) => Ok(timed_out),
+            None => Ok(rx.try_recv().unwrap_or(timed_out))
+        }
     }
 
 
-    pub fn from_hashmap(data: HashMap<Box<[u8]>, Box<[u8]>>) -> Self{
+    pub fn from_keyspace(data: Keyspace) -> Self{
         let data = Arc::new(Mutex::new(data));
         Self {data}
     }
 }
+
+
+// exclusive access to the keyspace, blocked clients whose keys became ready
+// are served before the lock is given up
+pub struct KeyspaceGuard<'a>(MutexGuard<'a, Keyspace>);
+
+impl Deref for KeyspaceGuard<'_>{
+    type Target = Keyspace;
+
+    fn deref(&self) -> &Keyspace{
+        &self.0
+    }
+}
+
+impl DerefMut for KeyspaceGuard<'_>{
+    fn deref_mut(&mut self) -> &mut Keyspace{
+        &mut self.0
+    }
+}
+
+impl Drop for KeyspaceGuard<'_>{
+    fn drop(&mut self){
+        self.0.serve_blocked();
+    }
+}
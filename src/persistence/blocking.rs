@@ -0,0 +1,135 @@
+/* bookkeeping for clients parked in BLPOP and friends
+ * a waiter is queued on every key it watches, first come first served. when
+ * one of those keys shows up in the keyspace the key is marked ready, and the
+ * waiters queued on it get a chance to run their operation before the lock
+ * guarding the keyspace is released, the reply travels back over a channel
+ * */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc;
+
+use crate::command::Propagate;
+use crate::error::Result;
+use crate::parser::RespValue;
+use super::Keyspace;
+
+
+// runs against the keyspace once a watched key is ready, Ok(None) keeps the client blocked
+pub type BlockedOp = Box<dyn FnMut(&mut Keyspace) -> Result<Option<(RespValue, Propagate)>> + Send>;
+
+/* what the clients a write woke up did, as replicas should run it
+ * blocked clients are served by the thread holding the keyspace lock when the
+ * write lands, so keeping this per thread lets that writer propagate them right
+ * after its own command and never ahead of it
+ * */
+thread_local! {
+    static SERVED: RefCell<Vec<Vec<Vec<u8>>>> = const {RefCell::new(vec!())};
+}
+
+pub(super) fn propagate_served(commands: Vec<Vec<Vec<u8>>>){
+    SERVED.with(|served| {served.borrow_mut().extend(commands)});
+}
+
+pub fn take_served() -> Vec<Vec<Vec<u8>>>{
+    SERVED.with(|served| {std::mem::take(&mut *served.borrow_mut())})
+}
+
+// tells whether the connection behind a client id is still open
+pub type PeerProbe = Box<dyn Fn() -> bool + Send>;
+
+pub struct Waiter{
+    pub keys: Vec<Box<[u8]>>,
+    pub op: BlockedOp,
+    // what the client gets when its timeout passes
+    pub timeout_reply: RespValue,
+    pub reply: mpsc::Sender<(RespValue, Propagate)>
+}
+
+#[derive(Default)]
+pub struct BlockedClients{
+    // keyed by client id, a client blocks on one command at a time
+    waiters: HashMap<u64, Waiter>,
+    queues: HashMap<Box<[u8]>, VecDeque<u64>>,
+    ready_keys: Vec<Box<[u8]>>,
+    peers: HashMap<u64, PeerProbe>
+}
+
+impl std::fmt::Debug for BlockedClients{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        f.debug_struct("BlockedClients")
+            .field("waiters", &self.waiters.len())
+            .field("ready_keys", &self.ready_keys)
+            .finish()
+    }
+}
+
+impl BlockedClients{
+    pub fn block(&mut self, client_id: u64, waiter: Waiter){
+        for key in &waiter.keys {
+            self.queues.entry(key.clone()).or_default().push_back(client_id);
+        }
+        self.waiters.insert(client_id, waiter);
+    }
+
+    // drop the client from every queue, handing back its waiter if it was still blocked
+    pub fn unblock(&mut self, client_id: u64) -> Option<Waiter>{
+        let waiter = self.waiters.remove(&client_id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|id| {*id != client_id});
+                if queue.is_empty() {self.queues.remove(key);}
+            }
+        }
+        Some(waiter)
+    }
+
+    pub fn register_peer(&mut self, client_id: u64, probe: PeerProbe){
+        self.peers.insert(client_id, probe);
+    }
+
+    // the connection is gone, so is anything it was still waiting for
+    pub fn forget_peer(&mut self, client_id: u64){
+        self.peers.remove(&client_id);
+        self.unblock(client_id);
+    }
+
+    // clients without a probe, e.g. ones driven from tests, count as connected
+    pub fn is_connected(&self, client_id: u64) -> bool{
+        self.peers.get(&client_id).is_none_or(|probe| {probe()})
+    }
+
+    pub fn is_blocked(&self, client_id: u64) -> bool{
+        self.waiters.contains_key(&client_id)
+    }
+
+    pub fn num_blocked(&self) -> usize{
+        self.waiters.len()
+    }
+
+    pub fn has_waiters(&self, key: &[u8]) -> bool{
+        self.queues.contains_key(key)
+    }
+
+    pub fn signal_ready(&mut self, key: &[u8]){
+        if self.has_waiters(key) && !self.ready_keys.iter().any(|ready| {&ready[..] == key}) {
+            self.ready_keys.push(Box::from(key));
+        }
+    }
+
+    pub(super) fn take_ready_keys(&mut self) -> Vec<Box<[u8]>>{
+        std::mem::take(&mut self.ready_keys)
+    }
+
+    pub(super) fn queue_snapshot(&self, key: &[u8]) -> Vec<u64>{
+        self.queues.get(key).map(|queue| {queue.iter().copied().collect()}).unwrap_or_default()
+    }
+
+    pub(super) fn take_waiter(&mut self, client_id: u64) -> Option<Waiter>{
+        self.waiters.remove(&client_id)
+    }
+
+    pub(super) fn put_back(&mut self, client_id: u64, waiter: Waiter){
+        self.waiters.insert(client_id, waiter);
+    }
+}
@@ -0,0 +1,417 @@
+/* hash table after redis' dict
+ * chained buckets in power of two tables. growing or shrinking allocates a
+ * second table and moves the buckets over a few at a time, one step on every
+ * write, so no single command pays for rehashing a huge table. during that
+ * time lookups check both tables and new entries only go to the new one.
+ *
+ * scan walks the buckets with a reverse binary cursor: the high bits of the
+ * bucket index are incremented first, so a cursor handed out before a resize
+ * still names the right place after it, and every element present for the
+ * whole walk is reported at least once (some may come back twice)
+ * */
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+use rand::Rng;
+
+
+const INITIAL_SIZE: usize = 4;
+// shrink once no more than one bucket in MIN_FILL is used
+const MIN_FILL: usize = 8;
+// empty buckets a rehash step may skip per bucket it is asked to move
+const EMPTY_VISITS_PER_STEP: usize = 10;
+
+
+#[derive(Clone)]
+struct Table<K, V>{
+    buckets: Vec<Vec<(K, V)>>,
+    used: usize
+}
+
+impl<K, V> Default for Table<K, V>{
+    fn default() -> Self{
+        Self{buckets: vec!(), used: 0}
+    }
+}
+
+impl<K, V> Table<K, V>{
+    fn with_size(size: usize) -> Self{
+        Self{buckets: (0..size).map(|_| {vec!()}).collect(), used: 0}
+    }
+
+    fn mask(&self) -> u64{
+        (self.buckets.len() as u64).wrapping_sub(1)
+    }
+
+    fn bucket_of(&self, hash: u64) -> usize{
+        (hash & self.mask()) as usize
+    }
+}
+
+
+#[derive(Clone)]
+pub struct Dict<K, V, S = RandomState>{
+    tables: [Table<K, V>; 2],
+    // next bucket of the old table to move, None when not rehashing
+    rehash_idx: Option<usize>,
+    hasher: S
+}
+
+impl<K, V, S: Default> Default for Dict<K, V, S>{
+    fn default() -> Self{
+        Self{tables: [Table::default(), Table::default()], rehash_idx: None, hasher: S::default()}
+    }
+}
+
+impl<K, V> Dict<K, V>{
+    pub fn new() -> Self{
+        Self::default()
+    }
+}
+
+impl<K, V, S> Dict<K, V, S>{
+    pub fn len(&self) -> usize{
+        self.tables[0].used + self.tables[1].used
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+
+    pub fn is_rehashing(&self) -> bool{
+        self.rehash_idx.is_some()
+    }
+
+    // the tables a lookup has to look at
+    fn live_tables(&self) -> &[Table<K, V>]{
+        if self.is_rehashing() {&self.tables} else {&self.tables[..1]}
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)>{
+        self.live_tables().iter()
+            .flat_map(|table| {table.buckets.iter().flatten()})
+            .map(|(key, value)| {(key, value)})
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K>{
+        self.iter().map(|(key, _)| {key})
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V>{
+        self.iter().map(|(_, value)| {value})
+    }
+
+    /* calls `visit` with every element of the bucket(s) under `cursor` and
+     * returns the cursor to continue from, 0 once the walk is complete. while
+     * rehashing the bucket of the smaller table is visited along with every
+     * bucket of the larger table it expands to
+     * */
+    pub fn scan<'a>(&'a self, cursor: u64, mut visit: impl FnMut(&'a K, &'a V)) -> u64{
+        if self.is_empty() {return 0;}
+        let mut emit = |table: &'a Table<K, V>, cursor: u64| {
+            table.buckets[table.bucket_of(cursor)].iter().for_each(|(key, value)| {visit(key, value)});
+        };
+        // bumps the masked bits of the cursor, most significant first
+        let next = |cursor: u64, mask: u64| {((cursor | !mask).reverse_bits().wrapping_add(1)).reverse_bits()};
+
+        let mut cursor = cursor;
+        if !self.is_rehashing() {
+            let table = &self.tables[0];
+            emit(table, cursor);
+            return next(cursor, table.mask());
+        }
+        let (small, large) = match self.tables[0].buckets.len() <= self.tables[1].buckets.len() {
+            true => (&self.tables[0], &self.tables[1]),
+            false => (&self.tables[1], &self.tables[0])
+        };
+        let (small_mask, large_mask) = (small.mask(), large.mask());
+        emit(small, cursor);
+        loop {
+            emit(large, cursor);
+            cursor = next(cursor, large_mask);
+            // done once the bits only the larger table has wrapped around
+            if cursor & (small_mask ^ large_mask) == 0 {return cursor;}
+        }
+    }
+
+    /* an element of a random non empty bucket, then a random one of its chain.
+     * like redis this favors elements in short chains a little
+     * */
+    pub fn random_entry(&self) -> Option<(&K, &V)>{
+        if self.is_empty() {return None;}
+        let mut rng = rand::thread_rng();
+        let bucket = match self.rehash_idx {
+            Some(rehash_idx) => {
+                // buckets of the old table before rehash_idx are known to be empty
+                let old_size = self.tables[0].buckets.len();
+                let total = old_size + self.tables[1].buckets.len();
+                loop {
+                    let idx = rng.gen_range(rehash_idx..total);
+                    let bucket = match idx >= old_size {
+                        true => &self.tables[1].buckets[idx - old_size],
+                        false => &self.tables[0].buckets[idx]
+                    };
+                    if !bucket.is_empty() {break bucket;}
+                }
+            },
+            None => loop {
+                let bucket = &self.tables[0].buckets[rng.gen_range(0..self.tables[0].buckets.len())];
+                if !bucket.is_empty() {break bucket;}
+            }
+        };
+        let (key, value) = &bucket[rng.gen_range(0..bucket.len())];
+        Some((key, value))
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Dict<K, V, S>{
+    fn find<Q>(&self, key: &Q) -> Option<(usize, usize, usize)>
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        if self.is_empty() {return None;}
+        let hash = self.hasher.hash_one(key);
+        self.live_tables().iter().enumerate().find_map(|(t, table)| {
+            let bucket = table.bucket_of(hash);
+            let pos = table.buckets.get(bucket)?.iter().position(|(candidate, _)| {candidate.borrow() == key})?;
+            Some((t, bucket, pos))
+        })
+    }
+
+    /* moves up to `n` buckets of the old table over, giving up after visiting
+     * n * EMPTY_VISITS_PER_STEP empty ones so a sparse table does not stall the caller
+     * */
+    fn rehash(&mut self, n: usize){
+        let mut idx = match self.rehash_idx {
+            Some(idx) => idx,
+            None => return
+        };
+        let mut empty_visits = n * EMPTY_VISITS_PER_STEP;
+        for _ in 0..n {
+            if self.tables[0].used == 0 {break;}
+            while self.tables[0].buckets[idx].is_empty() {
+                idx += 1;
+                empty_visits -= 1;
+                if empty_visits == 0 {
+                    self.rehash_idx = Some(idx);
+                    return;
+                }
+            }
+            let bucket = mem::take(&mut self.tables[0].buckets[idx]);
+            self.tables[0].used -= bucket.len();
+            for (key, value) in bucket {
+                let target = self.tables[1].bucket_of(self.hasher.hash_one(&key));
+                self.tables[1].buckets[target].push((key, value));
+                self.tables[1].used += 1;
+            }
+            idx += 1;
+        }
+        if self.tables[0].used == 0 {
+            self.tables[0] = mem::take(&mut self.tables[1]);
+            self.rehash_idx = None;
+        }else {
+            self.rehash_idx = Some(idx);
+        }
+    }
+
+    // resizes to the smallest power of two holding `len` elements, a no-op while rehashing
+    fn resize(&mut self, len: usize){
+        if self.is_rehashing() {return;}
+        let size = len.max(INITIAL_SIZE).next_power_of_two();
+        if size == self.tables[0].buckets.len() {return;}
+        // the first allocation has nothing to move
+        if self.tables[0].buckets.is_empty() {
+            self.tables[0] = Table::with_size(size);
+            return;
+        }
+        self.tables[1] = Table::with_size(size);
+        self.rehash_idx = Some(0);
+    }
+
+    fn expand_if_needed(&mut self){
+        let table = &self.tables[0];
+        if table.buckets.is_empty() || table.used >= table.buckets.len() {
+            self.resize(table.used + 1);
+        }
+    }
+
+    fn shrink_if_needed(&mut self){
+        let table = &self.tables[0];
+        if table.buckets.len() > INITIAL_SIZE && table.used * MIN_FILL <= table.buckets.len() {
+            self.resize(table.used);
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        let (t, bucket, pos) = self.find(key)?;
+        Some(&self.tables[t].buckets[bucket][pos].1)
+    }
+
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        let (t, bucket, pos) = self.find(key)?;
+        let (key, value) = &self.tables[t].buckets[bucket][pos];
+        Some((key, value))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        self.find(key).is_some()
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        self.rehash(1);
+        let (t, bucket, pos) = self.find(key)?;
+        Some(&mut self.tables[t].buckets[bucket][pos].1)
+    }
+
+    // returns the value previously stored under the key
+    pub fn insert(&mut self, key: K, value: V) -> Option<V>{
+        self.rehash(1);
+        if let Some((t, bucket, pos)) = self.find(&key) {
+            return Some(mem::replace(&mut self.tables[t].buckets[bucket][pos].1, value));
+        }
+        self.expand_if_needed();
+        let table = &mut self.tables[if self.rehash_idx.is_some() {1} else {0}];
+        let bucket = table.bucket_of(self.hasher.hash_one(&key));
+        table.buckets[bucket].push((key, value));
+        table.used += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        self.rehash(1);
+        let (t, bucket, pos) = self.find(key)?;
+        let (_, value) = self.tables[t].buckets[bucket].swap_remove(pos);
+        self.tables[t].used -= 1;
+        self.shrink_if_needed();
+        Some(value)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool){
+        for table in self.tables.iter_mut() {
+            for bucket in table.buckets.iter_mut() {
+                let before = bucket.len();
+                bucket.retain_mut(|(key, value)| {keep(key, value)});
+                table.used -= before - bucket.len();
+            }
+        }
+        self.shrink_if_needed();
+    }
+}
+
+impl<K, V, S> IntoIterator for Dict<K, V, S>{
+    type Item = (K, V);
+    type IntoIter = std::iter::Flatten<std::iter::Flatten<std::array::IntoIter<Vec<Vec<(K, V)>>, 2>>>;
+
+    fn into_iter(self) -> Self::IntoIter{
+        self.tables.map(|table| {table.buckets}).into_iter().flatten().flatten()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> FromIterator<(K, V)> for Dict<K, V, S>{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self{
+        let mut dict = Self::default();
+        dict.extend(iter);
+        dict
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for Dict<K, V, S>{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I){
+        iter.into_iter().for_each(|(key, value)| {self.insert(key, value);});
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, S: BuildHasher> PartialEq for Dict<K, V, S>{
+    fn eq(&self, other: &Self) -> bool{
+        self.len() == other.len() && self.iter().all(|(key, value)| {other.get(key) == Some(value)})
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for Dict<K, V, S>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+
+// a dict without values, what sets are made of
+#[derive(Clone, Default)]
+pub struct DictSet<K>(Dict<K, ()>);
+
+impl<K> DictSet<K>{
+    pub fn new() -> Self{
+        Self(Dict::new())
+    }
+
+    pub fn len(&self) -> usize{
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &K>{
+        self.0.keys()
+    }
+
+    pub fn scan<'a>(&'a self, cursor: u64, mut visit: impl FnMut(&'a K)) -> u64{
+        self.0.scan(cursor, |key, _| {visit(key)})
+    }
+
+    pub fn random(&self) -> Option<&K>{
+        self.0.random_entry().map(|(key, _)| {key})
+    }
+}
+
+impl<K: Hash + Eq> DictSet<K>{
+    // true if the member is new
+    pub fn insert(&mut self, member: K) -> bool{
+        self.0.insert(member, ()).is_none()
+    }
+
+    pub fn remove<Q>(&mut self, member: &Q) -> bool
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        self.0.remove(member).is_some()
+    }
+
+    pub fn contains<Q>(&self, member: &Q) -> bool
+    where K: Borrow<Q>, Q: Hash + Eq + ?Sized{
+        self.0.contains_key(member)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&K) -> bool){
+        self.0.retain(|member, _| {keep(member)});
+    }
+}
+
+impl<K: Hash + Eq> FromIterator<K> for DictSet<K>{
+    fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> Self{
+        Self(iter.into_iter().map(|member| {(member, ())}).collect())
+    }
+}
+
+impl<K: Hash + Eq> Extend<K> for DictSet<K>{
+    fn extend<I: IntoIterator<Item = K>>(&mut self, iter: I){
+        self.0.extend(iter.into_iter().map(|member| {(member, ())}));
+    }
+}
+
+impl<K: Hash + Eq> PartialEq for DictSet<K>{
+    fn eq(&self, other: &Self) -> bool{
+        self.0 == other.0
+    }
+}
+
+impl<K: fmt::Debug> fmt::Debug for DictSet<K>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,39 @@
+/* freeing large values off the request path
+ * like redis' lazyfree bio thread: UNLINK and FLUSHALL ASYNC detach what they
+ * remove under the keyspace lock and hand it to a background thread that runs
+ * the destructors. values cheap to drop are still freed inline
+ * */
+
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::thread;
+
+
+// elements a value may hold before dropping it inline is no longer worth it
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+type Garbage = Box<dyn Send>;
+
+fn lazyfree_queue() -> &'static Mutex<mpsc::Sender<Garbage>>{
+    static QUEUE: OnceLock<Mutex<mpsc::Sender<Garbage>>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Garbage>();
+        thread::spawn(move || {
+            // every received value is dropped as soon as the loop moves on
+            for garbage in rx {drop(garbage);}
+        });
+        Mutex::new(tx)
+    })
+}
+
+pub fn free_async<T: Send + 'static>(garbage: T){
+    // should the thread be gone the value is simply dropped right here
+    let _ = lazyfree_queue().lock().unwrap().send(Box::new(garbage));
+}
+
+// drops `garbage` in the background when `effort` says it is big enough
+pub fn free<T: Send + 'static>(garbage: T, effort: usize){
+    match effort > LAZYFREE_THRESHOLD {
+        true => free_async(garbage),
+        false => drop(garbage)
+    }
+}
@@ -0,0 +1,196 @@
+/* the value half of a keyspace entry
+ * one variant per redis data type, commands reach the payload through the
+ * typed accessors which turn a type mismatch into the WRONGTYPE error
+ * */
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::error::{RedisError, Result};
+use super::dict::{Dict, DictSet};
+
+mod zset;
+pub use zset::{LexBound, LexRange, ScoreRange, SortedSet};
+mod stream;
+pub use stream::{
+    Claimed, ClaimOptions, ConsumerGroup, Stream, StreamEntry, StreamFields, StreamId, TrimStrategy, BLOCK_MAX_ENTRIES
+};
+pub mod hyperloglog;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RedisValue{
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashObject),
+    Set(DictSet<Vec<u8>>),
+    ZSet(SortedSet),
+    Stream(Stream)
+}
+
+/* field value map where fields may carry their own deadline (HEXPIRE)
+ * expired fields are purged whenever the hash is looked up, so only the
+ * fields with a deadline are walked and the rest of the map stays untouched
+ * */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HashObject{
+    fields: Dict<Vec<u8>, Vec<u8>>,
+    field_expires: HashMap<Vec<u8>, u64>
+}
+
+impl HashObject{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    // drop every field past its deadline, returns how many went away
+    pub fn purge_expired(&mut self, now: u64) -> usize{
+        if self.field_expires.is_empty() {return 0;}
+        let expired = self.field_expires.iter()
+                        .filter(|(_, deadline)| {**deadline <= now})
+                        .map(|(field, _)| {field.clone()})
+                        .collect::<Vec<_>>();
+        for field in &expired {
+            self.field_expires.remove(field);
+            self.fields.remove(field);
+        }
+        expired.len()
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Vec<u8>>{
+        self.fields.get(field)
+    }
+
+    pub fn get_mut(&mut self, field: &[u8]) -> Option<&mut Vec<u8>>{
+        self.fields.get_mut(field)
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool{
+        self.fields.contains_key(field)
+    }
+
+    // overwriting a field drops its deadline like HSET does, true if the field is new
+    pub fn insert(&mut self, field: &[u8], value: Vec<u8>) -> bool{
+        self.field_expires.remove(field);
+        self.fields.insert(field.to_vec(), value).is_none()
+    }
+
+    // the in place update of HINCRBY and HINCRBYFLOAT, a deadline on the field stays
+    pub fn update(&mut self, field: &[u8], value: Vec<u8>){
+        match self.fields.get_mut(field) {
+            Some(current) => *current = value,
+            None => {self.fields.insert(field.to_vec(), value);}
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool{
+        self.field_expires.remove(field);
+        self.fields.remove(field).is_some()
+    }
+
+    pub fn expires_at(&self, field: &[u8]) -> Option<u64>{
+        self.field_expires.get(field).copied()
+    }
+
+    // None persists the field, false if there is no such field
+    pub fn set_expiry(&mut self, field: &[u8], deadline: Option<u64>) -> bool{
+        if !self.fields.contains_key(field) {return false;}
+        match deadline {
+            Some(deadline) => self.field_expires.insert(field.to_vec(), deadline),
+            None => self.field_expires.remove(field)
+        };
+        true
+    }
+
+    pub fn len(&self) -> usize{
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)>{
+        self.fields.iter()
+    }
+
+    // one step of HSCAN, see Dict::scan
+    pub fn scan<'a>(&'a self, cursor: u64, visit: impl FnMut(&'a Vec<u8>, &'a Vec<u8>)) -> u64{
+        self.fields.scan(cursor, visit)
+    }
+}
+
+macro_rules! typed_accessors {
+    ($($variant:ident: $ty:ty => $as_ref:ident, $as_mut:ident);* $(;)?) => {$(
+        pub fn $as_ref(&self) -> Result<&$ty>{
+            match self {
+                Self::$variant(inner) => Ok(inner),
+                _ => Err(RedisError::WrongType)
+            }
+        }
+
+        pub fn $as_mut(&mut self) -> Result<&mut $ty>{
+            match self {
+                Self::$variant(inner) => Ok(inner),
+                _ => Err(RedisError::WrongType)
+            }
+        }
+    )*};
+}
+
+impl RedisValue{
+    // name reported by TYPE
+    pub fn type_name(&self) -> &'static str{
+        match self {
+            Self::String(_) => "string",
+            Self::List(_) => "list",
+            Self::Hash(_) => "hash",
+            Self::Set(_) => "set",
+            Self::ZSet(_) => "zset",
+            Self::Stream(_) => "stream"
+        }
+    }
+
+    typed_accessors!(
+        String: Vec<u8> => as_string, as_string_mut;
+        List: VecDeque<Vec<u8>> => as_list, as_list_mut;
+        Hash: HashObject => as_hash, as_hash_mut;
+        Set: DictSet<Vec<u8>> => as_set, as_set_mut;
+        ZSet: SortedSet => as_zset, as_zset_mut;
+        Stream: Stream => as_stream, as_stream_mut;
+    );
+
+    // aggregates left without elements are removed from the keyspace like in redis
+    pub fn is_empty_aggregate(&self) -> bool{
+        match self {
+            Self::String(_) | Self::Stream(_) => false,
+            Self::List(items) => items.is_empty(),
+            Self::Hash(fields) => fields.is_empty(),
+            Self::Set(members) => members.is_empty(),
+            Self::ZSet(zset) => zset.is_empty()
+        }
+    }
+
+    // how much work dropping the value takes, roughly its number of allocations
+    pub fn free_effort(&self) -> usize{
+        match self {
+            Self::String(_) => 1,
+            Self::List(items) => items.len(),
+            Self::Hash(fields) => fields.len(),
+            Self::Set(members) => members.len(),
+            Self::ZSet(zset) => zset.len(),
+            Self::Stream(stream) => stream.len()
+        }
+    }
+}
+
+impl From<Vec<u8>> for RedisValue{
+    fn from(s: Vec<u8>) -> Self{
+        Self::String(s)
+    }
+}
+
+impl From<&[u8]> for RedisValue{
+    fn from(s: &[u8]) -> Self{
+        Self::String(s.to_vec())
+    }
+}
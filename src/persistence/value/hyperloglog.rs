@@ -0,0 +1,415 @@
+/* hyperloglog over plain string values, byte for byte the redis layout so keys
+ * moved between the two estimate the same. a 16 byte header ("HYLL", encoding,
+ * 3 unused bytes, cached cardinality little endian with the top bit marking it
+ * stale) is followed by the registers:
+ *   dense  - 16384 registers of 6 bits, packed starting from the low bits
+ *   sparse - run length opcodes, ZERO 00xxxxxx, XZERO 01xxxxxx yyyyyyyy and
+ *            VAL 1vvvvvxx, promoted to dense past SPARSE_MAX_BYTES or once a
+ *            register needs a value above 32
+ * */
+
+use crate::error::{RedisError, Result};
+
+
+const P: u32 = 14;
+const Q: u32 = 64 - P;
+pub const REGISTERS: usize = 1 << P;
+const REGISTER_MAX: u8 = 63;
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * 6).div_ceil(8);
+// hll-sparse-max-bytes
+const SPARSE_MAX_BYTES: usize = 3000;
+
+const ENCODING_DENSE: u8 = 0;
+const ENCODING_SPARSE: u8 = 1;
+
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+
+// MurmurHash64A, the seed redis uses for hll elements
+fn murmur_hash64a(key: &[u8], seed: u64) -> u64{
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (idx, byte) in tail.iter().enumerate().rev() {
+            h ^= (*byte as u64) << (8 * idx);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// the register an element maps to and the length of its 000..1 pattern
+fn pattern_len(element: &[u8]) -> (usize, u8){
+    let hash = murmur_hash64a(element, 0xadc83b19);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // the forced bit keeps the count at most Q + 1
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+
+fn dense_get(registers: &[u8], idx: usize) -> u8{
+    let (byte, fb) = (idx * 6 / 8, (idx * 6) & 7);
+    let b0 = registers[byte] as u32;
+    let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u32;
+    (((b0 >> fb) | (b1 << (8 - fb))) & REGISTER_MAX as u32) as u8
+}
+
+fn dense_put(registers: &mut [u8], idx: usize, value: u8){
+    let (byte, fb) = (idx * 6 / 8, (idx * 6) & 7);
+    let (value, max) = (value as u32, REGISTER_MAX as u32);
+    registers[byte] &= !((max << fb) as u8);
+    registers[byte] |= (value << fb) as u8;
+    // the last register does not spill into a next byte
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next &= !((max >> (8 - fb)) as u8);
+        *next |= (value >> (8 - fb)) as u8;
+    }
+}
+
+// true when the register went up
+fn dense_set(registers: &mut [u8], idx: usize, count: u8) -> bool{
+    if dense_get(registers, idx) >= count {return false;}
+    dense_put(registers, idx, count);
+    true
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Opcode{
+    Zero(usize),
+    XZero(usize),
+    Val(u8, usize)
+}
+
+impl Opcode{
+    fn decode(bytes: &[u8], at: usize) -> Option<Self>{
+        let byte = bytes[at];
+        Some(match byte & 0xc0 {
+            0x00 => Self::Zero((byte & 0x3f) as usize + 1),
+            0x40 => Self::XZero(((((byte & 0x3f) as usize) << 8) | *bytes.get(at + 1)? as usize) + 1),
+            _ => Self::Val(((byte >> 2) & 0x1f) + 1, (byte & 0x3) as usize + 1)
+        })
+    }
+
+    fn encode(self, out: &mut Vec<u8>){
+        match self {
+            Self::Zero(len) => out.push((len - 1) as u8),
+            Self::XZero(len) => out.extend([((len - 1) >> 8) as u8 | 0x40, ((len - 1) & 0xff) as u8]),
+            Self::Val(value, len) => out.push(((value - 1) << 2) | (len - 1) as u8 | 0x80)
+        }
+    }
+
+    fn size(self) -> usize{
+        if matches!(self, Self::XZero(_)) {2} else {1}
+    }
+
+    fn span(self) -> usize{
+        match self {
+            Self::Zero(len) | Self::XZero(len) | Self::Val(_, len) => len
+        }
+    }
+
+    // a zero run of any length up to a register count
+    fn zeros(len: usize) -> Self{
+        if len > SPARSE_ZERO_MAX_LEN {Self::XZero(len)} else {Self::Zero(len)}
+    }
+}
+
+// the opcodes of a sparse body, stopping early at a truncated one
+fn opcodes(sparse: &[u8]) -> impl Iterator<Item = (usize, Opcode)> + '_{
+    let mut at = 0;
+    std::iter::from_fn(move || {
+        if at >= sparse.len() {return None;}
+        let opcode = Opcode::decode(sparse, at)?;
+        let start = at;
+        at += opcode.size();
+        Some((start, opcode))
+    })
+}
+
+// calls `visit` with every non zero run, errors unless the runs cover exactly all registers
+fn walk_sparse(sparse: &[u8], mut visit: impl FnMut(usize, u8, usize)) -> Result<()>{
+    let mut idx = 0;
+    for (_, opcode) in opcodes(sparse) {
+        match opcode {
+            Opcode::Val(value, len) => {
+                if idx + len > REGISTERS {break;}
+                visit(idx, value, len);
+                idx += len;
+            },
+            _ => idx += opcode.span()
+        }
+    }
+    if idx != REGISTERS {return Err(RedisError::CorruptHll);}
+    Ok(())
+}
+
+
+/* the value under a key that PFADD creates, sparse and all zeros. the cached
+ * cardinality starts out valid at 0
+ * */
+pub fn new_hll() -> Vec<u8>{
+    let mut hll = Vec::with_capacity(HEADER_SIZE + 2);
+    hll.extend(b"HYLL");
+    hll.push(ENCODING_SPARSE);
+    hll.extend([0; 11]);
+    let mut left = REGISTERS;
+    while left > 0 {
+        let run = left.min(SPARSE_XZERO_MAX_LEN);
+        Opcode::XZero(run).encode(&mut hll);
+        left -= run;
+    }
+    hll
+}
+
+// header sanity, what redis checks before treating a string as an hll
+pub fn is_hll(value: &[u8]) -> bool{
+    value.len() >= HEADER_SIZE
+        && value.starts_with(b"HYLL")
+        && value[4] <= ENCODING_SPARSE
+        && (value[4] != ENCODING_DENSE || value.len() == DENSE_SIZE)
+}
+
+pub fn is_sparse(hll: &[u8]) -> bool{
+    hll[4] == ENCODING_SPARSE
+}
+
+fn invalidate_cache(hll: &mut [u8]){
+    hll[15] |= 1 << 7;
+}
+
+// false when the registers were already dense
+pub fn sparse_to_dense(hll: &mut Vec<u8>) -> Result<bool>{
+    if !is_sparse(hll) {return Ok(false);}
+    let mut dense = vec!(0; DENSE_SIZE);
+    // magic and cached cardinality carry over
+    dense[..HEADER_SIZE].copy_from_slice(&hll[..HEADER_SIZE]);
+    dense[4] = ENCODING_DENSE;
+    walk_sparse(&hll[HEADER_SIZE..], |idx, value, len| {
+        (idx..idx + len).for_each(|reg| {dense_put(&mut dense[HEADER_SIZE..], reg, value)});
+    })?;
+    *hll = dense;
+    Ok(true)
+}
+
+/* raises register `idx` to `count` in a sparse hll, splitting the opcode that
+ * covers it. the update is done in place the way redis does it so both produce
+ * the same bytes, including when to give up on sparse and promote to dense.
+ * returns whether the register changed
+ * */
+fn sparse_set(hll: &mut Vec<u8>, idx: usize, count: u8) -> Result<bool>{
+    if count > SPARSE_VAL_MAX_VALUE {return promote(hll, idx, count);}
+
+    // step 1, find the opcode covering the register
+    let sparse = &hll[HEADER_SIZE..];
+    let (mut first, mut prev, mut found) = (0, None, None);
+    for (at, opcode) in opcodes(sparse) {
+        if idx < first + opcode.span() {
+            found = Some((at, opcode));
+            break;
+        }
+        prev = Some(at);
+        first += opcode.span();
+    }
+    let (at, opcode) = found.ok_or(RedisError::CorruptHll)?;
+    let last = first + opcode.span() - 1;
+
+    // step 2, the trivial cases are updated in place
+    let mut seq = vec!();
+    match opcode {
+        Opcode::Val(current, _) if current >= count => return Ok(false),
+        Opcode::Val(_, 1) | Opcode::Zero(1) => Opcode::Val(count, 1).encode(&mut seq),
+        Opcode::Zero(_) | Opcode::XZero(_) => {
+            if idx != first {Opcode::zeros(idx - first).encode(&mut seq);}
+            Opcode::Val(count, 1).encode(&mut seq);
+            if idx != last {Opcode::zeros(last - idx).encode(&mut seq);}
+        },
+        Opcode::Val(current, _) => {
+            if idx != first {Opcode::Val(current, idx - first).encode(&mut seq);}
+            Opcode::Val(count, 1).encode(&mut seq);
+            if idx != last {Opcode::Val(current, last - idx).encode(&mut seq);}
+        }
+    }
+
+    // step 3, splice the new sequence in, unless it grows past the sparse limit. the
+    // limit counts the header too, like sdslen in redis
+    let grows = seq.len() > opcode.size();
+    if grows && hll.len() + seq.len() - opcode.size() > SPARSE_MAX_BYTES {
+        return promote(hll, idx, count);
+    }
+    let start = HEADER_SIZE + at;
+    hll.splice(start..start + opcode.size(), seq);
+
+    // step 4, merge adjacent VAL opcodes of the same value, up to 5 opcodes from prev on
+    let mut at = HEADER_SIZE + prev.unwrap_or(0);
+    let mut scans = 5;
+    while at < hll.len() && scans > 0 {
+        scans -= 1;
+        let current = Opcode::decode(hll, at).ok_or(RedisError::CorruptHll)?;
+        let (value, len) = match current {
+            Opcode::Val(value, len) => (value, len),
+            _ => {
+                at += current.size();
+                continue;
+            }
+        };
+        if at + 1 < hll.len() {
+            if let Some(Opcode::Val(next_value, next_len)) = Opcode::decode(hll, at + 1) {
+                if next_value == value && len + next_len <= SPARSE_VAL_MAX_LEN {
+                    let mut merged = vec!();
+                    Opcode::Val(value, len + next_len).encode(&mut merged);
+                    hll.splice(at..at + 2, merged);
+                    // try the merged opcode against the one after it too
+                    continue;
+                }
+            }
+        }
+        at += 1;
+    }
+    invalidate_cache(hll);
+    Ok(true)
+}
+
+// the register needs the dense encoding, PFADD always reports a change then
+fn promote(hll: &mut Vec<u8>, idx: usize, count: u8) -> Result<bool>{
+    sparse_to_dense(hll)?;
+    dense_set(&mut hll[HEADER_SIZE..], idx, count);
+    Ok(true)
+}
+
+fn set_register(hll: &mut Vec<u8>, idx: usize, count: u8) -> Result<bool>{
+    match is_sparse(hll) {
+        true => sparse_set(hll, idx, count),
+        false => Ok(dense_set(&mut hll[HEADER_SIZE..], idx, count))
+    }
+}
+
+// PFADD of a single element, true when a register changed
+pub fn add(hll: &mut Vec<u8>, element: &[u8]) -> Result<bool>{
+    let (idx, count) = pattern_len(element);
+    let changed = set_register(hll, idx, count)?;
+    if changed {invalidate_cache(hll);}
+    Ok(changed)
+}
+
+// register values of both encodings into `max`, keeping the larger of the two
+pub fn merge_into(max: &mut [u8], hll: &[u8]) -> Result<()>{
+    let registers = &hll[HEADER_SIZE..];
+    if !is_sparse(hll) {
+        for (idx, slot) in max.iter_mut().enumerate() {
+            *slot = (*slot).max(dense_get(registers, idx));
+        }
+        return Ok(());
+    }
+    walk_sparse(registers, |idx, value, len| {
+        max[idx..idx + len].iter_mut().for_each(|slot| {*slot = (*slot).max(value)});
+    })
+}
+
+// PFMERGE writes the merged registers through the destination's own encoding
+pub fn store_registers(hll: &mut Vec<u8>, max: &[u8], dense: bool) -> Result<()>{
+    if dense {sparse_to_dense(hll)?;}
+    for (idx, value) in max.iter().enumerate().filter(|(_, value)| {**value != 0}) {
+        // a sparse destination that turns out corrupted is left as is, like redis
+        if set_register(hll, idx, *value).is_err() {break;}
+    }
+    invalidate_cache(hll);
+    Ok(())
+}
+
+
+fn sigma(mut x: f64) -> f64{
+    if x == 1.0 {return f64::INFINITY;}
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {return z;}
+    }
+}
+
+fn tau(mut x: f64) -> f64{
+    if x == 0.0 || x == 1.0 {return 0.0;}
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {return z / 3.0;}
+    }
+}
+
+// the estimator from Ertl's "New cardinality estimation algorithms for HyperLogLog sketches"
+fn estimate(histogram: &[u32; Q as usize + 2]) -> u64{
+    let m = REGISTERS as f64;
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for count in histogram[1..=Q as usize].iter().rev() {
+        z += *count as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+// cardinality of one register per byte, the layout multi key PFCOUNT merges into
+pub fn count_raw(registers: &[u8]) -> u64{
+    let mut histogram = [0; Q as usize + 2];
+    registers.iter().for_each(|value| {histogram[*value as usize] += 1});
+    estimate(&histogram)
+}
+
+// PFCOUNT of a single key, served from and stored back into the cache
+pub fn count(hll: &mut [u8]) -> Result<u64>{
+    if hll[15] & (1 << 7) == 0 {
+        return Ok(u64::from_le_bytes(hll[8..16].try_into().unwrap()));
+    }
+    let mut histogram = [0; Q as usize + 2];
+    let registers = &hll[HEADER_SIZE..];
+    match is_sparse(hll) {
+        true => {
+            let mut covered = 0;
+            walk_sparse(registers, |_, value, len| {
+                histogram[value as usize] += len as u32;
+                covered += len;
+            })?;
+            histogram[0] += (REGISTERS - covered) as u32;
+        },
+        false => (0..REGISTERS).for_each(|idx| {histogram[dense_get(registers, idx) as usize] += 1})
+    }
+    let card = estimate(&histogram);
+    hll[8..16].copy_from_slice(&card.to_le_bytes());
+    Ok(card)
+}
+
+// every register of a dense hll, PFDEBUG GETREG
+pub fn dense_registers(hll: &[u8]) -> Vec<u8>{
+    (0..REGISTERS).map(|idx| {dense_get(&hll[HEADER_SIZE..], idx)}).collect()
+}
+
+pub fn sparse_opcodes(hll: &[u8]) -> Vec<Opcode>{
+    opcodes(&hll[HEADER_SIZE..]).map(|(_, opcode)| {opcode}).collect()
+}
@@ -0,0 +1,264 @@
+/* stream encoding
+ * entries are packed into blocks of up to BLOCK_MAX_ENTRIES, indexed by the id
+ * of the first entry appended to them, the way redis hangs listpacks off a
+ * radix tree. a block remembers the field names of its first entry and later
+ * entries with the same fields only store their values. deletion leaves a
+ * tombstone and a block goes away once all of its entries are gone
+ * */
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+mod group;
+pub use group::{Claimed, ClaimOptions, ConsumerGroup};
+
+
+// stream-node-max-entries
+pub const BLOCK_MAX_ENTRIES: usize = 100;
+
+// <ms>-<seq>
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId{
+    pub ms: u64,
+    pub seq: u64
+}
+
+impl StreamId{
+    pub const MIN: Self = Self{ms: 0, seq: 0};
+    pub const MAX: Self = Self{ms: u64::MAX, seq: u64::MAX};
+
+    pub fn new(ms: u64, seq: u64) -> Self{
+        Self{ms, seq}
+    }
+
+    // the id right after this one, None past the last possible id
+    pub fn successor(self) -> Option<Self>{
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self{ms: self.ms, seq}),
+            None => Some(Self{ms: self.ms.checked_add(1)?, seq: 0})
+        }
+    }
+
+    pub fn predecessor(self) -> Option<Self>{
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(Self{ms: self.ms, seq}),
+            None => Some(Self{ms: self.ms.checked_sub(1)?, seq: u64::MAX})
+        }
+    }
+}
+
+impl fmt::Display for StreamId{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+pub type StreamFields = Vec<(Vec<u8>, Vec<u8>)>;
+pub type StreamEntry = (StreamId, StreamFields);
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Values{
+    // same field names as the block's first entry, in the same order
+    Master(Vec<Vec<u8>>),
+    Own(StreamFields)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Block{
+    master_fields: Vec<Vec<u8>>,
+    ids: Vec<StreamId>,
+    // None marks a deleted entry
+    values: Vec<Option<Values>>,
+    live: usize
+}
+
+impl Block{
+    fn new(fields: &StreamFields) -> Self{
+        let master_fields = fields.iter().map(|(field, _)| {field.clone()}).collect();
+        Self{master_fields, ids: vec!(), values: vec!(), live: 0}
+    }
+
+    fn push(&mut self, id: StreamId, fields: StreamFields){
+        let same_fields = fields.len() == self.master_fields.len()
+                            && fields.iter().zip(&self.master_fields).all(|((field, _), master)| {field == master});
+        let values = match same_fields {
+            true => Values::Master(fields.into_iter().map(|(_, value)| {value}).collect()),
+            false => Values::Own(fields)
+        };
+        self.ids.push(id);
+        self.values.push(Some(values));
+        self.live += 1;
+    }
+
+    fn entry(&self, idx: usize) -> Option<StreamEntry>{
+        let fields = match self.values[idx].as_ref()? {
+            Values::Master(values) => self.master_fields.iter().cloned().zip(values.iter().cloned()).collect(),
+            Values::Own(fields) => fields.clone()
+        };
+        Some((self.ids[idx], fields))
+    }
+
+    fn delete(&mut self, idx: usize) -> bool{
+        if self.values[idx].take().is_none() {return false;}
+        self.live -= 1;
+        true
+    }
+
+    fn live_ids(&self) -> impl DoubleEndedIterator<Item = (usize, StreamId)> + '_{
+        self.ids.iter().copied().enumerate().filter(|(idx, _)| {self.values[*idx].is_some()})
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrimStrategy{
+    MaxLen(usize),
+    MinId(StreamId)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream{
+    blocks: BTreeMap<StreamId, Block>,
+    len: usize,
+    last_id: StreamId,
+    max_deleted_id: StreamId,
+    // every entry ever appended, deleted ones included
+    entries_added: u64,
+    groups: BTreeMap<Vec<u8>, ConsumerGroup>
+}
+
+impl Stream{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize{
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len == 0
+    }
+
+    pub fn last_id(&self) -> StreamId{
+        self.last_id
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId{
+        self.max_deleted_id
+    }
+
+    pub fn entries_added(&self) -> u64{
+        self.entries_added
+    }
+
+    pub fn num_blocks(&self) -> usize{
+        self.blocks.len()
+    }
+
+    // the id must be greater than last_id()
+    pub fn append(&mut self, id: StreamId, fields: StreamFields){
+        match self.blocks.last_entry() {
+            Some(mut block) if block.get().ids.len() < BLOCK_MAX_ENTRIES => block.get_mut().push(id, fields),
+            _ => {
+                let mut block = Block::new(&fields);
+                block.push(id, fields);
+                self.blocks.insert(id, block);
+            }
+        }
+        self.last_id = id;
+        self.len += 1;
+        self.entries_added += 1;
+    }
+
+    // key of the block that would hold `id`
+    fn block_key(&self, id: StreamId) -> Option<StreamId>{
+        self.blocks.range(..=id).next_back().map(|(key, _)| {*key})
+    }
+
+    // live entries with ids in [start, end], at most `count` of them
+    pub fn range(&self, start: StreamId, end: StreamId, reverse: bool, count: usize) -> Vec<StreamEntry>{
+        if start > end {return vec!();}
+        let from = self.block_key(start).unwrap_or(start);
+        let blocks = self.blocks.range(from..=end).map(|(_, block)| {block});
+        let in_range = |(_, id): &(usize, StreamId)| {(start..=end).contains(id)};
+        let entries: Box<dyn Iterator<Item = StreamEntry>> = match reverse {
+            false => Box::new(blocks.flat_map(|block| {
+                block.live_ids().filter(in_range).filter_map(|(idx, _)| {block.entry(idx)})
+            })),
+            true => Box::new(blocks.rev().flat_map(|block| {
+                block.live_ids().rev().filter(in_range).filter_map(|(idx, _)| {block.entry(idx)})
+            }))
+        };
+        entries.take(count).collect()
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<StreamEntry>{
+        let block = &self.blocks[&self.block_key(id)?];
+        block.entry(block.ids.binary_search(&id).ok()?)
+    }
+
+    pub fn first_id(&self) -> Option<StreamId>{
+        self.blocks.values().find_map(|block| {block.live_ids().next()}).map(|(_, id)| {id})
+    }
+
+    pub fn first_entry(&self) -> Option<StreamEntry>{
+        self.range(StreamId::MIN, StreamId::MAX, false, 1).pop()
+    }
+
+    pub fn last_entry(&self) -> Option<StreamEntry>{
+        self.range(StreamId::MIN, StreamId::MAX, true, 1).pop()
+    }
+
+    pub fn delete(&mut self, id: StreamId) -> bool{
+        let key = match self.block_key(id) {
+            Some(key) => key,
+            None => return false
+        };
+        let block = self.blocks.get_mut(&key).unwrap();
+        let deleted = block.ids.binary_search(&id).is_ok_and(|idx| {block.delete(idx)});
+        if !deleted {return false;}
+        if block.live == 0 {self.blocks.remove(&key);}
+        self.len -= 1;
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /* XTRIM and the trimming half of XADD, returns how many entries went away
+     * the approximate form only drops whole blocks, and stops at the first one
+     * it can not drop entirely. `limit` caps the work, 0 for no cap
+     * */
+    pub fn trim(&mut self, strategy: TrimStrategy, approx: bool, limit: usize) -> usize{
+        let mut removed = 0;
+        while let Some(mut first) = self.blocks.first_entry() {
+            let block = first.get_mut();
+            let whole_block = match strategy {
+                TrimStrategy::MaxLen(max) => self.len - block.live >= max,
+                TrimStrategy::MinId(min) => block.ids.last().is_some_and(|last| {*last < min})
+            };
+            if whole_block && (limit == 0 || removed + block.live <= limit) {
+                removed += block.live;
+                self.len -= block.live;
+                first.remove();
+                continue;
+            }
+            if approx {break;}
+
+            // exact trimming finishes inside the block
+            let live = block.live_ids().collect::<Vec<_>>();
+            for (idx, id) in live {
+                let doomed = match strategy {
+                    TrimStrategy::MaxLen(max) => self.len > max,
+                    TrimStrategy::MinId(min) => id < min
+                };
+                if !doomed || (limit != 0 && removed >= limit) {break;}
+                block.delete(idx);
+                self.len -= 1;
+                removed += 1;
+            }
+            break;
+        }
+        removed
+    }
+}
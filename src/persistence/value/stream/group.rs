@@ -0,0 +1,261 @@
+/* consumer groups
+ * a group remembers the last id it handed out and keeps every delivered but not
+ * yet acknowledged entry in its pending entries list (PEL). each pending entry
+ * is owned by one consumer, which keeps the same ids in its own PEL so either
+ * side can be walked in id order. entries_read counts how many entries of the
+ * stream's history the group has gone through, it is what lag is derived from
+ * and becomes unknown (None) once deletions make the count unreliable
+ * */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use super::{Stream, StreamEntry, StreamFields, StreamId};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry{
+    pub consumer: Vec<u8>,
+    pub delivery_time: u64,
+    pub delivery_count: u64
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Consumer{
+    // last time it tried anything, and last time it was actually handed entries
+    pub seen_time: u64,
+    pub active_time: Option<u64>,
+    pub pending: BTreeSet<StreamId>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerGroup{
+    pub last_id: StreamId,
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<Vec<u8>, Consumer>
+}
+
+impl ConsumerGroup{
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> Self{
+        Self{last_id, entries_read, pending: BTreeMap::new(), consumers: BTreeMap::new()}
+    }
+
+    // false when the consumer already existed
+    pub fn create_consumer(&mut self, name: &[u8], now: u64) -> bool{
+        if self.consumers.contains_key(name) {return false;}
+        self.consumers.insert(name.to_vec(), Consumer{seen_time: now, ..Consumer::default()});
+        true
+    }
+
+    // the consumer, created on first use, with its seen time bumped
+    pub fn touch_consumer(&mut self, name: &[u8], now: u64) -> &mut Consumer{
+        let consumer = self.consumers.entry(name.to_vec()).or_default();
+        consumer.seen_time = now;
+        consumer
+    }
+
+    // drops the consumer and whatever it still had pending, None if it did not exist
+    pub fn delete_consumer(&mut self, name: &[u8]) -> Option<usize>{
+        let consumer = self.consumers.remove(name)?;
+        consumer.pending.iter().for_each(|id| {self.pending.remove(id);});
+        Some(consumer.pending.len())
+    }
+
+    pub fn ack(&mut self, id: StreamId) -> bool{
+        let entry = match self.pending.remove(&id) {
+            Some(entry) => entry,
+            None => return false
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+
+    // records a delivery of `id` to `consumer`, taking the entry away from any previous owner
+    fn deliver(&mut self, id: StreamId, consumer: &[u8], now: u64){
+        let previous = self.pending.insert(id, PendingEntry{consumer: consumer.to_vec(), delivery_time: now, delivery_count: 1});
+        if let Some(previous) = previous {
+            self.consumers.get_mut(&previous.consumer).map(|owner| {owner.pending.remove(&id)});
+        }
+        self.consumers.entry(consumer.to_vec()).or_default().pending.insert(id);
+    }
+
+    fn set_owner(&mut self, id: StreamId, consumer: &[u8]){
+        let entry = match self.pending.get_mut(&id) {
+            Some(entry) => entry,
+            None => return
+        };
+        if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+            owner.pending.remove(&id);
+        }
+        entry.consumer = consumer.to_vec();
+        self.consumers.entry(consumer.to_vec()).or_default().pending.insert(id);
+    }
+}
+
+
+// how XCLAIM and XAUTOCLAIM treat the entries they are given
+pub struct ClaimOptions{
+    pub min_idle: u64,
+    pub delivery_time: u64,
+    pub retry_count: Option<u64>,
+    // create the pending entry when the id exists in the stream but nobody has it
+    pub force: bool,
+    // JUSTID leaves the delivery count alone
+    pub justid: bool
+}
+
+pub enum Claimed{
+    Entry(StreamEntry),
+    // the entry is gone from the stream, it was dropped from the PEL too
+    Deleted,
+    // not pending, or not idle for long enough
+    Skipped
+}
+
+
+impl Stream{
+    pub fn groups(&self) -> &BTreeMap<Vec<u8>, ConsumerGroup>{
+        &self.groups
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup>{
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup>{
+        self.groups.get_mut(name)
+    }
+
+    // false when a group by that name already exists
+    pub fn create_group(&mut self, name: &[u8], last_id: StreamId, entries_read: Option<u64>) -> bool{
+        if self.groups.contains_key(name) {return false;}
+        self.groups.insert(name.to_vec(), ConsumerGroup::new(last_id, entries_read));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool{
+        self.groups.remove(name).is_some()
+    }
+
+    // whether an entry at or after `start` was ever deleted
+    fn has_tombstones_from(&self, start: StreamId) -> bool{
+        self.len != 0 && self.max_deleted_id != StreamId::MIN && start <= self.max_deleted_id
+    }
+
+    /* how many entries were added up to and including `id`, as long as that can
+     * be told without walking the stream: the last id, or anything before the
+     * first entry when nothing past the first entry was ever deleted
+     * */
+    fn estimate_entries_read(&self, id: StreamId) -> Option<u64>{
+        if self.entries_added == 0 {return Some(0);}
+        if self.len == 0 && id <= self.last_id {return Some(self.entries_added);}
+        if id == self.last_id {return Some(self.entries_added);}
+        if id > self.last_id {return None;}
+        let first = self.first_id()?;
+        if self.max_deleted_id != StreamId::MIN && self.max_deleted_id >= first {return None;}
+        let before_first = self.entries_added - self.len as u64;
+        match id.cmp(&first) {
+            std::cmp::Ordering::Less => Some(before_first),
+            std::cmp::Ordering::Equal => Some(before_first + 1),
+            std::cmp::Ordering::Greater => None
+        }
+    }
+
+    // entries the group has yet to read, None when that can not be known
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64>{
+        if self.entries_added == 0 {return Some(0);}
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones_from(group.last_id) => read,
+            _ => self.estimate_entries_read(group.last_id)?
+        };
+        Some(self.entries_added.saturating_sub(read))
+    }
+
+    /* XREADGROUP with >, up to `count` entries past the group's last id are handed
+     * to `consumer` and, unless NOACK, added to the PEL. None without such a group
+     * */
+    pub fn read_group(
+        &mut self, group: &[u8], consumer: &[u8], count: usize, noack: bool, now: u64
+    ) -> Option<Vec<StreamEntry>>{
+        let (last_id, mut entries_read) = self.groups.get(group).map(|group| {(group.last_id, group.entries_read)})?;
+        let entries = match last_id.successor() {
+            Some(start) => self.range(start, StreamId::MAX, false, count),
+            None => vec!()
+        };
+        for (id, _) in &entries {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones_from(*id) => Some(read + 1),
+                _ => self.estimate_entries_read(*id)
+            };
+        }
+
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer, now);
+        if let Some((id, _)) = entries.last() {
+            group.last_id = *id;
+            group.entries_read = entries_read;
+            group.touch_consumer(consumer, now).active_time = Some(now);
+        }
+        if !noack {
+            entries.iter().for_each(|(id, _)| {group.deliver(*id, consumer, now)});
+        }
+        Some(entries)
+    }
+
+    /* XREADGROUP with an id, the consumer's own pending entries after it. an entry
+     * deleted from the stream since comes back without fields
+     * */
+    pub fn read_pending(
+        &mut self, group: &[u8], consumer: &[u8], after: StreamId, count: usize, now: u64
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>>{
+        let consumer = self.groups.get_mut(group)?.touch_consumer(consumer, now);
+        let ids = consumer.pending.range((Bound::Excluded(after), Bound::Unbounded))
+                    .take(count)
+                    .copied()
+                    .collect::<Vec<_>>();
+        Some(ids.into_iter().map(|id| {(id, self.get(id).map(|(_, fields)| {fields}))}).collect())
+    }
+
+    // hands the pending entry `id` over to `consumer`, the XCLAIM rules
+    pub fn claim(
+        &mut self, group: &[u8], consumer: &[u8], id: StreamId, options: &ClaimOptions, now: u64
+    ) -> Claimed{
+        let entry = self.get(id);
+        let group = match self.groups.get_mut(group) {
+            Some(group) => group,
+            None => return Claimed::Skipped
+        };
+        group.touch_consumer(consumer, now);
+        let forced = !group.pending.contains_key(&id);
+        if forced {
+            // a forced entry was never delivered, so it has no idle time to check
+            if !options.force || entry.is_none() {return Claimed::Skipped;}
+            group.deliver(id, consumer, now);
+            group.pending.get_mut(&id).unwrap().delivery_count = 0;
+        }
+        // only entries idle long enough are claimed, or dropped when they were deleted meanwhile
+        if !forced && now.saturating_sub(group.pending[&id].delivery_time) < options.min_idle {
+            return Claimed::Skipped;
+        }
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                group.ack(id);
+                return Claimed::Deleted;
+            }
+        };
+        group.set_owner(id, consumer);
+        let pending = group.pending.get_mut(&id).unwrap();
+        pending.delivery_time = options.delivery_time;
+        match options.retry_count {
+            Some(count) => pending.delivery_count = count,
+            None if !options.justid => pending.delivery_count += 1,
+            None => ()
+        }
+        group.touch_consumer(consumer, now).active_time = Some(now);
+        Claimed::Entry(entry)
+    }
+}
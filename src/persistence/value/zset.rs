@@ -0,0 +1,464 @@
+/* sorted set encoding, a skiplist ordered by (score, member) next to a hash
+ * map from member to score, the same layout as redis' zset
+ * the map answers ZSCORE style lookups in O(1), the skiplist keeps the order
+ * and, through the spans stored on every link, the rank of each element.
+ * nodes live in an arena and link to each other by index
+ * */
+
+use crate::persistence::dict::Dict;
+
+
+const MAX_LEVEL: usize = 32;
+// chance of a node reaching the next level
+const LEVEL_P: f64 = 0.25;
+// the header node sits at index 0 and holds no element
+const HEAD: usize = 0;
+
+#[derive(Clone, Debug)]
+struct Level{
+    forward: Option<usize>,
+    // number of elements the link jumps over, used to compute ranks
+    span: usize
+}
+
+#[derive(Clone, Debug)]
+struct Node{
+    member: Vec<u8>,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>
+}
+
+impl Node{
+    fn new(member: Vec<u8>, score: f64, level: usize) -> Self{
+        Self{member, score, backward: None, levels: vec!(Level{forward: None, span: 0}; level)}
+    }
+
+    // whether the node sorts strictly before (score, member)
+    fn precedes(&self, score: f64, member: &[u8]) -> bool{
+        self.score < score || (self.score == score && self.member.as_slice() < member)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SkipList{
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    len: usize,
+    level: usize
+}
+
+impl Default for SkipList{
+    fn default() -> Self{
+        Self{nodes: vec!(Node::new(vec!(), 0.0, MAX_LEVEL)), free: vec!(), tail: None, len: 0, level: 1}
+    }
+}
+
+fn random_level() -> usize{
+    let mut level = 1;
+    while level < MAX_LEVEL && rand::random::<f64>() < LEVEL_P {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList{
+    fn alloc(&mut self, node: Node) -> usize{
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn next(&self, idx: usize, level: usize) -> Option<usize>{
+        self.nodes[idx].levels[level].forward
+    }
+
+    // the last node on every level sorting before (score, member)
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]){
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level {0} else {rank[i+1]};
+            while let Some(next) = self.next(x, i) {
+                if !self.nodes[next].precedes(score, member) {break;}
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    // the element must not be in the list already
+    fn insert(&mut self, score: f64, member: Vec<u8>){
+        let (mut update, mut rank) = self.predecessors(score, &member);
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let x = self.alloc(Node::new(member, score, level));
+        for i in 0..level {
+            let prev = update[i];
+            let prev_span = self.nodes[prev].levels[i].span;
+            self.nodes[x].levels[i] = Level{
+                forward: self.nodes[prev].levels[i].forward,
+                span: prev_span - (rank[0] - rank[i])
+            };
+            self.nodes[prev].levels[i] = Level{forward: Some(x), span: rank[0] - rank[i] + 1};
+        }
+        for (i, prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*prev].levels[i].span += 1;
+        }
+
+        self.nodes[x].backward = (update[0] != HEAD).then_some(update[0]);
+        match self.next(x, 0) {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x)
+        }
+        self.len += 1;
+    }
+
+    fn unlink(&mut self, x: usize, update: &[usize; MAX_LEVEL]){
+        for (i, prev) in update.iter().enumerate().take(self.level) {
+            if self.next(*prev, i) == Some(x) {
+                self.nodes[*prev].levels[i].span += self.nodes[x].levels[i].span;
+                self.nodes[*prev].levels[i].span -= 1;
+                self.nodes[*prev].levels[i].forward = self.nodes[x].levels[i].forward;
+            } else {
+                self.nodes[*prev].levels[i].span -= 1;
+            }
+        }
+        match self.next(x, 0) {
+            Some(next) => self.nodes[next].backward = self.nodes[x].backward,
+            None => self.tail = self.nodes[x].backward
+        }
+        while self.level > 1 && self.next(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+        self.len -= 1;
+        self.nodes[x] = Node::new(vec!(), 0.0, 0);
+        self.free.push(x);
+    }
+
+    fn delete(&mut self, score: f64, member: &[u8]) -> bool{
+        let (update, _) = self.predecessors(score, member);
+        match self.next(update[0], 0) {
+            Some(x) if self.nodes[x].score == score && self.nodes[x].member == member => {
+                self.unlink(x, &update);
+                true
+            },
+            _ => false
+        }
+    }
+
+    // moves an element to its new score, in place when the order does not change
+    fn update_score(&mut self, score: f64, member: &[u8], new_score: f64){
+        let (update, _) = self.predecessors(score, member);
+        let x = match self.next(update[0], 0) {
+            Some(x) if self.nodes[x].member == member => x,
+            _ => return
+        };
+        let after_prev = self.nodes[x].backward.is_none_or(|prev| {self.nodes[prev].precedes(new_score, member)});
+        let before_next = self.next(x, 0).is_none_or(|next| {
+            let next = &self.nodes[next];
+            new_score < next.score || (new_score == next.score && member < next.member.as_slice())
+        });
+        if after_prev && before_next {
+            self.nodes[x].score = new_score;
+            return;
+        }
+        let member = std::mem::take(&mut self.nodes[x].member);
+        self.unlink(x, &update);
+        self.insert(new_score, member);
+    }
+
+    // 1 based rank of an element known to be in the list
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize>{
+        let mut x = HEAD;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                let node = &self.nodes[next];
+                if !(node.precedes(score, member) || (node.score == score && node.member == member)) {break;}
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].member == member {return Some(rank);}
+        }
+        None
+    }
+
+    // node at a 1 based rank
+    fn by_rank(&self, rank: usize) -> Option<usize>{
+        let mut x = HEAD;
+        let mut traversed = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                if traversed + self.nodes[x].levels[i].span > rank {break;}
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == rank {return (x != HEAD).then_some(x);}
+        }
+        None
+    }
+
+    // first node for which `below` is false
+    fn first_not(&self, below: impl Fn(&Node) -> bool) -> Option<usize>{
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                if !below(&self.nodes[next]) {break;}
+                x = next;
+            }
+        }
+        self.next(x, 0)
+    }
+
+    // last node for which `above` is false
+    fn last_not(&self, above: impl Fn(&Node) -> bool) -> Option<usize>{
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                if above(&self.nodes[next]) {break;}
+                x = next;
+            }
+        }
+        (x != HEAD).then_some(x)
+    }
+}
+
+
+// min and max of a ZRANGEBYSCORE style interval, either side may be exclusive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreRange{
+    pub min: f64,
+    pub max: f64,
+    pub min_exclusive: bool,
+    pub max_exclusive: bool
+}
+
+impl ScoreRange{
+    fn above_min(&self, score: f64) -> bool{
+        if self.min_exclusive {score > self.min} else {score >= self.min}
+    }
+
+    fn below_max(&self, score: f64) -> bool{
+        if self.max_exclusive {score < self.max} else {score <= self.max}
+    }
+
+    fn is_empty(&self) -> bool{
+        self.min > self.max || (self.min == self.max && (self.min_exclusive || self.max_exclusive))
+    }
+
+    pub fn contains(&self, score: f64) -> bool{
+        self.above_min(score) && self.below_max(score)
+    }
+}
+
+// one side of a ZRANGEBYLEX interval, - and + stand for the infinities
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LexBound{
+    NegInf,
+    PosInf,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LexRange{
+    pub min: LexBound,
+    pub max: LexBound
+}
+
+impl LexRange{
+    fn above_min(&self, member: &[u8]) -> bool{
+        match &self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_slice(),
+            LexBound::Exclusive(min) => member > min.as_slice()
+        }
+    }
+
+    fn below_max(&self, member: &[u8]) -> bool{
+        match &self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_slice(),
+            LexBound::Exclusive(max) => member < max.as_slice()
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool{
+        self.above_min(member) && self.below_max(member)
+    }
+}
+
+
+pub struct Iter<'a>{
+    list: &'a SkipList,
+    next: Option<usize>,
+    reverse: bool
+}
+
+impl<'a> Iterator for Iter<'a>{
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item>{
+        let node = &self.list.nodes[self.next?];
+        self.next = if self.reverse {node.backward} else {node.levels[0].forward};
+        Some((&node.member, node.score))
+    }
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet{
+    dict: Dict<Vec<u8>, f64>,
+    list: SkipList
+}
+
+impl PartialEq for SortedSet{
+    fn eq(&self, other: &Self) -> bool{
+        self.dict == other.dict
+    }
+}
+
+impl SortedSet{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize{
+        self.dict.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.dict.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64>{
+        self.dict.get(member).copied()
+    }
+
+    // adds the member or moves it to the new score, true if it is new
+    pub fn insert(&mut self, member: &[u8], score: f64) -> bool{
+        match self.dict.get_mut(member) {
+            Some(current) => {
+                if *current != score {
+                    self.list.update_score(*current, member, score);
+                    *current = score;
+                }
+                false
+            },
+            None => {
+                self.dict.insert(member.to_vec(), score);
+                self.list.insert(score, member.to_vec());
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool{
+        match self.dict.remove(member) {
+            Some(score) => self.list.delete(score, member),
+            None => false
+        }
+    }
+
+    // 0 based position counting from the lowest score, or from the highest with `reverse`
+    pub fn rank(&self, member: &[u8], reverse: bool) -> Option<usize>{
+        let rank = self.list.rank(self.score(member)?, member)?;
+        Some(if reverse {self.len() - rank} else {rank - 1})
+    }
+
+    fn iter_from(&self, start: Option<usize>, reverse: bool) -> Iter<'_>{
+        Iter{list: &self.list, next: start, reverse}
+    }
+
+    // every element by ascending score
+    pub fn iter(&self) -> Iter<'_>{
+        self.iter_from(self.list.next(HEAD, 0), false)
+    }
+
+    // one step of ZSCAN, walks the member dict rather than the skiplist, see Dict::scan
+    pub fn scan<'a>(&'a self, cursor: u64, mut visit: impl FnMut(&'a [u8], f64)) -> u64{
+        self.dict.scan(cursor, |member, score| {visit(member, *score)})
+    }
+
+    // elements `start` to `stop` inclusive, both 0 based and already within bounds
+    pub fn range_by_rank(&self, start: usize, stop: usize, reverse: bool) -> impl Iterator<Item = (&[u8], f64)>{
+        let first = match reverse {
+            false => self.list.by_rank(start + 1),
+            true => self.list.by_rank(self.len() - start)
+        };
+        self.iter_from(first, reverse).take(stop + 1 - start)
+    }
+
+    pub fn range_by_score(&self, range: ScoreRange, reverse: bool) -> impl Iterator<Item = (&[u8], f64)>{
+        let first = match (range.is_empty(), reverse) {
+            (true, _) => None,
+            (false, false) => self.list.first_not(|node| {!range.above_min(node.score)}),
+            (false, true) => self.list.last_not(|node| {!range.below_max(node.score)})
+        };
+        self.iter_from(first, reverse).take_while(move |(_, score)| {range.contains(*score)})
+    }
+
+    pub fn range_by_lex<'a>(&'a self, range: &'a LexRange, reverse: bool) -> impl Iterator<Item = (&'a [u8], f64)>{
+        let first = match reverse {
+            false => self.list.first_not(|node| {!range.above_min(&node.member)}),
+            true => self.list.last_not(|node| {!range.below_max(&node.member)})
+        };
+        self.iter_from(first, reverse).take_while(move |(member, _)| {range.contains(member)})
+    }
+
+    // ZCOUNT, done with two rank lookups instead of a walk
+    pub fn count_by_score(&self, range: ScoreRange) -> usize{
+        if range.is_empty() {return 0;}
+        let first = self.list.first_not(|node| {!range.above_min(node.score)});
+        let last = self.list.last_not(|node| {!range.below_max(node.score)});
+        self.count_between(first, last)
+    }
+
+    pub fn count_by_lex(&self, range: &LexRange) -> usize{
+        let first = self.list.first_not(|node| {!range.above_min(&node.member)});
+        let last = self.list.last_not(|node| {!range.below_max(&node.member)});
+        self.count_between(first, last)
+    }
+
+    fn count_between(&self, first: Option<usize>, last: Option<usize>) -> usize{
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (&self.list.nodes[first], &self.list.nodes[last]),
+            _ => return 0
+        };
+        let first_rank = self.list.rank(first.score, &first.member).unwrap_or(0);
+        let last_rank = self.list.rank(last.score, &last.member).unwrap_or(0);
+        (last_rank + 1).saturating_sub(first_rank)
+    }
+
+    // lowest scored element, or the highest with `reverse`
+    pub fn pop(&mut self, reverse: bool) -> Option<(Vec<u8>, f64)>{
+        let (member, score) = match reverse {
+            false => self.iter().next(),
+            true => self.iter_from(self.list.tail, true).next()
+        }.map(|(member, score)| {(member.to_vec(), score)})?;
+        self.remove(&member);
+        Some((member, score))
+    }
+}
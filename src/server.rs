@@ -1,16 +1,34 @@
-use std::collections::{HashMap, VecDeque, HashSet};
-use std::io::{Read, Write};
-use std::net::{TcpStream, SocketAddr, ToSocketAddrs};
+use std::collections::{VecDeque, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, SocketAddr, ToSocketAddrs};
 use std::sync::{mpsc, Mutex, Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use rand::Rng;
 
 
+use bytes::BytesMut;
+
 use crate::{command, parser};
+use crate::command::{TimeUnit, Deadline, Propagate};
+use crate::command::list::{self, End};
+use crate::command::hash::{self, HashPart};
+use crate::command::set::{self, SetOp};
+use crate::command::zset::{self, ZSetOp};
+use crate::command::stream;
+use crate::command::string;
+use crate::command::bitmap;
+use crate::command::hyperloglog;
+use crate::command::geo;
+use crate::error::{RedisError, Result};
 use crate::parser::{
-    RESPObject, SimpleRESPObject, AggrRESPObject, AtomicItem
+    RESPObject, SimpleRESPObject, AggrRESPObject, AtomicItem, ProtocolVersion
 };
+use crate::parser::{RespValue, ToResp};
+use crate::parser::decrypt::{ProtoLimits, StreamDecoder};
+use crate::parser::encrypt::{write_array_header, write_bulk_str};
 use crate::persistence::RedisStorage;
+use crate::persistence::blocking::{self, PeerProbe};
 
 use self::master::nod_replica;
 
@@ -28,25 +46,61 @@ pub struct LaunchConfig{
     pub binding_addr: String,
     pub server_type: ServerType,
     pub replicaof: Option<(String, String)>,
-    pub replica_id: Option<Vec<u8>>
+    pub replica_id: Option<Vec<u8>>,
+    // password of the default user, clients must authenticate when it is set
+    pub requirepass: Option<String>,
+    pub proto_limits: ProtoLimits
 } 
 
 
 
 impl LaunchConfig {
-    fn new() -> Self{
+    pub fn new() -> Self{
         Self{
           binding_addr: String::from("localhost:6379"),
           server_type: ServerType::Master,
           replicaof: None,
-          replica_id: None 
+          replica_id: None,
+          requirepass: None,
+          proto_limits: ProtoLimits::default()
+        }
+    }
+}
+
+
+impl Default for LaunchConfig {
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+
+pub const REDIS_VERSION: &str = "7.4.0";
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// state private to one connection, owned by the thread serving it
+pub struct ClientSession{
+    pub id: u64,
+    pub protocol: ProtocolVersion,
+    pub name: Option<Vec<u8>>,
+    pub authenticated: bool
+}
+
+impl ClientSession{
+    pub fn new(server_state: &LaunchConfig) -> Self{
+        Self{
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: ProtocolVersion::default(),
+            name: None,
+            authenticated: server_state.requirepass.is_none()
         }
     }
 }
 
 // not only stores cmd but also their parameters
 
-type CmdCallback<'a> = Box<dyn Fn(Vec<Vec<String>>) -> Option<Box<[u8]>> + 'a>;
+type CmdCallback<'a> = Box<dyn Fn(Vec<Vec<String>>) -> Option<RespValue> + 'a>;
 pub struct CommandCache<'a>{
     // cache fixed sized command history and invoke callback accordingly
     size: usize,
@@ -61,7 +115,7 @@ pub trait ToCmdCache{
 impl ToCmdCache for &str{
     fn to_vec_string(&self) -> Vec<String>{
         self.to_lowercase().split(' ')
-               .filter(|chunk| {chunk.len() > 0})
+               .filter(|chunk| {!chunk.is_empty()})
                .map(|s| {String::from(s)})
                .collect::<Vec<_>>()
     }
@@ -69,7 +123,14 @@ impl ToCmdCache for &str{
 
 impl ToCmdCache for &[u8]{
     fn to_vec_string(&self) -> Vec<String>{
-        std::str::from_utf8(&self).unwrap().to_vec_string()
+        String::from_utf8_lossy(self).as_ref().to_vec_string()
+    }
+}
+
+// one entry per token, tokens are kept whole even if they contain spaces or binary data
+impl ToCmdCache for &[&[u8]]{
+    fn to_vec_string(&self) -> Vec<String>{
+        self.iter().map(|item| {String::from_utf8_lossy(item).to_lowercase()}).collect::<Vec<_>>()
     }
 }
 
@@ -86,7 +147,7 @@ impl<'a> CommandCache<'a>{
         Self{size, buf: VecDeque::new(), callbacks: Vec::new()}
     }
 
-    pub fn push<T: ToCmdCache>(&mut self, cmd: T) -> Option<Box<[u8]>>{
+    pub fn push<T: ToCmdCache>(&mut self, cmd: T) -> Option<RespValue>{
         if self.buf.len() >= self.size {self.buf.pop_front();}
         self.buf.push_back(cmd.to_vec_string());
 
@@ -133,28 +194,64 @@ pub struct SharedGlobalState{
     pub comm_channels: mpsc::Sender<Box<[u8]>>
 }
 
+const READ_CHUNK_SIZE: usize = 4096;
+
+/* peeks the socket of a client parked in a blocking command, a closed peer reads 0 bytes
+ * the probe only runs while the connection thread waits on its reply channel, so
+ * flipping the shared socket to non blocking for a moment can not upset a read
+ * */
+fn peer_probe(stream: &TcpStream) -> Option<PeerProbe>{
+    let peer = stream.try_clone().ok()?;
+    Some(Box::new(move || {
+        if peer.set_nonblocking(true).is_err() {return false;}
+        let connected = match peer.peek(&mut [0u8; 1]) {
+            Ok(num_readin) => num_readin > 0,
+            Err(e) => matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+        };
+        let _ = peer.set_nonblocking(false);
+        connected
+    }))
+}
+
+// takes the client out of the blocking bookkeeping however the connection ends
+struct PeerGuard<'a>{
+    id: u64,
+    storage: &'a RedisStorage
+}
+
+impl Drop for PeerGuard<'_>{
+    fn drop(&mut self){
+        self.storage.lock().blocked.forget_peer(self.id);
+    }
+}
+
 pub fn serve_one_connection(
     mut stream: TcpStream,
     mut client_state: RedisStorage,
     server_state: LaunchConfig,
     shared_state: SharedGlobalState
 ){
-    let mut buf = vec!(0u8; 2048).into_boxed_slice(); 
+    let mut inbound = BytesMut::with_capacity(READ_CHUNK_SIZE);
+    // replies are encoded in place here, the buffer is reused across batches
+    let mut outbound = BytesMut::with_capacity(READ_CHUNK_SIZE);
+    let mut decoder = StreamDecoder::with_limits(server_state.proto_limits);
+    let mut session = ClientSession::new(&server_state);
     let mut cmd_cache = CommandCache::new(6);
+    if let Some(probe) = peer_probe(&stream) {
+        client_state.lock().blocked.register_peer(session.id, probe);
+    }
+    let storage = client_state.clone();
+    let _peer_guard = PeerGuard{id: session.id, storage: &storage};
 
     
     cmd_cache.register_callback(
         vec!["ping", "replconf listening-port", "replconf capa", "psync"],
         Box::new(
             |cmd_rollback|{
-               println!("callback here!");
-               for i in &cmd_rollback{
-                   println!("{:?}", i);
-               };
-               let slave_port = &cmd_rollback[1].last().unwrap();
+               let slave_port = cmd_rollback[1].last()?;
                let slave_socket_addrs = format!("localhost:{}", slave_port)
-                                            .to_socket_addrs().unwrap()
-                                            .next().unwrap();
+                                            .to_socket_addrs().ok()?
+                                            .next()?;
                let mut locked_slave_hub = shared_state.slave_hub.lock().unwrap();
                locked_slave_hub.insert(slave_socket_addrs);
                Some(nod_replica(&server_state))
@@ -163,110 +260,353 @@ pub fn serve_one_connection(
     );
     
     loop{
-        let num_readin = stream.read(&mut buf).unwrap();
-        let str_wo_escape = std::str::from_utf8(&buf[..num_readin]).unwrap()
-                                .chars().collect::<Vec<_>>(); 
-        
-        println!("#bytes read: {}", num_readin);
-        println!("{:?}", str_wo_escape);
-        while num_readin == 0 {};
-        let client_msg = parser::decrypt::parse_resp(&buf[..num_readin]);
-
-
-        match client_msg {
-            Ok(resp_object) => {
-                let (mut cmd, mut params) = ("none".as_bytes(), vec!());
-                let client_raw_bytes: Vec<&[u8]>;
-                match resp_object {
-                   RESPObject::Simple(simple_object) => {
-                      match simple_object {
-                        SimpleRESPObject::Str(client_msg) => {
-                            (cmd, params) = (client_msg.as_bytes(), vec!());
-                        },
-                        _ => {
-                            (cmd, params) = ("none".as_bytes(), vec!());
-                        }
-                      } 
-                      client_raw_bytes = vec!(cmd);
-                      
-                   },
-                   
-                   RESPObject::Aggregate(aggr_object) => {
-                       match aggr_object {
-                         AggrRESPObject::BulkStr(client_msg) => {
-                           (cmd, params) = (client_msg, vec!());
-                           client_raw_bytes = vec!(cmd);
-                         },
-                         AggrRESPObject::Array(objects_arr) => {
-                             let unpacked_arr = unpack_resp_array(objects_arr);
-                             (cmd, params) = (unpacked_arr[0], (&unpacked_arr[1..]).to_vec());
-                             client_raw_bytes = unpacked_arr;
-                         }
-                       }
-                   }
+        // serve every complete frame already buffered before touching the socket again,
+        // replies of a pipelined batch are flushed together
+        let mut close_after_reply = false;
+        loop {
+            let served = decoder.decode(&mut inbound).and_then(|frame| {
+                match frame {
+                    Some(frame) => serve_one_frame(
+                        &frame, &mut cmd_cache, &mut session, &mut client_state, &server_state, &shared_state
+                    ).map(Some),
+                    None => Ok(None)
                 }
-                // considering push both cmd & params
-                let client_raw_bytes = client_raw_bytes.iter()
-                                            .map(|s|{std::str::from_utf8(s).unwrap()})
-                                            .collect::<Vec<_>>();
-                let callback_ret = cmd_cache.push(client_raw_bytes);
-                if let Some(callback_msg) = callback_ret{
-                    stream.write_all(&callback_msg);
-                }else {
-                    let server_response = command_router(cmd, params, &mut client_state, &server_state);
-
-                    // propagate modification on master to possible slaves by send it to
-                    // dispatching thread
-                    if let ServerType::Master = server_state.server_type{
-                        match cmd {
-                            b"set" => {
-                                let client_msg = buf[..num_readin].to_vec().into_boxed_slice();
-                                shared_state.comm_channels.send(client_msg);
-                            },
-                            _ => {}
-                        }
-                    }
-                    stream.write_all(&server_response);
+            });
+            match served {
+                Ok(Some(Some(reply))) => reply.write_to(&mut outbound, session.protocol),
+                Ok(Some(None)) => {},
+                Ok(None) => break,
+                Err(e) => {
+                    // like redis, a protocol violation is answered and then the client is dropped
+                    close_after_reply = e.is_fatal();
+                    e.to_resp().write_to(&mut outbound, session.protocol);
+                    if close_after_reply {break;}
                 }
             }
-            Err(_) => {}
+        }
+        if !outbound.is_empty() && stream.write_all(&outbound).is_err(){
+            return;
+        }
+        outbound.clear();
+        if close_after_reply {
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        match stream.read(&mut chunk){
+            // peer closed the connection
+            Ok(0) | Err(_) => return,
+            Ok(num_readin) => inbound.extend_from_slice(&chunk[..num_readin])
+        }
+    }
+}
+
+fn serve_one_frame(
+    frame: &[u8],
+    cmd_cache: &mut CommandCache,
+    session: &mut ClientSession,
+    client_state: &mut RedisStorage,
+    server_state: &LaunchConfig,
+    shared_state: &SharedGlobalState
+) -> Result<Option<RespValue>>{
+    let inline_args;
+    let client_raw_bytes = if parser::decrypt::is_inline(frame) {
+        inline_args = parser::decrypt::parse_inline(frame)?;
+        inline_args.iter().map(|arg| {&arg[..]}).collect()
+    }else {
+        match parser::decrypt::parse_resp(frame)? {
+            RESPObject::Simple(simple_object) => match simple_object.as_bytes() {
+                Some(client_msg) => vec!(client_msg),
+                None => return Err(RedisError::Protocol("expected '*' or '$'"))
+            },
+            RESPObject::Aggregate(AggrRESPObject::BulkStr(client_msg)) => vec!(client_msg),
+            RESPObject::Aggregate(AggrRESPObject::Array(objects_arr)) => unpack_resp_array(objects_arr)?,
+            // RESP3 replies are never sent by clients as commands
+            RESPObject::Aggregate(_) => return Err(RedisError::Protocol("expected '*' or '$'"))
+        }
+    };
+    if client_raw_bytes.is_empty() {return Ok(None);}
+    let (cmd, params) = (client_raw_bytes[0], client_raw_bytes[1..].to_vec());
+
+    // considering push both cmd & params
+    if let Some(callback_msg) = cmd_cache.push(&client_raw_bytes[..]){
+        return Ok(Some(callback_msg));
+    }
+    let (server_response, propagate) = command_router(cmd, params, session, client_state, server_state)?;
+
+    // propagate modification on master to possible slaves by send it to
+    // dispatching thread, failed commands already returned above
+    if command::is_write(&String::from_utf8_lossy(cmd).to_lowercase()){
+        let mut commands = match propagate {
+            Propagate::Verbatim => vec!(command::to_args(&client_raw_bytes)),
+            Propagate::Rewritten(commands) => commands
+        };
+        // pops of blocked clients this write served follow it
+        commands.extend(blocking::take_served());
+        if let ServerType::Master = server_state.server_type{
+            for args in commands {
+                // re-encode as a multibulk, inline frames are not valid on the replication link
+                let mut propagated = BytesMut::with_capacity(frame.len());
+                write_array_header(&mut propagated, args.len());
+                args.iter().for_each(|arg| {write_bulk_str(&mut propagated, Some(arg))});
+                let _ = shared_state.comm_channels.send(propagated.to_vec().into_boxed_slice());
+            }
         }
     }
+    Ok(Some(server_response))
 }
 
 fn command_router(
     cmd: &[u8], params: Vec<&[u8]>,
+    session: &mut ClientSession,
     client_state: &mut RedisStorage,
     server_state: &LaunchConfig
-) -> Box<[u8]>{
-   let lowercase_cmd = std::str::from_utf8(cmd).unwrap().to_lowercase();
+) -> Result<(RespValue, Propagate)>{
+   let lowercase_cmd = String::from_utf8_lossy(cmd).to_lowercase();
+   let arity = command::arity(&lowercase_cmd).ok_or_else(|| {RedisError::unknown_command(cmd, &params)})?;
+   let num_args = params.len() as i64 + 1;
+   if (arity > 0 && num_args != arity) || num_args < -arity {
+       return Err(RedisError::wrong_arity(&lowercase_cmd));
+   }
+   if !session.authenticated && !matches!(lowercase_cmd.as_str(), "hello" | "auth") {
+       return Err(RedisError::NoAuth("Authentication required."));
+   }
    match lowercase_cmd.as_str() {
-       "ping" => command::ping(),
+       "ping" => command::ping(params),
        "echo" => command::echo(params[0]),
        "set" => {
-            let mut data = client_state.data.lock().unwrap();
-            command::set(params[0], params[1], &mut data)
+            let mut data = client_state.lock();
+            return command::set(params, &mut data);
         },
        "get" => {
-            let data = client_state.data.lock().unwrap();
-            command::get(params[0], &data)
+            let mut data = client_state.lock();
+            command::get(params[0], &mut data)
+        },
+       "incr" | "decr" | "incrby" | "decrby" => string::incrby(&lowercase_cmd, params, &mut client_state.lock()),
+       "incrbyfloat" => return string::incrbyfloat(params, &mut client_state.lock()),
+       "append" => string::append(params, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "strlen" => string::strlen(params[0], &mut client_state.lock()),
+       "getrange" => string::getrange(params, &mut client_state.lock()),
+       "setrange" => string::setrange(params, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "mget" => string::mget(params, &mut client_state.lock()),
+       "mset" => string::mset(params, false, &mut client_state.lock()),
+       "msetnx" => string::mset(params, true, &mut client_state.lock()),
+       "getdel" => string::getdel(params[0], &mut client_state.lock()),
+       "getex" => return string::getex(params, &mut client_state.lock()),
+       "lcs" => string::lcs(params, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "setbit" => bitmap::setbit(params, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "getbit" => bitmap::getbit(params, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "bitcount" => bitmap::bitcount(params, &mut client_state.lock()),
+       "bitpos" => bitmap::bitpos(params, &mut client_state.lock()),
+       "bitop" => bitmap::bitop(params, &mut client_state.lock()),
+       "bitfield" => bitmap::bitfield(params, false, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "bitfield_ro" => bitmap::bitfield(params, true, server_state.proto_limits.max_bulk_len, &mut client_state.lock()),
+       "pfadd" => hyperloglog::pfadd(params, &mut client_state.lock()),
+       "pfcount" => hyperloglog::pfcount(params, &mut client_state.lock()),
+       "pfmerge" => hyperloglog::pfmerge(params, &mut client_state.lock()),
+       "pfdebug" => hyperloglog::pfdebug(params, &mut client_state.lock()),
+       "geoadd" => geo::geoadd(params, &mut client_state.lock()),
+       "geopos" => geo::geopos(params, session.protocol, &mut client_state.lock()),
+       "geodist" => geo::geodist(params, &mut client_state.lock()),
+       "geohash" => geo::geohash(params, &mut client_state.lock()),
+       "geosearch" => geo::geosearch(params, session.protocol, &mut client_state.lock()),
+       "geosearchstore" => geo::geosearchstore(params, &mut client_state.lock()),
+       "expire" | "pexpire" | "expireat" | "pexpireat" => {
+            let (unit, base) = match lowercase_cmd.as_str() {
+                "expire" => (TimeUnit::Seconds, Deadline::Relative),
+                "pexpire" => (TimeUnit::Milliseconds, Deadline::Relative),
+                "expireat" => (TimeUnit::Seconds, Deadline::Absolute),
+                _ => (TimeUnit::Milliseconds, Deadline::Absolute)
+            };
+            let mut data = client_state.lock();
+            return command::expire(&lowercase_cmd, params, unit, base, &mut data);
+        },
+       "ttl" | "pttl" => {
+            let unit = if lowercase_cmd == "ttl" {TimeUnit::Seconds} else {TimeUnit::Milliseconds};
+            let mut data = client_state.lock();
+            command::ttl(params[0], unit, &mut data)
+        },
+       "expiretime" | "pexpiretime" => {
+            let unit = if lowercase_cmd == "expiretime" {TimeUnit::Seconds} else {TimeUnit::Milliseconds};
+            let mut data = client_state.lock();
+            command::expire_time(params[0], unit, &mut data)
+        },
+       "type" => {
+            let mut data = client_state.lock();
+            command::key_type(params[0], &mut data)
+        },
+       "del" | "unlink" => command::del(params, lowercase_cmd == "unlink", &mut client_state.lock()),
+       "exists" => command::exists(params, &mut client_state.lock()),
+       "touch" => command::touch(params, &mut client_state.lock()),
+       "rename" | "renamenx" => command::rename(params, lowercase_cmd == "renamenx", &mut client_state.lock()),
+       "copy" => command::copy(params, &mut client_state.lock()),
+       "keys" => command::keys(params[0], &mut client_state.lock()),
+       "scan" => command::scan(params, &mut client_state.lock()),
+       "randomkey" => command::randomkey(&mut client_state.lock()),
+       "dbsize" => command::dbsize(&mut client_state.lock()),
+       "flushdb" | "flushall" => command::flush(params, &mut client_state.lock()),
+       "persist" => {
+            let mut data = client_state.lock();
+            command::persist(params[0], &mut data)
+        },
+       "lpush" | "rpush" | "lpushx" | "rpushx" => {
+            let end = if lowercase_cmd.starts_with('l') {End::Left} else {End::Right};
+            let mut data = client_state.lock();
+            list::push(params, end, lowercase_cmd.ends_with('x'), &mut data)
+        },
+       "lpop" | "rpop" => {
+            let end = if lowercase_cmd == "lpop" {End::Left} else {End::Right};
+            let mut data = client_state.lock();
+            list::pop(params, end, &mut data)
+        },
+       "llen" => list::llen(params[0], &mut client_state.lock()),
+       "lrange" => list::lrange(params, &mut client_state.lock()),
+       "lindex" => list::lindex(params, &mut client_state.lock()),
+       "lset" => list::lset(params, &mut client_state.lock()),
+       "linsert" => list::linsert(params, &mut client_state.lock()),
+       "lrem" => list::lrem(params, &mut client_state.lock()),
+       "ltrim" => list::ltrim(params, &mut client_state.lock()),
+       "lpos" => list::lpos(params, &mut client_state.lock()),
+       "lmove" => list::lmove(params, &mut client_state.lock()),
+       "rpoplpush" => list::rpoplpush(params, &mut client_state.lock()),
+       "lmpop" => list::lmpop(params, &mut client_state.lock()),
+       "blpop" | "brpop" => {
+            let end = if lowercase_cmd == "blpop" {End::Left} else {End::Right};
+            return list::blpop(params, end, session.id, client_state);
+        },
+       "blmove" => return list::blmove(params, session.id, client_state),
+       "brpoplpush" => return list::brpoplpush(params, session.id, client_state),
+       "blmpop" => return list::blmpop(params, session.id, client_state),
+       "hset" | "hmset" => hash::hset(&lowercase_cmd, params, &mut client_state.lock()),
+       "hsetnx" => hash::hsetnx(params, &mut client_state.lock()),
+       "hget" => hash::hget(params, &mut client_state.lock()),
+       "hmget" => hash::hmget(params, &mut client_state.lock()),
+       "hdel" => hash::hdel(params, &mut client_state.lock()),
+       "hlen" => hash::hlen(params[0], &mut client_state.lock()),
+       "hstrlen" => hash::hstrlen(params, &mut client_state.lock()),
+       "hexists" => hash::hexists(params, &mut client_state.lock()),
+       "hkeys" => hash::hgetall(params[0], HashPart::Keys, &mut client_state.lock()),
+       "hvals" => hash::hgetall(params[0], HashPart::Values, &mut client_state.lock()),
+       "hgetall" => hash::hgetall(params[0], HashPart::Both, &mut client_state.lock()),
+       "hincrby" => hash::hincrby(params, &mut client_state.lock()),
+       "hincrbyfloat" => return hash::hincrbyfloat(params, &mut client_state.lock()),
+       "hrandfield" => hash::hrandfield(params, &mut client_state.lock()),
+       "hscan" => hash::hscan(params, &mut client_state.lock()),
+       "hexpire" | "hpexpire" | "hexpireat" | "hpexpireat" => {
+            let (unit, base) = match lowercase_cmd.as_str() {
+                "hexpire" => (TimeUnit::Seconds, Deadline::Relative),
+                "hpexpire" => (TimeUnit::Milliseconds, Deadline::Relative),
+                "hexpireat" => (TimeUnit::Seconds, Deadline::Absolute),
+                _ => (TimeUnit::Milliseconds, Deadline::Absolute)
+            };
+            let mut data = client_state.lock();
+            return hash::hexpire(&lowercase_cmd, params, unit, base, &mut data);
+        },
+       "httl" | "hpttl" => {
+            let unit = if lowercase_cmd == "httl" {TimeUnit::Seconds} else {TimeUnit::Milliseconds};
+            hash::httl(params, unit, &mut client_state.lock())
+        },
+       "hexpiretime" | "hpexpiretime" => {
+            let unit = if lowercase_cmd == "hexpiretime" {TimeUnit::Seconds} else {TimeUnit::Milliseconds};
+            hash::hexpiretime(params, unit, &mut client_state.lock())
+        },
+       "hpersist" => hash::hpersist(params, &mut client_state.lock()),
+       "sadd" => set::sadd(params, &mut client_state.lock()),
+       "srem" => set::srem(params, &mut client_state.lock()),
+       "sismember" => set::sismember(params, &mut client_state.lock()),
+       "smismember" => set::smismember(params, &mut client_state.lock()),
+       "smembers" => set::smembers(params[0], &mut client_state.lock()),
+       "scard" => set::scard(params[0], &mut client_state.lock()),
+       "smove" => set::smove(params, &mut client_state.lock()),
+       "spop" => return set::spop(params, &mut client_state.lock()),
+       "srandmember" => set::srandmember(params, &mut client_state.lock()),
+       "sinter" | "sunion" | "sdiff" | "sinterstore" | "sunionstore" | "sdiffstore" => {
+            let op = match &lowercase_cmd[..6] {
+                "sinter" => SetOp::Inter,
+                "sunion" => SetOp::Union,
+                _ => SetOp::Diff
+            };
+            let mut data = client_state.lock();
+            match lowercase_cmd.ends_with("store") {
+                true => set::set_algebra_store(params, op, &mut data),
+                false => set::set_algebra(params, op, &mut data)
+            }
+        },
+       "sintercard" => set::sintercard(params, &mut client_state.lock()),
+       "sscan" => set::sscan(params, &mut client_state.lock()),
+       "zadd" => zset::zadd(params, &mut client_state.lock()),
+       "zincrby" => zset::zincrby(params, &mut client_state.lock()),
+       "zrem" => zset::zrem(params, &mut client_state.lock()),
+       "zcard" => zset::zcard(params[0], &mut client_state.lock()),
+       "zscore" => zset::zscore(params, &mut client_state.lock()),
+       "zmscore" => zset::zmscore(params, &mut client_state.lock()),
+       "zrank" | "zrevrank" => zset::zrank(params, lowercase_cmd == "zrevrank", &mut client_state.lock()),
+       "zcount" => zset::zcount(params, &mut client_state.lock()),
+       "zlexcount" => zset::zlexcount(params, &mut client_state.lock()),
+       "zrange" | "zrevrange" | "zrangebyscore" | "zrevrangebyscore" | "zrangebylex" | "zrevrangebylex" => {
+            let mut data = client_state.lock();
+            zset::zrange(&lowercase_cmd, params, session.protocol, &mut data)
+        },
+       "zrangestore" => zset::zrangestore(params, &mut client_state.lock()),
+       "zremrangebyrank" | "zremrangebyscore" | "zremrangebylex" => {
+            let mut data = client_state.lock();
+            zset::zremrange(&lowercase_cmd, params, &mut data)
         },
-       "info" => command::info(params[0], &server_state),
+       "zunion" | "zinter" | "zdiff" | "zunionstore" | "zinterstore" | "zdiffstore" => {
+            let op = match &lowercase_cmd[..5] {
+                "zunio" => ZSetOp::Union,
+                "zinte" => ZSetOp::Inter,
+                _ => ZSetOp::Diff
+            };
+            let mut data = client_state.lock();
+            match lowercase_cmd.ends_with("store") {
+                true => zset::zcombine_store(&lowercase_cmd, params, op, &mut data),
+                false => zset::zcombine(&lowercase_cmd, params, op, session.protocol, &mut data)
+            }
+        },
+       "zintercard" => zset::zintercard(params, &mut client_state.lock()),
+       "zrandmember" => zset::zrandmember(params, session.protocol, &mut client_state.lock()),
+       "zscan" => zset::zscan(params, &mut client_state.lock()),
+       "zpopmin" | "zpopmax" => {
+            let mut data = client_state.lock();
+            zset::zpop(params, lowercase_cmd == "zpopmax", session.protocol, &mut data)
+        },
+       "zmpop" => zset::zmpop(params, &mut client_state.lock()),
+       "bzpopmin" | "bzpopmax" => return zset::bzpop(params, lowercase_cmd == "bzpopmax", session.id, client_state),
+       "bzmpop" => return zset::bzmpop(params, session.id, client_state),
+       "xadd" => stream::xadd(params, &mut client_state.lock()),
+       "xlen" => stream::xlen(params[0], &mut client_state.lock()),
+       "xrange" | "xrevrange" => stream::xrange(params, lowercase_cmd == "xrevrange", &mut client_state.lock()),
+       "xdel" => stream::xdel(params, &mut client_state.lock()),
+       "xtrim" => stream::xtrim(params, &mut client_state.lock()),
+       "xread" => stream::xread(params, session.protocol, session.id, client_state),
+       "xgroup" => stream::xgroup(params, &mut client_state.lock()),
+       "xreadgroup" => stream::xreadgroup(params, session.protocol, session.id, client_state),
+       "xack" => stream::xack(params, &mut client_state.lock()),
+       "xpending" => stream::xpending(params, &mut client_state.lock()),
+       "xclaim" => stream::xclaim(params, &mut client_state.lock()),
+       "xautoclaim" => stream::xautoclaim(params, &mut client_state.lock()),
+       "xinfo" => stream::xinfo(params, &mut client_state.lock()),
+       "client" => command::client(params, session, client_state),
+       "hello" => command::hello(params, session, server_state),
+       "auth" => command::auth(params, session, server_state),
+       "info" => command::info(params, server_state),
        "replconf" => command::replconf(params),
-       _ => unreachable!() 
-   } 
+       _ => Err(RedisError::unknown_command(cmd, &params))
+   }.map(|reply| {(reply, Propagate::Verbatim)})
 }
 
-fn unpack_resp_array<'a>(object_arr: Vec<AtomicItem<'a>>) -> Vec<&'a [u8]>{
-    object_arr.into_iter().take_while(
-        |item| {
-            match item{
-                AtomicItem::SimpleItem(SimpleRESPObject::Str(_)) => true,
-                AtomicItem::AggrItem(AggrRESPObject::BulkStr(_)) => true,
-                _ => false
-            }
+// a command is an array of bulk strings, anything else nested inside is a protocol violation
+fn unpack_resp_array<'a>(object_arr: Vec<AtomicItem<'a>>) -> Result<Vec<&'a [u8]>>{
+    object_arr.iter().map(|item| {
+        match item {
+            AtomicItem::SimpleItem(SimpleRESPObject::Str(_)) | AtomicItem::AggrItem(AggrRESPObject::BulkStr(_)) => {
+                Ok(item.as_bytes().unwrap())
+            },
+            _ => Err(RedisError::Protocol("expected '$'"))
         }
-    ).map(move |item| {item.as_bytes().unwrap()}).collect()
+    }).collect()
 }
 
 
@@ -276,8 +616,8 @@ pub fn parse_cmd_args() -> LaunchConfig{
     let mut args_iter = args.iter().skip(1);
 
     while let Some(arg) = args_iter.next(){
-        if &arg[..2] == "--" {
-            match &arg[2..]{
+        if let Some(key) = arg.strip_prefix("--") {
+            match key{
                 "port" => {
                     let port_id = args_iter.next().unwrap();
                     config.binding_addr = format!("localhost:{port_id}");
@@ -285,9 +625,24 @@ pub fn parse_cmd_args() -> LaunchConfig{
                 "replicaof" => {
                     let master_ip = args_iter.next().unwrap();
                     let master_port = args_iter.next().unwrap();
-                    config.replicaof = Some((format!("{master_ip}"), format!("{master_port}")));
+                    config.replicaof = Some((master_ip.to_string(), master_port.to_string()));
                     config.server_type = ServerType::Slave;
                 },
+                "requirepass" => {
+                    config.requirepass = Some(args_iter.next().unwrap().to_string());
+                },
+                "proto-max-bulk-len" => {
+                    config.proto_limits.max_bulk_len = parse_memory(args_iter.next().unwrap())
+                                                        .expect("invalid proto-max-bulk-len");
+                },
+                "proto-max-multibulk-len" => {
+                    config.proto_limits.max_multibulk_len = args_iter.next().unwrap().parse()
+                                                        .expect("invalid proto-max-multibulk-len");
+                },
+                "client-query-buffer-limit" => {
+                    config.proto_limits.max_query_buffer = parse_memory(args_iter.next().unwrap())
+                                                        .expect("invalid client-query-buffer-limit");
+                },
                 _ => panic!("unacceptable cmd line key arg")
             }
         }else{
@@ -306,18 +661,33 @@ pub fn parse_cmd_args() -> LaunchConfig{
 }
 
 
+// byte counts as written in redis.conf, e.g. 1024, 64kb, 512mb or 1g
+pub fn parse_memory(spec: &str) -> Option<usize>{
+    let spec = spec.to_ascii_lowercase();
+    let digits_end = spec.find(|c: char| {!c.is_ascii_digit()}).unwrap_or(spec.len());
+    let unit = match &spec[digits_end..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000*1000,
+        "mb" => 1024*1024,
+        "g" => 1000*1000*1000,
+        "gb" => 1024*1024*1024,
+        _ => return None
+    };
+    spec[..digits_end].parse::<usize>().ok()?.checked_mul(unit)
+}
 
 
 
 pub mod master{
-   use crate::parser::encrypt::as_array;
+   use crate::parser::{RespValue, ToResp};
    use super::LaunchConfig;
 
-   pub fn nod_replica(config: &LaunchConfig) -> Box<[u8]>{
-       println!("{:?}", config.replica_id.as_ref().unwrap());
-       let replica_id = std::str::from_utf8(config.replica_id.as_ref().unwrap()).unwrap();
-       let msg = vec!["+FULLRESYNC", replica_id, "0"];
-       as_array(msg)
+   pub fn nod_replica(config: &LaunchConfig) -> RespValue{
+       let replica_id = config.replica_id.as_ref().unwrap();
+       let msg: Vec<&[u8]> = vec![b"+FULLRESYNC", replica_id, b"0"];
+       msg.to_resp()
    }
 
    pub mod service{
@@ -334,7 +704,7 @@ pub mod master{
                let msg = rc.recv().unwrap();
                for slave_addr in &*slave_hub.lock().unwrap(){
                    let mut stream = TcpStream::connect(slave_addr).expect("slave server is not up");
-                   stream.write_all(&msg);
+                   let _ = stream.write_all(&msg);
                }
            }
        }
@@ -344,45 +714,88 @@ pub mod master{
 
 
 pub mod slave{
-    use crate::parser::encrypt::{as_bulk_str, as_array};
-    use crate::parser::decrypt::parse_resp;
+    use crate::parser::encrypt::{write_array_header, write_bulk_str};
+    use crate::parser::decrypt::{parse_resp, StreamDecoder};
+    use crate::parser::{RESPObject, SimpleRESPObject};
 
+    use bytes::{Bytes, BytesMut};
+    use std::io::{Error, ErrorKind, Write, Read};
     use std::net::TcpStream;
-    use std::io::{Write, Read};
     use super::LaunchConfig;
+
+    // block until one whole reply from master is buffered
+    fn read_frame(
+        stream: &mut TcpStream, inbound: &mut BytesMut, decoder: &mut StreamDecoder
+    ) -> Result<Bytes, Error>{
+        let mut chunk = [0u8; 512];
+        loop {
+            let decoded = decoder.decode(inbound).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, e)
+            })?;
+            if let Some(frame) = decoded {return Ok(frame);}
+            let num_readin = stream.read(&mut chunk)?;
+            if num_readin == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            inbound.extend_from_slice(&chunk[..num_readin]);
+        }
+    }
+
+    // overwrite the buffer with one command, sent as an array of bulk strings
+    fn encode_command(outbound: &mut BytesMut, args: &[&str]){
+        outbound.clear();
+        write_array_header(outbound, args.len());
+        args.iter().for_each(|arg| {write_bulk_str(outbound, Some(arg.as_bytes()))});
+    }
+
+    // send one handshake step and fail on an error reply from master
+    fn request(
+        stream: &mut TcpStream, inbound: &mut BytesMut, decoder: &mut StreamDecoder, msg: &[u8]
+    ) -> Result<Bytes, Error>{
+        stream.write_all(msg)?;
+        let frame = read_frame(stream, inbound, decoder)?;
+        match parse_resp(&frame) {
+            Ok(RESPObject::Simple(SimpleRESPObject::Error(reason))) => {
+                Err(Error::other(format!("master refused handshake: {reason}")))
+            },
+            Ok(_) => Ok(frame),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e))
+        }
+    }
     
-    pub fn initiate_replica(config: &mut LaunchConfig) -> Result<(), std::io::Error>{
+    pub fn initiate_replica(config: &mut LaunchConfig) -> Result<(), Error>{
        println!("try to connect to master");
        let (master_ip, master_port) = &config.replicaof.as_ref().unwrap();
        let (_, slave_port) = config.binding_addr.rsplit_once(':').unwrap();
        println!("{}:{}", master_ip, master_port);
        let mut stream = TcpStream::connect(format!("{}:{}", master_ip, master_port))?;
        
-       let mut buf = vec![0u8; 512].into_boxed_slice();
+       let mut inbound = BytesMut::new();
+       let mut outbound = BytesMut::new();
+       let mut decoder = StreamDecoder::new();
        // three way handshake
 
        // first: sending ping -> expecting pong
-       stream.write(&as_bulk_str(Some(b"PING")))?;
-       stream.read(&mut buf)?;
+       encode_command(&mut outbound, &["PING"]);
+       request(&mut stream, &mut inbound, &mut decoder, &outbound)?;
 
        // second: sending $replconf listening-port <port_id>
-       let mut msg = vec!["REPLCONF", "listening-port", slave_port];
-       stream.write(&as_array(msg))?;
-       stream.read(&mut buf)?;
+       encode_command(&mut outbound, &["REPLCONF", "listening-port", slave_port]);
+       request(&mut stream, &mut inbound, &mut decoder, &outbound)?;
 
        // sending $replconfg capa eof capa psync2
-       msg = vec!["REPLCONF", "capa", "eof", "capa", "psync2"];
-       stream.write(&as_array(msg))?;
-       stream.read(&mut buf)?;
+       encode_command(&mut outbound, &["REPLCONF", "capa", "eof", "capa", "psync2"]);
+       request(&mut stream, &mut inbound, &mut decoder, &outbound)?;
 
        // third stage
-       msg = vec!["PSYNC", "-1", "?"];
-       stream.write(&as_array(msg))?;
-       let num_readin = stream.read(&mut buf)?;
-       let decoded_replica_config = parse_resp(&buf[..num_readin]).unwrap();
-       config.replica_id = Some(decoded_replica_config.to_vec().unwrap()[1].to_vec()); 
-       println!("id: {:?}", std::str::from_utf8(config.replica_id.as_ref().unwrap()).unwrap());
+       encode_command(&mut outbound, &["PSYNC", "-1", "?"]);
+       let frame = request(&mut stream, &mut inbound, &mut decoder, &outbound)?;
+       let replica_id = parse_resp(&frame).ok()
+                            .and_then(|reply| {reply.to_vec()})
+                            .and_then(|fields| {fields.get(1).map(|id| {id.to_vec()})})
+                            .ok_or_else(|| {Error::new(ErrorKind::InvalidData, "malformed FULLRESYNC reply")})?;
+       println!("id: {:?}", String::from_utf8_lossy(&replica_id));
+       config.replica_id = Some(replica_id);
        Ok(())
     }
 }
-